@@ -9,8 +9,13 @@ use naga::{
     back::spv, front::wgsl,
     valid::{Capabilities, ValidationFlags, Validator}
 };
-use shaderc::ShaderKind;
-use std::{env, fs, path::Path};
+use shaderc::{OptimizationLevel, ShaderKind, SpirvVersion};
+use std::{collections::HashMap, env, fs, path::Path};
+
+#[path = "src/resources/shader_variant.rs"]
+mod shader_variant;
+
+use shader_variant::{ShaderVariant, declared_keywords};
 
 fn main() -> Result<()> {
     println!("cargo:rerun-if-changed=shaders/*");
@@ -25,53 +30,121 @@ enum ShaderLanguage {
     Wgsl,
 }
 
+/// Debug builds keep names and source lines for graphics debuggers, release builds are optimised
+struct ShaderProfile {
+    optimize: bool,
+    debug_info: bool,
+}
+
+impl ShaderProfile {
+    fn from_env() -> Self {
+        let release = env::var("PROFILE").is_ok_and(|profile| profile == "release");
+        Self {
+            optimize: release,
+            debug_info: !release,
+        }
+    }
+}
+
 fn compile_shaders() -> Result<()> {
     let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR")?;
     let shaders_in_dir = Path::new(&cargo_manifest_dir).join("shaders");
     let shaders_out_dir = Path::new(&cargo_manifest_dir).join("shaders-built");
 
+    let profile = ShaderProfile::from_env();
+
+    let mut paths = Vec::new();
     for entry in fs::read_dir(shaders_in_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-
-        let ext = path
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .ok_or_eyre(format!("Shader file has no extension: {:#?}", path))?;
-
-        let shader_lang = match ext {
-            "vert" => ShaderLanguage::Glsl,
-            "frag" => ShaderLanguage::Glsl,
-            "comp" => ShaderLanguage::Glsl,
-            "wgsl" => ShaderLanguage::Wgsl,
-            _ => return Err(eyre!("Shader language not recognized for file: {:#?}", path)),
-        };
+        paths.push(entry?.path());
+    }
+
+    // All stages of a shader are compiled for the keywords declared by any of them
+    let mut glsl_sources_by_shader = HashMap::<String, Vec<String>>::new();
+    for path in &paths {
+        if let ShaderLanguage::Glsl = shader_language(path)? {
+            glsl_sources_by_shader
+                .entry(file_stem(path)?.to_owned())
+                .or_default()
+                .push(fs::read_to_string(path)?);
+        }
+    }
 
-        let spv_binary = match shader_lang {
-            ShaderLanguage::Glsl => compile_glsl(&path)?,
-            ShaderLanguage::Wgsl => compile_wgsl(&path)?,
+    for path in &paths {
+        let variants = match shader_language(path)? {
+            ShaderLanguage::Glsl => {
+                let sources = &glsl_sources_by_shader[file_stem(path)?];
+                let keywords = declared_keywords(sources)
+                    .map_err(|e| eyre!("{e} in shader {:#?}", path))?;
+                ShaderVariant::all_of(&keywords)
+            }
+            // WGSL has no preprocessor
+            ShaderLanguage::Wgsl => vec![ShaderVariant::default()],
         };
 
-        // Write the SPIR-V binary to a file
-        let shader_name = path
-            .file_name()
-            .ok_or_eyre("Shader file has no name")?
-            .to_str()
-            .ok_or_eyre("Shader file name is not valid UTF-8")?;
-        let output_filepath = shaders_out_dir
-            .join(format!("{}.spv", shader_name));
-        fs::create_dir_all(output_filepath.parent().ok_or_eyre("No parent")?)?;
-        fs::write(output_filepath, bytemuck::cast_slice(&spv_binary))?;
+        for variant in variants {
+            let spv_binary = match shader_language(path)? {
+                ShaderLanguage::Glsl => compile_glsl(path, &variant, &profile)?,
+                ShaderLanguage::Wgsl => compile_wgsl(path, &profile)?,
+            };
+
+            // Write the SPIR-V binary to a file, e.g. `default+ALPHA_TEST.frag.spv`
+            let output_filepath = shaders_out_dir
+                .join(spirv_file_name(path, &variant)?);
+            fs::create_dir_all(output_filepath.parent().ok_or_eyre("No parent")?)?;
+            fs::write(output_filepath, bytemuck::cast_slice(&spv_binary))?;
+        }
     }
 
     Ok(())
 }
 
-fn compile_glsl(filepath: &Path) -> Result<Vec<u32>> {
+fn shader_language(path: &Path) -> Result<ShaderLanguage> {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .ok_or_eyre(format!("Shader file has no extension: {:#?}", path))?;
+
+    match ext {
+        "vert" | "tesc" | "tese" | "geom" | "frag" => Ok(ShaderLanguage::Glsl),
+        "task" | "mesh" => Ok(ShaderLanguage::Glsl),
+        "comp" => Ok(ShaderLanguage::Glsl),
+        "wgsl" => Ok(ShaderLanguage::Wgsl),
+        _ => Err(eyre!("Shader language not recognized for file: {:#?}", path)),
+    }
+}
+
+fn file_stem(path: &Path) -> Result<&str> {
+    path
+        .file_stem()
+        .ok_or_eyre("Shader file has no name")?
+        .to_str()
+        .ok_or_eyre("Shader file name is not valid UTF-8")
+}
+
+fn spirv_file_name(path: &Path, variant: &ShaderVariant) -> Result<String> {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .ok_or_eyre(format!("Shader file has no extension: {:#?}", path))?;
+    Ok(format!("{}.{}.spv", variant.file_stem(file_stem(path)?), ext))
+}
+
+fn compile_glsl(filepath: &Path, variant: &ShaderVariant, profile: &ShaderProfile) -> Result<Vec<u32>> {
     let compiler = shaderc::Compiler::new()
         .ok_or_eyre("Failed to create shaderc compiler")?;
-    let options = shaderc::CompileOptions::new()
+    let mut options = shaderc::CompileOptions::new()
         .ok_or_eyre("Failed to create shaderc compile options")?;
+    for keyword in variant.keywords() {
+        options.add_macro_definition(keyword, Some("1"));
+    }
+    if profile.optimize {
+        options.set_optimization_level(OptimizationLevel::Performance);
+    } else {
+        options.set_optimization_level(OptimizationLevel::Zero);
+    }
+    if profile.debug_info {
+        options.set_generate_debug_info();
+    }
 
     let ext = filepath
         .extension()
@@ -80,8 +153,20 @@ fn compile_glsl(filepath: &Path) -> Result<Vec<u32>> {
 
     let shader_kind = match ext {
         "vert" => ShaderKind::Vertex,
+        "tesc" => ShaderKind::TessControl,
+        "tese" => ShaderKind::TessEvaluation,
+        "geom" => ShaderKind::Geometry,
         "frag" => ShaderKind::Fragment,
         "comp" => ShaderKind::Compute,
+        // `VK_EXT_mesh_shader` needs SPIR-V 1.4
+        "task" => {
+            options.set_target_spirv(SpirvVersion::V1_4);
+            ShaderKind::Task
+        }
+        "mesh" => {
+            options.set_target_spirv(SpirvVersion::V1_4);
+            ShaderKind::Mesh
+        }
         _ => {
             return Err(eyre!("Shader kind not recognized for GLSL file: {:#?}", filepath));
         }
@@ -104,7 +189,8 @@ fn compile_glsl(filepath: &Path) -> Result<Vec<u32>> {
     Ok(artifact.as_binary().to_vec())
 }
 
-fn compile_wgsl(filepath: &Path) -> Result<Vec<u32>> {
+/// Every entry point of the file ends up in the same SPIR-V module
+fn compile_wgsl(filepath: &Path, profile: &ShaderProfile) -> Result<Vec<u32>> {
     // Read the WGSL file and parse into IR
     let source = fs::read_to_string(&filepath)?;
     let module = wgsl::parse_str(&source)?;
@@ -115,5 +201,7 @@ fn compile_wgsl(filepath: &Path) -> Result<Vec<u32>> {
     log::info!("{:#?}", validation_info);
 
     // Generate the SPIR-V binary
-    Ok(spv::write_vec(&module, &validation_info, &spv::Options::default(), None)?)
+    let mut options = spv::Options::default();
+    options.flags.set(spv::WriterFlags::DEBUG, profile.debug_info);
+    Ok(spv::write_vec(&module, &validation_info, &options, None)?)
 }
\ No newline at end of file
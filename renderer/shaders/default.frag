@@ -1,11 +1,13 @@
 #version 450
 #extension GL_EXT_nonuniform_qualifier : require
+#pragma variants ALPHA_TEST
 
 struct PerFrameData {
     mat4 viewproj;
     float near;
     float far;
-    float _padding[2];
+    uvec2 vertex_buffer_address;
+    vec4 frustum_planes[6];
 };
 struct PerMaterialData {
     uint texture_index;
@@ -13,6 +15,8 @@ struct PerMaterialData {
 };
 struct PerObjectData {
     mat4 model;
+    uint joint_offset;
+    uint padding[3];
 };
 
 layout(set = 0, binding = 0) uniform PerFrameBuffer {
@@ -28,15 +32,16 @@ layout(set = 0, binding = 3) uniform sampler samplers[];
 layout(set = 0, binding = 4) uniform texture2D textures[];
 
 layout(push_constant) uniform PerDrawData {
-    uint object_index;
+    // Instances of a draw read consecutive objects from here
+    uint base_object_index;
     uint material_index;
+    uint vertex_offset;
 } per_draw;
 
 layout(location = 0) in vec2 in_texcoord;
 layout(location = 0) out vec4 out_color;
 
 void main() {
-    uint object_index = per_draw.object_index;
     uint material_index = per_draw.material_index;
     uint texture_index = per_material.data[material_index].texture_index;
     uint sampler_index = per_material.data[material_index].sampler_index;
@@ -48,4 +53,10 @@ void main() {
         ),
         in_texcoord
     );
+
+#ifdef ALPHA_TEST
+    if (out_color.a < 0.5) {
+        discard;
+    }
+#endif
 }
\ No newline at end of file
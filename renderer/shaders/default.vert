@@ -1,11 +1,18 @@
 #version 450
 #extension GL_EXT_nonuniform_qualifier : require
+#pragma variants VERTEX_PULLING SKINNING
+
+#ifdef VERTEX_PULLING
+#extension GL_EXT_buffer_reference : require
+#extension GL_EXT_buffer_reference_uvec2 : require
+#endif
 
 struct PerFrameData {
     mat4 viewproj;
     float near;
     float far;
-    float _padding[2];
+    uvec2 vertex_buffer_address;
+    vec4 frustum_planes[6];
 };
 struct PerMaterialData {
     uint texture_index;
@@ -13,6 +20,8 @@ struct PerMaterialData {
 };
 struct PerObjectData {
     mat4 model;
+    uint joint_offset;
+    uint padding[3];
 };
 
 layout(set = 0, binding = 0) uniform PerFrameBuffer {
@@ -26,26 +35,67 @@ layout(set = 0, binding = 2) buffer PerObjectBuffer {
 } per_object;
 layout(set = 0, binding = 3) uniform sampler samplers[];
 layout(set = 0, binding = 4) uniform texture2D textures[];
+layout(set = 0, binding = 8) readonly buffer JointBuffer {
+    mat4 joints[];
+} joint_palettes;
 
 layout(push_constant) uniform PerDrawData {
-    uint object_index;
+    // Instances of a draw read consecutive objects from here
+    uint base_object_index;
     uint material_index;
+    uint vertex_offset;
 } per_draw;
 
+#ifdef VERTEX_PULLING
+// Matches `VertexLayout::pulled`. Float arrays keep the members tightly packed.
+struct PulledVertex {
+    float position[3];
+    float texcoord[2];
+    float normal[3];
+};
+layout(buffer_reference, std430) readonly buffer PulledVertices {
+    PulledVertex vertices[];
+};
+#else
 layout(location = 0) in vec3 in_position;
 layout(location = 1) in vec2 in_texcoord;
+#endif
+
+#ifdef SKINNING
+// Matches `VertexLayout::skinned`, whose vertices are never pulled
+layout(location = 2) in uvec4 in_joints;
+layout(location = 3) in vec4 in_weights;
+#endif
 
 layout(location = 0) out vec2 out_texcoord;
 
 void main() {
-    uint object_index = per_draw.object_index;
+    uint object_index = per_draw.base_object_index + uint(gl_InstanceIndex);
     uint material_index = per_draw.material_index;
     uint texture_index = per_material.data[material_index].texture_index;
     uint sampler_index = per_material.data[material_index].sampler_index;
 
     mat4 model = per_object.data[object_index].model;
+#ifdef SKINNING
+    uint joint_offset = per_object.data[object_index].joint_offset;
+    mat4 skin = in_weights.x * joint_palettes.joints[joint_offset + in_joints.x]
+        + in_weights.y * joint_palettes.joints[joint_offset + in_joints.y]
+        + in_weights.z * joint_palettes.joints[joint_offset + in_joints.z]
+        + in_weights.w * joint_palettes.joints[joint_offset + in_joints.w];
+    model = model * skin;
+#endif
     mat4 viewproj = per_frame.data.viewproj;
 
-    gl_Position = viewproj * model * vec4(0.0, 0.0, 0.0, 1.0);
-    out_texcoord = in_texcoord;
+#ifdef VERTEX_PULLING
+    PulledVertices pulled_vertices = PulledVertices(per_frame.data.vertex_buffer_address);
+    PulledVertex vertex = pulled_vertices.vertices[per_draw.vertex_offset + uint(gl_VertexIndex)];
+    vec3 position = vec3(vertex.position[0], vertex.position[1], vertex.position[2]);
+    vec2 texcoord = vec2(vertex.texcoord[0], vertex.texcoord[1]);
+#else
+    vec3 position = in_position;
+    vec2 texcoord = in_texcoord;
+#endif
+
+    gl_Position = viewproj * model * vec4(position, 1.0);
+    out_texcoord = texcoord;
 }
\ No newline at end of file
@@ -0,0 +1,12 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform textureCube skybox_texture;
+layout(set = 0, binding = 1) uniform sampler skybox_sampler;
+
+layout(location = 0) in vec3 in_direction;
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    vec3 color = texture(samplerCube(skybox_texture, skybox_sampler), in_direction).rgb;
+    out_color = vec4(color, 1.0);
+}
@@ -0,0 +1,35 @@
+#version 450
+
+layout(push_constant) uniform SkyboxDrawData {
+    mat4 viewproj;
+} per_draw;
+
+layout(location = 0) out vec3 out_direction;
+
+const vec3 CUBE_CORNERS[8] = vec3[](
+    vec3(-1.0, -1.0, -1.0),
+    vec3( 1.0, -1.0, -1.0),
+    vec3( 1.0,  1.0, -1.0),
+    vec3(-1.0,  1.0, -1.0),
+    vec3(-1.0, -1.0,  1.0),
+    vec3( 1.0, -1.0,  1.0),
+    vec3( 1.0,  1.0,  1.0),
+    vec3(-1.0,  1.0,  1.0)
+);
+const int CUBE_INDICES[36] = int[](
+    0, 1, 2, 2, 3, 0, // -Z
+    4, 6, 5, 6, 4, 7, // +Z
+    0, 3, 7, 7, 4, 0, // -X
+    1, 5, 6, 6, 2, 1, // +X
+    3, 2, 6, 6, 7, 3, // +Y
+    0, 4, 5, 5, 1, 0  // -Y
+);
+
+void main() {
+    vec3 position = CUBE_CORNERS[CUBE_INDICES[gl_VertexIndex]];
+    out_direction = position;
+
+    // Setting z to w puts every fragment on the far plane, behind the rest of the scene
+    vec4 clip_position = per_draw.viewproj * vec4(position, 1.0);
+    gl_Position = clip_position.xyww;
+}
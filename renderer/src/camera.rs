@@ -1,4 +1,4 @@
-use glam::{Mat4, Vec2, Vec3, Vec4};
+use glam::{Mat3, Mat4, Vec2, Vec3, Vec4};
 use std::f32::consts::PI;
 
 pub struct Camera {
@@ -67,6 +67,13 @@ impl Camera {
         Mat4::look_to_rh(self.position, self.forward, self.up)
     }
 
+    /// View-projection matrix with the translation stripped from the view,
+    /// so the skybox stays centered on the camera no matter where it moves
+    pub fn get_skybox_viewproj_mat(&self, viewport_width: f32, viewport_height: f32) -> Mat4 {
+        let view_rotation = Mat4::from_mat3(Mat3::from_mat4(self.get_view_mat()));
+        self.get_proj_mat(viewport_width, viewport_height) * view_rotation
+    }
+
     pub fn get_proj_mat(&self, viewport_width: f32, viewport_height: f32) -> Mat4 {
         let aspect_ratio = viewport_width / viewport_height;
         Mat4::perspective_rh(
@@ -77,6 +84,14 @@ impl Camera {
         )
     }
 
+    pub fn near(&self) -> f32 {
+        self.near
+    }
+
+    pub fn far(&self) -> f32 {
+        self.far
+    }
+
     pub fn get_pitch(&self) -> f32 {
         calculate_pitch(self.forward)
     }
@@ -0,0 +1,104 @@
+use crate::profiler::GpuProfiling;
+use ash::vk;
+
+/// Format of the color target the scene is rendered into before being copied to the swapchain
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ColorFormat {
+    #[default]
+    Rgba8Srgb,
+    /// HDR target; values above 1.0 are clamped when copied to the swapchain
+    Rgba16Float,
+}
+
+impl ColorFormat {
+    pub(crate) fn vk_format(self) -> vk::Format {
+        match self {
+            ColorFormat::Rgba8Srgb => vk::Format::R8G8B8A8_SRGB,
+            ColorFormat::Rgba16Float => vk::Format::R16G16B16A16_SFLOAT,
+        }
+    }
+}
+
+/// Format of the depth (and optionally stencil) target
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum DepthFormat {
+    #[default]
+    D32,
+    D24S8,
+    D32S8,
+}
+
+impl DepthFormat {
+    pub(crate) fn vk_format(self) -> vk::Format {
+        match self {
+            DepthFormat::D32 => vk::Format::D32_SFLOAT,
+            DepthFormat::D24S8 => vk::Format::D24_UNORM_S8_UINT,
+            DepthFormat::D32S8 => vk::Format::D32_SFLOAT_S8_UINT,
+        }
+    }
+}
+
+/// Number of samples per pixel of the color and depth targets
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Msaa {
+    #[default]
+    Off,
+    X2,
+    X4,
+    X8,
+}
+
+impl Msaa {
+    pub(crate) fn sample_count(self) -> vk::SampleCountFlags {
+        match self {
+            Msaa::Off => vk::SampleCountFlags::TYPE_1,
+            Msaa::X2 => vk::SampleCountFlags::TYPE_2,
+            Msaa::X4 => vk::SampleCountFlags::TYPE_4,
+            Msaa::X8 => vk::SampleCountFlags::TYPE_8,
+        }
+    }
+}
+
+/// Vulkan validation layer options
+#[derive(Clone, Debug)]
+pub struct ValidationConfig {
+    /// On by default in debug builds.
+    /// The `DUNWARD_VALIDATION` environment variable (`on`, `off` or `panic`) takes precedence over this.
+    pub enabled: bool,
+    /// Panic once a validation error has been reported, e.g. to fail render tests in CI
+    pub panic_on_error: bool,
+    /// Message ID names like `VUID-vkCmdDraw-None-08600`, or ID numbers, that are not reported.
+    /// `DUNWARD_VALIDATION_SUPPRESS` can add more as a comma-separated list.
+    pub suppressed_message_ids: Vec<String>,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: cfg!(debug_assertions),
+            panic_on_error: false,
+            suppressed_message_ids: Vec::new(),
+        }
+    }
+}
+
+/// Options that have to be known when the renderer is created
+#[derive(Clone, Debug, Default)]
+pub struct RendererConfig {
+    pub color_format: ColorFormat,
+    pub depth_format: DepthFormat,
+    /// Can be changed later with `Renderer::set_msaa`
+    pub msaa: Msaa,
+    /// Index or part of the name of the GPU to use, instead of the best scoring one.
+    /// The `DUNWARD_GPU` environment variable takes precedence over this.
+    pub gpu: Option<String>,
+    pub gpu_profiling: GpuProfiling,
+    pub validation: ValidationConfig,
+    /// Fetch vertices in the default shader through the vertex megabuffer's device address,
+    /// so every model is drawn with the same pipeline without binding vertex buffers
+    pub vertex_pulling: bool,
+    /// Cull instances against the view frustum in a compute shader on the compute queue,
+    /// which writes the indirect draws of the frame. Ignored without `drawIndirectCount`,
+    /// `multiDrawIndirect` and `drawIndirectFirstInstance` support.
+    pub gpu_culling: bool,
+}
@@ -1,16 +1,22 @@
+use super::super::debug_utils::{DebugLabelScope, DebugUtils};
 use super::super::queue::Queue;
 use super::cmd_encoder_alloc::{CommandEncoderAllocator, CommandEncoderAllocatorExt};
-use crate::resources::texture::Texture;
+use crate::profiler::{GpuProfile, GpuProfiler};
+use crate::resources::texture::{Texture, transition_image_layout};
+use crate::utils::GuardResultExt;
 use ash::vk;
 use color_eyre::Result;
 use color_eyre::eyre::eyre;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 pub(crate) struct CommandEncoder {
     pub command_buffer: vk::CommandBuffer,
     pub queue: Arc<Queue>,
 
     is_recording: bool,
+    /// Only locked while recording, it is a `Mutex` so scopes can be opened through `&self`
+    profiler: Option<Mutex<GpuProfiler>>,
+    debug_utils: DebugUtils,
     device: Arc<ash::Device>,
     /// Note that this is only an `Option` to allow for the allocator to be dropped.
     allocator: Option<CommandEncoderAllocator>,
@@ -20,18 +26,35 @@ impl CommandEncoder {
     pub fn new(
         command_buffer: vk::CommandBuffer,
         queue: Arc<Queue>,
+        debug_utils: DebugUtils,
         device: Arc<ash::Device>,
         allocator: CommandEncoderAllocator,
     ) -> Self {
         Self {
             command_buffer,
             queue,
+            profiler: None,
+            debug_utils,
             device,
             allocator: Some(allocator),
             is_recording: false,
         }
     }
 
+    /// Measure the scopes opened with `scope`, see `take_gpu_profile`
+    pub fn attach_profiler(&mut self, profiler: GpuProfiler) {
+        self.profiler = Some(Mutex::new(profiler));
+    }
+
+    /// GPU timings of the most recent submission that has completed since the last call
+    pub fn take_gpu_profile(&self) -> Result<Option<GpuProfile>> {
+        match &self.profiler {
+            Some(profiler) => Ok(profiler.lock().eyre()?.take_profile()),
+            None => Ok(None),
+        }
+    }
+
+    /// The previous submission of this encoder must have completed
     pub fn begin_recording(&mut self) -> Result<()> {
         if self.is_recording {
             return Err(eyre!("Command buffer is already recording"));
@@ -43,6 +66,9 @@ impl CommandEncoder {
             self.device
                 .begin_command_buffer(self.command_buffer, &begin_info)?;
         }
+        if let Some(profiler) = &self.profiler {
+            profiler.lock().eyre()?.begin_frame(self.command_buffer)?;
+        }
 
         self.is_recording = true;
 
@@ -61,6 +87,28 @@ impl CommandEncoder {
         Ok(())
     }
 
+    /// Label the commands recorded until the returned scope is dropped, e.g. a whole pass
+    pub fn debug_label(&self, name: &str) -> DebugLabelScope<'_> {
+        self.debug_utils.scoped_label(self.command_buffer, name)
+    }
+
+    /// Label the commands recorded until the returned scope is dropped,
+    /// and measure them on the GPU if a profiler is attached
+    pub fn scope(&self, name: &str) -> CommandScope<'_> {
+        let label = self.debug_label(name);
+        if let Some(profiler) = &self.profiler {
+            match profiler.lock() {
+                Ok(mut profiler) => profiler.begin_scope(self.command_buffer, name),
+                Err(e) => log::error!("GPU profiler lock poisoned: {}", e),
+            }
+        }
+        CommandScope {
+            _label: label,
+            profiler: self.profiler.as_ref(),
+            command_buffer: self.command_buffer,
+        }
+    }
+
     pub fn transition_image_layout(
         &self,
         image: &mut Texture,
@@ -70,9 +118,237 @@ impl CommandEncoder {
         image.transition_layout(self.command_buffer, old_layout, new_layout)
     }
 
+    pub fn transition_vkimage_layout(
+        &self,
+        image: vk::Image,
+        aspect: vk::ImageAspectFlags,
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+    ) {
+        transition_image_layout(
+            self.command_buffer,
+            image,
+            aspect,
+            1,
+            old_layout,
+            new_layout,
+            self.device.as_ref(),
+        )
+    }
+
     pub fn copy_texture_to_texture(&self, src: &Texture, dst: &Texture) {
         src.copy_to(dst, self.command_buffer)
     }
+
+    pub fn copy_texture_to_vkimage(&self, src: &Texture, dst: vk::Image, dst_extent: vk::Extent2D) {
+        src.copy_to_vkimage(dst, dst_extent, self.command_buffer)
+    }
+
+    pub fn begin_rendering(&self, rendering_info: &vk::RenderingInfo) {
+        unsafe {
+            self.device
+                .cmd_begin_rendering(self.command_buffer, rendering_info);
+        }
+    }
+
+    pub fn end_rendering(&self) {
+        unsafe {
+            self.device.cmd_end_rendering(self.command_buffer);
+        }
+    }
+
+    /// Covers the whole render area, since the pipelines use dynamic viewport and scissor state
+    pub fn set_viewport_and_scissor(&self, extent: vk::Extent2D) {
+        let viewport = vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: extent.width as f32,
+            height: extent.height as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        };
+        let scissor = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        };
+        unsafe {
+            // The `_with_count` variants are the only ones shader objects accept
+            self.device
+                .cmd_set_viewport_with_count(self.command_buffer, &[viewport]);
+            self.device
+                .cmd_set_scissor_with_count(self.command_buffer, &[scissor]);
+        }
+    }
+
+    pub fn draw(&self, vertex_count: u32, instance_count: u32) {
+        unsafe {
+            self.device
+                .cmd_draw(self.command_buffer, vertex_count, instance_count, 0, 0);
+        }
+    }
+
+    /// `vertex_offset` is added to every index. `first_instance` offsets `gl_InstanceIndex`.
+    pub fn draw_indexed(
+        &self,
+        index_count: u32,
+        instance_count: u32,
+        first_index: u32,
+        vertex_offset: i32,
+        first_instance: u32,
+    ) {
+        unsafe {
+            self.device.cmd_draw_indexed(
+                self.command_buffer,
+                index_count,
+                instance_count,
+                first_index,
+                vertex_offset,
+                first_instance,
+            );
+        }
+    }
+
+    /// Bind `buffer` at each of `offsets`, to consecutive bindings from 0
+    pub fn bind_vertex_buffers(&self, buffer: vk::Buffer, offsets: &[vk::DeviceSize]) {
+        let buffers = vec![buffer; offsets.len()];
+        unsafe {
+            self.device
+                .cmd_bind_vertex_buffers(self.command_buffer, 0, &buffers, offsets);
+        }
+    }
+
+    pub fn bind_index_buffer(&self, buffer: vk::Buffer, index_type: vk::IndexType) {
+        unsafe {
+            self.device
+                .cmd_bind_index_buffer(self.command_buffer, buffer, 0, index_type);
+        }
+    }
+
+    /// Draw with the commands in `buffer` from `offset`, as many as the count in `count_buffer`
+    /// at `count_offset` but at most `max_draw_count`
+    pub fn draw_indexed_indirect_count(
+        &self,
+        buffer: vk::Buffer,
+        offset: vk::DeviceSize,
+        count_buffer: vk::Buffer,
+        count_offset: vk::DeviceSize,
+        max_draw_count: u32,
+    ) {
+        unsafe {
+            self.device.cmd_draw_indexed_indirect_count(
+                self.command_buffer,
+                buffer,
+                offset,
+                count_buffer,
+                count_offset,
+                max_draw_count,
+                size_of::<vk::DrawIndexedIndirectCommand>() as u32,
+            );
+        }
+    }
+
+    pub fn dispatch(&self, group_count_x: u32, group_count_y: u32, group_count_z: u32) {
+        unsafe {
+            self.device.cmd_dispatch(
+                self.command_buffer,
+                group_count_x,
+                group_count_y,
+                group_count_z,
+            );
+        }
+    }
+
+    /// Set `size` bytes of `buffer` from `offset` to repeats of `data`
+    pub fn fill_buffer(
+        &self,
+        buffer: vk::Buffer,
+        offset: vk::DeviceSize,
+        size: vk::DeviceSize,
+        data: u32,
+    ) {
+        unsafe {
+            self.device
+                .cmd_fill_buffer(self.command_buffer, buffer, offset, size, data);
+        }
+    }
+
+    /// Make the memory written by the source stages visible to the destination stages
+    pub fn memory_barrier(
+        &self,
+        src_stage_mask: vk::PipelineStageFlags2,
+        src_access_mask: vk::AccessFlags2,
+        dst_stage_mask: vk::PipelineStageFlags2,
+        dst_access_mask: vk::AccessFlags2,
+    ) {
+        let memory_barriers = [vk::MemoryBarrier2::default()
+            .src_stage_mask(src_stage_mask)
+            .src_access_mask(src_access_mask)
+            .dst_stage_mask(dst_stage_mask)
+            .dst_access_mask(dst_access_mask)];
+        let dependency_info = vk::DependencyInfo::default().memory_barriers(&memory_barriers);
+        unsafe {
+            self.device
+                .cmd_pipeline_barrier2(self.command_buffer, &dependency_info);
+        }
+    }
+
+    pub fn buffer_barriers(&self, barriers: &[vk::BufferMemoryBarrier2]) {
+        let dependency_info = vk::DependencyInfo::default().buffer_memory_barriers(barriers);
+        unsafe {
+            self.device
+                .cmd_pipeline_barrier2(self.command_buffer, &dependency_info);
+        }
+    }
+
+    /// Launch the task (or mesh) workgroups of the bound shader, see `RenderDevice::mesh_shaders`
+    pub fn draw_mesh_tasks(
+        &self,
+        mesh_shaders: &ash::ext::mesh_shader::Device,
+        group_counts: [u32; 3],
+    ) {
+        let [x, y, z] = group_counts;
+        unsafe {
+            mesh_shaders.cmd_draw_mesh_tasks(self.command_buffer, x, y, z);
+        }
+    }
+
+    /// Submit the recorded commands to this encoder's queue
+    pub fn submit(
+        &self,
+        wait_semaphores: &[vk::SemaphoreSubmitInfo],
+        signal_semaphores: &[vk::SemaphoreSubmitInfo],
+        fence: vk::Fence,
+    ) -> Result<()> {
+        if self.is_recording {
+            return Err(eyre!(
+                "Cannot submit a command buffer that is still recording"
+            ));
+        }
+
+        let command_buffer_infos =
+            [vk::CommandBufferSubmitInfo::default().command_buffer(self.command_buffer)];
+        let submit_info = vk::SubmitInfo2::default()
+            .wait_semaphore_infos(wait_semaphores)
+            .command_buffer_infos(&command_buffer_infos)
+            .signal_semaphore_infos(signal_semaphores);
+        self.queue.submit2(&[submit_info], fence)
+    }
+}
+
+/// Ends its label and profiler scope when dropped, see `CommandEncoder::scope`
+pub(crate) struct CommandScope<'a> {
+    /// Dropped after the profiler scope ends, since it began before it
+    _label: DebugLabelScope<'a>,
+    profiler: Option<&'a Mutex<GpuProfiler>>,
+    command_buffer: vk::CommandBuffer,
+}
+
+impl Drop for CommandScope<'_> {
+    fn drop(&mut self) {
+        if let Some(Ok(mut profiler)) = self.profiler.map(Mutex::lock) {
+            profiler.end_scope(self.command_buffer);
+        }
+    }
 }
 
 impl Drop for CommandEncoder {
@@ -1,5 +1,6 @@
 use super::super::queue::{Queue, QueueFamily};
 use super::cmd_encoder::CommandEncoder;
+use crate::context::debug_utils::DebugUtils;
 use ash::vk;
 use color_eyre::Result;
 use color_eyre::eyre::OptionExt;
@@ -17,9 +18,9 @@ impl Clone for CommandEncoderAllocator {
 }
 
 pub(crate) trait CommandEncoderAllocatorExt<A> {
-    fn new(device: Arc<ash::Device>) -> Result<A>;
+    fn new(device: Arc<ash::Device>, debug_utils: DebugUtils) -> Result<A>;
     /// Note that this is mutably borrowed to force the allocator to be used in a single-threaded context.
-    fn allocate(&mut self, queue: Arc<Queue>) -> Result<CommandEncoder>;
+    fn allocate(&mut self, queue: Arc<Queue>, name: &str) -> Result<CommandEncoder>;
     /// Note that this is mutably borrowed to force the allocator to be used in a single-threaded context.
     fn deallocate(&mut self, command_encoder: &CommandEncoder) -> Result<()>;
 }
@@ -27,22 +28,24 @@ pub(crate) trait CommandEncoderAllocatorExt<A> {
 struct CommandEncoderAllocatorInner {
     command_pools: HashMap<QueueFamily, vk::CommandPool>,
     allocated_command_buffers: HashMap<QueueFamily, Vec<vk::CommandBuffer>>,
+    debug_utils: DebugUtils,
     device: Arc<ash::Device>,
 }
 
 impl CommandEncoderAllocatorExt<CommandEncoderAllocator> for CommandEncoderAllocator {
-    fn new(device: Arc<ash::Device>) -> Result<CommandEncoderAllocator> {
+    fn new(device: Arc<ash::Device>, debug_utils: DebugUtils) -> Result<CommandEncoderAllocator> {
         Ok(CommandEncoderAllocator(Arc::new(Mutex::new(
             CommandEncoderAllocatorInner {
                 command_pools: HashMap::new(),
                 allocated_command_buffers: HashMap::new(),
+                debug_utils,
                 device,
             },
         ))))
     }
 
-    fn allocate(&mut self, queue: Arc<Queue>) -> Result<CommandEncoder> {
-        let (command_buffer, device) = {
+    fn allocate(&mut self, queue: Arc<Queue>, name: &str) -> Result<CommandEncoder> {
+        let (command_buffer, debug_utils, device) = {
             let mut guard = self.0.lock().map_err(|e| eyre!(e.to_string()))?;
 
             let device = guard.device.clone();
@@ -67,6 +70,7 @@ impl CommandEncoderAllocatorExt<CommandEncoderAllocator> for CommandEncoderAlloc
                     .device
                     .allocate_command_buffers(&command_buffer_info)?[0]
             };
+            guard.debug_utils.set_object_name(command_buffer, name);
 
             guard
                 .allocated_command_buffers
@@ -74,10 +78,11 @@ impl CommandEncoderAllocatorExt<CommandEncoderAllocator> for CommandEncoderAlloc
                 .or_insert_with(Vec::new)
                 .push(command_buffer);
 
-            (command_buffer, device)
+            (command_buffer, guard.debug_utils.clone(), device)
         };
 
-        let command_encoder = CommandEncoder::new(command_buffer, queue, device, self.clone());
+        let command_encoder =
+            CommandEncoder::new(command_buffer, queue, debug_utils, device, self.clone());
 
         Ok(command_encoder)
     }
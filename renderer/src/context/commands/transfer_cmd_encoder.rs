@@ -1,3 +1,4 @@
+use super::super::debug_utils::DebugUtils;
 use super::super::queue::Queue;
 use ash::vk;
 use color_eyre::eyre::Result;
@@ -27,7 +28,12 @@ pub(crate) struct TransferCommandEncoder {
 }
 
 impl TransferCommandEncoder {
-    pub fn new(transfer_queue: Arc<Queue>, device: Arc<ash::Device>) -> Result<Self> {
+    pub fn new(
+        name: &str,
+        transfer_queue: Arc<Queue>,
+        debug_utils: &DebugUtils,
+        device: Arc<ash::Device>,
+    ) -> Result<Self> {
         let transfer_fence_info = vk::FenceCreateInfo::default();
         let transfer_fence = unsafe { device.create_fence(&transfer_fence_info, None)? };
 
@@ -42,6 +48,8 @@ impl TransferCommandEncoder {
             .command_buffer_count(1)
             .level(vk::CommandBufferLevel::PRIMARY);
         let command_buffer = unsafe { device.allocate_command_buffers(&command_buffer_info)?[0] };
+        debug_utils.set_object_name(command_buffer, name);
+        debug_utils.set_object_name(transfer_fence, &format!("{name} fence"));
 
         Ok(Self {
             transfer_fence,
@@ -76,16 +84,10 @@ impl TransferCommandEncoder {
         }
 
         // Submit command buffer to the queue and execute it
-        let cmd = [cmd];
-        let submit = vk::SubmitInfo::default()
-            .wait_semaphores(&[])
-            .wait_dst_stage_mask(&[])
-            .command_buffers(&cmd)
-            .signal_semaphores(&[]);
-        unsafe {
-            self.device
-                .queue_submit(self.transfer_queue.handle, &[submit], self.transfer_fence)?;
-        }
+        let command_buffer_infos = [vk::CommandBufferSubmitInfo::default().command_buffer(cmd)];
+        let submit = vk::SubmitInfo2::default().command_buffer_infos(&command_buffer_infos);
+        self.transfer_queue
+            .submit2(&[submit], self.transfer_fence)?;
 
         unsafe {
             // `transfer_fence` will now block until the commands finish execution
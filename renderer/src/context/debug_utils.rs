@@ -0,0 +1,73 @@
+use super::instance::RenderInstance;
+use ash::vk;
+use std::ffi::CString;
+
+/// Names Vulkan objects and labels regions of command buffers through `VK_EXT_debug_utils`,
+/// so validation messages and captures in tools like RenderDoc are readable.
+///
+/// The extension is only enabled along with validation, otherwise this does nothing.
+#[derive(Clone)]
+pub(crate) struct DebugUtils {
+    loader: Option<ash::ext::debug_utils::Device>,
+}
+
+impl DebugUtils {
+    pub fn new(instance: &RenderInstance, device: &ash::Device) -> Self {
+        let loader = instance
+            .debug_utils_enabled()
+            .then(|| ash::ext::debug_utils::Device::new(instance.inner(), device));
+        Self { loader }
+    }
+
+    pub fn set_object_name<H: vk::Handle>(&self, handle: H, name: &str) {
+        let Some(loader) = &self.loader else {
+            return;
+        };
+
+        let name = debug_name(name);
+        let name_info = vk::DebugUtilsObjectNameInfoEXT::default()
+            .object_handle(handle)
+            .object_name(&name);
+        if let Err(e) = unsafe { loader.set_debug_utils_object_name(&name_info) } {
+            log::warn!("Failed to name Vulkan object {:?}: {}", name, e);
+        }
+    }
+
+    /// Label the commands recorded into `command_buffer` until the returned scope is dropped.
+    /// Scopes can be nested, but must be dropped before the command buffer stops recording.
+    pub fn scoped_label(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        name: &str,
+    ) -> DebugLabelScope<'_> {
+        if let Some(loader) = &self.loader {
+            let name = debug_name(name);
+            let label = vk::DebugUtilsLabelEXT::default().label_name(&name);
+            unsafe { loader.cmd_begin_debug_utils_label(command_buffer, &label) };
+        }
+
+        DebugLabelScope {
+            loader: self.loader.as_ref(),
+            command_buffer,
+        }
+    }
+}
+
+/// Ends its command buffer label when dropped, see `DebugUtils::scoped_label`
+pub(crate) struct DebugLabelScope<'a> {
+    loader: Option<&'a ash::ext::debug_utils::Device>,
+    command_buffer: vk::CommandBuffer,
+}
+
+impl Drop for DebugLabelScope<'_> {
+    fn drop(&mut self) {
+        if let Some(loader) = self.loader {
+            unsafe { loader.cmd_end_debug_utils_label(self.command_buffer) };
+        }
+    }
+}
+
+/// Names are only ever used for debugging, so interior NUL bytes are dropped rather than reported
+fn debug_name(name: &str) -> CString {
+    CString::new(name.replace('\0', "")).expect("NUL bytes were removed from the debug name")
+}
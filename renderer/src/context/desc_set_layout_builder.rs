@@ -1,6 +1,16 @@
 use ash::vk;
 use color_eyre::Result;
 
+/// A binding as declared by a descriptor set layout or by a shader
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct DescriptorBinding {
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    /// `None` for runtime-sized arrays in shaders, layouts always have a count
+    pub count: Option<u32>,
+    pub stages: vk::ShaderStageFlags,
+}
+
 pub struct DescriptorSetLayoutBuilder<'a> {
     bindings: Vec<vk::DescriptorSetLayoutBinding<'a>>,
     binding_flags: Vec<vk::DescriptorBindingFlags>,
@@ -37,6 +47,19 @@ impl DescriptorSetLayoutBuilder<'_> {
         self
     }
 
+    /// The bindings added so far, to check shaders against the layout
+    pub fn describe(&self) -> Vec<DescriptorBinding> {
+        self.bindings
+            .iter()
+            .map(|binding| DescriptorBinding {
+                binding: binding.binding,
+                descriptor_type: binding.descriptor_type,
+                count: Some(binding.descriptor_count),
+                stages: binding.stage_flags,
+            })
+            .collect()
+    }
+
     pub fn build(
         mut self,
         flags: vk::DescriptorSetLayoutCreateFlags,
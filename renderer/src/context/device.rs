@@ -1,23 +1,42 @@
 use super::{
     commands::CommandEncoderAllocator,
     commands::{CommandEncoderAllocatorExt, TransferCommandEncoder},
+    debug_utils::DebugUtils,
+    device_selection::{
+        GPU_OVERRIDE_ENV, GpuOverride, QueueFamilyIndices, missing_required_features, score_device,
+    },
     instance::RenderInstance,
-    queue::{Queue, QueueFamily},
+    pipeline_cache::PipelineCache,
+    queue::{Queue, QueueFamily, assign_queue_indices, queue_counts},
 };
+use crate::config::RendererConfig;
 use crate::context::commands::CommandEncoder;
+use crate::profiler::{GpuProfiler, GpuProfiling};
+use crate::resources::material::GraphicsMaterialFactoryBuilder;
+use crate::resources::render_target::{RenderTargetFormats, format_has_stencil};
 use crate::resources::resource_type::RenderResourceType;
-use crate::resources::texture::{ColorTexture, DepthTexture, StorageTexture};
+use crate::resources::shader_object::ShaderObjectBackend;
+use crate::resources::texture::{ColorTexture, CubemapTexture, DepthTexture, StorageTexture};
 use crate::resources::{
     megabuffer::{Megabuffer, MegabufferExt},
     texture::Texture,
 };
 use ash::vk;
-use color_eyre::{Result, eyre::OptionExt};
+use color_eyre::Result;
+use color_eyre::eyre::{OptionExt, eyre};
 use gpu_descriptor::DescriptorAllocator;
 use std::ffi::{CStr, c_char};
-use std::str::Utf8Error;
 use std::sync::{Arc, Mutex};
 
+/// Optional features enabled because the device supports them
+struct OptionalFeatures {
+    core: vk::PhysicalDeviceFeatures,
+    shader_object: bool,
+    task_shader: bool,
+    mesh_shader: bool,
+    multi_draw_indirect_count: bool,
+}
+
 /// Main way to submit rendering commands to the GPU.
 pub(crate) struct RenderDevice {
     pub logical: Arc<ash::Device>,
@@ -32,47 +51,118 @@ pub(crate) struct RenderDevice {
         Arc<Mutex<DescriptorAllocator<vk::DescriptorPool, vk::DescriptorSet>>>,
     pub memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
     pub command_encoder_allocator: CommandEncoderAllocator,
+    pub pipeline_cache: PipelineCache,
+    /// `None` when `VK_EXT_shader_object` is unsupported, in which case materials use pipelines
+    pub shader_objects: Option<ShaderObjectBackend>,
+    /// `None` when `VK_EXT_mesh_shader` is unsupported, in which case shaders cannot have
+    /// task or mesh stages
+    pub mesh_shaders: Option<ash::ext::mesh_shader::Device>,
+    /// Whether `vkCmdDrawIndexedIndirectCount` can draw many commands, each from its own first
+    /// instance, which GPU culling needs
+    pub multi_draw_indirect_count: bool,
+    pub debug_utils: DebugUtils,
 
     pub transfer: Arc<TransferCommandEncoder>,
+    /// Same as `transfer`, but submits to the graphics queue for one-off work on resources the
+    /// frames sample, which then stay owned by its family
+    pub graphics: Arc<TransferCommandEncoder>,
 }
 
 impl RenderDevice {
     pub fn new(
         instance: &RenderInstance,
         surface: Option<(&vk::SurfaceKHR, &ash::khr::surface::Instance)>,
+        config: &RendererConfig,
     ) -> Result<Self> {
+        let gpu_override = GpuOverride::from_env_or_config(config.gpu.as_deref());
         let (physical_device, graphics_queue_family, compute_queue_family, transfer_queue_family) =
-            Self::select_physical_device(instance.inner(), surface)?;
-
-        let (logical_device, graphics_queue, compute_queue, transfer_queue) =
-            Self::create_logical_device(
-                instance.inner(),
-                &physical_device,
-                graphics_queue_family,
-                compute_queue_family,
-                transfer_queue_family,
-            )?;
+            Self::select_physical_device(instance.inner(), surface, gpu_override.as_ref())?;
+
+        let queue_indices = assign_queue_indices(&[
+            &graphics_queue_family,
+            &compute_queue_family,
+            &transfer_queue_family,
+        ]);
+        let (logical_device, optional_features) = Self::create_logical_device(
+            instance.inner(),
+            &physical_device,
+            &[
+                &graphics_queue_family,
+                &compute_queue_family,
+                &transfer_queue_family,
+            ],
+            &queue_indices,
+        )?;
 
         let memory_allocator = unsafe {
-            vk_mem::Allocator::new(vk_mem::AllocatorCreateInfo::new(
+            let mut allocator_info = vk_mem::AllocatorCreateInfo::new(
                 instance.inner(),
                 &logical_device,
                 physical_device,
-            ))?
+            );
+            // Buffers created with `SHADER_DEVICE_ADDRESS` usage need memory allocated for it
+            allocator_info.flags = vk_mem::AllocatorCreateFlags::BUFFER_DEVICE_ADDRESS;
+            allocator_info.vulkan_api_version = vk::API_VERSION_1_3;
+            vk_mem::Allocator::new(allocator_info)?
         };
 
-        let logical_device = Arc::new(logical_device);
-        let graphics_queue = Arc::new(graphics_queue);
-        let compute_queue = Arc::new(compute_queue);
-        let transfer_queue = Arc::new(transfer_queue);
+        let shader_objects = optional_features
+            .shader_object
+            .then(|| ShaderObjectBackend {
+                loader: ash::ext::shader_object::Device::new(instance.inner(), &logical_device),
+                features: optional_features.core,
+                task_shader: optional_features.task_shader,
+                mesh_shader: optional_features.mesh_shader,
+            });
+        log::info!(
+            "Using {} for materials",
+            if shader_objects.is_some() {
+                "shader objects"
+            } else {
+                "pipelines"
+            }
+        );
+        let mesh_shaders = optional_features
+            .mesh_shader
+            .then(|| ash::ext::mesh_shader::Device::new(instance.inner(), &logical_device));
+        if mesh_shaders.is_some() {
+            log::info!("Mesh shaders are supported");
+        }
+        let multi_draw_indirect_count = optional_features.multi_draw_indirect_count;
 
-        let command_encoder_allocator = CommandEncoderAllocator::new(logical_device.clone())?;
+        let debug_utils = DebugUtils::new(instance, &logical_device);
+
+        let logical_device = Arc::new(logical_device);
+        let [graphics_queue, compute_queue, transfer_queue] = Queue::get_for_roles(
+            &logical_device,
+            [
+                (graphics_queue_family, queue_indices[0]),
+                (compute_queue_family, queue_indices[1]),
+                (transfer_queue_family, queue_indices[2]),
+            ],
+        );
+
+        let command_encoder_allocator =
+            CommandEncoderAllocator::new(logical_device.clone(), debug_utils.clone())?;
+        let pipeline_cache =
+            PipelineCache::new(instance.inner(), physical_device, logical_device.clone())?;
         let descriptor_allocator: DescriptorAllocator<vk::DescriptorPool, vk::DescriptorSet> =
             DescriptorAllocator::new(
                 RenderResourceType::max_update_after_bind_descriptors_in_all_pools(),
             );
 
-        let transfer = TransferCommandEncoder::new(transfer_queue.clone(), logical_device.clone())?;
+        let transfer = TransferCommandEncoder::new(
+            "Immediate transfer",
+            transfer_queue.clone(),
+            &debug_utils,
+            logical_device.clone(),
+        )?;
+        let graphics = TransferCommandEncoder::new(
+            "Immediate graphics",
+            graphics_queue.clone(),
+            &debug_utils,
+            logical_device.clone(),
+        )?;
 
         let dev = Self {
             logical: logical_device,
@@ -85,8 +175,14 @@ impl RenderDevice {
             descriptor_allocator: Arc::new(Mutex::new(descriptor_allocator)),
             memory_allocator: Arc::new(Mutex::new(memory_allocator)),
             command_encoder_allocator,
+            pipeline_cache,
+            shader_objects,
+            mesh_shaders,
+            multi_draw_indirect_count,
+            debug_utils,
 
             transfer: Arc::new(transfer),
+            graphics: Arc::new(graphics),
         };
 
         Ok(dev)
@@ -99,16 +195,41 @@ impl RenderDevice {
         self.transfer.immediate_submit(func)
     }
 
+    pub fn immediate_graphics_submit<F>(&self, func: F) -> Result<()>
+    where
+        F: FnOnce(vk::CommandBuffer, &ash::Device) -> Result<()>,
+    {
+        self.graphics.immediate_submit(func)
+    }
+
+    /// Builds shader objects when the device supports them and pipelines otherwise
+    pub fn create_graphics_material_factory_builder(&self) -> GraphicsMaterialFactoryBuilder {
+        let builder = GraphicsMaterialFactoryBuilder::new(
+            self.logical.clone(),
+            self.descriptor_allocator.clone(),
+            self.pipeline_cache.handle,
+            self.debug_utils.clone(),
+        )
+        .with_mesh_shaders_supported(self.mesh_shaders.is_some());
+        match &self.shader_objects {
+            Some(backend) => builder.with_shader_objects(backend.clone()),
+            None => builder,
+        }
+    }
+
     pub fn create_megabuffer(
         &self,
+        name: &str,
         size: u64,
         alignment: u64,
         buf_usage: vk::BufferUsageFlags,
     ) -> Result<Megabuffer> {
         Megabuffer::new(
+            name,
             size,
             alignment,
             buf_usage,
+            &self.debug_utils,
             self.memory_allocator.clone(),
             self.logical.clone(),
             self.transfer.clone(),
@@ -117,26 +238,123 @@ impl RenderDevice {
 
     pub fn create_color_texture(
         &self,
+        name: &str,
         width: u32,
         height: u32,
         data: Option<&[u8]>,
         use_dedicated_memory: bool,
     ) -> Result<ColorTexture> {
         Texture::new_color_texture_from_bytes(
+            name,
             width,
             height,
             data,
             use_dedicated_memory,
+            &self.debug_utils,
             self.memory_allocator.clone(),
             self.logical.clone(),
             &self.transfer.clone(),
         )
     }
 
-    pub fn create_depth_texture(&self, width: u32, height: u32) -> Result<DepthTexture> {
+    /// Uploaded on the graphics queue, which converts it to a cubemap
+    pub fn create_hdr_texture(
+        &self,
+        name: &str,
+        image: &image::DynamicImage,
+    ) -> Result<ColorTexture> {
+        Texture::new_hdr_texture_from_image(
+            name,
+            image,
+            &self.debug_utils,
+            self.memory_allocator.clone(),
+            self.logical.clone(),
+            &self.graphics.clone(),
+        )
+    }
+
+    pub fn create_draw_color_texture(
+        &self,
+        name: &str,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+    ) -> Result<ColorTexture> {
+        Texture::new_draw_color_texture(
+            name,
+            width,
+            height,
+            format,
+            &self.debug_utils,
+            self.memory_allocator.clone(),
+            self.logical.clone(),
+        )
+    }
+
+    pub fn create_msaa_color_texture(
+        &self,
+        name: &str,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        samples: vk::SampleCountFlags,
+    ) -> Result<ColorTexture> {
+        Texture::new_msaa_color_texture(
+            name,
+            width,
+            height,
+            format,
+            samples,
+            &self.debug_utils,
+            self.memory_allocator.clone(),
+            self.logical.clone(),
+        )
+    }
+
+    pub fn create_cubemap_texture(
+        &self,
+        name: &str,
+        faces: &[image::DynamicImage; 6],
+    ) -> Result<CubemapTexture> {
+        Texture::new_cubemap_texture_from_faces(
+            name,
+            faces,
+            &self.debug_utils,
+            self.memory_allocator.clone(),
+            self.logical.clone(),
+            &self.transfer.clone(),
+        )
+    }
+
+    pub fn create_storage_cubemap_texture(
+        &self,
+        name: &str,
+        face_size: u32,
+    ) -> Result<CubemapTexture> {
+        Texture::new_storage_cubemap_texture(
+            name,
+            face_size,
+            &self.debug_utils,
+            self.memory_allocator.clone(),
+            self.logical.clone(),
+        )
+    }
+
+    pub fn create_depth_texture(
+        &self,
+        name: &str,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        samples: vk::SampleCountFlags,
+    ) -> Result<DepthTexture> {
         Texture::new_depth_texture(
+            name,
             width,
             height,
+            format,
+            samples,
+            &self.debug_utils,
             self.memory_allocator.clone(),
             self.logical.clone(),
         )
@@ -144,21 +362,174 @@ impl RenderDevice {
 
     pub fn create_storage_texture(
         &self,
+        name: &str,
         width: u32,
         height: u32,
         use_dedicated_memory: bool,
     ) -> Result<StorageTexture> {
         Texture::new_storage_texture(
+            name,
             width,
             height,
             use_dedicated_memory,
+            &self.debug_utils,
             self.memory_allocator.clone(),
             self.logical.clone(),
         )
     }
 
-    pub fn allocate_command_encoder(&mut self, queue: Arc<Queue>) -> Result<CommandEncoder> {
-        self.command_encoder_allocator.allocate(queue)
+    /// Pick the render target formats closest to `config` that this device supports.
+    /// Depth formats fall back to one with the same components and MSAA falls back to fewer samples.
+    pub fn select_render_target_formats(
+        &self,
+        instance: &ash::Instance,
+        config: &RendererConfig,
+    ) -> Result<RenderTargetFormats> {
+        let supports = |format: vk::Format, features: vk::FormatFeatureFlags| unsafe {
+            instance
+                .get_physical_device_format_properties(self.physical, format)
+                .optimal_tiling_features
+                .contains(features)
+        };
+
+        let color = config.color_format.vk_format();
+        if !supports(
+            color,
+            vk::FormatFeatureFlags::COLOR_ATTACHMENT | vk::FormatFeatureFlags::BLIT_SRC,
+        ) {
+            return Err(eyre!(
+                "Color format {:?} cannot be used as a render target on this device",
+                color
+            ));
+        }
+
+        let requested_depth = config.depth_format.vk_format();
+        let depth_candidates: &[vk::Format] = if format_has_stencil(requested_depth) {
+            &[
+                requested_depth,
+                vk::Format::D32_SFLOAT_S8_UINT,
+                vk::Format::D24_UNORM_S8_UINT,
+            ]
+        } else {
+            &[requested_depth, vk::Format::D32_SFLOAT]
+        };
+        let depth = depth_candidates
+            .iter()
+            .copied()
+            .find(|format| supports(*format, vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT))
+            .ok_or_eyre("No supported depth format found")?;
+        if depth != requested_depth {
+            log::warn!(
+                "Depth format {:?} is not supported, falling back to {:?}",
+                requested_depth,
+                depth
+            );
+        }
+
+        let limits = unsafe { instance.get_physical_device_properties(self.physical) }.limits;
+        let mut supported_samples =
+            limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+        if format_has_stencil(depth) {
+            supported_samples &= limits.framebuffer_stencil_sample_counts;
+        }
+        let requested_samples = config.msaa.sample_count();
+        let samples = [
+            vk::SampleCountFlags::TYPE_8,
+            vk::SampleCountFlags::TYPE_4,
+            vk::SampleCountFlags::TYPE_2,
+        ]
+        .into_iter()
+        .filter(|count| count.as_raw() <= requested_samples.as_raw())
+        .find(|count| supported_samples.contains(*count))
+        .unwrap_or(vk::SampleCountFlags::TYPE_1);
+        if samples != requested_samples {
+            log::warn!(
+                "{:?} MSAA is not supported, falling back to {:?}",
+                requested_samples,
+                samples
+            );
+        }
+
+        Ok(RenderTargetFormats {
+            color,
+            depth,
+            samples,
+        })
+    }
+
+    /// `vkDeviceWaitIdle` needs every queue to be externally synchronized, so all of them are locked
+    pub fn wait_idle(&self) -> Result<()> {
+        let mut queues: Vec<&Queue> = Vec::new();
+        for queue in [
+            &self.graphics_queue,
+            &self.compute_queue,
+            &self.transfer_queue,
+        ] {
+            if !queues.iter().any(|other| other.is_same_queue(queue)) {
+                queues.push(queue);
+            }
+        }
+        let _guards = queues
+            .iter()
+            .map(|queue| queue.lock())
+            .collect::<Result<Vec<_>>>()?;
+        unsafe { self.logical.device_wait_idle()? };
+        Ok(())
+    }
+
+    pub fn allocate_command_encoder(
+        &mut self,
+        queue: Arc<Queue>,
+        name: &str,
+    ) -> Result<CommandEncoder> {
+        self.command_encoder_allocator.allocate(queue, name)
+    }
+
+    /// `None` when profiling is off or the queue cannot write timestamps
+    pub fn create_gpu_profiler(
+        &self,
+        instance: &ash::Instance,
+        name: &str,
+        profiling: GpuProfiling,
+        queue: &Queue,
+    ) -> Result<Option<GpuProfiler>> {
+        if profiling == GpuProfiling::Off {
+            return Ok(None);
+        }
+
+        let limits = unsafe { instance.get_physical_device_properties(self.physical) }.limits;
+        let timestamp_valid_bits = queue.family.properties.timestamp_valid_bits;
+        if timestamp_valid_bits == 0 || limits.timestamp_period == 0.0 {
+            log::warn!("GPU profiling disabled, the queue does not support timestamps");
+            return Ok(None);
+        }
+
+        let collect_statistics = profiling == GpuProfiling::TimestampsAndPipelineStatistics;
+        let statistics_supported = unsafe { instance.get_physical_device_features(self.physical) }
+            .pipeline_statistics_query
+            == vk::TRUE;
+        if collect_statistics && !statistics_supported {
+            log::warn!("Pipeline statistics queries are unsupported, only timestamps are profiled");
+        }
+
+        GpuProfiler::new(
+            name,
+            limits.timestamp_period,
+            timestamp_valid_bits,
+            collect_statistics && statistics_supported,
+            &self.debug_utils,
+            self.logical.clone(),
+        )
+        .map(Some)
+    }
+
+    pub fn create_semaphore(&self, name: &str) -> Result<vk::Semaphore> {
+        let semaphore = unsafe {
+            self.logical
+                .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?
+        };
+        self.debug_utils.set_object_name(semaphore, name);
+        Ok(semaphore)
     }
 
     pub fn get_present_queue(&self) -> Arc<Queue> {
@@ -180,227 +551,359 @@ impl RenderDevice {
     fn select_physical_device(
         instance: &ash::Instance,
         surface: Option<(&vk::SurfaceKHR, &ash::khr::surface::Instance)>,
+        gpu_override: Option<&GpuOverride>,
     ) -> Result<(vk::PhysicalDevice, QueueFamily, QueueFamily, QueueFamily)> {
-        let req_device_exts = Self::get_required_device_extensions();
-        let req_device_exts = req_device_exts
+        let mut candidates = Vec::new();
+        let devices = unsafe { instance.enumerate_physical_devices()? };
+        for (index, device) in devices.into_iter().enumerate() {
+            let props = unsafe { instance.get_physical_device_properties(device) };
+            let name = props
+                .device_name_as_c_str()
+                .map_or("<unknown>".into(), |name| {
+                    name.to_string_lossy().into_owned()
+                });
+
+            if let Some(gpu_override) = gpu_override {
+                if !gpu_override.matches(index, &name) {
+                    log::info!("Skipping GPU {index} ({name}): not selected by {GPU_OVERRIDE_ENV}");
+                    continue;
+                }
+            }
+
+            match Self::evaluate_physical_device(instance, device, &props, surface) {
+                Ok((families, score)) => {
+                    log::info!("GPU {index} ({name}) is suitable, score {score}");
+                    candidates.push((device, name, families, score));
+                }
+                Err(reason) => log::warn!("Skipping GPU {index} ({name}): {reason}"),
+            }
+        }
+
+        // The first device wins a tie, so the driver's preferred order is kept
+        let (device, name, (graphics, compute, transfer), _) = candidates
+            .into_iter()
+            .min_by_key(|(_, _, _, score)| std::cmp::Reverse(*score))
+            .ok_or_else(|| match gpu_override {
+                Some(gpu_override) => eyre!(
+                    "No suitable physical device matches the GPU selection {:?}",
+                    gpu_override
+                ),
+                None => eyre!("No suitable physical device found"),
+            })?;
+        log::info!(
+            "Selected GPU {name} with queue families graphics {}, compute {}, transfer {}",
+            graphics.index,
+            compute.index,
+            transfer.index
+        );
+
+        Ok((device, graphics, compute, transfer))
+    }
+
+    /// Either the queue families to use and a score to rank the device by, or why it cannot be used
+    fn evaluate_physical_device(
+        instance: &ash::Instance,
+        device: vk::PhysicalDevice,
+        props: &vk::PhysicalDeviceProperties,
+        surface: Option<(&vk::SurfaceKHR, &ash::khr::surface::Instance)>,
+    ) -> std::result::Result<((QueueFamily, QueueFamily, QueueFamily), u64), String> {
+        if props.api_version < vk::API_VERSION_1_3 {
+            return Err(format!(
+                "supports Vulkan {}.{}, but 1.3 is required",
+                vk::api_version_major(props.api_version),
+                vk::api_version_minor(props.api_version)
+            ));
+        }
+
+        let supported_extensions =
+            unsafe { instance.enumerate_device_extension_properties(device) }
+                .map_or(Vec::new(), |exts| exts);
+        let missing_extensions = Self::get_required_device_extensions()
+            .into_iter()
+            .filter(|req_ext| {
+                !supported_extensions
+                    .iter()
+                    .any(|sup_ext| sup_ext.extension_name_as_c_str() == Ok(*req_ext))
+            })
+            .map(|ext| ext.to_string_lossy())
+            .collect::<Vec<_>>();
+        if !missing_extensions.is_empty() {
+            return Err(format!(
+                "missing device extensions {}",
+                missing_extensions.join(", ")
+            ));
+        }
+
+        let (_, features11, features12, features13) = Self::query_vulkan_features(instance, device);
+        let missing_features = missing_required_features(&features11, &features12, &features13);
+        if !missing_features.is_empty() {
+            return Err(format!(
+                "missing device features {}",
+                missing_features.join(", ")
+            ));
+        }
+
+        let queue_family_props =
+            unsafe { instance.get_physical_device_queue_family_properties(device) };
+        let supports_present = (0..queue_family_props.len() as u32)
+            .map(|i| match surface {
+                Some((surface, surface_loader)) => unsafe {
+                    surface_loader
+                        .get_physical_device_surface_support(device, i, *surface)
+                        .unwrap_or(false)
+                },
+                None => true,
+            })
+            .collect::<Vec<bool>>();
+        let flags = queue_family_props
             .iter()
-            .map(|ext| ext.to_str())
-            .collect::<std::result::Result<Vec<&str>, Utf8Error>>()?;
+            .map(|props| props.queue_flags)
+            .collect::<Vec<vk::QueueFlags>>();
+        let indices = QueueFamilyIndices::select(&flags, &supports_present)
+            .ok_or("no queue family supports both graphics and presentation")?;
+
+        let memory_props = unsafe { instance.get_physical_device_memory_properties(device) };
+        let device_local_bytes = memory_props
+            .memory_heaps_as_slice()
+            .iter()
+            .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum();
+        let score = score_device(props.device_type, &indices, device_local_bytes);
+
+        let family = |index: usize| {
+            QueueFamily::new(
+                index as u32,
+                queue_family_props[index],
+                supports_present[index],
+            )
+        };
+        Ok((
+            (
+                family(indices.graphics),
+                family(indices.compute),
+                family(indices.transfer),
+            ),
+            score,
+        ))
+    }
 
-        Ok(unsafe {
-            instance
-                .enumerate_physical_devices()?
-                .into_iter()
-                // Filter out devices that do not contain the required device extensions
-                .filter(|device| {
-                    let supported_extensions = instance
-                        .enumerate_device_extension_properties(*device)
-                        .map_or(Vec::new(), |exts| exts);
-
-                    req_device_exts.iter().all(|req_ext| {
-                        let req_ext_supported = supported_extensions
-                            .iter()
-                            .map(|sup_exts| sup_exts.extension_name.as_ptr())
-                            .any(
-                                |sup_ext| match (*req_ext, CStr::from_ptr(sup_ext).to_str()) {
-                                    (req, Ok(sup)) => req == sup,
-                                    _ => false,
-                                },
-                            );
-                        if !req_ext_supported {
-                            log::error!("Device extension not supported: {}", req_ext);
-                        }
-                        req_ext_supported
-                    })
-                })
-                // Filter out devices that do not contain the required queues
-                .filter_map(|device| {
-                    let props = instance.get_physical_device_queue_family_properties(device);
-
-                    let graphics_queue_family_index =
-                        props.iter().enumerate().position(|(i, q)| {
-                            let supports_graphics =
-                                q.queue_flags.contains(vk::QueueFlags::GRAPHICS);
-                            if let Some((surface, surface_loader)) = surface {
-                                let supports_present = {
-                                    surface_loader
-                                        .get_physical_device_surface_support(
-                                            device, i as u32, *surface,
-                                        )
-                                        .map_or(false, |b| b)
-                                };
-                                supports_graphics && supports_present
-                            } else {
-                                supports_graphics
-                            }
-                        });
-
-                    let compute_queue_family_index = props.iter().enumerate().position(|(i, q)| {
-                        let supports_compute = q.queue_flags.contains(vk::QueueFlags::COMPUTE);
-                        let same_as_graphics = graphics_queue_family_index == Some(i);
-                        supports_compute && !same_as_graphics
-                    });
-
-                    let transfer_queue_family_index =
-                        props.iter().enumerate().position(|(i, q)| {
-                            let supports_transfer =
-                                q.queue_flags.contains(vk::QueueFlags::TRANSFER);
-                            let same_as_graphics = graphics_queue_family_index == Some(i);
-                            let same_as_compute = compute_queue_family_index == Some(i);
-                            supports_transfer && !same_as_graphics && !same_as_compute
-                        });
-
-                    if let (
-                        Some(graphics_queue_family_index),
-                        Some(compute_queue_family_index),
-                        Some(transfer_queue_family_index),
-                    ) = (
-                        graphics_queue_family_index,
-                        compute_queue_family_index,
-                        transfer_queue_family_index,
-                    ) {
-                        Some((
-                            device,
-                            graphics_queue_family_index as u32,
-                            compute_queue_family_index as u32,
-                            transfer_queue_family_index as u32,
-                        ))
-                    } else {
-                        None
-                    }
-                })
-                .min_by_key(|(device, _, _, _)| {
-                    let props = instance.get_physical_device_properties(*device);
-                    match props.device_type {
-                        vk::PhysicalDeviceType::DISCRETE_GPU => 0,
-                        vk::PhysicalDeviceType::INTEGRATED_GPU => 1,
-                        vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
-                        vk::PhysicalDeviceType::CPU => 3,
-                        vk::PhysicalDeviceType::OTHER => 4,
-                        _ => 5,
-                    }
-                })
-                .map(
-                    |(
-                        device,
-                        graphics_queue_family_index,
-                        compute_queue_family_index,
-                        transfer_queue_family_index,
-                    )| {
-                        let queue_family_props =
-                            instance.get_physical_device_queue_family_properties(device);
-                        let graphics_props = queue_family_props
-                            .get(graphics_queue_family_index as usize)
-                            .unwrap();
-                        let compute_props = queue_family_props
-                            .get(compute_queue_family_index as usize)
-                            .unwrap();
-                        let transfer_props = queue_family_props
-                            .get(transfer_queue_family_index as usize)
-                            .unwrap();
-                        (
-                            device,
-                            QueueFamily::new(graphics_queue_family_index, *graphics_props, true),
-                            QueueFamily::new(compute_queue_family_index, *compute_props, false),
-                            QueueFamily::new(transfer_queue_family_index, *transfer_props, false),
-                        )
-                    },
-                )
-                .ok_or_eyre("No suitable physical device found")?
-        })
+    /// The returned structs have their `p_next` cleared, so they can be chained again
+    fn query_vulkan_features(
+        instance: &ash::Instance,
+        device: vk::PhysicalDevice,
+    ) -> (
+        vk::PhysicalDeviceFeatures,
+        vk::PhysicalDeviceVulkan11Features<'static>,
+        vk::PhysicalDeviceVulkan12Features<'static>,
+        vk::PhysicalDeviceVulkan13Features<'static>,
+    ) {
+        let mut features11 = vk::PhysicalDeviceVulkan11Features::default();
+        let mut features12 = vk::PhysicalDeviceVulkan12Features::default();
+        let mut features13 = vk::PhysicalDeviceVulkan13Features::default();
+        let features = {
+            let mut features2 = vk::PhysicalDeviceFeatures2::default()
+                .push_next(&mut features11)
+                .push_next(&mut features12)
+                .push_next(&mut features13);
+            unsafe { instance.get_physical_device_features2(device, &mut features2) };
+            features2.features
+        };
+        features11.p_next = std::ptr::null_mut();
+        features12.p_next = std::ptr::null_mut();
+        features13.p_next = std::ptr::null_mut();
+        (features, features11, features12, features13)
     }
 
+    /// `queue_families[i]` gets a queue at `queue_indices[i]`.
+    /// Also returns the optional features that were enabled
     fn create_logical_device(
         instance: &ash::Instance,
         physical_device: &vk::PhysicalDevice,
-        graphics_queue_family: QueueFamily,
-        compute_queue_family: QueueFamily,
-        transfer_queue_family: QueueFamily,
-    ) -> Result<(ash::Device, Queue, Queue, Queue)> {
-        let queue_priorities = [1.0];
-        let queue_create_infos = [
-            vk::DeviceQueueCreateInfo::default()
-                .queue_family_index(graphics_queue_family.index)
-                .queue_priorities(&queue_priorities),
-            vk::DeviceQueueCreateInfo::default()
-                .queue_family_index(compute_queue_family.index)
-                .queue_priorities(&queue_priorities),
-            vk::DeviceQueueCreateInfo::default()
-                .queue_family_index(transfer_queue_family.index)
-                .queue_priorities(&queue_priorities),
-        ];
+        queue_families: &[&QueueFamily],
+        queue_indices: &[u32],
+    ) -> Result<(ash::Device, OptionalFeatures)> {
+        let queue_counts = queue_counts(queue_families, queue_indices);
+        let queue_priorities = queue_counts
+            .iter()
+            .map(|(_, count)| vec![1.0; *count as usize])
+            .collect::<Vec<Vec<f32>>>();
+        let queue_create_infos = queue_counts
+            .iter()
+            .zip(&queue_priorities)
+            .map(|((index, _), priorities)| {
+                vk::DeviceQueueCreateInfo::default()
+                    .queue_family_index(*index)
+                    .queue_priorities(priorities)
+            })
+            .collect::<Vec<_>>();
+
+        let shader_object_supported = Self::is_shader_object_supported(instance, *physical_device);
+        let mesh_shader_supported =
+            Self::supported_mesh_shader_features(instance, *physical_device);
 
         // Create device
-        let device = {
-            let enabled_extension_names = Self::get_required_device_extensions()
+        let (device, optional_features) = {
+            let mut enabled_extension_names = Self::get_required_device_extensions()
                 .iter()
                 .map(|ext| ext.as_ptr())
                 .collect::<Vec<*const c_char>>();
-
-            let mut features2 = vk::PhysicalDeviceFeatures2::default();
-            unsafe {
-                instance.get_physical_device_features2(*physical_device, &mut features2);
+            if shader_object_supported {
+                enabled_extension_names.push(ash::ext::shader_object::NAME.as_ptr());
             }
-            let mut features11 =
-                vk::PhysicalDeviceVulkan11Features::default().shader_draw_parameters(true);
+            if mesh_shader_supported.mesh_shader == vk::TRUE {
+                enabled_extension_names.push(ash::ext::mesh_shader::NAME.as_ptr());
+            }
+
+            // Required features were checked during selection, the optional core ones are enabled
+            // when supported and checked again where they are used
+            let (supported, supported11, supported12, _) =
+                Self::query_vulkan_features(instance, *physical_device);
+            let multi_draw_indirect_count = supported12.draw_indirect_count == vk::TRUE
+                && supported.multi_draw_indirect == vk::TRUE
+                && supported.draw_indirect_first_instance == vk::TRUE;
+            let core = vk::PhysicalDeviceFeatures::default()
+                .tessellation_shader(supported.tessellation_shader == vk::TRUE)
+                .geometry_shader(supported.geometry_shader == vk::TRUE)
+                .depth_clamp(supported.depth_clamp == vk::TRUE)
+                .alpha_to_one(supported.alpha_to_one == vk::TRUE)
+                .logic_op(supported.logic_op == vk::TRUE)
+                .depth_bounds(supported.depth_bounds == vk::TRUE)
+                // Line and point polygon modes of material definitions
+                .fill_mode_non_solid(supported.fill_mode_non_solid == vk::TRUE)
+                .pipeline_statistics_query(supported.pipeline_statistics_query == vk::TRUE)
+                .multi_draw_indirect(multi_draw_indirect_count)
+                .draw_indirect_first_instance(multi_draw_indirect_count);
+            let mut features2 = vk::PhysicalDeviceFeatures2::default().features(core);
+            let mut features11 = vk::PhysicalDeviceVulkan11Features::default()
+                .shader_draw_parameters(supported11.shader_draw_parameters == vk::TRUE);
             let mut features12 = vk::PhysicalDeviceVulkan12Features::default()
                 .runtime_descriptor_array(true)
                 .buffer_device_address(true)
                 .descriptor_indexing(true)
                 .descriptor_binding_partially_bound(true)
                 .descriptor_binding_variable_descriptor_count(true)
+                .draw_indirect_count(multi_draw_indirect_count)
                 // Dynamic indexing
-                .shader_input_attachment_array_dynamic_indexing(true)
-                .shader_uniform_texel_buffer_array_dynamic_indexing(true)
-                .shader_storage_texel_buffer_array_dynamic_indexing(true)
+                .shader_input_attachment_array_dynamic_indexing(
+                    supported12.shader_input_attachment_array_dynamic_indexing == vk::TRUE,
+                )
+                .shader_uniform_texel_buffer_array_dynamic_indexing(
+                    supported12.shader_uniform_texel_buffer_array_dynamic_indexing == vk::TRUE,
+                )
+                .shader_storage_texel_buffer_array_dynamic_indexing(
+                    supported12.shader_storage_texel_buffer_array_dynamic_indexing == vk::TRUE,
+                )
                 // Non-uniform indexing
-                .shader_uniform_buffer_array_non_uniform_indexing(true)
+                .shader_uniform_buffer_array_non_uniform_indexing(
+                    supported12.shader_uniform_buffer_array_non_uniform_indexing == vk::TRUE,
+                )
                 .shader_sampled_image_array_non_uniform_indexing(true)
-                .shader_storage_buffer_array_non_uniform_indexing(true)
-                .shader_storage_image_array_non_uniform_indexing(true)
-                .shader_input_attachment_array_non_uniform_indexing(true)
-                .shader_uniform_texel_buffer_array_non_uniform_indexing(true)
-                .shader_storage_texel_buffer_array_non_uniform_indexing(true)
+                .shader_storage_buffer_array_non_uniform_indexing(
+                    supported12.shader_storage_buffer_array_non_uniform_indexing == vk::TRUE,
+                )
+                .shader_storage_image_array_non_uniform_indexing(
+                    supported12.shader_storage_image_array_non_uniform_indexing == vk::TRUE,
+                )
+                .shader_input_attachment_array_non_uniform_indexing(
+                    supported12.shader_input_attachment_array_non_uniform_indexing == vk::TRUE,
+                )
+                .shader_uniform_texel_buffer_array_non_uniform_indexing(
+                    supported12.shader_uniform_texel_buffer_array_non_uniform_indexing == vk::TRUE,
+                )
+                .shader_storage_texel_buffer_array_non_uniform_indexing(
+                    supported12.shader_storage_texel_buffer_array_non_uniform_indexing == vk::TRUE,
+                )
                 // Update after bind
                 .descriptor_binding_uniform_buffer_update_after_bind(true)
                 .descriptor_binding_sampled_image_update_after_bind(true)
                 .descriptor_binding_storage_image_update_after_bind(true)
                 .descriptor_binding_storage_buffer_update_after_bind(true)
-                .descriptor_binding_uniform_texel_buffer_update_after_bind(true)
-                .descriptor_binding_storage_texel_buffer_update_after_bind(true);
+                .descriptor_binding_uniform_texel_buffer_update_after_bind(
+                    supported12.descriptor_binding_uniform_texel_buffer_update_after_bind
+                        == vk::TRUE,
+                )
+                .descriptor_binding_storage_texel_buffer_update_after_bind(
+                    supported12.descriptor_binding_storage_texel_buffer_update_after_bind
+                        == vk::TRUE,
+                );
             let mut features13 = vk::PhysicalDeviceVulkan13Features::default()
                 .synchronization2(true)
                 .dynamic_rendering(true);
 
             let mut shader_object_features =
                 vk::PhysicalDeviceShaderObjectFeaturesEXT::default().shader_object(true);
-
-            let device_create_info = vk::DeviceCreateInfo::default() //enabled_features.device_create_info()
+            let mut mesh_shader_features = vk::PhysicalDeviceMeshShaderFeaturesEXT::default()
+                .task_shader(mesh_shader_supported.task_shader == vk::TRUE)
+                .mesh_shader(true);
+
+            let mesh_shader = mesh_shader_supported.mesh_shader == vk::TRUE;
+            let optional_features = OptionalFeatures {
+                core: features2.features,
+                shader_object: shader_object_supported,
+                task_shader: mesh_shader && mesh_shader_supported.task_shader == vk::TRUE,
+                mesh_shader,
+                multi_draw_indirect_count,
+            };
+
+            let mut device_create_info = vk::DeviceCreateInfo::default() //enabled_features.device_create_info()
                 .push_next(&mut features2)
                 .push_next(&mut features11)
                 .push_next(&mut features12)
                 .push_next(&mut features13)
-                .push_next(&mut shader_object_features)
                 .queue_create_infos(&queue_create_infos)
                 .enabled_extension_names(&enabled_extension_names);
+            if shader_object_supported {
+                device_create_info = device_create_info.push_next(&mut shader_object_features);
+            }
+            if optional_features.mesh_shader {
+                device_create_info = device_create_info.push_next(&mut mesh_shader_features);
+            }
 
-            unsafe { instance.create_device(*physical_device, &device_create_info, None)? }
+            let device =
+                unsafe { instance.create_device(*physical_device, &device_create_info, None)? };
+            (device, optional_features)
         };
 
-        let graphics_queue = unsafe {
-            let queue = device.get_device_queue(graphics_queue_family.index, 0);
-            Queue::new(graphics_queue_family, queue)
-        };
-        let compute_queue = unsafe {
-            let queue = device.get_device_queue(compute_queue_family.index, 0);
-            Queue::new(compute_queue_family, queue)
-        };
-        let transfer_queue = unsafe {
-            let queue = device.get_device_queue(transfer_queue_family.index, 0);
-            Queue::new(transfer_queue_family, queue)
-        };
+        Ok((device, optional_features))
+    }
 
-        Ok((device, graphics_queue, compute_queue, transfer_queue))
+    fn is_extension_supported(
+        instance: &ash::Instance,
+        device: vk::PhysicalDevice,
+        name: &CStr,
+    ) -> bool {
+        unsafe { instance.enumerate_device_extension_properties(device) }.map_or(false, |exts| {
+            exts.iter()
+                .any(|ext| ext.extension_name_as_c_str() == Ok(name))
+        })
+    }
+
+    fn is_shader_object_supported(instance: &ash::Instance, device: vk::PhysicalDevice) -> bool {
+        if !Self::is_extension_supported(instance, device, ash::ext::shader_object::NAME) {
+            return false;
+        }
+
+        let mut shader_object_features = vk::PhysicalDeviceShaderObjectFeaturesEXT::default();
+        let mut features2 =
+            vk::PhysicalDeviceFeatures2::default().push_next(&mut shader_object_features);
+        unsafe { instance.get_physical_device_features2(device, &mut features2) };
+        shader_object_features.shader_object == vk::TRUE
+    }
+
+    /// All features are false when `VK_EXT_mesh_shader` is unsupported
+    fn supported_mesh_shader_features(
+        instance: &ash::Instance,
+        device: vk::PhysicalDevice,
+    ) -> vk::PhysicalDeviceMeshShaderFeaturesEXT<'static> {
+        let mut mesh_shader_features = vk::PhysicalDeviceMeshShaderFeaturesEXT::default();
+        if Self::is_extension_supported(instance, device, ash::ext::mesh_shader::NAME) {
+            let mut features2 =
+                vk::PhysicalDeviceFeatures2::default().push_next(&mut mesh_shader_features);
+            unsafe { instance.get_physical_device_features2(device, &mut features2) };
+        }
+        mesh_shader_features.p_next = std::ptr::null_mut();
+        mesh_shader_features
     }
 
     fn get_required_device_extensions() -> Vec<&'static CStr> {
@@ -411,7 +914,6 @@ impl RenderDevice {
             ash::khr::synchronization2::NAME,
             ash::khr::maintenance3::NAME,
             ash::ext::descriptor_indexing::NAME,
-            ash::ext::shader_object::NAME,
             #[cfg(target_os = "macos")]
             ash::khr::portability_subset::NAME,
         ]
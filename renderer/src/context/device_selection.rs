@@ -0,0 +1,265 @@
+use ash::vk;
+
+/// Environment variable that overrides `RendererConfig::gpu`
+pub(crate) const GPU_OVERRIDE_ENV: &str = "DUNWARD_GPU";
+
+/// A user's choice of GPU: its position in the enumeration order, or part of its name
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum GpuOverride {
+    Index(usize),
+    /// Lowercase, matched case-insensitively against the device name
+    Name(String),
+}
+
+impl GpuOverride {
+    /// `DUNWARD_GPU` takes precedence over the config field
+    pub fn from_env_or_config(config: Option<&str>) -> Option<Self> {
+        match std::env::var(GPU_OVERRIDE_ENV) {
+            Ok(value) => Self::parse(&value),
+            Err(_) => config.and_then(Self::parse),
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if value.is_empty() {
+            return None;
+        }
+        match value.parse::<usize>() {
+            Ok(index) => Some(Self::Index(index)),
+            Err(_) => Some(Self::Name(value.to_lowercase())),
+        }
+    }
+
+    pub fn matches(&self, index: usize, name: &str) -> bool {
+        match self {
+            Self::Index(wanted) => *wanted == index,
+            Self::Name(wanted) => name.to_lowercase().contains(wanted.as_str()),
+        }
+    }
+}
+
+/// Queue family chosen for each role. Roles share a family when the device has no separate one,
+/// e.g. integrated GPUs and software rasterizers that expose a single universal family.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct QueueFamilyIndices {
+    pub graphics: usize,
+    pub compute: usize,
+    pub transfer: usize,
+}
+
+impl QueueFamilyIndices {
+    /// `supports_present[i]` tells whether family `i` can present to the surface.
+    /// Separate families are preferred, so compute and transfer work can overlap with rendering.
+    pub fn select(flags: &[vk::QueueFlags], supports_present: &[bool]) -> Option<Self> {
+        // For now, require the graphics queue to support presentation
+        let graphics = (0..flags.len())
+            .find(|&i| flags[i].contains(vk::QueueFlags::GRAPHICS) && supports_present[i])?;
+
+        let compute = (0..flags.len())
+            .filter(|&i| flags[i].contains(vk::QueueFlags::COMPUTE))
+            .min_by_key(|&i| (i == graphics, flags[i].contains(vk::QueueFlags::GRAPHICS)))?;
+
+        // Graphics and compute families support transfers even when they do not advertise it
+        let transfer = (0..flags.len())
+            .filter(|&i| {
+                flags[i].intersects(
+                    vk::QueueFlags::TRANSFER | vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE,
+                )
+            })
+            .min_by_key(|&i| {
+                (
+                    i == graphics,
+                    i == compute,
+                    flags[i].contains(vk::QueueFlags::GRAPHICS),
+                    flags[i].contains(vk::QueueFlags::COMPUTE),
+                )
+            })?;
+
+        Some(Self {
+            graphics,
+            compute,
+            transfer,
+        })
+    }
+
+    pub fn has_async_compute(&self) -> bool {
+        self.compute != self.graphics
+    }
+
+    pub fn has_dedicated_transfer(&self) -> bool {
+        self.transfer != self.graphics && self.transfer != self.compute
+    }
+}
+
+/// Higher is better. The device type dominates, separate queues and memory break ties.
+pub(crate) fn score_device(
+    device_type: vk::PhysicalDeviceType,
+    families: &QueueFamilyIndices,
+    device_local_bytes: u64,
+) -> u64 {
+    let type_score = match device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 4000,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 3000,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2000,
+        vk::PhysicalDeviceType::CPU => 1000,
+        _ => 0,
+    };
+    let mut queue_score = 0;
+    if families.has_async_compute() {
+        queue_score += 200;
+    }
+    if families.has_dedicated_transfer() {
+        queue_score += 100;
+    }
+    let memory_score = (device_local_bytes >> 30).min(99);
+    type_score + queue_score + memory_score
+}
+
+/// Names of the features the renderer cannot work without that the device lacks
+pub(crate) fn missing_required_features(
+    features11: &vk::PhysicalDeviceVulkan11Features,
+    features12: &vk::PhysicalDeviceVulkan12Features,
+    features13: &vk::PhysicalDeviceVulkan13Features,
+) -> Vec<&'static str> {
+    [
+        ("shaderDrawParameters", features11.shader_draw_parameters),
+        (
+            "runtimeDescriptorArray",
+            features12.runtime_descriptor_array,
+        ),
+        ("bufferDeviceAddress", features12.buffer_device_address),
+        ("descriptorIndexing", features12.descriptor_indexing),
+        (
+            "descriptorBindingPartiallyBound",
+            features12.descriptor_binding_partially_bound,
+        ),
+        (
+            "descriptorBindingVariableDescriptorCount",
+            features12.descriptor_binding_variable_descriptor_count,
+        ),
+        (
+            "shaderSampledImageArrayNonUniformIndexing",
+            features12.shader_sampled_image_array_non_uniform_indexing,
+        ),
+        (
+            "descriptorBindingUniformBufferUpdateAfterBind",
+            features12.descriptor_binding_uniform_buffer_update_after_bind,
+        ),
+        (
+            "descriptorBindingSampledImageUpdateAfterBind",
+            features12.descriptor_binding_sampled_image_update_after_bind,
+        ),
+        (
+            "descriptorBindingStorageImageUpdateAfterBind",
+            features12.descriptor_binding_storage_image_update_after_bind,
+        ),
+        (
+            "descriptorBindingStorageBufferUpdateAfterBind",
+            features12.descriptor_binding_storage_buffer_update_after_bind,
+        ),
+        ("synchronization2", features13.synchronization2),
+        ("dynamicRendering", features13.dynamic_rendering),
+    ]
+    .into_iter()
+    .filter(|(_, supported)| *supported != vk::TRUE)
+    .map(|(name, _)| name)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNIVERSAL: vk::QueueFlags = vk::QueueFlags::from_raw(
+        vk::QueueFlags::GRAPHICS.as_raw()
+            | vk::QueueFlags::COMPUTE.as_raw()
+            | vk::QueueFlags::TRANSFER.as_raw(),
+    );
+    const ASYNC_COMPUTE: vk::QueueFlags = vk::QueueFlags::from_raw(
+        vk::QueueFlags::COMPUTE.as_raw() | vk::QueueFlags::TRANSFER.as_raw(),
+    );
+
+    #[test]
+    fn single_universal_family_is_shared() {
+        let families = QueueFamilyIndices::select(&[UNIVERSAL], &[true]).unwrap();
+        assert_eq!(families.graphics, 0);
+        assert_eq!(families.compute, 0);
+        assert_eq!(families.transfer, 0);
+        assert!(!families.has_async_compute());
+        assert!(!families.has_dedicated_transfer());
+    }
+
+    #[test]
+    fn prefers_dedicated_families() {
+        let flags = [
+            UNIVERSAL,
+            vk::QueueFlags::TRANSFER,
+            ASYNC_COMPUTE,
+            UNIVERSAL,
+        ];
+        let families = QueueFamilyIndices::select(&flags, &[true; 4]).unwrap();
+        assert_eq!(families.graphics, 0);
+        assert_eq!(families.compute, 2);
+        assert_eq!(families.transfer, 1);
+    }
+
+    #[test]
+    fn transfer_falls_back_to_compute_family() {
+        let families = QueueFamilyIndices::select(&[UNIVERSAL, ASYNC_COMPUTE], &[true, false]);
+        assert_eq!(
+            families,
+            Some(QueueFamilyIndices {
+                graphics: 0,
+                compute: 1,
+                transfer: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn requires_a_presenting_graphics_family() {
+        assert_eq!(
+            QueueFamilyIndices::select(&[UNIVERSAL, ASYNC_COMPUTE], &[false, true]),
+            None
+        );
+    }
+
+    #[test]
+    fn discrete_beats_integrated_regardless_of_queues() {
+        let shared = QueueFamilyIndices {
+            graphics: 0,
+            compute: 0,
+            transfer: 0,
+        };
+        let separate = QueueFamilyIndices {
+            graphics: 0,
+            compute: 1,
+            transfer: 2,
+        };
+        assert!(
+            score_device(vk::PhysicalDeviceType::DISCRETE_GPU, &shared, 1 << 30)
+                > score_device(vk::PhysicalDeviceType::INTEGRATED_GPU, &separate, 1 << 36)
+        );
+        assert!(
+            score_device(vk::PhysicalDeviceType::INTEGRATED_GPU, &separate, 0)
+                > score_device(vk::PhysicalDeviceType::INTEGRATED_GPU, &shared, 0)
+        );
+    }
+
+    #[test]
+    fn parses_gpu_override() {
+        assert_eq!(GpuOverride::parse("1"), Some(GpuOverride::Index(1)));
+        assert_eq!(
+            GpuOverride::parse(" GeForce "),
+            Some(GpuOverride::Name("geforce".to_owned()))
+        );
+        assert_eq!(GpuOverride::parse("  "), None);
+
+        let by_name = GpuOverride::parse("llvmpipe").unwrap();
+        assert!(by_name.matches(3, "llvmpipe (LLVM 17.0.6, 256 bits)"));
+        assert!(!by_name.matches(3, "AMD Radeon RX 7800 XT"));
+        assert!(GpuOverride::Index(0).matches(0, "anything"));
+        assert!(!GpuOverride::Index(0).matches(1, "anything"));
+    }
+}
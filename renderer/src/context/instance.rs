@@ -1,53 +1,71 @@
 use super::device::RenderDevice;
+use super::validation::{ValidationSettings, ValidationSink};
 use ash::vk;
 use color_eyre::Result;
 use color_eyre::eyre::eyre;
 use raw_window_handle::{HasDisplayHandle, HasWindowHandle};
 use std::ffi::{CStr, FromBytesUntilNulError, c_char, c_void};
 use winit::window::Window;
+use crate::config::{RendererConfig, ValidationConfig};
 use crate::viewport::{RenderSurface, RenderViewport};
 
 /// Initializes Vulkan and keeps the Vulkan instance alive
 pub(crate) struct RenderInstance {
     pub instance: ash::Instance,
     pub entry: ash::Entry,
+    /// `None` when validation is disabled, see `ValidationConfig`
+    pub validation: Option<ValidationSink>,
 
-    #[cfg(debug_assertions)]
-    _debug_utils_messenger: vk::DebugUtilsMessengerEXT,
-    #[cfg(debug_assertions)]
-    _debug_utils_loader: ash::ext::debug_utils::Instance,
+    // Only present with validation enabled
+    _debug_utils_messenger: Option<vk::DebugUtilsMessengerEXT>,
+    debug_utils_loader: Option<ash::ext::debug_utils::Instance>,
 }
 
 impl RenderInstance {
-    const ENABLE_VALIDATION_LAYERS: bool = cfg!(debug_assertions);
     const REQUIRED_VALIDATION_LAYERS: &'static [&'static CStr] = &[c"VK_LAYER_KHRONOS_validation"];
 
-    pub fn new(window: Option<&Window>) -> Result<Self> {
+    pub fn new(window: Option<&Window>, validation: &ValidationConfig) -> Result<Self> {
         let entry = unsafe { ash::Entry::load() }?;
 
-        let instance = Self::create_instance(&entry, window)?;
+        let validation =
+            ValidationSettings::from_env_or_config(validation).map(ValidationSink::new);
+        let instance = Self::create_instance(&entry, window, validation.as_ref())?;
 
-        #[cfg(debug_assertions)]
-        let (debug_utils_messenger, debug_utils_loader) =
-            Self::create_debug_utils_messenger(&entry, &instance)?;
+        let (debug_utils_messenger, debug_utils_loader) = validation
+            .as_ref()
+            .map(|sink| Self::create_debug_utils_messenger(&entry, &instance, sink))
+            .transpose()?
+            .unzip();
 
         Ok(Self {
             instance,
             entry,
+            validation,
 
-            #[cfg(debug_assertions)]
             _debug_utils_messenger: debug_utils_messenger,
-            #[cfg(debug_assertions)]
-            _debug_utils_loader: debug_utils_loader,
+            debug_utils_loader,
         })
     }
 
+    /// `VK_EXT_debug_utils` is only enabled along with validation
+    pub fn debug_utils_enabled(&self) -> bool {
+        self.debug_utils_loader.is_some()
+    }
+
     pub fn inner(&self) -> &ash::Instance {
         &self.instance
     }
 
-    pub fn create_device(&self, surface: &RenderSurface) -> Result<RenderDevice> {
-        RenderDevice::new(self, Some((&surface.surface, &surface.surface_loader)))
+    pub fn create_device(
+        &self,
+        surface: &RenderSurface,
+        config: &RendererConfig,
+    ) -> Result<RenderDevice> {
+        RenderDevice::new(
+            self,
+            Some((&surface.surface, &surface.surface_loader)),
+            config,
+        )
     }
 
     pub fn create_surface(&self, window: &Window) -> Result<RenderSurface> {
@@ -78,13 +96,17 @@ impl RenderInstance {
         RenderViewport::new(sfc, win, self, dev)
     }
 
-    fn create_instance(entry: &ash::Entry, window: Option<&Window>) -> Result<ash::Instance> {
-        if Self::ENABLE_VALIDATION_LAYERS {
+    fn create_instance(
+        entry: &ash::Entry,
+        window: Option<&Window>,
+        validation: Option<&ValidationSink>,
+    ) -> Result<ash::Instance> {
+        if validation.is_some() {
             Self::check_validation_layers_supported(entry)?;
         }
 
         let application_info = vk::ApplicationInfo::default().api_version(vk::API_VERSION_1_3);
-        let enabled_layer_names = if Self::ENABLE_VALIDATION_LAYERS {
+        let enabled_layer_names = if validation.is_some() {
             Self::REQUIRED_VALIDATION_LAYERS
                 .iter()
                 .map(|layer| layer.as_ptr())
@@ -92,25 +114,21 @@ impl RenderInstance {
         } else {
             Vec::new()
         };
-        let enabled_extension_names = Self::get_required_instance_extensions(window)?
-            .iter()
-            .map(|ext| ext.as_ptr())
-            .collect::<Vec<*const c_char>>();
+        let enabled_extension_names =
+            Self::get_required_instance_extensions(window, validation.is_some())?
+                .iter()
+                .map(|ext| ext.as_ptr())
+                .collect::<Vec<*const c_char>>();
 
-        #[cfg(not(debug_assertions))]
-        let instance_info = vk::InstanceCreateInfo::default()
+        // Also reports messages from instance creation and destruction
+        let mut debug_info = validation.map(debug_utils_messenger_create_info);
+        let mut instance_info = vk::InstanceCreateInfo::default()
             .application_info(&application_info)
             .enabled_layer_names(&enabled_layer_names)
             .enabled_extension_names(&enabled_extension_names);
-
-        #[cfg(debug_assertions)]
-        let mut debug_info = debug_utils_messenger_create_info();
-        #[cfg(debug_assertions)]
-        let instance_info = vk::InstanceCreateInfo::default()
-            .application_info(&application_info)
-            .enabled_layer_names(&enabled_layer_names)
-            .enabled_extension_names(&enabled_extension_names)
-            .push_next(&mut debug_info);
+        if let Some(debug_info) = &mut debug_info {
+            instance_info = instance_info.push_next(debug_info);
+        }
 
         #[cfg(target_os = "macos")]
         let instance_info = instance_info.flags(vk::InstanceCreateFlags::ENUMERATE_PORTABILITY_KHR);
@@ -118,19 +136,22 @@ impl RenderInstance {
         Ok(unsafe { entry.create_instance(&instance_info, None)? })
     }
 
-    #[cfg(debug_assertions)]
     fn create_debug_utils_messenger(
         entry: &ash::Entry,
         instance: &ash::Instance,
+        validation: &ValidationSink,
     ) -> Result<(vk::DebugUtilsMessengerEXT, ash::ext::debug_utils::Instance)> {
         let debug_utils_loader = ash::ext::debug_utils::Instance::new(entry, instance);
-        let debug_utils_info = debug_utils_messenger_create_info();
+        let debug_utils_info = debug_utils_messenger_create_info(validation);
         let debug_utils_messenger =
             unsafe { debug_utils_loader.create_debug_utils_messenger(&debug_utils_info, None)? };
         Ok((debug_utils_messenger, debug_utils_loader))
     }
 
-    fn get_required_instance_extensions(window: Option<&Window>) -> Result<Vec<&'static CStr>> {
+    fn get_required_instance_extensions(
+        window: Option<&Window>,
+        validation: bool,
+    ) -> Result<Vec<&'static CStr>> {
         let mut exts = if let Some(window) = window {
             ash_window::enumerate_required_extensions(window.display_handle()?.as_raw())?
                 .iter()
@@ -140,8 +161,7 @@ impl RenderInstance {
             Vec::new()
         };
 
-        #[cfg(debug_assertions)]
-        if Self::ENABLE_VALIDATION_LAYERS {
+        if validation {
             exts.push(ash::ext::debug_utils::NAME);
         }
 
@@ -171,8 +191,9 @@ impl RenderInstance {
     }
 }
 
-#[cfg(debug_assertions)]
-fn debug_utils_messenger_create_info() -> vk::DebugUtilsMessengerCreateInfoEXT<'static> {
+fn debug_utils_messenger_create_info(
+    validation: &ValidationSink,
+) -> vk::DebugUtilsMessengerCreateInfoEXT<'static> {
     let message_severity = vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE
         | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
         | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR;
@@ -183,14 +204,14 @@ fn debug_utils_messenger_create_info() -> vk::DebugUtilsMessengerCreateInfoEXT<'
         .message_severity(message_severity)
         .message_type(message_type)
         .pfn_user_callback(Some(debug_callback))
+        .user_data(validation.callback_user_data())
 }
 
-#[cfg(debug_assertions)]
 unsafe extern "system" fn debug_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
     message_type: vk::DebugUtilsMessageTypeFlagsEXT,
     p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _p_user_data: *mut c_void,
+    p_user_data: *mut c_void,
 ) -> vk::Bool32 {
     let msg_type = match message_type {
         vk::DebugUtilsMessageTypeFlagsEXT::GENERAL => "[General]",
@@ -198,7 +219,24 @@ unsafe extern "system" fn debug_callback(
         vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION => "[Validation]",
         _ => "[Unknown]",
     };
-    let msg = unsafe { CStr::from_ptr((*p_callback_data).p_message) };
+    let callback_data = unsafe { &*p_callback_data };
+    let msg = unsafe { CStr::from_ptr(callback_data.p_message) };
+    let id_name = if callback_data.p_message_id_name.is_null() {
+        Default::default()
+    } else {
+        unsafe { CStr::from_ptr(callback_data.p_message_id_name) }.to_string_lossy()
+    };
+    let validation = unsafe { ValidationSink::from_callback_user_data(p_user_data) };
+    let suppressed = validation.record(
+        message_severity,
+        &id_name,
+        callback_data.message_id_number,
+        &msg.to_string_lossy(),
+    );
+    if suppressed {
+        return vk::FALSE;
+    }
+
     match message_severity {
         vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE => {
             log::trace!("[Verbose]{} {:?}", msg_type, msg);
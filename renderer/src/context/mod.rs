@@ -1,9 +1,14 @@
 pub(crate) mod commands;
+pub(crate) mod debug_utils;
 pub(crate) mod desc_set_layout_builder;
 pub(crate) mod device;
+pub(crate) mod device_selection;
 pub(crate) mod instance;
+pub(crate) mod pipeline_cache;
 pub(crate) mod queue;
+pub(crate) mod validation;
 
+use crate::config::RendererConfig;
 use crate::viewport::RenderViewport;
 use ash::vk;
 use color_eyre::Result;
@@ -16,25 +21,31 @@ pub(crate) struct RenderContext {
 }
 
 impl RenderContext {
-    pub fn new(win: &winit::window::Window) -> Result<(Self, RenderViewport)> {
+    pub fn new(
+        win: &winit::window::Window,
+        config: &RendererConfig,
+    ) -> Result<(Self, RenderViewport)> {
         log::info!("Creating RenderContext");
 
-        let ins = instance::RenderInstance::new(Some(win))?;
+        let ins = instance::RenderInstance::new(Some(win), &config.validation)?;
         let sfc = ins.create_surface(win)?;
-        let dev = ins.create_device(&sfc)?;
+        let dev = ins.create_device(&sfc, config)?;
         let vpt = ins.create_viewport(sfc, win, &dev)?;
 
         Ok((Self { ins, dev }, vpt))
     }
 
-    pub fn wait_and_reset_fence(&self, fence: vk::Fence, timeout: Duration) -> Result<()> {
+    pub fn wait_for_fence(&self, fence: vk::Fence, timeout: Duration) -> Result<()> {
         unsafe {
-            let fences = [fence];
             self.dev
                 .logical
-                .wait_for_fences(&fences, true, timeout.as_nanos() as u64)?;
-            self.dev.logical.reset_fences(&fences)?;
+                .wait_for_fences(&[fence], true, timeout.as_nanos() as u64)?;
         }
         Ok(())
     }
+
+    pub fn reset_fence(&self, fence: vk::Fence) -> Result<()> {
+        unsafe { self.dev.logical.reset_fences(&[fence])? };
+        Ok(())
+    }
 }
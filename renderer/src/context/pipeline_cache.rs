@@ -0,0 +1,179 @@
+use ash::vk;
+use color_eyre::Result;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Size of `VkPipelineCacheHeaderVersionOne`
+const HEADER_SIZE: usize = 16 + vk::UUID_SIZE;
+
+/// Pipeline cache shared by every material builder.
+/// Loaded from disk when the device is created and written back when it is dropped,
+/// so pipelines compiled in a previous run do not have to be compiled again.
+pub(crate) struct PipelineCache {
+    pub handle: vk::PipelineCache,
+
+    /// `None` when no cache directory could be determined, in which case nothing is persisted
+    path: Option<PathBuf>,
+    device: Arc<ash::Device>,
+}
+
+impl PipelineCache {
+    pub fn new(
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+        device: Arc<ash::Device>,
+    ) -> Result<Self> {
+        let mut id_props = vk::PhysicalDeviceIDProperties::default();
+        let mut props2 = vk::PhysicalDeviceProperties2::default().push_next(&mut id_props);
+        unsafe { instance.get_physical_device_properties2(physical_device, &mut props2) };
+        let props = props2.properties;
+
+        // A driver update invalidates the cache, so it is part of the file name
+        let path = dirs::cache_dir().map(|dir| {
+            dir.join("dunward").join(format!(
+                "pipeline_cache_{}_{}.bin",
+                hex(&id_props.device_uuid),
+                props.driver_version
+            ))
+        });
+
+        let initial_data = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .filter(|data| {
+                let valid = is_header_valid(
+                    data,
+                    props.vendor_id,
+                    props.device_id,
+                    &props.pipeline_cache_uuid,
+                );
+                if !valid {
+                    log::warn!("Discarding pipeline cache with a mismatching header");
+                }
+                valid
+            })
+            .unwrap_or_default();
+        log::info!(
+            "Creating pipeline cache from {} bytes of existing data",
+            initial_data.len()
+        );
+
+        let handle = unsafe {
+            device.create_pipeline_cache(
+                &vk::PipelineCacheCreateInfo::default().initial_data(&initial_data),
+                None,
+            )?
+        };
+
+        Ok(Self {
+            handle,
+            path,
+            device,
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let data = unsafe { self.device.get_pipeline_cache_data(self.handle)? };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first, so a crash mid-write never leaves a truncated cache behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, &data)?;
+        std::fs::rename(&tmp_path, path)?;
+        log::info!("Saved {} bytes of pipeline cache to {:?}", data.len(), path);
+
+        Ok(())
+    }
+}
+
+impl Drop for PipelineCache {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            log::error!("Failed to save pipeline cache: {e}");
+        }
+        unsafe {
+            self.device.destroy_pipeline_cache(self.handle, None);
+        }
+    }
+}
+
+/// Drivers are supposed to reject foreign cache data themselves, but not all of them do
+fn is_header_valid(
+    data: &[u8],
+    vendor_id: u32,
+    device_id: u32,
+    pipeline_cache_uuid: &[u8; vk::UUID_SIZE],
+) -> bool {
+    if data.len() < HEADER_SIZE {
+        return false;
+    }
+    let read_u32 = |offset: usize| {
+        u32::from_ne_bytes(
+            data[offset..offset + 4]
+                .try_into()
+                .expect("Slice is exactly 4 bytes"),
+        )
+    };
+
+    let header_size = read_u32(0) as usize;
+    let header_version = read_u32(4);
+    header_size >= HEADER_SIZE
+        && header_size <= data.len()
+        && header_version == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+        && read_u32(8) == vendor_id
+        && read_u32(12) == device_id
+        && data[16..HEADER_SIZE] == pipeline_cache_uuid[..]
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: [u8; vk::UUID_SIZE] = [7; vk::UUID_SIZE];
+
+    fn header(vendor_id: u32, device_id: u32, uuid: &[u8; vk::UUID_SIZE]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(HEADER_SIZE as u32).to_ne_bytes());
+        data.extend_from_slice(&1u32.to_ne_bytes());
+        data.extend_from_slice(&vendor_id.to_ne_bytes());
+        data.extend_from_slice(&device_id.to_ne_bytes());
+        data.extend_from_slice(uuid);
+        data
+    }
+
+    #[test]
+    fn accepts_matching_header() {
+        let mut data = header(0x10de, 42, &UUID);
+        data.extend_from_slice(&[0xab; 64]);
+        assert!(is_header_valid(&data, 0x10de, 42, &UUID));
+    }
+
+    #[test]
+    fn rejects_other_device() {
+        let data = header(0x10de, 42, &UUID);
+        assert!(!is_header_valid(&data, 0x1002, 42, &UUID));
+        assert!(!is_header_valid(&data, 0x10de, 43, &UUID));
+        assert!(!is_header_valid(&data, 0x10de, 42, &[0; vk::UUID_SIZE]));
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let data = header(0x10de, 42, &UUID);
+        assert!(!is_header_valid(
+            &data[..HEADER_SIZE - 1],
+            0x10de,
+            42,
+            &UUID
+        ));
+        assert!(!is_header_valid(&[], 0x10de, 42, &UUID));
+    }
+}
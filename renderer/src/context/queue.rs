@@ -1,15 +1,103 @@
+use crate::utils::GuardResultExt;
+use ash::prelude::VkResult;
 use ash::vk;
+use color_eyre::Result;
 use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
 
+/// A device queue that can be submitted to from any thread.
+/// Several roles (graphics, compute, transfer) may resolve to the same `Queue`.
 pub(crate) struct Queue {
     pub family: QueueFamily,
-    pub handle: vk::Queue,
+    /// Index of the queue within its family
+    pub index: u32,
+    handle: vk::Queue,
+    /// Vulkan requires host access to a queue to be externally synchronized
+    lock: Mutex<()>,
+    device: Arc<ash::Device>,
 }
 
 impl Queue {
-    pub fn new(family: QueueFamily, handle: vk::Queue) -> Self {
-        Self { family, handle }
+    /// Get the queue of every `(family, index)` role.
+    /// Roles that resolve to the same queue get the same `Queue`, so they also share its lock.
+    pub fn get_for_roles<const N: usize>(
+        device: &Arc<ash::Device>,
+        roles: [(QueueFamily, u32); N],
+    ) -> [Arc<Queue>; N] {
+        let mut queues: Vec<Arc<Queue>> = Vec::with_capacity(N);
+        roles.map(|(family, index)| {
+            if let Some(queue) = queues
+                .iter()
+                .find(|queue| queue.family == family && queue.index == index)
+            {
+                return queue.clone();
+            }
+            let handle = unsafe { device.get_device_queue(family.index, index) };
+            let queue = Arc::new(Self {
+                family,
+                index,
+                handle,
+                lock: Mutex::new(()),
+                device: device.clone(),
+            });
+            queues.push(queue.clone());
+            queue
+        })
+    }
+
+    pub fn submit2(&self, submits: &[vk::SubmitInfo2], fence: vk::Fence) -> Result<()> {
+        let _guard = self.lock()?;
+        unsafe { self.device.queue_submit2(self.handle, submits, fence)? };
+        Ok(())
+    }
+
+    /// `Ok(true)` means the swapchain is suboptimal
+    pub fn present(
+        &self,
+        swapchain_loader: &ash::khr::swapchain::Device,
+        present_info: &vk::PresentInfoKHR,
+    ) -> Result<VkResult<bool>> {
+        let _guard = self.lock()?;
+        Ok(unsafe { swapchain_loader.queue_present(self.handle, present_info) })
+    }
+
+    /// Hold this to keep other threads from using the queue, e.g. around `vkDeviceWaitIdle`
+    pub fn lock(&self) -> Result<MutexGuard<'_, ()>> {
+        self.lock.lock().eyre()
+    }
+
+    pub fn is_same_queue(&self, other: &Queue) -> bool {
+        self.family == other.family && self.index == other.index
+    }
+}
+
+/// Index of the queue each role gets within its family.
+/// Roles sharing a family get distinct queues while the family has enough of them,
+/// after which the family's last queue is shared.
+pub(crate) fn assign_queue_indices(families: &[&QueueFamily]) -> Vec<u32> {
+    families
+        .iter()
+        .enumerate()
+        .map(|(role, family)| {
+            let earlier_roles = families[..role]
+                .iter()
+                .filter(|earlier| **earlier == *family)
+                .count() as u32;
+            earlier_roles.min(family.properties.queue_count.saturating_sub(1))
+        })
+        .collect()
+}
+
+/// How many queues to request from each family, as `(family index, queue count)`
+pub(crate) fn queue_counts(families: &[&QueueFamily], queue_indices: &[u32]) -> Vec<(u32, u32)> {
+    let mut counts: Vec<(u32, u32)> = Vec::new();
+    for (family, queue_index) in families.iter().zip(queue_indices) {
+        match counts.iter_mut().find(|(index, _)| *index == family.index) {
+            Some((_, count)) => *count = (*count).max(queue_index + 1),
+            None => counts.push((family.index, queue_index + 1)),
+        }
     }
+    counts
 }
 
 #[derive(Clone)]
@@ -70,3 +158,49 @@ impl Hash for QueueFamily {
         self.index.hash(state);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn family(index: u32, queue_count: u32) -> QueueFamily {
+        let properties = vk::QueueFamilyProperties {
+            queue_count,
+            ..Default::default()
+        };
+        QueueFamily::new(index, properties, true)
+    }
+
+    #[test]
+    fn separate_families_use_their_first_queue() {
+        let (graphics, compute, transfer) = (family(0, 1), family(1, 1), family(2, 1));
+        let families = [&graphics, &compute, &transfer];
+        let indices = assign_queue_indices(&families);
+        assert_eq!(indices, [0, 0, 0]);
+        assert_eq!(queue_counts(&families, &indices), [(0, 1), (1, 1), (2, 1)]);
+    }
+
+    #[test]
+    fn shared_family_gets_distinct_queues_when_available() {
+        let universal = family(0, 16);
+        let families = [&universal, &universal, &universal];
+        let indices = assign_queue_indices(&families);
+        assert_eq!(indices, [0, 1, 2]);
+        assert_eq!(queue_counts(&families, &indices), [(0, 3)]);
+    }
+
+    #[test]
+    fn shared_family_with_few_queues_shares_the_last_one() {
+        let universal = family(0, 2);
+        let families = [&universal, &universal, &universal];
+        let indices = assign_queue_indices(&families);
+        assert_eq!(indices, [0, 1, 1]);
+        assert_eq!(queue_counts(&families, &indices), [(0, 2)]);
+
+        let (graphics, compute) = (family(0, 1), family(1, 1));
+        let families = [&graphics, &compute, &compute];
+        let indices = assign_queue_indices(&families);
+        assert_eq!(indices, [0, 0, 0]);
+        assert_eq!(queue_counts(&families, &indices), [(0, 1), (1, 1)]);
+    }
+}
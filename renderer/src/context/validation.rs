@@ -0,0 +1,294 @@
+use crate::config::ValidationConfig;
+use crate::utils::GuardResultExt;
+use ash::vk;
+use color_eyre::Result;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::sync::{Arc, Mutex};
+
+pub(crate) const VALIDATION_ENV: &str = "DUNWARD_VALIDATION";
+/// Comma-separated message IDs that are suppressed in addition to the configured ones
+pub(crate) const VALIDATION_SUPPRESS_ENV: &str = "DUNWARD_VALIDATION_SUPPRESS";
+
+/// Validation settings after applying the environment variables to the config
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ValidationSettings {
+    pub panic_on_error: bool,
+    pub suppressed_message_ids: Vec<String>,
+}
+
+impl ValidationSettings {
+    /// `None` when validation is disabled.
+    /// `DUNWARD_VALIDATION` takes precedence over `ValidationConfig::enabled`
+    /// and can also turn on `panic_on_error`, e.g. to fail CI runs.
+    pub fn from_env_or_config(config: &ValidationConfig) -> Option<Self> {
+        Self::resolve(
+            config,
+            std::env::var(VALIDATION_ENV).ok().as_deref(),
+            std::env::var(VALIDATION_SUPPRESS_ENV).ok().as_deref(),
+        )
+    }
+
+    fn resolve(
+        config: &ValidationConfig,
+        mode: Option<&str>,
+        suppressed: Option<&str>,
+    ) -> Option<Self> {
+        let (enabled, panic_on_error) = match mode.map(|mode| mode.trim().to_lowercase()) {
+            Some(mode) => match mode.as_str() {
+                "0" | "off" | "false" => (false, false),
+                "1" | "on" | "true" => (true, config.panic_on_error),
+                "panic" => (true, true),
+                _ => {
+                    log::warn!("Ignoring unknown {} value: {:?}", VALIDATION_ENV, mode);
+                    (config.enabled, config.panic_on_error)
+                }
+            },
+            None => (config.enabled, config.panic_on_error),
+        };
+        if !enabled {
+            return None;
+        }
+
+        let mut suppressed_message_ids = config.suppressed_message_ids.clone();
+        suppressed_message_ids.extend(
+            suppressed
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(str::to_owned),
+        );
+        Some(Self {
+            panic_on_error,
+            suppressed_message_ids,
+        })
+    }
+
+    /// IDs are matched by name, or by number written in decimal or as `0x`-prefixed hex
+    fn is_suppressed(&self, id_name: &str, id_number: i32) -> bool {
+        self.suppressed_message_ids.iter().any(|id| {
+            id == id_name
+                || id.parse::<i32>() == Ok(id_number)
+                || id
+                    .strip_prefix("0x")
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    == Some(id_number as u32)
+        })
+    }
+}
+
+/// A validation error reported by the validation layers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationMessage {
+    /// e.g. `VUID-vkCmdDraw-None-08600`, empty if the layer did not name the message
+    pub id_name: String,
+    pub id_number: i32,
+    pub message: String,
+}
+
+#[derive(Default)]
+struct ValidationLog {
+    settings: ValidationSettings,
+    /// Errors in the order they were reported
+    errors: Vec<ValidationMessage>,
+    /// Number of errors and warnings reported per message ID name
+    counts: HashMap<String, usize>,
+    /// Errors reported before the last `check`
+    checked_errors: usize,
+}
+
+/// Collects the validation errors reported by the validation layers.
+/// Clones share the same messages.
+#[derive(Clone)]
+pub struct ValidationSink(Arc<Mutex<ValidationLog>>);
+
+impl ValidationSink {
+    pub(crate) fn new(settings: ValidationSettings) -> Self {
+        Self(Arc::new(Mutex::new(ValidationLog {
+            settings,
+            ..Default::default()
+        })))
+    }
+
+    /// Every error reported so far, oldest first
+    pub fn errors(&self) -> Vec<ValidationMessage> {
+        self.0
+            .lock()
+            .map(|log| log.errors.clone())
+            .unwrap_or_default()
+    }
+
+    /// How many errors and warnings were reported with each message ID name
+    pub fn counts(&self) -> HashMap<String, usize> {
+        self.0
+            .lock()
+            .map(|log| log.counts.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut log) = self.0.lock() {
+            log.errors.clear();
+            log.counts.clear();
+            log.checked_errors = 0;
+        }
+    }
+
+    /// Pointer to pass as the messenger's user data, see `from_callback_user_data`.
+    /// It holds a reference that is never released, since the instance is never destroyed
+    /// and its messenger may be called back until then.
+    pub(crate) fn callback_user_data(&self) -> *mut c_void {
+        Arc::into_raw(self.0.clone()) as *mut c_void
+    }
+
+    /// # Safety
+    /// `user_data` must come from `callback_user_data`
+    pub(crate) unsafe fn from_callback_user_data(user_data: *mut c_void) -> Self {
+        let log = user_data as *const Mutex<ValidationLog>;
+        unsafe {
+            Arc::increment_strong_count(log);
+            Self(Arc::from_raw(log))
+        }
+    }
+
+    /// Panics on the first error reported since the last check, if `panic_on_error` is set.
+    /// Panicking in the messenger callback would abort, since it is called from the driver.
+    pub(crate) fn check(&self) -> Result<()> {
+        let mut log = self.0.lock().eyre()?;
+        let new_error = log.errors.get(log.checked_errors).cloned();
+        log.checked_errors = log.errors.len();
+        let panic_on_error = log.settings.panic_on_error;
+        drop(log);
+
+        if let Some(error) = new_error.filter(|_| panic_on_error) {
+            panic!(
+                "Vulkan validation error {} ({:#x}): {}",
+                error.id_name, error.id_number, error.message
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns whether the message is suppressed, in which case it should not be logged
+    pub(crate) fn record(
+        &self,
+        severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+        id_name: &str,
+        id_number: i32,
+        message: &str,
+    ) -> bool {
+        let Ok(mut log) = self.0.lock() else {
+            return false;
+        };
+        if log.settings.is_suppressed(id_name, id_number) {
+            return true;
+        }
+
+        if severity.intersects(
+            vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+        ) {
+            *log.counts.entry(id_name.to_owned()).or_default() += 1;
+        }
+        if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+            log.errors.push(ValidationMessage {
+                id_name: id_name.to_owned(),
+                id_number,
+                message: message.to_owned(),
+            });
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ERROR: vk::DebugUtilsMessageSeverityFlagsEXT =
+        vk::DebugUtilsMessageSeverityFlagsEXT::ERROR;
+    const WARNING: vk::DebugUtilsMessageSeverityFlagsEXT =
+        vk::DebugUtilsMessageSeverityFlagsEXT::WARNING;
+
+    fn settings(panic_on_error: bool, suppressed: &[&str]) -> ValidationSettings {
+        ValidationSettings {
+            panic_on_error,
+            suppressed_message_ids: suppressed.iter().map(|id| id.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn environment_overrides_the_config() {
+        let config = ValidationConfig {
+            enabled: false,
+            panic_on_error: false,
+            suppressed_message_ids: vec!["VUID-A".to_owned()],
+        };
+        assert_eq!(ValidationSettings::resolve(&config, None, None), None);
+        assert_eq!(
+            ValidationSettings::resolve(&config, Some("panic"), Some("VUID-B, 0x1f ,")),
+            Some(settings(true, &["VUID-A", "VUID-B", "0x1f"]))
+        );
+
+        let config = ValidationConfig {
+            enabled: true,
+            ..config
+        };
+        assert_eq!(
+            ValidationSettings::resolve(&config, Some("1"), None),
+            Some(settings(false, &["VUID-A"]))
+        );
+        assert_eq!(
+            ValidationSettings::resolve(&config, Some("off"), None),
+            None
+        );
+    }
+
+    #[test]
+    fn errors_are_counted_per_message_id() {
+        let sink = ValidationSink::new(settings(false, &[]));
+        sink.record(ERROR, "VUID-A", 1, "first");
+        sink.record(ERROR, "VUID-A", 1, "second");
+        sink.record(WARNING, "BestPractices-B", 2, "warning");
+        sink.record(
+            vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE,
+            "",
+            0,
+            "chatter",
+        );
+
+        let messages = sink
+            .errors()
+            .into_iter()
+            .map(|error| error.message)
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["first", "second"]);
+        assert_eq!(
+            sink.counts(),
+            HashMap::from([("VUID-A".to_owned(), 2), ("BestPractices-B".to_owned(), 1)])
+        );
+
+        sink.clear();
+        assert!(sink.errors().is_empty() && sink.counts().is_empty());
+    }
+
+    #[test]
+    fn suppressed_ids_are_ignored() {
+        let sink = ValidationSink::new(settings(true, &["VUID-A", "0x00000002", "-3"]));
+        assert!(sink.record(ERROR, "VUID-A", 1, "by name"));
+        assert!(sink.record(ERROR, "VUID-B", 2, "by hex number"));
+        assert!(sink.record(ERROR, "VUID-C", -3, "by decimal number"));
+        assert!(!sink.record(ERROR, "VUID-D", 4, "reported"));
+        assert_eq!(sink.errors().len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "VUID-A")]
+    fn check_panics_on_new_errors() {
+        let sink = ValidationSink::new(settings(true, &[]));
+        sink.check().unwrap();
+        sink.record(ERROR, "VUID-A", 1, "message");
+        sink.check().unwrap();
+    }
+}
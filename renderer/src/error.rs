@@ -0,0 +1,88 @@
+use ash::vk;
+use color_eyre::Report;
+
+/// Errors returned by `Renderer`.
+/// Vulkan results that callers can act on get their own variant, everything else is `Other`.
+#[derive(Debug, thiserror::Error)]
+pub enum RendererError {
+    /// The swapchain no longer matches the surface. The frame was skipped and a resize requested.
+    #[error("Swapchain is out of date")]
+    SwapchainOutOfDate,
+    /// Recoverable with `Renderer::recover`
+    #[error("Surface was lost")]
+    SurfaceLost,
+    /// Recoverable with `Renderer::recover`
+    #[error("Device was lost")]
+    DeviceLost,
+    #[error("Out of host or device memory")]
+    OutOfMemory,
+    #[error("Timed out waiting for the GPU")]
+    Timeout,
+    #[error("{0:#}")]
+    Other(Report),
+}
+
+pub type RendererResult<T> = std::result::Result<T, RendererError>;
+
+impl RendererError {
+    /// Whether `Renderer::recover` can bring the renderer back after this error
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, RendererError::SurfaceLost | RendererError::DeviceLost)
+    }
+
+    fn from_vk_result(result: vk::Result) -> Option<Self> {
+        match result {
+            vk::Result::ERROR_OUT_OF_DATE_KHR => Some(RendererError::SwapchainOutOfDate),
+            vk::Result::ERROR_SURFACE_LOST_KHR => Some(RendererError::SurfaceLost),
+            vk::Result::ERROR_DEVICE_LOST => Some(RendererError::DeviceLost),
+            vk::Result::ERROR_OUT_OF_HOST_MEMORY | vk::Result::ERROR_OUT_OF_DEVICE_MEMORY => {
+                Some(RendererError::OutOfMemory)
+            }
+            vk::Result::TIMEOUT | vk::Result::NOT_READY => Some(RendererError::Timeout),
+            _ => None,
+        }
+    }
+}
+
+impl From<Report> for RendererError {
+    /// Internally errors are reports, with the `vk::Result` that caused them somewhere in the chain
+    fn from(report: Report) -> Self {
+        report
+            .chain()
+            .find_map(|error| error.downcast_ref::<vk::Result>().copied())
+            .and_then(Self::from_vk_result)
+            .unwrap_or(RendererError::Other(report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_eyre::eyre::eyre;
+
+    #[test]
+    fn classifies_vk_results_anywhere_in_the_chain() {
+        let report = Report::new(vk::Result::ERROR_DEVICE_LOST).wrap_err("Failed to submit");
+        assert!(matches!(
+            RendererError::from(report),
+            RendererError::DeviceLost
+        ));
+
+        let report = Report::new(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY);
+        assert!(matches!(
+            RendererError::from(report),
+            RendererError::OutOfMemory
+        ));
+    }
+
+    #[test]
+    fn other_errors_keep_their_report() {
+        let error = RendererError::from(eyre!("Shader not found"));
+        assert!(matches!(error, RendererError::Other(_)));
+        assert_eq!(error.to_string(), "Shader not found");
+        assert!(!error.is_recoverable());
+
+        let error = RendererError::from(Report::new(vk::Result::ERROR_FORMAT_NOT_SUPPORTED));
+        assert!(matches!(error, RendererError::Other(_)));
+    }
+}
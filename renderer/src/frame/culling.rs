@@ -0,0 +1,550 @@
+use crate::context::commands::CommandEncoder;
+use crate::context::desc_set_layout_builder::DescriptorBinding;
+use crate::context::device::RenderDevice;
+use crate::frame::instancing::InstanceBatch;
+use crate::resources::material::{ComputeMaterialFactoryBuilder, Material, MaterialFactory};
+use crate::resources::megabuffer::{AllocatedMegabufferRegion, Megabuffer, MegabufferExt};
+use crate::resources::resource_type::RenderResourceType;
+use crate::resources::shader::ComputeShader;
+use crate::storage::shader_data::{DrawCandidateData, PerDrawData, PerFrameData};
+use crate::storage::{RenderStorage, TargetMaterialFactories};
+use crate::utils::GuardResultExt;
+use ash::vk;
+use color_eyre::Result;
+use color_eyre::eyre::{OptionExt, eyre};
+use glam::{Mat4, Vec4};
+use std::ops::Range;
+use std::sync::Mutex;
+
+/// Must match `local_size_x` in `cull.comp`
+const WORKGROUP_SIZE: u32 = 64;
+/// Most meshes of objects a frame can cull, see `Renderer::draw`
+pub(crate) const MAX_DRAW_CANDIDATES: u64 = 16 * 1024;
+
+const DRAW_CANDIDATE_BUFFER_SIZE: u64 = 4 * 1024 * 1024; // 4 MB
+const INDIRECT_BUFFER_SIZE: u64 = 4 * 1024 * 1024; // 4 MB
+const STORAGE_BUFFER_ALIGNMENT: u64 = 16;
+
+/// The culling pipeline and the megabuffers every frame culls from and into
+pub(crate) struct GpuCulling {
+    material_factory: MaterialFactory,
+    /// Bounds and draw parameters of the meshes of every object to cull
+    candidate_megabuffer: Megabuffer,
+    /// Indirect commands and draw counts written by the culling shader
+    indirect_megabuffer: Megabuffer,
+}
+
+impl GpuCulling {
+    /// The culling shader reads the bindless set, so it shares the bindless layouts
+    pub fn new(
+        pipeline_layout: vk::PipelineLayout,
+        push_constant_ranges: &[vk::PushConstantRange],
+        descriptor_set_layout: vk::DescriptorSetLayout,
+        descriptor_set_layout_bindings: &[DescriptorBinding],
+        dev: &RenderDevice,
+    ) -> Result<Self> {
+        let shader = ComputeShader::new("cull", "main", dev.logical.clone())?;
+        let material_factory = ComputeMaterialFactoryBuilder::new(
+            dev.logical.clone(),
+            dev.descriptor_allocator.clone(),
+            dev.pipeline_cache.handle,
+            dev.debug_utils.clone(),
+        )
+        .with_shader(shader)
+        .with_pipeline_layout(pipeline_layout, push_constant_ranges)
+        .with_descriptor_set_layout(descriptor_set_layout, descriptor_set_layout_bindings)
+        .build()?;
+
+        let candidate_megabuffer = dev.create_megabuffer(
+            "Draw candidate megabuffer",
+            DRAW_CANDIDATE_BUFFER_SIZE,
+            STORAGE_BUFFER_ALIGNMENT,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+        )?;
+        // Only written on the GPU, the draw counts being cleared with a fill
+        let indirect_megabuffer = dev.create_megabuffer(
+            "Indirect megabuffer",
+            INDIRECT_BUFFER_SIZE,
+            STORAGE_BUFFER_ALIGNMENT,
+            vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::INDIRECT_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_DST,
+        )?;
+
+        Ok(Self {
+            material_factory,
+            candidate_megabuffer,
+            indirect_megabuffer,
+        })
+    }
+
+    #[cfg(feature = "hot-reload")]
+    pub fn material_factory_mut(&mut self) -> &mut MaterialFactory {
+        &mut self.material_factory
+    }
+}
+
+/// Draws of a group share a pipeline and material, so one indirect draw covers all of them
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct IndirectDrawGroup<'a> {
+    /// Name of the defined material whose pipeline draws the group, the bindless one otherwise
+    pub material: Option<&'a str>,
+    pub material_index: u32,
+    /// Blended groups come after every opaque one, see `InstanceBatch::blended`
+    pub blended: bool,
+    /// The visible candidates of the group are compacted into the first of these commands
+    pub commands: Range<u32>,
+}
+
+/// A candidate with whether its material blends, and the pipeline and material it is drawn
+/// with, see `group_candidates`
+pub(crate) type UngroupedCandidate<'a> = (bool, Option<&'a str>, u32, DrawCandidateData);
+
+/// One candidate for every mesh of every instance of the batches, whose objects are in batch
+/// order. `material_indices` has the material of each mesh of each batch, or `None` to skip it.
+/// Models that are not in the vertex megabuffer as a single stream are skipped too, since
+/// indirect draws of different models can only share vertex buffer bindings that way.
+pub(crate) fn collect_candidates<'a>(
+    sto: &RenderStorage,
+    batches: &[InstanceBatch<'a>],
+    material_indices: &[Option<Vec<u32>>],
+) -> Vec<UngroupedCandidate<'a>> {
+    let mut candidates = Vec::new();
+    for (batch, material_indices) in batches.iter().zip(material_indices) {
+        let Some(material_indices) = material_indices else {
+            continue;
+        };
+        let Some((model, _)) = sto.model(batch.model) else {
+            continue;
+        };
+        let Some(submeshes) = model.submeshes(batch.lod) else {
+            continue;
+        };
+        let Some(first_vertex) = model.first_vertex() else {
+            log::warn!(
+                "Model {:?} does not start at a whole vertex of the vertex megabuffer, skipping it",
+                batch.model
+            );
+            continue;
+        };
+
+        let sphere = model.bounding_sphere();
+        for (submesh, &material_index) in submeshes.iter().zip(material_indices) {
+            candidates.extend(batch.instances.clone().map(|object_index| {
+                let data = DrawCandidateData {
+                    center: sphere.center,
+                    radius: sphere.radius,
+                    object_index,
+                    first_index: submesh.first_index,
+                    index_count: submesh.index_count,
+                    vertex_offset: first_vertex as i32 + submesh.vertex_offset,
+                    ..Default::default()
+                };
+                (batch.blended, batch.material, material_index, data)
+            }));
+        }
+    }
+    candidates
+}
+
+/// Sort candidates by pipeline and material, blended ones last, and give every run sharing
+/// both a range of commands and a draw count. Returns the candidates in sorted order and the groups.
+pub(crate) fn group_candidates<'a>(
+    mut candidates: Vec<UngroupedCandidate<'a>>,
+) -> (Vec<DrawCandidateData>, Vec<IndirectDrawGroup<'a>>) {
+    candidates
+        .sort_by_key(|&(blended, material, material_index, _)| (blended, material, material_index));
+
+    let mut groups = Vec::<IndirectDrawGroup>::new();
+    let mut sorted = Vec::with_capacity(candidates.len());
+    for (blended, material, material_index, mut data) in candidates {
+        let index = sorted.len() as u32;
+        match groups.last_mut() {
+            Some(group) if (group.material, group.material_index) == (material, material_index) => {
+                group.commands.end = index + 1;
+            }
+            _ => groups.push(IndirectDrawGroup {
+                material,
+                material_index,
+                blended,
+                commands: index..index + 1,
+            }),
+        }
+        data.first_command = groups.last().unwrap().commands.start;
+        data.group_index = groups.len() as u32 - 1;
+        sorted.push(data);
+    }
+    (sorted, groups)
+}
+
+/// Left, right, bottom, top, near and far planes of `viewproj`, normalized and pointing inwards.
+/// Clip space depth goes from 0 to 1.
+pub(crate) fn frustum_planes(viewproj: Mat4) -> [Vec4; 6] {
+    let [x, y, z, w] = [0, 1, 2, 3].map(|row| viewproj.row(row));
+    [w + x, w - x, w + y, w - y, z, w - z].map(|plane| plane / plane.truncate().length())
+}
+
+/// Regions and commands of one frame's culling pass
+pub(crate) struct FrameCulling {
+    candidate_region: AllocatedMegabufferRegion,
+    command_region: AllocatedMegabufferRegion,
+    count_region: AllocatedMegabufferRegion,
+    /// Has its own bindless set, which is bound for compute
+    material: Material,
+    cmd_encoder: Mutex<CommandEncoder>,
+    /// Signals when the indirect commands have been written
+    semaphore: vk::Semaphore,
+    /// The command and count regions are written by the first family and read by the second
+    compute_family: u32,
+    graphics_family: u32,
+}
+
+impl FrameCulling {
+    pub fn new(culling: &mut GpuCulling, dev: &mut RenderDevice) -> Result<Self> {
+        let candidate_region = culling
+            .candidate_megabuffer
+            .allocate_region(MAX_DRAW_CANDIDATES * size_of::<DrawCandidateData>() as u64)?;
+        let command_region = culling.indirect_megabuffer.allocate_region(
+            MAX_DRAW_CANDIDATES * size_of::<vk::DrawIndexedIndirectCommand>() as u64,
+        )?;
+        // Every candidate may be in a group of its own
+        let count_region = culling
+            .indirect_megabuffer
+            .allocate_region(MAX_DRAW_CANDIDATES * size_of::<u32>() as u64)?;
+
+        let material = culling.material_factory.create_material()?;
+        let cmd_encoder =
+            dev.allocate_command_encoder(dev.compute_queue.clone(), "Culling commands")?;
+        let semaphore = dev.create_semaphore("Culling semaphore")?;
+        let compute_family = dev.compute_queue.family.index;
+        let graphics_family = dev.graphics_queue.family.index;
+
+        Ok(Self {
+            candidate_region,
+            command_region,
+            count_region,
+            material,
+            cmd_encoder: Mutex::new(cmd_encoder),
+            semaphore,
+            compute_family,
+            graphics_family,
+        })
+    }
+
+    /// Cull the candidates on the compute queue, returning the semaphore the draws have to wait
+    /// for. The per-frame and per-object regions must have been uploaded already.
+    /// If a semaphore is returned, the draws have to `acquire_commands` before reading them.
+    pub fn cull(
+        &self,
+        candidates: &[DrawCandidateData],
+        group_count: usize,
+        per_frame_region: &AllocatedMegabufferRegion,
+        per_object_region: &AllocatedMegabufferRegion,
+        sto: &RenderStorage,
+        culling: &GpuCulling,
+    ) -> Result<Option<vk::SemaphoreSubmitInfo<'static>>> {
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+        if candidates.len() as u64 > MAX_DRAW_CANDIDATES {
+            return Err(eyre!(
+                "{} meshes to cull, but at most {} fit",
+                candidates.len(),
+                MAX_DRAW_CANDIDATES
+            ));
+        }
+
+        culling
+            .candidate_megabuffer
+            .write(candidates, &self.candidate_region)?;
+        culling
+            .candidate_megabuffer
+            .upload_region(&self.candidate_region)?;
+        self.write_descriptors(
+            size_of_val(candidates) as u64,
+            per_frame_region,
+            per_object_region,
+            sto,
+            culling,
+        )?;
+
+        let indirect_buffer = culling.indirect_megabuffer.raw_buffer()?;
+        let mut cmd = self.cmd_encoder.lock().eyre()?;
+        cmd.begin_recording()?;
+        {
+            let _scope = cmd.scope("Culling");
+            cmd.fill_buffer(
+                indirect_buffer,
+                self.count_region.offset(),
+                (group_count * size_of::<u32>()) as u64,
+                0,
+            );
+            cmd.memory_barrier(
+                vk::PipelineStageFlags2::CLEAR,
+                vk::AccessFlags2::TRANSFER_WRITE,
+                vk::PipelineStageFlags2::COMPUTE_SHADER,
+                vk::AccessFlags2::SHADER_STORAGE_READ | vk::AccessFlags2::SHADER_STORAGE_WRITE,
+            );
+            self.material.bind_pipeline(cmd.command_buffer)?;
+            self.material.bind_descriptor_sets(cmd.command_buffer);
+            cmd.dispatch((candidates.len() as u32).div_ceil(WORKGROUP_SIZE), 1, 1);
+            if let Some(release) = self.ownership_transfers(culling)? {
+                cmd.buffer_barriers(&release.map(|barrier| {
+                    barrier
+                        .src_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)
+                        .src_access_mask(vk::AccessFlags2::SHADER_STORAGE_WRITE)
+                }));
+            }
+        }
+        cmd.end_recording()?;
+
+        // Waiting for the frame's fence covers this submission too, since the draws wait for it
+        let signal_semaphores = [vk::SemaphoreSubmitInfo::default()
+            .semaphore(self.semaphore)
+            .stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)];
+        cmd.submit(&[], &signal_semaphores, vk::Fence::null())?;
+
+        Ok(Some(
+            vk::SemaphoreSubmitInfo::default()
+                .semaphore(self.semaphore)
+                .stage_mask(vk::PipelineStageFlags2::DRAW_INDIRECT),
+        ))
+    }
+
+    /// Take the commands and counts over from the compute queue's family, with the other half
+    /// of the ownership transfer `cull` recorded. Must be recorded before the draws, outside
+    /// of rendering.
+    pub fn acquire_commands(&self, cmd: &CommandEncoder, culling: &GpuCulling) -> Result<()> {
+        if let Some(acquire) = self.ownership_transfers(culling)? {
+            cmd.buffer_barriers(&acquire.map(|barrier| {
+                barrier
+                    .dst_stage_mask(vk::PipelineStageFlags2::DRAW_INDIRECT)
+                    .dst_access_mask(vk::AccessFlags2::INDIRECT_COMMAND_READ)
+            }));
+        }
+        Ok(())
+    }
+
+    /// Barriers handing the command and count regions over from the compute queue's family to
+    /// the graphics queue's, without stages and accesses. `None` if the families are the same.
+    /// The regions are overwritten by the next cull, so they are never handed back.
+    fn ownership_transfers(
+        &self,
+        culling: &GpuCulling,
+    ) -> Result<Option<[vk::BufferMemoryBarrier2<'static>; 2]>> {
+        if self.compute_family == self.graphics_family {
+            return Ok(None);
+        }
+        let indirect_buffer = culling.indirect_megabuffer.raw_buffer()?;
+        Ok(Some([&self.command_region, &self.count_region].map(
+            |region| {
+                vk::BufferMemoryBarrier2::default()
+                    .src_queue_family_index(self.compute_family)
+                    .dst_queue_family_index(self.graphics_family)
+                    .buffer(indirect_buffer)
+                    .offset(region.offset())
+                    .size(region.size())
+            },
+        )))
+    }
+
+    fn write_descriptors(
+        &self,
+        candidates_size: u64,
+        per_frame_region: &AllocatedMegabufferRegion,
+        per_object_region: &AllocatedMegabufferRegion,
+        sto: &RenderStorage,
+        culling: &GpuCulling,
+    ) -> Result<()> {
+        let uniform_buffer = RenderResourceType::UniformBuffer.descriptor_type();
+        let storage_buffer = RenderResourceType::StorageBuffer.descriptor_type();
+        let indirect_buffer = culling.indirect_megabuffer.raw_buffer()?;
+        self.material.write_buffer(
+            0,
+            uniform_buffer,
+            sto.per_frame_megabuffer.raw_buffer()?,
+            per_frame_region.offset(),
+            size_of::<PerFrameData>() as u64,
+        );
+        self.material.write_buffer(
+            2,
+            storage_buffer,
+            sto.per_object_megabuffer.raw_buffer()?,
+            per_object_region.offset(),
+            per_object_region.size(),
+        );
+        // The shader culls as many candidates as the range holds
+        self.material.write_buffer(
+            5,
+            storage_buffer,
+            culling.candidate_megabuffer.raw_buffer()?,
+            self.candidate_region.offset(),
+            candidates_size,
+        );
+        self.material.write_buffer(
+            6,
+            storage_buffer,
+            indirect_buffer,
+            self.command_region.offset(),
+            self.command_region.size(),
+        );
+        self.material.write_buffer(
+            7,
+            storage_buffer,
+            indirect_buffer,
+            self.count_region.offset(),
+            self.count_region.size(),
+        );
+        Ok(())
+    }
+
+    /// One indirect draw per group that is `blended` or not, with the commands the culling
+    /// pass wrote for it.
+    /// Objects are indexed by `firstInstance`, so the draws have a `base_object_index` of 0.
+    pub fn record_draws(
+        &self,
+        cmd: &CommandEncoder,
+        groups: &[IndirectDrawGroup],
+        blended: bool,
+        bindless_material: &Material,
+        factories: &TargetMaterialFactories,
+        sto: &RenderStorage,
+    ) -> Result<()> {
+        if !groups.iter().any(|group| group.blended == blended) {
+            return Ok(());
+        }
+        let _scope = cmd.scope(if blended {
+            "Blended indirect draws"
+        } else {
+            "Indirect draws"
+        });
+
+        let culling = sto
+            .gpu_culling
+            .as_ref()
+            .ok_or_eyre("GPU culling is disabled")?;
+        let indirect_buffer = culling.indirect_megabuffer.raw_buffer()?;
+        cmd.bind_index_buffer(sto.index_megabuffer.raw_buffer()?, vk::IndexType::UINT32);
+        // The commands index vertices from the start of the vertex megabuffer
+        cmd.bind_vertex_buffers(sto.vertex_megabuffer.raw_buffer()?, &[0]);
+        bindless_material.bind_descriptor_sets(cmd.command_buffer);
+
+        let command_size = size_of::<vk::DrawIndexedIndirectCommand>() as u64;
+        let count_size = size_of::<u32>() as u64;
+        for (group_index, group) in (0..).zip(groups) {
+            if group.blended != blended {
+                continue;
+            }
+            match group.material.and_then(|name| factories.defined.get(name)) {
+                Some(factory) => factory.bind_pipeline(cmd.command_buffer)?,
+                None => bindless_material.bind_pipeline(cmd.command_buffer)?,
+            }
+            let per_draw = PerDrawData {
+                base_object_index: 0,
+                material_index: group.material_index,
+                vertex_offset: 0,
+            };
+            bindless_material
+                .update_push_constants(cmd.command_buffer, bytemuck::bytes_of(&per_draw));
+            cmd.draw_indexed_indirect_count(
+                indirect_buffer,
+                self.command_region.offset() + u64::from(group.commands.start) * command_size,
+                indirect_buffer,
+                self.count_region.offset() + group_index * count_size,
+                group.commands.len() as u32,
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec3;
+
+    fn candidate(object_index: u32) -> DrawCandidateData {
+        DrawCandidateData {
+            object_index,
+            ..Default::default()
+        }
+    }
+
+    fn is_visible(planes: &[Vec4; 6], center: Vec3, radius: f32) -> bool {
+        planes
+            .iter()
+            .all(|plane| plane.truncate().dot(center) + plane.w >= -radius)
+    }
+
+    #[test]
+    fn groups_candidates_by_pipeline_and_material() {
+        let (sorted, groups) = group_candidates(vec![
+            (false, None, 1, candidate(0)),
+            (false, Some("glow"), 0, candidate(1)),
+            (false, None, 0, candidate(2)),
+            (false, None, 1, candidate(3)),
+        ]);
+
+        assert_eq!(
+            sorted.iter().map(|c| c.object_index).collect::<Vec<_>>(),
+            [2, 0, 3, 1]
+        );
+        assert_eq!(
+            groups,
+            [
+                IndirectDrawGroup {
+                    material: None,
+                    material_index: 0,
+                    blended: false,
+                    commands: 0..1,
+                },
+                IndirectDrawGroup {
+                    material: None,
+                    material_index: 1,
+                    blended: false,
+                    commands: 1..3,
+                },
+                IndirectDrawGroup {
+                    material: Some("glow"),
+                    material_index: 0,
+                    blended: false,
+                    commands: 3..4,
+                },
+            ]
+        );
+        // Each candidate knows where its group's commands and count are
+        assert_eq!(
+            sorted
+                .iter()
+                .map(|c| (c.first_command, c.group_index))
+                .collect::<Vec<_>>(),
+            [(0, 0), (1, 1), (1, 1), (3, 2)]
+        );
+    }
+
+    #[test]
+    fn frustum_planes_enclose_what_the_camera_sees() {
+        let proj = Mat4::perspective_rh(90f32.to_radians(), 1.0, 0.1, 100.0);
+        let view = Mat4::look_to_rh(Vec3::ZERO, Vec3::NEG_Z, Vec3::Y);
+        let planes = frustum_planes(proj * view);
+
+        assert!(is_visible(&planes, Vec3::new(0.0, 0.0, -10.0), 0.5));
+        // Behind the camera and past the far plane
+        assert!(!is_visible(&planes, Vec3::new(0.0, 0.0, 10.0), 0.5));
+        assert!(!is_visible(&planes, Vec3::new(0.0, 0.0, -110.0), 0.5));
+        // A 90° field of view ends where |x| or |y| equals the distance
+        assert!(!is_visible(&planes, Vec3::new(12.0, 0.0, -10.0), 0.5));
+        assert!(!is_visible(&planes, Vec3::new(0.0, -12.0, -10.0), 0.5));
+        // Spheres straddling a plane are kept
+        assert!(is_visible(&planes, Vec3::new(12.0, 0.0, -10.0), 2.0));
+    }
+
+    #[test]
+    fn frustum_planes_are_normalized() {
+        let proj = Mat4::perspective_rh(60f32.to_radians(), 16.0 / 9.0, 0.1, 50.0);
+        let view = Mat4::look_at_rh(Vec3::new(3.0, 4.0, 5.0), Vec3::ZERO, Vec3::Y);
+        for plane in frustum_planes(proj * view) {
+            assert!((plane.truncate().length() - 1.0).abs() < 1e-5);
+        }
+    }
+}
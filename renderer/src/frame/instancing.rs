@@ -0,0 +1,207 @@
+use glam::Mat4;
+use std::ops::Range;
+
+/// A model loaded with `Renderer::load_obj` or `Renderer::load_gltf`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ModelKey {
+    /// Name returned by `Renderer::load_obj`
+    Obj(String),
+    /// Index into `ModelScene::meshes` of a glTF file, by its scene name
+    Gltf { file: String, mesh: usize },
+}
+
+/// One placement of a model in the next frame, see `Renderer::draw`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DrawInstance {
+    pub model: ModelKey,
+    /// Name returned by `Renderer::load_material`, drawing every mesh of the model with it
+    /// instead of the model's own materials
+    pub material: Option<String>,
+    pub transform: Mat4,
+    /// Stable across frames for the same object, so its level of detail changes with some
+    /// hysteresis instead of popping back and forth. Instances without one choose it afresh.
+    pub id: Option<u64>,
+    /// Joint palette of a skinned model, from `SceneSkin::joint_palette`, with at least
+    /// `Model::joint_count` matrices. Required for skinned models and refused for others.
+    pub joints: Option<Vec<Mat4>>,
+}
+
+/// Instances drawn together by one instanced draw per mesh of their model
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct InstanceBatch<'a> {
+    pub model: &'a ModelKey,
+    pub material: Option<&'a str>,
+    /// Whether the material blends with what is behind it, so it is drawn after every opaque batch
+    pub blended: bool,
+    /// Level of detail of the model drawn
+    pub lod: usize,
+    /// Range into the sorted instances, which is also the range of their `PerObjectData`
+    pub instances: Range<u32>,
+}
+
+/// Sort instances by material, then by model and level of detail from `lods`, so pipelines
+/// are switched as little as possible and instances sharing all three end up next to each
+/// other. Materials for which `is_blended` is true go after the opaque ones.
+/// Returns the indices of the instances in sorted order, and the batches of consecutive
+/// instances sharing all three.
+pub(crate) fn batch_instances<'a>(
+    instances: &'a [DrawInstance],
+    lods: &[usize],
+    is_blended: impl Fn(&str) -> bool,
+) -> (Vec<usize>, Vec<InstanceBatch<'a>>) {
+    let key = |index: usize| {
+        let instance = &instances[index];
+        let material = instance.material.as_deref();
+        (
+            material.is_some_and(&is_blended),
+            material,
+            &instance.model,
+            lods[index],
+        )
+    };
+
+    // Stable, so instances sharing a batch keep the order they were submitted in
+    let mut order = (0..instances.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| key(a).cmp(&key(b)));
+
+    let mut batches = Vec::new();
+    let mut start = 0;
+    for run in order.chunk_by(|&a, &b| key(a) == key(b)) {
+        let (blended, material, model, lod) = key(run[0]);
+        let end = start + run.len() as u32;
+        batches.push(InstanceBatch {
+            model,
+            material,
+            blended,
+            lod,
+            instances: start..end,
+        });
+        start = end;
+    }
+    (order, batches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec3;
+
+    fn instance(model: &str, material: Option<&str>, x: f32) -> DrawInstance {
+        DrawInstance {
+            model: ModelKey::Obj(model.to_owned()),
+            material: material.map(str::to_owned),
+            transform: Mat4::from_translation(Vec3::X * x),
+            id: None,
+            joints: None,
+        }
+    }
+
+    #[test]
+    fn batches_instances_sharing_model_and_material() {
+        let instances = [
+            instance("tile", None, 0.0),
+            instance("wall", None, 1.0),
+            instance("tile", Some("glow"), 2.0),
+            instance("tile", None, 3.0),
+            instance("wall", None, 4.0),
+            instance("tile", None, 5.0),
+        ];
+        let (order, batches) = batch_instances(&instances, &[0; 6], |_| false);
+
+        // Submission order is kept within a batch
+        assert_eq!(order, [0, 3, 5, 1, 4, 2]);
+        let tile = ModelKey::Obj("tile".to_owned());
+        let wall = ModelKey::Obj("wall".to_owned());
+        assert_eq!(
+            batches,
+            [
+                InstanceBatch {
+                    model: &tile,
+                    material: None,
+                    blended: false,
+                    lod: 0,
+                    instances: 0..3,
+                },
+                InstanceBatch {
+                    model: &wall,
+                    material: None,
+                    blended: false,
+                    lod: 0,
+                    instances: 3..5,
+                },
+                InstanceBatch {
+                    model: &tile,
+                    material: Some("glow"),
+                    blended: false,
+                    lod: 0,
+                    instances: 5..6,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn gltf_meshes_of_one_file_batch_separately() {
+        let mesh = |mesh| DrawInstance {
+            model: ModelKey::Gltf {
+                file: "props".to_owned(),
+                mesh,
+            },
+            material: None,
+            transform: Mat4::IDENTITY,
+            id: None,
+            joints: None,
+        };
+        let instances = [mesh(1), mesh(0), mesh(1)];
+        let (_, batches) = batch_instances(&instances, &[0; 3], |_| false);
+        assert_eq!(
+            batches
+                .iter()
+                .map(|batch| batch.instances.clone())
+                .collect::<Vec<_>>(),
+            [0..1, 1..3]
+        );
+    }
+
+    #[test]
+    fn blended_materials_batch_after_opaque_ones() {
+        let instances = [
+            instance("tile", Some("glass"), 0.0),
+            instance("tile", Some("stone"), 1.0),
+            instance("tile", None, 2.0),
+        ];
+        let (order, batches) = batch_instances(&instances, &[0; 3], |material| material == "glass");
+        assert_eq!(order, [2, 1, 0]);
+        assert_eq!(
+            batches
+                .iter()
+                .map(|batch| (batch.material, batch.blended))
+                .collect::<Vec<_>>(),
+            [(None, false), (Some("stone"), false), (Some("glass"), true)]
+        );
+    }
+
+    #[test]
+    fn no_instances_make_no_batches() {
+        let (order, batches) = batch_instances(&[], &[], |_| false);
+        assert!(order.is_empty() && batches.is_empty());
+    }
+
+    #[test]
+    fn levels_of_detail_batch_separately() {
+        let instances = [
+            instance("tile", None, 0.0),
+            instance("tile", None, 1.0),
+            instance("tile", None, 2.0),
+        ];
+        let (order, batches) = batch_instances(&instances, &[2, 0, 2], |_| false);
+        assert_eq!(order, [1, 0, 2]);
+        assert_eq!(
+            batches
+                .iter()
+                .map(|batch| (batch.lod, batch.instances.clone()))
+                .collect::<Vec<_>>(),
+            [(0, 0..1), (2, 1..3)]
+        );
+    }
+}
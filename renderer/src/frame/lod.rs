@@ -0,0 +1,113 @@
+use crate::Camera;
+use crate::frame::instancing::DrawInstance;
+use crate::storage::RenderStorage;
+use std::collections::HashMap;
+
+/// Largest deviation from the full model allowed on screen, in pixels
+const MAX_SCREEN_ERROR: f32 = 1.0;
+/// Fraction of `MAX_SCREEN_ERROR` the on-screen error has to pass it by before an instance
+/// with an id changes level, so one near a switching distance does not flicker between two
+const HYSTERESIS: f32 = 0.25;
+
+/// Picks the level of detail of every instance from how large its model appears on screen,
+/// remembering the levels of instances with an id for the hysteresis
+#[derive(Default)]
+pub(crate) struct LodSelector {
+    previous: HashMap<u64, usize>,
+}
+
+impl LodSelector {
+    /// One level per instance, 0 for models that are not loaded. Ids that are not drawn
+    /// this time are forgotten.
+    pub fn select(
+        &mut self,
+        instances: &[DrawInstance],
+        sto: &RenderStorage,
+        cam: &Camera,
+        target_size: winit::dpi::PhysicalSize<u32>,
+    ) -> Vec<usize> {
+        let (width, height) = (target_size.width as f32, target_size.height as f32);
+        // How many pixels a unit spans at a distance of 1 in front of the camera
+        let pixels_per_unit = cam.get_proj_mat(width, height).y_axis.y * height / 2.0;
+        let view = cam.get_view_mat();
+
+        let previous = std::mem::take(&mut self.previous);
+        instances
+            .iter()
+            .map(|instance| {
+                let Some((model, _)) = sto.model(&instance.model) else {
+                    return 0;
+                };
+                let sphere = model.bounding_sphere();
+                let transform = instance.transform;
+                let scale = [transform.x_axis, transform.y_axis, transform.z_axis]
+                    .map(|axis| axis.truncate().length())
+                    .into_iter()
+                    .fold(0.0, f32::max);
+                let distance = (view * transform).transform_point3(sphere.center).length();
+                // Full detail once the camera is inside the model's bounds
+                let model_pixels_per_unit = if distance > sphere.radius * scale {
+                    pixels_per_unit * scale / distance
+                } else {
+                    f32::INFINITY
+                };
+
+                let previous_lod = instance.id.and_then(|id| previous.get(&id).copied());
+                let lod = select_lod(&model.lod_errors(), model_pixels_per_unit, previous_lod);
+                if let Some(id) = instance.id {
+                    self.previous.insert(id, lod);
+                }
+                lod
+            })
+            .collect()
+    }
+}
+
+/// Coarsest level whose error in `errors`, growing from 0 for the full model, spans at most
+/// `MAX_SCREEN_ERROR` pixels at `pixels_per_unit`. Coming from the `previous` level, the
+/// error has to be `HYSTERESIS` below the limit to go coarser and above it to go finer.
+fn select_lod(errors: &[f32], pixels_per_unit: f32, previous: Option<usize>) -> usize {
+    let fits = |level: usize, limit: f32| level == 0 || errors[level] * pixels_per_unit <= limit;
+    let coarsest_from = |first: usize, limit: f32| {
+        (first..errors.len())
+            .take_while(|&level| fits(level, limit))
+            .last()
+            .unwrap_or(first)
+    };
+    match previous {
+        Some(previous)
+            if previous < errors.len() && fits(previous, MAX_SCREEN_ERROR * (1.0 + HYSTERESIS)) =>
+        {
+            coarsest_from(previous, MAX_SCREEN_ERROR * (1.0 - HYSTERESIS))
+        }
+        _ => coarsest_from(0, MAX_SCREEN_ERROR),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ERRORS: [f32; 3] = [0.0, 1.0, 2.0];
+
+    #[test]
+    fn smaller_on_screen_is_coarser() {
+        assert_eq!(select_lod(&ERRORS, f32::INFINITY, None), 0);
+        assert_eq!(select_lod(&ERRORS, 2.0, None), 0);
+        assert_eq!(select_lod(&ERRORS, 0.9, None), 1);
+        assert_eq!(select_lod(&ERRORS, 0.4, None), 2);
+        assert_eq!(select_lod(&[0.0], 0.0, None), 0);
+    }
+
+    #[test]
+    fn levels_change_only_past_the_hysteresis() {
+        // Coarser only once well within the limit
+        assert_eq!(select_lod(&ERRORS, 0.9, Some(0)), 0);
+        assert_eq!(select_lod(&ERRORS, 0.7, Some(0)), 1);
+        // Finer only once well past it
+        assert_eq!(select_lod(&ERRORS, 1.1, Some(1)), 1);
+        assert_eq!(select_lod(&ERRORS, 1.3, Some(1)), 0);
+        // Levels that no longer exist are chosen afresh
+        assert_eq!(select_lod(&ERRORS, 0.9, Some(5)), 1);
+    }
+}
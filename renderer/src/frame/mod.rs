@@ -1,19 +1,34 @@
+pub(crate) mod culling;
+pub(crate) mod instancing;
+pub(crate) mod lod;
 pub(crate) mod packet;
 
 pub(crate) use crate::viewport::PresentResult;
 
+use crate::Camera;
 use crate::context::RenderContext;
 use crate::context::commands::CommandEncoder;
+use crate::frame::culling::{
+    FrameCulling, IndirectDrawGroup, collect_candidates, frustum_planes, group_candidates,
+};
+use crate::frame::instancing::{InstanceBatch, batch_instances};
 use crate::frame::packet::{FramePresentPacket, FrameRenderPacket};
+use crate::profiler::{GpuProfile, GpuProfiling};
 use crate::resources::material::Material;
+use crate::resources::material_def::MaterialDefinition;
 use crate::resources::megabuffer::MegabufferExt;
 use crate::resources::megabuffer::{AllocatedMegabufferRegion, Megabuffer};
+use crate::resources::render_target::RenderTargetFormats;
+use crate::resources::resource_type::RenderResourceType;
 use crate::resources::texture::{ColorTexture, DepthTexture, Texture};
 use crate::storage::RenderStorage;
+use crate::storage::shader_data::{PerDrawData, PerFrameData, PerMaterialData, PerObjectData};
 use crate::utils::GuardResultExt;
-use crate::viewport::RenderViewport;
+use crate::viewport::{PresentImage, RenderViewport};
 use ash::vk;
 use color_eyre::Result;
+use glam::Mat4;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
@@ -22,16 +37,46 @@ const FRAME_INDEX_BUFFER_SIZE: u64 = 1024 * 1024; // 1 MB
 const FRAME_PER_FRAME_BUFFER_SIZE: u64 = 1024 * 1024; // 1 MB
 const FRAME_PER_MATERIAL_BUFFER_SIZE: u64 = 1024 * 1024; // 1 MB
 const FRAME_PER_OBJECT_BUFFER_SIZE: u64 = 1024 * 1024; // 1 MB
+const FRAME_JOINT_BUFFER_SIZE: u64 = 1024 * 1024; // 1 MB
+
+/// Most instances a frame can draw, see `Renderer::draw`
+pub(crate) const MAX_FRAME_OBJECTS: usize =
+    FRAME_PER_OBJECT_BUFFER_SIZE as usize / size_of::<PerObjectData>();
+/// Most joint matrices the skinned instances of a frame can have together
+pub(crate) const MAX_FRAME_JOINTS: usize = FRAME_JOINT_BUFFER_SIZE as usize / size_of::<Mat4>();
+
+/// How the instances of a frame are drawn
+enum SceneDraws<'a> {
+    /// One instanced draw per mesh of each batch
+    Instanced {
+        batches: Vec<InstanceBatch<'a>>,
+        /// Index of the material of each mesh of each batch
+        material_indices: Vec<Option<Vec<u32>>>,
+    },
+    /// One indirect draw per group, of the commands the culling pass wrote. Skinned models
+    /// move out of their bounds, so they are still drawn instanced.
+    Culled {
+        groups: Vec<IndirectDrawGroup<'a>>,
+        /// Whether the culling pass was submitted, which it is not without candidates
+        culled: bool,
+        skinned_batches: Vec<InstanceBatch<'a>>,
+        skinned_material_indices: Vec<Option<Vec<u32>>>,
+    },
+}
 
 pub(crate) struct RenderFrame {
     draw_color_tex: ColorTexture,
+    /// Only present with MSAA enabled, in which case it resolves into `draw_color_tex`
+    msaa_color_tex: Option<ColorTexture>,
     draw_depth_tex: DepthTexture,
+    target_formats: RenderTargetFormats,
 
     vertex_region: AllocatedMegabufferRegion,
     index_region: AllocatedMegabufferRegion,
     per_frame_region: AllocatedMegabufferRegion,
     per_material_region: AllocatedMegabufferRegion,
     per_object_region: AllocatedMegabufferRegion,
+    joint_region: AllocatedMegabufferRegion,
 
     /// Signals when the swapchain is ready to present (i.e. when the next swapchain image has been acquired successfully).
     present_semaphore: vk::Semaphore,
@@ -40,8 +85,10 @@ pub(crate) struct RenderFrame {
     /// Signals when all rendering commands have finished execution.
     render_fence: vk::Fence,
 
-    cmd_encoder: CommandEncoder,
+    cmd_encoder: Mutex<CommandEncoder>,
     bindless_material: Material,
+    /// `None` unless the storage has GPU culling
+    culling: Option<FrameCulling>,
 
     ctx: Arc<Mutex<RenderContext>>,
     vpt: Arc<Mutex<RenderViewport>>,
@@ -53,6 +100,7 @@ impl RenderFrame {
         ctx: Arc<Mutex<RenderContext>>,
         vpt: Arc<Mutex<RenderViewport>>,
         sto: Arc<Mutex<RenderStorage>>,
+        gpu_profiling: GpuProfiling,
     ) -> Result<Self> {
         log::info!("Creating RenderFrame");
 
@@ -61,15 +109,31 @@ impl RenderFrame {
         let mut sto_grd = sto.lock().eyre()?;
 
         let vpt_size = vpt_grd.get_size();
-        let draw_color_tex = ctx_grd.dev.create_color_texture(
+        let target_formats = sto_grd.target_formats;
+        let draw_color_tex = ctx_grd.dev.create_draw_color_texture(
+            "Draw color",
             vpt_size.width,
             vpt_size.height,
-            None,
-            true,
+            target_formats.color,
+        )?;
+        let msaa_color_tex = if target_formats.is_multisampled() {
+            Some(ctx_grd.dev.create_msaa_color_texture(
+                "MSAA color",
+                vpt_size.width,
+                vpt_size.height,
+                target_formats.color,
+                target_formats.samples,
+            )?)
+        } else {
+            None
+        };
+        let draw_depth_tex = ctx_grd.dev.create_depth_texture(
+            "Draw depth",
+            vpt_size.width,
+            vpt_size.height,
+            target_formats.depth,
+            target_formats.samples,
         )?;
-        let draw_depth_tex = ctx_grd
-            .dev
-            .create_depth_texture(vpt_size.width, vpt_size.height)?;
 
         let vertex_region = sto_grd
             .vertex_megabuffer
@@ -86,30 +150,45 @@ impl RenderFrame {
         let per_object_region = sto_grd
             .per_object_megabuffer
             .allocate_region(FRAME_PER_OBJECT_BUFFER_SIZE)?;
+        let joint_region = sto_grd
+            .joint_megabuffer
+            .allocate_region(FRAME_JOINT_BUFFER_SIZE)?;
 
-        let present_semaphore = unsafe {
-            ctx_grd
-                .dev
-                .logical
-                .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?
-        };
-        let render_semaphore = unsafe {
-            ctx_grd
-                .dev
-                .logical
-                .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?
-        };
+        let present_semaphore = ctx_grd.dev.create_semaphore("Present semaphore")?;
+        let render_semaphore = ctx_grd.dev.create_semaphore("Render semaphore")?;
         let render_fence = unsafe {
             ctx_grd.dev.logical.create_fence(
                 &vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED),
                 None,
             )?
         };
+        ctx_grd
+            .dev
+            .debug_utils
+            .set_object_name(render_fence, "Render fence");
 
         let graphics_queue = ctx_grd.dev.graphics_queue.clone();
-        let cmd_encoder = ctx_grd.dev.allocate_command_encoder(graphics_queue)?;
+        let mut cmd_encoder = ctx_grd
+            .dev
+            .allocate_command_encoder(graphics_queue.clone(), "Frame commands")?;
+        if let Some(profiler) = ctx_grd.dev.create_gpu_profiler(
+            ctx_grd.ins.inner(),
+            "Frame profiler",
+            gpu_profiling,
+            &graphics_queue,
+        )? {
+            cmd_encoder.attach_profiler(profiler);
+        }
 
-        let bindless_material = sto_grd.bindless_material_factory.create_material()?;
+        let bindless_material = sto_grd
+            .material_factories_mut(target_formats)?
+            .bindless
+            .create_material()?;
+        let culling = sto_grd
+            .gpu_culling
+            .as_mut()
+            .map(|gpu_culling| FrameCulling::new(gpu_culling, &mut ctx_grd.dev))
+            .transpose()?;
 
         drop(ctx_grd);
         drop(vpt_grd);
@@ -117,20 +196,24 @@ impl RenderFrame {
 
         Ok(Self {
             draw_color_tex,
+            msaa_color_tex,
             draw_depth_tex,
+            target_formats,
 
             vertex_region,
             index_region,
             per_frame_region,
             per_material_region,
             per_object_region,
+            joint_region,
 
             present_semaphore,
             render_semaphore,
             render_fence,
 
-            cmd_encoder,
+            cmd_encoder: Mutex::new(cmd_encoder),
             bindless_material,
+            culling,
 
             ctx,
             sto,
@@ -141,22 +224,493 @@ impl RenderFrame {
     pub fn render(&self, pkt: FrameRenderPacket) -> Result<FramePresentPacket> {
         let ctx = self.ctx.lock().eyre()?;
         let vpt = self.vpt.lock().eyre()?;
+        let sto = self.sto.lock().eyre()?;
 
         let timeout = Duration::from_secs(1);
 
         // Wait until the commands have finished from the last time this frame was rendered
-        ctx.wait_and_reset_fence(self.render_fence, timeout)?;
+        ctx.wait_for_fence(self.render_fence, timeout)?;
 
-        // Acquire the next image from the swapchain
+        // Acquire the next image from the swapchain.
+        // The fence is only reset afterwards, so it stays signaled if the frame is skipped.
         let image = vpt.acquire_next_present_image(self.present_semaphore, timeout)?;
+        ctx.reset_fence(self.render_fence)?;
 
-        Ok(FramePresentPacket {
-            image
-        })
+        // If the frame fails, the semaphores signaled for it are still waited on and the fence
+        // signaled, so the next time it is rendered can start over
+        let mut wait_semaphores = vec![
+            vk::SemaphoreSubmitInfo::default()
+                .semaphore(self.present_semaphore)
+                .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS),
+        ];
+        if let Err(e) = self.submit(&sto, &pkt, &image, &mut wait_semaphores) {
+            let submit_info = vk::SubmitInfo2::default().wait_semaphore_infos(&wait_semaphores);
+            ctx.dev
+                .graphics_queue
+                .submit2(&[submit_info], self.render_fence)?;
+            return Err(e);
+        }
+
+        Ok(FramePresentPacket { image })
+    }
+
+    /// Write and upload the frame's data, cull and submit the draws. Semaphores the draws wait
+    /// for are added to `wait_semaphores` as soon as they are signaled.
+    fn submit(
+        &self,
+        sto: &RenderStorage,
+        pkt: &FrameRenderPacket,
+        image: &PresentImage,
+        wait_semaphores: &mut Vec<vk::SemaphoreSubmitInfo<'static>>,
+    ) -> Result<()> {
+        let cam = pkt.payload.cam;
+        let extent = self.draw_color_tex.extent;
+        let viewproj = cam.get_viewproj_mat(extent.width as f32, extent.height as f32);
+        let per_frame_data = PerFrameData {
+            viewproj,
+            near: cam.near(),
+            far: cam.far(),
+            vertex_buffer_address: sto.vertex_buffer_address,
+            frustum_planes: frustum_planes(viewproj),
+        };
+        sto.per_frame_megabuffer
+            .write(&[per_frame_data], &self.per_frame_region)?;
+
+        // Instances of a batch get consecutive objects, so a draw can index them from the first one
+        let instances = pkt.payload.instances;
+        let (order, batches) = batch_instances(instances, pkt.payload.lods, |material| {
+            sto.material_definitions
+                .get(material)
+                .is_some_and(MaterialDefinition::is_blended)
+        });
+        // Joint palettes of skinned instances follow each other in the same order
+        let mut joints = Vec::new();
+        let objects = order
+            .iter()
+            .map(|&index| {
+                let joint_offset = joints.len() as u32;
+                if let Some(palette) = &instances[index].joints {
+                    joints.extend_from_slice(palette);
+                }
+                PerObjectData {
+                    model: instances[index].transform,
+                    joint_offset,
+                    ..Default::default()
+                }
+            })
+            .collect::<Vec<_>>();
+        sto.per_object_megabuffer
+            .write(&objects, &self.per_object_region)?;
+        sto.joint_megabuffer.write(&joints, &self.joint_region)?;
+        let (materials, material_indices) = Self::collect_materials(sto, &batches);
+        sto.per_material_megabuffer
+            .write(&materials, &self.per_material_region)?;
+
+        sto.per_frame_megabuffer
+            .upload_region(&self.per_frame_region)?;
+        sto.per_material_megabuffer
+            .upload_region(&self.per_material_region)?;
+        sto.per_object_megabuffer
+            .upload_region(&self.per_object_region)?;
+        sto.joint_megabuffer.upload_region(&self.joint_region)?;
+        self.write_bindless_descriptors(sto)?;
+
+        let draws = match (&self.culling, &sto.gpu_culling) {
+            (Some(culling), Some(gpu_culling)) => {
+                let is_skinned = |batch: &InstanceBatch| {
+                    sto.model(batch.model)
+                        .is_some_and(|(model, _)| model.is_skinned())
+                };
+                let ((skinned_batches, skinned_material_indices), (batches, material_indices)): (
+                    (Vec<_>, Vec<_>),
+                    (Vec<_>, Vec<_>),
+                ) = batches
+                    .into_iter()
+                    .zip(material_indices)
+                    .partition(|(batch, _)| is_skinned(batch));
+                let candidates = collect_candidates(sto, &batches, &material_indices);
+                let (candidates, groups) = group_candidates(candidates);
+                let culling_semaphore = culling.cull(
+                    &candidates,
+                    groups.len(),
+                    &self.per_frame_region,
+                    &self.per_object_region,
+                    sto,
+                    gpu_culling,
+                )?;
+                wait_semaphores.extend(culling_semaphore);
+                SceneDraws::Culled {
+                    groups,
+                    culled: culling_semaphore.is_some(),
+                    skinned_batches,
+                    skinned_material_indices,
+                }
+            }
+            _ => SceneDraws::Instanced {
+                batches,
+                material_indices,
+            },
+        };
+
+        self.record_and_submit(sto, cam, &draws, image, wait_semaphores)
+    }
+
+    fn record_and_submit(
+        &self,
+        sto: &RenderStorage,
+        cam: &Camera,
+        draws: &SceneDraws,
+        image: &PresentImage,
+        wait_semaphores: &[vk::SemaphoreSubmitInfo],
+    ) -> Result<()> {
+        let mut cmd = self.cmd_encoder.lock().eyre()?;
+        cmd.begin_recording()?;
+        self.record_scene_pass(&cmd, sto, cam, draws)?;
+        self.record_copy_to_present_image(&cmd, image);
+        cmd.end_recording()?;
+
+        // The swapchain image is first touched by a layout transition that waits on all commands,
+        // and the indirect draws wait for their commands to be culled
+        let signal_semaphores = [vk::SemaphoreSubmitInfo::default()
+            .semaphore(self.render_semaphore)
+            .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)];
+        cmd.submit(wait_semaphores, &signal_semaphores, self.render_fence)
+    }
+
+    /// GPU timings of the last time this frame was rendered and has completed, if not taken yet
+    pub fn take_gpu_profile(&self) -> Result<Option<GpuProfile>> {
+        self.cmd_encoder.lock().eyre()?.take_gpu_profile()
     }
 
     pub fn present(&self, pkt: FramePresentPacket) -> Result<PresentResult> {
         let vpt = self.vpt.lock().eyre()?;
         vpt.present(pkt.image, self.render_semaphore)
     }
+
+    /// Data of every distinct material drawn, and the index of the material of every mesh of
+    /// each batch. Batches whose model or material is not loaded get `None`.
+    fn collect_materials(
+        sto: &RenderStorage,
+        batches: &[InstanceBatch],
+    ) -> (Vec<PerMaterialData>, Vec<Option<Vec<u32>>>) {
+        let mut materials = Vec::new();
+        let mut material_indices = HashMap::new();
+        let mut material_index = |data: PerMaterialData| {
+            *material_indices.entry(data).or_insert_with(|| {
+                materials.push(data);
+                materials.len() as u32 - 1
+            })
+        };
+
+        let batch_material_indices = batches
+            .iter()
+            .map(|batch| {
+                let (model, mesh_materials) = sto.model(batch.model)?;
+                match batch.material {
+                    Some(name) => {
+                        let definition = sto.material_definitions.get(name)?;
+                        let index = material_index(definition.per_material_data());
+                        Some(vec![index; model.get_meshes().len()])
+                    }
+                    None => Some(
+                        mesh_materials
+                            .iter()
+                            .copied()
+                            .map(&mut material_index)
+                            .collect(),
+                    ),
+                }
+            })
+            .collect();
+        (materials, batch_material_indices)
+    }
+
+    /// Point the bindless set at this frame's regions and at every loaded sampler and texture
+    fn write_bindless_descriptors(&self, sto: &RenderStorage) -> Result<()> {
+        let material = &self.bindless_material;
+        material.write_buffer(
+            0,
+            RenderResourceType::UniformBuffer.descriptor_type(),
+            sto.per_frame_megabuffer.raw_buffer()?,
+            self.per_frame_region.offset(),
+            size_of::<PerFrameData>() as u64,
+        );
+        material.write_buffer(
+            1,
+            RenderResourceType::StorageBuffer.descriptor_type(),
+            sto.per_material_megabuffer.raw_buffer()?,
+            self.per_material_region.offset(),
+            self.per_material_region.size(),
+        );
+        material.write_buffer(
+            2,
+            RenderResourceType::StorageBuffer.descriptor_type(),
+            sto.per_object_megabuffer.raw_buffer()?,
+            self.per_object_region.offset(),
+            self.per_object_region.size(),
+        );
+        material.write_buffer(
+            8,
+            RenderResourceType::StorageBuffer.descriptor_type(),
+            sto.joint_megabuffer.raw_buffer()?,
+            self.joint_region.offset(),
+            self.joint_region.size(),
+        );
+
+        let sampler_count = RenderResourceType::Sampler.descriptor_count();
+        for (index, &sampler) in (0..sampler_count).zip(&sto.samplers) {
+            material.write_sampler(3, index, sampler);
+        }
+        let texture_count = RenderResourceType::SampledImage.descriptor_count();
+        for (index, texture) in (0..texture_count).zip(&sto.sampled_textures) {
+            material.write_sampled_image(
+                4,
+                index,
+                texture.view,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            );
+        }
+        Ok(())
+    }
+
+    fn record_scene_pass(
+        &self,
+        cmd: &CommandEncoder,
+        sto: &RenderStorage,
+        cam: &Camera,
+        draws: &SceneDraws,
+    ) -> Result<()> {
+        let _scope = cmd.scope("Scene pass");
+        if let (SceneDraws::Culled { culled: true, .. }, Some(culling), Some(gpu_culling)) =
+            (draws, &self.culling, &sto.gpu_culling)
+        {
+            culling.acquire_commands(cmd, gpu_culling)?;
+        }
+        let extent = vk::Extent2D {
+            width: self.draw_color_tex.extent.width,
+            height: self.draw_color_tex.extent.height,
+        };
+
+        // Contents from the previous frame are cleared anyway, so they can be discarded
+        cmd.transition_vkimage_layout(
+            self.draw_color_tex.image,
+            self.draw_color_tex.aspect,
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        );
+        if let Some(msaa_color_tex) = &self.msaa_color_tex {
+            cmd.transition_vkimage_layout(
+                msaa_color_tex.image,
+                msaa_color_tex.aspect,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            );
+        }
+        let depth_layout = self.target_formats.depth_attachment_layout();
+        cmd.transition_vkimage_layout(
+            self.draw_depth_tex.image,
+            self.draw_depth_tex.aspect,
+            vk::ImageLayout::UNDEFINED,
+            depth_layout,
+        );
+
+        let color_attachment = vk::RenderingAttachmentInfo::default()
+            .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .clear_value(vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 1.0],
+                },
+            });
+        let color_attachment = match &self.msaa_color_tex {
+            // The samples are averaged into the draw texture at the end of the rendering scope,
+            // after which they are no longer needed
+            Some(msaa_color_tex) => color_attachment
+                .image_view(msaa_color_tex.view)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .resolve_mode(vk::ResolveModeFlags::AVERAGE)
+                .resolve_image_view(self.draw_color_tex.view)
+                .resolve_image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+            None => color_attachment
+                .image_view(self.draw_color_tex.view)
+                .store_op(vk::AttachmentStoreOp::STORE),
+        };
+        let color_attachments = [color_attachment];
+        let depth_attachment = vk::RenderingAttachmentInfo::default()
+            .image_view(self.draw_depth_tex.view)
+            .image_layout(depth_layout)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .clear_value(vk::ClearValue {
+                // The skybox is drawn at depth 1.0, so it only shows where nothing else was drawn
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            });
+        let rendering_info = vk::RenderingInfo::default()
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            })
+            .layer_count(1)
+            .color_attachments(&color_attachments)
+            .depth_attachment(&depth_attachment);
+        // A depth-stencil texture is bound as both attachments through the same view
+        let rendering_info = if self.target_formats.has_stencil() {
+            rendering_info.stencil_attachment(&depth_attachment)
+        } else {
+            rendering_info
+        };
+
+        cmd.begin_rendering(&rendering_info);
+        cmd.set_viewport_and_scissor(extent);
+
+        // Blended draws come after the skybox, which would otherwise cover them where they
+        // don't write depth
+        for blended in [false, true] {
+            match (draws, &self.culling, &sto.gpu_culling) {
+                (
+                    SceneDraws::Culled {
+                        groups,
+                        skinned_batches,
+                        skinned_material_indices,
+                        ..
+                    },
+                    Some(culling),
+                    Some(_),
+                ) => {
+                    culling.record_draws(
+                        cmd,
+                        groups,
+                        blended,
+                        &self.bindless_material,
+                        sto.material_factories(self.target_formats)?,
+                        sto,
+                    )?;
+                    self.record_instanced_draws(
+                        cmd,
+                        sto,
+                        skinned_batches,
+                        skinned_material_indices,
+                        blended,
+                    )?;
+                }
+                (
+                    SceneDraws::Instanced {
+                        batches,
+                        material_indices,
+                    },
+                    _,
+                    _,
+                ) => self.record_instanced_draws(cmd, sto, batches, material_indices, blended)?,
+                _ => {}
+            }
+
+            if let (false, Some(skybox)) = (blended, &sto.skybox) {
+                let _scope = cmd.scope("Skybox");
+                let factories = sto.material_factories(self.target_formats)?;
+                skybox.draw(cmd, cam, extent, &factories.skybox)?;
+            }
+        }
+
+        cmd.end_rendering();
+        Ok(())
+    }
+
+    /// One draw per mesh of each batch that is `blended` or not, with an instance for each of
+    /// the batch's objects
+    fn record_instanced_draws(
+        &self,
+        cmd: &CommandEncoder,
+        sto: &RenderStorage,
+        batches: &[InstanceBatch],
+        material_indices: &[Option<Vec<u32>>],
+        blended: bool,
+    ) -> Result<()> {
+        if !batches.iter().any(|batch| batch.blended == blended) {
+            return Ok(());
+        }
+        let _scope = cmd.scope(if blended {
+            "Blended instances"
+        } else {
+            "Instances"
+        });
+
+        let vertex_buffer = sto.vertex_megabuffer.raw_buffer()?;
+        cmd.bind_index_buffer(sto.index_megabuffer.raw_buffer()?, vk::IndexType::UINT32);
+        // Every material factory shares the bindless pipeline layout
+        self.bindless_material
+            .bind_descriptor_sets(cmd.command_buffer);
+        let factories = sto.material_factories(self.target_formats)?;
+
+        for (batch, material_indices) in batches.iter().zip(material_indices) {
+            if batch.blended != blended {
+                continue;
+            }
+            let Some(material_indices) = material_indices else {
+                continue;
+            };
+            let Some((model, _)) = sto.model(batch.model) else {
+                continue;
+            };
+            let Some(submeshes) = model.submeshes(batch.lod) else {
+                continue;
+            };
+
+            match batch.material.and_then(|name| factories.defined.get(name)) {
+                Some(factory) => factory.bind_pipeline(cmd.command_buffer)?,
+                None if model.is_skinned() => {
+                    factories.skinned.bind_pipeline(cmd.command_buffer)?
+                }
+                None => self.bindless_material.bind_pipeline(cmd.command_buffer)?,
+            }
+            // Ignored by pipelines pulling vertices, which read from `vertex_offset` instead
+            cmd.bind_vertex_buffers(vertex_buffer, &model.vertex_buffer_offsets());
+
+            let instance_count = batch.instances.len() as u32;
+            for (&material_index, submesh) in material_indices.iter().zip(submeshes) {
+                let per_draw = PerDrawData {
+                    base_object_index: batch.instances.start,
+                    material_index,
+                    vertex_offset: model.first_vertex().unwrap_or(0),
+                };
+                self.bindless_material
+                    .update_push_constants(cmd.command_buffer, bytemuck::bytes_of(&per_draw));
+                cmd.draw_indexed(
+                    submesh.index_count,
+                    instance_count,
+                    submesh.first_index,
+                    submesh.vertex_offset,
+                    0,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn record_copy_to_present_image(&self, cmd: &CommandEncoder, image: &PresentImage) {
+        let _scope = cmd.scope("Copy to present image");
+        cmd.transition_vkimage_layout(
+            self.draw_color_tex.image,
+            self.draw_color_tex.aspect,
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        );
+        cmd.transition_vkimage_layout(
+            image.image,
+            vk::ImageAspectFlags::COLOR,
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        );
+
+        cmd.copy_texture_to_vkimage(&self.draw_color_tex, image.image, image.extent);
+
+        cmd.transition_vkimage_layout(
+            image.image,
+            vk::ImageAspectFlags::COLOR,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::PRESENT_SRC_KHR,
+        );
+    }
 }
@@ -17,6 +17,10 @@ pub(crate) struct FrameRenderPacket<'a> {
 /// It is also a lightweight struct that holds references, so it is cheap to create and pass around.
 pub(crate) struct FrameRenderPayload<'a> {
     pub cam: &'a crate::Camera,
+    /// Drawn in batches of instances sharing a model and material
+    pub instances: &'a [crate::frame::instancing::DrawInstance],
+    /// Level of detail of each instance
+    pub lods: &'a [usize],
 }
 
 /// This struct is used to pass metadata about the frame being rendered.
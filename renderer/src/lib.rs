@@ -1,24 +1,57 @@
 mod camera;
+mod config;
 mod context;
+mod error;
 mod frame;
+mod profiler;
 mod resources;
 mod storage;
 mod utils;
 mod viewport;
 
 pub use camera::Camera;
+pub use config::{ColorFormat, DepthFormat, Msaa, RendererConfig, ValidationConfig};
+pub use context::validation::{ValidationMessage, ValidationSink};
+pub use error::{RendererError, RendererResult};
+pub use frame::instancing::{DrawInstance, ModelKey};
+pub use profiler::{GpuProfile, GpuProfiling, GpuScope, PipelineStatistics};
+pub use resources::animation::{
+    AnimationChannel, AnimationClip, AnimationPlayer, ChannelValues, Interpolation,
+};
+pub use resources::bounds::{Aabb, BoundingSphere};
+pub use resources::gltf_loader::{
+    AlphaMode, MaterialParams, ModelScene, NodeTransform, SceneMesh, SceneNode, SceneSkin,
+};
+pub use resources::mesh::Mesh;
+pub use resources::procedural::{HexOrientation, HexPrism};
+pub use resources::vertex::{Vertex, VertexAttribute, VertexLayout, VertexSemantic, VertexStream};
 
 use crate::utils::GuardResultExt;
 use crate::viewport::RenderViewport;
 use color_eyre::eyre::OptionExt;
 use color_eyre::Result;
+use color_eyre::eyre::{WrapErr, eyre};
 use context::RenderContext;
+use frame::culling::MAX_DRAW_CANDIDATES;
 use frame::packet::FrameRenderPacket;
 use frame::packet::{FrameRenderMetadata, FrameRenderPayload};
-use frame::RenderFrame;
+use frame::{MAX_FRAME_JOINTS, MAX_FRAME_OBJECTS, RenderFrame};
+use frame::lod::LodSelector;
+#[cfg(feature = "hot-reload")]
+use resources::shader_watcher::ShaderWatcher;
+use resources::skybox::Skybox;
+use resources::texture::CubemapTexture;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use storage::RenderStorage;
 
+/// Where the current skybox was loaded from, to load it again in `Renderer::recover`
+#[derive(Clone)]
+enum SkyboxSource {
+    Faces([PathBuf; 6]),
+    Equirect { path: PathBuf, face_size: u32 },
+}
+
 pub struct Renderer {
     ctx: Arc<Mutex<RenderContext>>,
     vpt: Arc<Mutex<RenderViewport>>,
@@ -27,24 +60,65 @@ pub struct Renderer {
 
     current_frame_index: usize,
     resize_requested: bool,
+    gpu_profile: Option<GpuProfile>,
+    /// `None` when validation is disabled
+    validation: Option<ValidationSink>,
+
+    /// Kept to recreate everything in `recover`, along with the resources to restore
+    config: RendererConfig,
+    skybox_source: Option<SkyboxSource>,
+    material_paths: Vec<PathBuf>,
+    gltf_paths: Vec<PathBuf>,
+    obj_paths: Vec<PathBuf>,
+
+    /// Queued by `draw` for the next frame
+    instances: Vec<DrawInstance>,
+    /// Joint matrices of the queued instances together
+    queued_joint_count: usize,
+    /// Meshes of the queued instances that are culled on the GPU
+    queued_candidate_count: usize,
+    lod_selector: LodSelector,
+
+    /// `None` when the shader directory could not be watched
+    #[cfg(feature = "hot-reload")]
+    shader_watcher: Option<ShaderWatcher>,
 }
 
 impl Renderer {
     const FRAMES_IN_FLIGHT: usize = 1;
 
-    pub fn new(window: &winit::window::Window) -> Result<Self> {
+    pub fn new(window: &winit::window::Window) -> RendererResult<Self> {
+        Self::with_config(window, RendererConfig::default())
+    }
+
+    pub fn with_config(
+        window: &winit::window::Window,
+        config: RendererConfig,
+    ) -> RendererResult<Self> {
         let _ = color_eyre::install();
         let _ = env_logger::try_init();
 
-        let (ctx, vpt) = RenderContext::new(window)?;
-        let sto = RenderStorage::new(&ctx, &vpt)?;
+        let (ctx, vpt) = RenderContext::new(window, &config)?;
+        let target_formats = ctx
+            .dev
+            .select_render_target_formats(ctx.ins.inner(), &config)?;
+        let sto = RenderStorage::new(
+            &ctx,
+            &vpt,
+            target_formats,
+            config.vertex_pulling,
+            config.gpu_culling,
+        )?;
+        let validation = ctx.ins.validation.clone();
 
         let ctx = Arc::new(Mutex::new(ctx));
         let vpt = Arc::new(Mutex::new(vpt));
         let sto = Arc::new(Mutex::new(sto));
-        let frm = (0..Self::FRAMES_IN_FLIGHT)
-            .map(|_| RenderFrame::new(ctx.clone(), vpt.clone(), sto.clone()).map(Arc::new))
-            .collect::<Result<Vec<_>>>()?;
+        let frm = Self::create_frames(&ctx, &vpt, &sto, config.gpu_profiling)?;
+
+        if let Some(validation) = &validation {
+            validation.check()?;
+        }
 
         Ok(Self {
             ctx,
@@ -53,18 +127,111 @@ impl Renderer {
             frm,
             current_frame_index: 0,
             resize_requested: false,
+            gpu_profile: None,
+            validation,
+            config,
+            skybox_source: None,
+            material_paths: Vec::new(),
+            gltf_paths: Vec::new(),
+            obj_paths: Vec::new(),
+            instances: Vec::new(),
+            queued_joint_count: 0,
+            queued_candidate_count: 0,
+            lod_selector: LodSelector::default(),
+            #[cfg(feature = "hot-reload")]
+            shader_watcher: ShaderWatcher::new()
+                .inspect_err(|e| log::error!("Shader hot reload disabled: {e:?}"))
+                .ok(),
         })
     }
 
-    pub fn render_frame(&mut self, cam: &Camera) -> Result<()> {
+    /// On `RendererError::SwapchainOutOfDate` the frame is skipped,
+    /// and on errors that are recoverable the renderer is unusable until `recover` is called.
+    pub fn render_frame(&mut self, cam: &Camera) -> RendererResult<()> {
+        if self.frm.is_empty() {
+            // A previous `recover` failed, so the device is still lost
+            return Err(RendererError::DeviceLost);
+        }
+
+        let result = self.try_render_frame(cam).map_err(RendererError::from);
+        if let Err(RendererError::SwapchainOutOfDate) = result {
+            self.request_resize();
+        }
+        result
+    }
+
+    /// Tear down and recreate the Vulkan context, viewport, storage and frames after
+    /// a recoverable error (see `RendererError::is_recoverable`).
+    /// The skybox, materials, glTF and OBJ files are loaded again, and the GPU profile and validation
+    /// messages start over.
+    pub fn recover(&mut self, window: &winit::window::Window) -> RendererResult<()> {
+        log::warn!("Recreating the renderer");
+
+        // Frames reference the context, viewport and storage, so they have to go first
+        self.frm.clear();
+        self.gpu_profile = None;
+        {
+            let ctx = self.ctx.lock().eyre()?;
+            // A lost device never becomes idle, in which case there is nothing to wait for
+            if let Err(e) = ctx.dev.wait_idle() {
+                log::warn!("Failed to wait for the device before recreating it: {e}");
+            }
+            // The window can only have one surface, so the old one is destroyed right away
+            unsafe { self.vpt.lock().eyre()?.destroy(&ctx.dev) };
+        }
+
+        let mut renderer = Self::with_config(window, self.config.clone())?;
+        #[cfg(feature = "hot-reload")]
+        std::mem::swap(&mut renderer.shader_watcher, &mut self.shader_watcher);
+        renderer.material_paths = std::mem::take(&mut self.material_paths);
+        renderer.gltf_paths = std::mem::take(&mut self.gltf_paths);
+        renderer.obj_paths = std::mem::take(&mut self.obj_paths);
+        renderer.skybox_source = self.skybox_source.take();
+        *self = renderer;
+
+        for path in self.material_paths.clone() {
+            self.load_material(&path)?;
+        }
+        for path in self.gltf_paths.clone() {
+            self.load_gltf(&path)?;
+        }
+        for path in self.obj_paths.clone() {
+            self.load_obj(&path)?;
+        }
+        match self.skybox_source.clone() {
+            Some(SkyboxSource::Faces(paths)) => {
+                self.set_skybox_from_faces(paths.each_ref().map(PathBuf::as_path))?;
+            }
+            Some(SkyboxSource::Equirect { path, face_size }) => {
+                self.set_skybox_from_equirect(&path, face_size)?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn try_render_frame(&mut self, cam: &Camera) -> Result<()> {
+        #[cfg(feature = "hot-reload")]
+        self.reload_changed_shaders()?;
+
         self.current_frame_index = (self.current_frame_index + 1) % self.frm.len();
         let current_frame = self.frm[self.current_frame_index].clone();
 
         // Update the scene and prepare the frame packet
-        let render_pkt = self.update_scene(cam)?;
+        let mut instances = std::mem::take(&mut self.instances);
+        self.queued_joint_count = 0;
+        self.queued_candidate_count = 0;
+        let lods = self.select_lods(cam, &instances)?;
+        let render_pkt = self.update_scene(cam, &instances, &lods)?;
 
         // Record and submit the commands for the current frame
         let present_pkt = current_frame.render(render_pkt)?;
+        // Keep the allocation for the next frame's instances
+        instances.clear();
+        self.instances = instances;
+        if let Some(profile) = current_frame.take_gpu_profile()? {
+            self.gpu_profile = Some(profile);
+        }
 
         // Present the frame
         match current_frame.present(present_pkt)? {
@@ -74,6 +241,196 @@ impl Renderer {
             viewport::PresentResult::Success => {}
         }
 
+        if let Some(validation) = &self.validation {
+            validation.check()?;
+        }
+        Ok(())
+    }
+
+    /// GPU timings of the most recent frame that has completed,
+    /// `None` unless `RendererConfig::gpu_profiling` is enabled
+    pub fn gpu_profile(&self) -> Option<&GpuProfile> {
+        self.gpu_profile.as_ref()
+    }
+
+    /// Validation errors reported so far, `None` unless `RendererConfig::validation` is enabled
+    pub fn validation(&self) -> Option<&ValidationSink> {
+        self.validation.as_ref()
+    }
+
+    /// Use six images as the skybox, ordered +X, -X, +Y, -Y, +Z, -Z
+    pub fn set_skybox_from_faces(&mut self, face_paths: [&Path; 6]) -> RendererResult<()> {
+        let faces = face_paths
+            .iter()
+            .map(|path| {
+                image::open(path)
+                    .wrap_err_with(|| format!("Failed to open skybox face {}", path.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let faces: [image::DynamicImage; 6] = faces
+            .try_into()
+            .map_err(|_| eyre!("Expected exactly 6 cubemap faces"))?;
+
+        let cubemap = self
+            .ctx
+            .lock()
+            .eyre()?
+            .dev
+            .create_cubemap_texture("Skybox cubemap", &faces)?;
+        self.set_skybox(cubemap)?;
+        self.skybox_source = Some(SkyboxSource::Faces(face_paths.map(Path::to_path_buf)));
+        Ok(())
+    }
+
+    /// Use an equirectangular `.hdr` or `.exr` image as the skybox,
+    /// converted into a cubemap with faces of `face_size` texels
+    pub fn set_skybox_from_equirect(&mut self, path: &Path, face_size: u32) -> RendererResult<()> {
+        let cubemap = {
+            let ctx = self.ctx.lock().eyre()?;
+            let sto = self.sto.lock().eyre()?;
+            sto.equirect_converter
+                .convert_file(path, face_size, &ctx.dev)?
+        };
+        self.set_skybox(cubemap)?;
+        self.skybox_source = Some(SkyboxSource::Equirect {
+            path: path.to_path_buf(),
+            face_size,
+        });
+        Ok(())
+    }
+
+    /// Load a `.ron` or `.toml` material definition, returning the name it is registered under
+    pub fn load_material(&mut self, path: &Path) -> RendererResult<String> {
+        let name = {
+            let ctx = self.ctx.lock().eyre()?;
+            let mut sto = self.sto.lock().eyre()?;
+            sto.load_material_definition(path, &ctx.dev)?
+        };
+        if !self.material_paths.iter().any(|loaded| loaded == path) {
+            self.material_paths.push(path.to_path_buf());
+        }
+        Ok(name)
+    }
+
+    /// Load the meshes, textures and materials of a `.gltf` or `.glb` file,
+    /// returning its scene graph of local transforms and its material parameters
+    pub fn load_gltf(&mut self, path: &Path) -> RendererResult<ModelScene> {
+        let scene = {
+            let ctx = self.ctx.lock().eyre()?;
+            let mut sto = self.sto.lock().eyre()?;
+            sto.load_gltf(path, &ctx.dev)?
+        };
+        if !self.gltf_paths.iter().any(|loaded| loaded == path) {
+            self.gltf_paths.push(path.to_path_buf());
+        }
+        Ok(scene)
+    }
+
+    /// Load the objects and groups of an `.obj` file as the meshes of one model,
+    /// with the diffuse textures of its `.mtl` files. Returns the name the model is kept under.
+    pub fn load_obj(&mut self, path: &Path) -> RendererResult<String> {
+        let name = {
+            let ctx = self.ctx.lock().eyre()?;
+            let mut sto = self.sto.lock().eyre()?;
+            sto.load_obj(path, &ctx.dev)?
+        };
+        if !self.obj_paths.iter().any(|loaded| loaded == path) {
+            self.obj_paths.push(path.to_path_buf());
+        }
+        Ok(name)
+    }
+
+    /// Bounds of an OBJ model loaded with `load_obj`, in the model's space
+    pub fn obj_bounds(&self, name: &str) -> RendererResult<Option<(Aabb, BoundingSphere)>> {
+        let sto = self.sto.lock().eyre()?;
+        Ok(sto
+            .obj_models
+            .get(name)
+            .map(|(model, _)| (model.aabb(), model.bounding_sphere())))
+    }
+
+    /// Draw a loaded model in the next `render_frame`. Instances sharing a model and material
+    /// are drawn together with a single draw per mesh, whatever order they are queued in.
+    pub fn draw(&mut self, instance: DrawInstance) -> RendererResult<()> {
+        let sto = self.sto.lock().eyre()?;
+        let (model, _) = sto
+            .model(&instance.model)
+            .ok_or_else(|| eyre!("Model {:?} is not loaded", instance.model))?;
+        if model.submeshes(0).is_none() {
+            return Err(eyre!("Model {:?} has no indices to draw", instance.model).into());
+        }
+        if let Some(material) = &instance.material
+            && !sto.material_definitions.contains_key(material)
+        {
+            return Err(eyre!("Material {} is not loaded", material).into());
+        }
+        match (&instance.joints, model.is_skinned()) {
+            (Some(joints), true) if joints.len() < model.joint_count() as usize => {
+                return Err(eyre!(
+                    "Model {:?} has {} joints, but only {} were given",
+                    instance.model,
+                    model.joint_count(),
+                    joints.len()
+                )
+                .into());
+            }
+            (None, true) => {
+                return Err(
+                    eyre!("Model {:?} is skinned but has no joints", instance.model).into(),
+                );
+            }
+            (Some(_), false) => {
+                return Err(eyre!("Model {:?} is not skinned", instance.model).into());
+            }
+            _ => {}
+        }
+        // Defined materials read the vertices of unskinned models
+        if model.is_skinned() && instance.material.is_some() {
+            return Err(eyre!(
+                "Skinned model {:?} cannot be drawn with a material",
+                instance.model
+            )
+            .into());
+        }
+        // Every mesh of an unskinned instance is a candidate, at any level of detail
+        let candidate_count = if sto.gpu_culling.is_some() && !model.is_skinned() {
+            model.get_meshes().len()
+        } else {
+            0
+        };
+        drop(sto);
+
+        // The frame's regions hold this many, which is checked before any of it is written
+        if self.instances.len() >= MAX_FRAME_OBJECTS {
+            return Err(eyre!(
+                "At most {} instances can be drawn per frame",
+                MAX_FRAME_OBJECTS
+            )
+            .into());
+        }
+        let joint_count = instance.joints.as_ref().map_or(0, Vec::len);
+        if self.queued_joint_count + joint_count > MAX_FRAME_JOINTS {
+            return Err(eyre!("At most {} joints can be drawn per frame", MAX_FRAME_JOINTS).into());
+        }
+
+        if (self.queued_candidate_count + candidate_count) as u64 > MAX_DRAW_CANDIDATES {
+            return Err(eyre!(
+                "At most {} meshes can be culled per frame",
+                MAX_DRAW_CANDIDATES
+            )
+            .into());
+        }
+
+        self.queued_joint_count += joint_count;
+        self.queued_candidate_count += candidate_count;
+        self.instances.push(instance);
+        Ok(())
+    }
+
+    pub fn clear_skybox(&mut self) -> RendererResult<()> {
+        self.wait_idle()?;
+        self.sto.lock().eyre()?.skybox = None;
+        self.skybox_source = None;
         Ok(())
     }
 
@@ -81,7 +438,102 @@ impl Renderer {
         self.resize_requested = true;
     }
 
-    fn update_scene<'a>(&mut self, cam: &'a Camera) -> Result<FrameRenderPacket<'a>> {
+    /// Render with `msaa` from the next frame on, with fewer samples if the device does not
+    /// support as many. Materials stay built for the previous formats, so switching back is quick.
+    pub fn set_msaa(&mut self, msaa: Msaa) -> RendererResult<()> {
+        // The frames being replaced may still be in flight
+        self.wait_idle()?;
+
+        let config = RendererConfig {
+            msaa,
+            ..self.config.clone()
+        };
+        let previous_formats = {
+            let ctx = self.ctx.lock().eyre()?;
+            let formats = ctx
+                .dev
+                .select_render_target_formats(ctx.ins.inner(), &config)?;
+            let mut sto = self.sto.lock().eyre()?;
+            sto.add_target_formats(formats, &ctx.dev)?;
+            std::mem::replace(&mut sto.target_formats, formats)
+        };
+
+        // The frames take their formats from the storage
+        match Self::create_frames(&self.ctx, &self.vpt, &self.sto, config.gpu_profiling) {
+            Ok(frm) => {
+                self.frm = frm;
+                self.current_frame_index = 0;
+                self.config = config;
+                Ok(())
+            }
+            Err(e) => {
+                self.sto.lock().eyre()?.target_formats = previous_formats;
+                Err(e.into())
+            }
+        }
+    }
+
+    fn set_skybox(&mut self, cubemap: CubemapTexture) -> Result<()> {
+        // The previous skybox may still be in use by a frame in flight
+        self.wait_idle()?;
+
+        let device = self.ctx.lock().eyre()?.dev.logical.clone();
+        let mut sto = self.sto.lock().eyre()?;
+        let target_formats = sto.target_formats;
+        let material_factory = &mut sto.material_factories_mut(target_formats)?.skybox;
+        let skybox = Skybox::new(cubemap, material_factory, device)?;
+        sto.skybox = Some(skybox);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "hot-reload")]
+    fn reload_changed_shaders(&mut self) -> Result<()> {
+        let Some(watcher) = &self.shader_watcher else {
+            return Ok(());
+        };
+        let changed_shaders = watcher.poll();
+        if changed_shaders.is_empty() {
+            return Ok(());
+        }
+
+        // The pipelines being replaced may still be in use by a frame in flight
+        self.wait_idle()?;
+        self.sto.lock().eyre()?.reload_shaders(&changed_shaders);
+        Ok(())
+    }
+
+    fn create_frames(
+        ctx: &Arc<Mutex<RenderContext>>,
+        vpt: &Arc<Mutex<RenderViewport>>,
+        sto: &Arc<Mutex<RenderStorage>>,
+        gpu_profiling: GpuProfiling,
+    ) -> Result<Vec<Arc<RenderFrame>>> {
+        (0..Self::FRAMES_IN_FLIGHT)
+            .map(|_| {
+                RenderFrame::new(ctx.clone(), vpt.clone(), sto.clone(), gpu_profiling).map(Arc::new)
+            })
+            .collect()
+    }
+
+    fn wait_idle(&self) -> Result<()> {
+        let ctx = self.ctx.lock().eyre()?;
+        ctx.dev.wait_idle()
+    }
+
+    /// Level of detail of each instance, from how large it appears from `cam`
+    fn select_lods(&mut self, cam: &Camera, instances: &[DrawInstance]) -> Result<Vec<usize>> {
+        let target_size = self.vpt.lock().eyre()?.get_size();
+        let sto = self.sto.lock().eyre()?;
+        Ok(self.lod_selector.select(instances, &sto, cam, target_size))
+    }
+
+    fn update_scene<'a>(
+        &mut self,
+        cam: &'a Camera,
+        instances: &'a [DrawInstance],
+        lods: &'a [usize],
+    ) -> Result<FrameRenderPacket<'a>> {
         let target_size = self.vpt.lock().eyre()?.get_size();
         let frame_metadata = FrameRenderMetadata {
             frame_index: self.current_frame_index,
@@ -89,7 +541,11 @@ impl Renderer {
             resize_requested: self.resize_requested,
         };
         Ok(FrameRenderPacket {
-            payload: FrameRenderPayload { cam },
+            payload: FrameRenderPayload {
+                cam,
+                instances,
+                lods,
+            },
             metadata: frame_metadata,
         })
     }
@@ -0,0 +1,492 @@
+//! GPU timings of named scopes of a command encoder, see `CommandEncoder::scope`.
+//!
+//! Every scope writes a timestamp query when it begins and ends. The results are read back
+//! the next time the encoder starts recording, when the previous submission has completed,
+//! so a profile always describes an earlier frame than the one being recorded.
+
+use crate::context::debug_utils::DebugUtils;
+use ash::vk;
+use color_eyre::Result;
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// Scopes beyond this are not measured
+const MAX_SCOPES_PER_FRAME: u32 = 64;
+
+/// What the GPU profiler measures, see `Renderer::gpu_profile`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum GpuProfiling {
+    #[default]
+    Off,
+    Timestamps,
+    /// Also count shader invocations of top-level scopes, if the device supports it
+    TimestampsAndPipelineStatistics,
+}
+
+/// Shader invocations counted during a scope
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PipelineStatistics {
+    pub vertex_shader_invocations: u64,
+    pub fragment_shader_invocations: u64,
+}
+
+/// A named scope of GPU work and the scopes nested in it
+#[derive(Clone, Debug, PartialEq)]
+pub struct GpuScope {
+    pub name: String,
+    /// Relative to the start of the first scope of the frame
+    pub start_ms: f64,
+    pub duration_ms: f64,
+    /// Only collected for top-level scopes, since statistics queries cannot be nested
+    pub pipeline_statistics: Option<PipelineStatistics>,
+    pub children: Vec<GpuScope>,
+}
+
+/// GPU timings of one completed frame
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GpuProfile {
+    pub scopes: Vec<GpuScope>,
+}
+
+impl GpuProfile {
+    /// Time spent in the top-level scopes
+    pub fn total_ms(&self) -> f64 {
+        self.scopes.iter().map(|scope| scope.duration_ms).sum()
+    }
+
+    /// Every scope as a complete event in the Chrome trace event format,
+    /// which `chrome://tracing` and Perfetto can open
+    pub fn to_chrome_trace_json(&self) -> String {
+        let mut events = Vec::new();
+        for scope in &self.scopes {
+            push_trace_events(scope, &mut events);
+        }
+        format!("{{\"traceEvents\":[{}]}}", events.join(","))
+    }
+}
+
+fn push_trace_events(scope: &GpuScope, events: &mut Vec<String>) {
+    let mut event = format!(
+        "{{\"name\":{},\"cat\":\"gpu\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":0,\"tid\":0",
+        json_string(&scope.name),
+        scope.start_ms * 1000.0,
+        scope.duration_ms * 1000.0
+    );
+    if let Some(statistics) = scope.pipeline_statistics {
+        let _ = write!(
+            event,
+            ",\"args\":{{\"vertex_shader_invocations\":{},\"fragment_shader_invocations\":{}}}",
+            statistics.vertex_shader_invocations, statistics.fragment_shader_invocations
+        );
+    }
+    event.push('}');
+    events.push(event);
+
+    for child in &scope.children {
+        push_trace_events(child, events);
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// A scope as it was recorded, in the order the scopes began
+struct RecordedScope {
+    name: String,
+    depth: usize,
+    /// Whether a statistics query was begun, at the same index as the scope
+    has_statistics: bool,
+}
+
+/// Writes the timestamp and pipeline statistics queries of one command encoder
+pub(crate) struct GpuProfiler {
+    timestamp_pool: vk::QueryPool,
+    /// `None` unless pipeline statistics were requested and are supported
+    statistics_pool: Option<vk::QueryPool>,
+    timestamp_period_ns: f64,
+    timestamp_mask: u64,
+
+    recorded: Vec<RecordedScope>,
+    /// Indices into `recorded` of the scopes that have not ended yet
+    open: Vec<Option<usize>>,
+    /// Whether queries were recorded since the results were last read back
+    pending: bool,
+    /// Whether this frame had more scopes than there are queries, which is only reported once
+    overflowed: bool,
+    latest: Option<GpuProfile>,
+
+    device: Arc<ash::Device>,
+}
+
+impl GpuProfiler {
+    pub fn new(
+        name: &str,
+        timestamp_period_ns: f32,
+        timestamp_valid_bits: u32,
+        collect_statistics: bool,
+        debug_utils: &DebugUtils,
+        device: Arc<ash::Device>,
+    ) -> Result<Self> {
+        let timestamp_pool = unsafe {
+            device.create_query_pool(
+                &vk::QueryPoolCreateInfo::default()
+                    .query_type(vk::QueryType::TIMESTAMP)
+                    .query_count(MAX_SCOPES_PER_FRAME * 2),
+                None,
+            )?
+        };
+        debug_utils.set_object_name(timestamp_pool, &format!("{name} timestamps"));
+
+        let statistics_pool = if collect_statistics {
+            let pool = unsafe {
+                device.create_query_pool(
+                    &vk::QueryPoolCreateInfo::default()
+                        .query_type(vk::QueryType::PIPELINE_STATISTICS)
+                        .query_count(MAX_SCOPES_PER_FRAME)
+                        .pipeline_statistics(
+                            vk::QueryPipelineStatisticFlags::VERTEX_SHADER_INVOCATIONS
+                                | vk::QueryPipelineStatisticFlags::FRAGMENT_SHADER_INVOCATIONS,
+                        ),
+                    None,
+                )
+            };
+            match pool {
+                Ok(pool) => Some(pool),
+                Err(e) => {
+                    unsafe { device.destroy_query_pool(timestamp_pool, None) };
+                    return Err(e.into());
+                }
+            }
+        } else {
+            None
+        };
+        if let Some(pool) = statistics_pool {
+            debug_utils.set_object_name(pool, &format!("{name} pipeline statistics"));
+        }
+
+        Ok(Self {
+            timestamp_pool,
+            statistics_pool,
+            timestamp_period_ns: timestamp_period_ns as f64,
+            timestamp_mask: timestamp_mask(timestamp_valid_bits),
+            recorded: Vec::new(),
+            open: Vec::new(),
+            pending: false,
+            overflowed: false,
+            latest: None,
+            device,
+        })
+    }
+
+    /// Read back the results of the previous submission, then reset the queries for the next one.
+    /// The previous submission must have completed.
+    pub fn begin_frame(&mut self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        if let Some(profile) = self.read_results()? {
+            self.latest = Some(profile);
+        }
+
+        unsafe {
+            self.device.cmd_reset_query_pool(
+                command_buffer,
+                self.timestamp_pool,
+                0,
+                MAX_SCOPES_PER_FRAME * 2,
+            );
+            if let Some(pool) = self.statistics_pool {
+                self.device
+                    .cmd_reset_query_pool(command_buffer, pool, 0, MAX_SCOPES_PER_FRAME);
+            }
+        }
+        self.recorded.clear();
+        self.open.clear();
+        self.pending = true;
+        self.overflowed = false;
+        Ok(())
+    }
+
+    pub fn begin_scope(&mut self, command_buffer: vk::CommandBuffer, name: &str) {
+        let index = self.recorded.len() as u32;
+        if index >= MAX_SCOPES_PER_FRAME {
+            if !std::mem::replace(&mut self.overflowed, true) {
+                log::warn!(
+                    "More than {} GPU profiler scopes in a frame, {} is not measured",
+                    MAX_SCOPES_PER_FRAME,
+                    name
+                );
+            }
+            self.open.push(None);
+            return;
+        }
+
+        let depth = self.open.len();
+        let statistics_pool = self.statistics_pool.filter(|_| depth == 0);
+        unsafe {
+            self.device.cmd_write_timestamp2(
+                command_buffer,
+                vk::PipelineStageFlags2::ALL_COMMANDS,
+                self.timestamp_pool,
+                index * 2,
+            );
+            if let Some(pool) = statistics_pool {
+                self.device.cmd_begin_query(
+                    command_buffer,
+                    pool,
+                    index,
+                    vk::QueryControlFlags::empty(),
+                );
+            }
+        }
+
+        self.open.push(Some(self.recorded.len()));
+        self.recorded.push(RecordedScope {
+            name: name.to_owned(),
+            depth,
+            has_statistics: statistics_pool.is_some(),
+        });
+    }
+
+    pub fn end_scope(&mut self, command_buffer: vk::CommandBuffer) {
+        let Some(Some(index)) = self.open.pop() else {
+            return;
+        };
+
+        let query = index as u32;
+        unsafe {
+            if let Some(pool) = self
+                .statistics_pool
+                .filter(|_| self.recorded[index].has_statistics)
+            {
+                self.device.cmd_end_query(command_buffer, pool, query);
+            }
+            self.device.cmd_write_timestamp2(
+                command_buffer,
+                vk::PipelineStageFlags2::ALL_COMMANDS,
+                self.timestamp_pool,
+                query * 2 + 1,
+            );
+        }
+    }
+
+    /// The profile of the most recent submission that has been read back
+    pub fn take_profile(&mut self) -> Option<GpuProfile> {
+        self.latest.take()
+    }
+
+    fn read_results(&mut self) -> Result<Option<GpuProfile>> {
+        if !std::mem::take(&mut self.pending) || self.recorded.is_empty() {
+            return Ok(None);
+        }
+
+        let mut timestamps = vec![0u64; self.recorded.len() * 2];
+        let result = unsafe {
+            self.device.get_query_pool_results(
+                self.timestamp_pool,
+                0,
+                &mut timestamps,
+                vk::QueryResultFlags::TYPE_64,
+            )
+        };
+        match result {
+            Ok(()) => {}
+            // The submission never happened, e.g. recording failed halfway
+            Err(vk::Result::NOT_READY) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut statistics = vec![None; self.recorded.len()];
+        if let Some(pool) = self.statistics_pool {
+            for (index, scope) in self.recorded.iter().enumerate() {
+                if !scope.has_statistics {
+                    continue;
+                }
+                // Ordered like the bits of the statistics flags
+                let mut counts = [[0u64; 2]];
+                unsafe {
+                    self.device.get_query_pool_results(
+                        pool,
+                        index as u32,
+                        &mut counts,
+                        vk::QueryResultFlags::TYPE_64,
+                    )?
+                };
+                statistics[index] = Some(PipelineStatistics {
+                    vertex_shader_invocations: counts[0][0],
+                    fragment_shader_invocations: counts[0][1],
+                });
+            }
+        }
+
+        let measured = self
+            .recorded
+            .iter()
+            .zip(timestamps.chunks_exact(2))
+            .zip(statistics)
+            .map(|((scope, ticks), statistics)| MeasuredScope {
+                name: scope.name.clone(),
+                depth: scope.depth,
+                begin_ticks: ticks[0],
+                end_ticks: ticks[1],
+                statistics,
+            })
+            .collect::<Vec<_>>();
+        Ok(Some(build_profile(
+            &measured,
+            self.timestamp_period_ns,
+            self.timestamp_mask,
+        )))
+    }
+}
+
+impl Drop for GpuProfiler {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_query_pool(self.timestamp_pool, None);
+            if let Some(pool) = self.statistics_pool {
+                self.device.destroy_query_pool(pool, None);
+            }
+        }
+    }
+}
+
+/// A recorded scope with its query results
+struct MeasuredScope {
+    name: String,
+    depth: usize,
+    begin_ticks: u64,
+    end_ticks: u64,
+    statistics: Option<PipelineStatistics>,
+}
+
+/// Timestamps only have `valid_bits` bits and wrap around
+fn timestamp_mask(valid_bits: u32) -> u64 {
+    if valid_bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << valid_bits) - 1
+    }
+}
+
+/// Nest the scopes, which are in the order they began, and convert their ticks to milliseconds
+fn build_profile(scopes: &[MeasuredScope], timestamp_period_ns: f64, mask: u64) -> GpuProfile {
+    let Some(first) = scopes.first() else {
+        return GpuProfile::default();
+    };
+    let ticks_to_ms = |ticks: u64| (ticks & mask) as f64 * timestamp_period_ns / 1_000_000.0;
+
+    let mut roots = Vec::new();
+    // The ancestors of the next scope, innermost last
+    let mut stack: Vec<GpuScope> = Vec::new();
+    for scope in scopes {
+        while stack.len() > scope.depth {
+            let finished = stack.pop().expect("The stack is not empty");
+            attach_scope(finished, &mut stack, &mut roots);
+        }
+        stack.push(GpuScope {
+            name: scope.name.clone(),
+            start_ms: ticks_to_ms(scope.begin_ticks.wrapping_sub(first.begin_ticks)),
+            duration_ms: ticks_to_ms(scope.end_ticks.wrapping_sub(scope.begin_ticks)),
+            pipeline_statistics: scope.statistics,
+            children: Vec::new(),
+        });
+    }
+    while let Some(finished) = stack.pop() {
+        attach_scope(finished, &mut stack, &mut roots);
+    }
+
+    GpuProfile { scopes: roots }
+}
+
+fn attach_scope(scope: GpuScope, stack: &mut [GpuScope], roots: &mut Vec<GpuScope>) {
+    match stack.last_mut() {
+        Some(parent) => parent.children.push(scope),
+        None => roots.push(scope),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measured(name: &str, depth: usize, begin_ticks: u64, end_ticks: u64) -> MeasuredScope {
+        MeasuredScope {
+            name: name.to_owned(),
+            depth,
+            begin_ticks,
+            end_ticks,
+            statistics: None,
+        }
+    }
+
+    #[test]
+    fn scopes_are_nested_by_depth() {
+        let scopes = [
+            measured("Scene pass", 0, 1_000, 5_000),
+            measured("Skybox", 1, 1_500, 2_500),
+            measured("Opaque", 1, 2_500, 4_000),
+            measured("Copy to present image", 0, 5_000, 6_000),
+        ];
+        let profile = build_profile(&scopes, 1.0, u64::MAX);
+
+        let names = |scopes: &[GpuScope]| scopes.iter().map(|s| s.name.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            names(&profile.scopes),
+            ["Scene pass", "Copy to present image"]
+        );
+        assert_eq!(names(&profile.scopes[0].children), ["Skybox", "Opaque"]);
+        assert!(profile.scopes[1].children.is_empty());
+
+        let skybox = &profile.scopes[0].children[0];
+        assert!((skybox.start_ms - 0.0005).abs() < 1e-12);
+        assert!((skybox.duration_ms - 0.001).abs() < 1e-12);
+        assert!((profile.total_ms() - 0.005).abs() < 1e-12);
+    }
+
+    #[test]
+    fn ticks_are_scaled_by_the_timestamp_period_and_wrap_around() {
+        // 36 valid bits, and the scope crosses the point where the counter wraps
+        let mask = timestamp_mask(36);
+        let scopes = [measured("Wrapping", 0, mask - 99, 100)];
+        let profile = build_profile(&scopes, 2.5, mask);
+        assert!((profile.scopes[0].duration_ms - 200.0 * 2.5 / 1_000_000.0).abs() < 1e-12);
+        assert_eq!(profile.scopes[0].start_ms, 0.0);
+        assert_eq!(timestamp_mask(64), u64::MAX);
+    }
+
+    #[test]
+    fn chrome_trace_has_an_event_per_scope() {
+        let mut scopes = [
+            measured("Scene \"main\" pass", 0, 0, 2_000),
+            measured("Skybox", 1, 500, 1_000),
+        ];
+        scopes[0].statistics = Some(PipelineStatistics {
+            vertex_shader_invocations: 36,
+            fragment_shader_invocations: 1024,
+        });
+        let json = build_profile(&scopes, 1.0, u64::MAX).to_chrome_trace_json();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"traceEvents":["#,
+                r#"{"name":"Scene \"main\" pass","cat":"gpu","ph":"X","ts":0.000,"dur":2.000,"pid":0,"tid":0,"#,
+                r#""args":{"vertex_shader_invocations":36,"fragment_shader_invocations":1024}},"#,
+                r#"{"name":"Skybox","cat":"gpu","ph":"X","ts":0.500,"dur":0.500,"pid":0,"tid":0}"#,
+                r#"]}"#
+            )
+        );
+    }
+}
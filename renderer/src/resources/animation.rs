@@ -0,0 +1,346 @@
+//! Keyframe animation of scene nodes, sampled on the CPU. `GltfImport` reads the clips of a
+//! file into `ModelScene::animations`, and `SceneSkin::joint_palette` turns a sampled pose
+//! into the joint palette of a skinned `DrawInstance`.
+
+use crate::resources::gltf_loader::{ModelScene, NodeTransform};
+use glam::{Quat, Vec3};
+use std::ops::{Add, Mul};
+
+/// How a channel's value changes between two keyframes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Holds the value of the previous keyframe
+    Step,
+    /// Linear for translation and scale, spherical for rotation
+    #[default]
+    Linear,
+    /// Hermite spline through the keyframes, with their tangents
+    CubicSpline,
+}
+
+/// Values of a channel's keyframes. With `Interpolation::CubicSpline` every keyframe has an
+/// in-tangent, a value and an out-tangent, in that order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChannelValues {
+    Translation(Vec<Vec3>),
+    Rotation(Vec<Quat>),
+    Scale(Vec<Vec3>),
+}
+
+/// Keyframes of one property of one node
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimationChannel {
+    /// Index into `ModelScene::nodes`
+    pub node: usize,
+    pub interpolation: Interpolation,
+    /// Increasing, in seconds
+    pub times: Vec<f32>,
+    pub values: ChannelValues,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AnimationClip {
+    pub name: Option<String>,
+    pub channels: Vec<AnimationChannel>,
+    /// Time of the last keyframe of any channel, in seconds
+    pub duration: f32,
+}
+
+impl AnimationClip {
+    /// Set the properties of `pose`, one local transform per node, that the clip animates to
+    /// their values at `time`. Before the first or after the last keyframe of a channel, its
+    /// value is that keyframe's.
+    pub fn sample(&self, time: f32, pose: &mut [NodeTransform]) {
+        for channel in &self.channels {
+            let Some(transform) = pose.get_mut(channel.node) else {
+                continue;
+            };
+            match &channel.values {
+                ChannelValues::Translation(values) => {
+                    if let Some(translation) = channel.sample(values, time, Vec3::lerp) {
+                        transform.translation = translation;
+                    }
+                }
+                ChannelValues::Rotation(values) => {
+                    if let Some(rotation) = channel.sample(values, time, Quat::slerp) {
+                        transform.rotation = rotation.normalize();
+                    }
+                }
+                ChannelValues::Scale(values) => {
+                    if let Some(scale) = channel.sample(values, time, Vec3::lerp) {
+                        transform.scale = scale;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl AnimationChannel {
+    /// `None` if the channel has no keyframes or too few values for them
+    fn sample<T>(&self, values: &[T], time: f32, lerp: impl Fn(T, T, f32) -> T) -> Option<T>
+    where
+        T: Copy + Add<Output = T> + Mul<f32, Output = T>,
+    {
+        let cubic = self.interpolation == Interpolation::CubicSpline;
+        let value = |keyframe: usize| {
+            let index = if cubic { keyframe * 3 + 1 } else { keyframe };
+            values.get(index).copied()
+        };
+
+        let last = self.times.len().checked_sub(1)?;
+        let next = self.times.partition_point(|&keyframe| keyframe <= time);
+        if next == 0 {
+            return value(0);
+        }
+        if next > last {
+            return value(last);
+        }
+        let previous = next - 1;
+        let span = self.times[next] - self.times[previous];
+        let t = (time - self.times[previous]) / span;
+
+        match self.interpolation {
+            Interpolation::Step => value(previous),
+            Interpolation::Linear => Some(lerp(value(previous)?, value(next)?, t)),
+            Interpolation::CubicSpline => {
+                let out_tangent = *values.get(previous * 3 + 2)?;
+                let in_tangent = *values.get(next * 3)?;
+                let (t2, t3) = (t * t, t * t * t);
+                Some(
+                    value(previous)? * (2.0 * t3 - 3.0 * t2 + 1.0)
+                        + out_tangent * ((t3 - 2.0 * t2 + t) * span)
+                        + value(next)? * (-2.0 * t3 + 3.0 * t2)
+                        + in_tangent * ((t3 - t2) * span),
+                )
+            }
+        }
+    }
+}
+
+/// Plays the clips of a `ModelScene` in a loop, blending from the previous clip for a while
+/// after switching to another
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimationPlayer {
+    /// Index into `ModelScene::animations`
+    clip: usize,
+    time: f32,
+    fade: Option<Crossfade>,
+}
+
+/// The clip being faded out, which keeps playing until it is gone
+#[derive(Clone, Debug, PartialEq)]
+struct Crossfade {
+    clip: usize,
+    time: f32,
+    elapsed: f32,
+    duration: f32,
+}
+
+impl AnimationPlayer {
+    /// Play `clip`, an index into `ModelScene::animations`, from its start
+    pub fn new(clip: usize) -> Self {
+        Self {
+            clip,
+            time: 0.0,
+            fade: None,
+        }
+    }
+
+    /// Play `clip` from its start, fading from the current clip over `crossfade` seconds.
+    /// A fade still in progress is cut short.
+    pub fn play(&mut self, clip: usize, crossfade: f32) {
+        self.fade = (crossfade > 0.0).then_some(Crossfade {
+            clip: self.clip,
+            time: self.time,
+            elapsed: 0.0,
+            duration: crossfade,
+        });
+        self.clip = clip;
+        self.time = 0.0;
+    }
+
+    pub fn advance(&mut self, seconds: f32) {
+        self.time += seconds;
+        if let Some(fade) = &mut self.fade {
+            fade.time += seconds;
+            fade.elapsed += seconds;
+            if fade.elapsed >= fade.duration {
+                self.fade = None;
+            }
+        }
+    }
+
+    pub fn clip(&self) -> usize {
+        self.clip
+    }
+
+    /// Local transform of every node of `scene`. Nodes the clips do not animate keep their
+    /// rest transform, as do all of them if the clip does not exist.
+    pub fn pose(&self, scene: &ModelScene) -> Vec<NodeTransform> {
+        let sample = |clip: usize, time: f32| {
+            let mut pose = scene
+                .nodes
+                .iter()
+                .map(|node| node.transform)
+                .collect::<Vec<_>>();
+            if let Some(clip) = scene.animations.get(clip) {
+                let looped = if clip.duration > 0.0 {
+                    time.rem_euclid(clip.duration)
+                } else {
+                    0.0
+                };
+                clip.sample(looped, &mut pose);
+            }
+            pose
+        };
+
+        let pose = sample(self.clip, self.time);
+        match &self.fade {
+            Some(fade) => {
+                let weight = fade.elapsed / fade.duration;
+                sample(fade.clip, fade.time)
+                    .iter()
+                    .zip(&pose)
+                    .map(|(from, to)| from.lerp(to, weight))
+                    .collect()
+            }
+            None => pose,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::gltf_loader::SceneNode;
+    use std::f32::consts::FRAC_PI_2;
+
+    fn channel(interpolation: Interpolation, values: Vec<Vec3>) -> AnimationChannel {
+        AnimationChannel {
+            node: 0,
+            interpolation,
+            times: vec![1.0, 3.0],
+            values: ChannelValues::Translation(values),
+        }
+    }
+
+    fn translation_at(channel: &AnimationChannel, time: f32) -> Vec3 {
+        let clip = AnimationClip {
+            channels: vec![channel.clone()],
+            ..Default::default()
+        };
+        let mut pose = [NodeTransform::default()];
+        clip.sample(time, &mut pose);
+        pose[0].translation
+    }
+
+    #[test]
+    fn step_and_linear_interpolation() {
+        let step = channel(Interpolation::Step, vec![Vec3::ZERO, Vec3::X * 4.0]);
+        assert_eq!(translation_at(&step, 2.5), Vec3::ZERO);
+        assert_eq!(translation_at(&step, 3.0), Vec3::X * 4.0);
+
+        let linear = channel(Interpolation::Linear, vec![Vec3::ZERO, Vec3::X * 4.0]);
+        assert_eq!(translation_at(&linear, 2.0), Vec3::X * 2.0);
+        // Held outside of the keyframes
+        assert_eq!(translation_at(&linear, 0.0), Vec3::ZERO);
+        assert_eq!(translation_at(&linear, 10.0), Vec3::X * 4.0);
+    }
+
+    #[test]
+    fn rotations_are_slerped() {
+        let clip = AnimationClip {
+            channels: vec![AnimationChannel {
+                node: 0,
+                interpolation: Interpolation::Linear,
+                times: vec![0.0, 1.0],
+                values: ChannelValues::Rotation(vec![
+                    Quat::IDENTITY,
+                    Quat::from_rotation_y(FRAC_PI_2),
+                ]),
+            }],
+            ..Default::default()
+        };
+        let mut pose = [NodeTransform::default()];
+        clip.sample(0.5, &mut pose);
+        let expected = Quat::from_rotation_y(FRAC_PI_2 / 2.0);
+        assert!(pose[0].rotation.abs_diff_eq(expected, 1e-6));
+    }
+
+    #[test]
+    fn cubic_spline_follows_the_tangents() {
+        // In-tangent, value and out-tangent of each keyframe
+        let flat = channel(
+            Interpolation::CubicSpline,
+            vec![
+                Vec3::ZERO,
+                Vec3::ZERO,
+                Vec3::ZERO,
+                Vec3::ZERO,
+                Vec3::X * 4.0,
+                Vec3::ZERO,
+            ],
+        );
+        assert_eq!(translation_at(&flat, 1.0), Vec3::ZERO);
+        assert_eq!(translation_at(&flat, 2.0), Vec3::X * 2.0);
+        assert_eq!(translation_at(&flat, 3.0), Vec3::X * 4.0);
+
+        // Tangents of a straight line make the spline linear
+        let line = channel(
+            Interpolation::CubicSpline,
+            vec![
+                Vec3::X * 2.0,
+                Vec3::ZERO,
+                Vec3::X * 2.0,
+                Vec3::X * 2.0,
+                Vec3::X * 4.0,
+                Vec3::X * 2.0,
+            ],
+        );
+        assert!(translation_at(&line, 1.5).abs_diff_eq(Vec3::X, 1e-6));
+        // Ease in without them
+        assert!(translation_at(&flat, 1.5).x < 1.0);
+    }
+
+    #[test]
+    fn player_loops_and_crossfades() {
+        let clip = |x: f32| AnimationClip {
+            channels: vec![AnimationChannel {
+                node: 0,
+                interpolation: Interpolation::Linear,
+                times: vec![0.0, 2.0],
+                values: ChannelValues::Translation(vec![Vec3::ZERO, Vec3::X * x]),
+            }],
+            duration: 2.0,
+            ..Default::default()
+        };
+        let scene = ModelScene {
+            nodes: vec![SceneNode {
+                name: None,
+                transform: NodeTransform::default(),
+                mesh: None,
+                skin: None,
+                children: Vec::new(),
+            }],
+            animations: vec![clip(2.0), clip(-2.0)],
+            ..Default::default()
+        };
+
+        let mut player = AnimationPlayer::new(0);
+        player.advance(3.0);
+        assert_eq!(player.pose(&scene)[0].translation, Vec3::X);
+
+        // Halfway through the fade, between the clip at 1.5s and the next one at 0.5s
+        player.play(1, 1.0);
+        player.advance(0.5);
+        assert!(
+            player.pose(&scene)[0]
+                .translation
+                .abs_diff_eq(Vec3::X * 0.5, 1e-6)
+        );
+        player.advance(0.5);
+        assert_eq!(player.pose(&scene)[0].translation, Vec3::NEG_X);
+        assert_eq!(player.clip(), 1);
+    }
+}
@@ -0,0 +1,70 @@
+use glam::Vec3;
+
+/// Axis-aligned bounding box. Empty point sets give a box at the origin.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn from_points(points: impl IntoIterator<Item = Vec3>) -> Self {
+        points
+            .into_iter()
+            .fold(None, |aabb: Option<Self>, point| {
+                Some(match aabb {
+                    Some(aabb) => Self {
+                        min: aabb.min.min(point),
+                        max: aabb.max.max(point),
+                    },
+                    None => Self {
+                        min: point,
+                        max: point,
+                    },
+                })
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn size(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn contains(&self, point: Vec3) -> bool {
+        point.cmpge(self.min).all() && point.cmple(self.max).all()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    /// Centered on the bounding box of the points, which is quick and at most
+    /// √3 times larger than the smallest enclosing sphere
+    pub fn from_points(points: impl IntoIterator<Item = Vec3> + Clone) -> Self {
+        let center = Aabb::from_points(points.clone()).center();
+        let radius = points
+            .into_iter()
+            .map(|point| point.distance(center))
+            .fold(0.0, f32::max);
+        Self { center, radius }
+    }
+
+    pub fn contains(&self, point: Vec3) -> bool {
+        point.distance(self.center) <= self.radius
+    }
+}
@@ -1,4 +1,5 @@
 use std::sync::{Arc, Mutex};
+use crate::context::debug_utils::DebugUtils;
 use ash::vk;
 use color_eyre::eyre::Result;
 use color_eyre::eyre::eyre;
@@ -16,12 +17,14 @@ pub(crate) struct Buffer {
 
 impl Buffer {
     pub fn new(
+        name: &str,
         size: u64,
         alignment: u64,
         buf_usage: vk::BufferUsageFlags,
         mem_usage: vk_mem::MemoryUsage,
         mapped: bool,
 
+        debug_utils: &DebugUtils,
         mem_allocator: Arc<Mutex<vk_mem::Allocator>>,
         device: Arc<ash::Device>,
     ) -> Result<Self> {
@@ -50,6 +53,7 @@ impl Buffer {
                     alignment,
                 )?
         };
+        debug_utils.set_object_name(buffer, name);
 
         Ok(Self {
             buffer,
@@ -101,6 +105,15 @@ impl Buffer {
 
         Ok(copy_record)
     }
+
+    /// Only valid for buffers created with `SHADER_DEVICE_ADDRESS` usage
+    pub fn device_address(&self) -> vk::DeviceAddress {
+        unsafe {
+            self.device.get_buffer_device_address(
+                &vk::BufferDeviceAddressInfo::default().buffer(self.buffer),
+            )
+        }
+    }
 }
 
 impl Drop for Buffer {
@@ -0,0 +1,144 @@
+use crate::context::device::RenderDevice;
+use crate::resources::{
+    material::{ComputeMaterialFactoryBuilder, Material, MaterialFactory},
+    shader::ComputeShader,
+    texture::{ColorTexture, CubemapTexture},
+};
+use ash::vk;
+use color_eyre::Result;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Must match `local_size_x` and `local_size_y` in `equirect_to_cube.comp`
+const WORKGROUP_SIZE: u32 = 8;
+
+/// Converts equirectangular (latitude-longitude) HDR images into cubemaps with a compute pass.
+pub(crate) struct EquirectToCubemapConverter {
+    _material_factory: MaterialFactory,
+    material: Material,
+    sampler: vk::Sampler,
+    device: Arc<ash::Device>,
+}
+
+impl EquirectToCubemapConverter {
+    pub fn new(dev: &RenderDevice) -> Result<Self> {
+        let device = dev.logical.clone();
+
+        // The layouts are generated from the shader
+        let shader = ComputeShader::new("equirect_to_cube", "main", device.clone())?;
+        let mut material_factory = ComputeMaterialFactoryBuilder::new(
+            device.clone(),
+            dev.descriptor_allocator.clone(),
+            dev.pipeline_cache.handle,
+            dev.debug_utils.clone(),
+        )
+        .with_shader(shader)
+        .build()?;
+        let material = material_factory.create_material()?;
+
+        let sampler = unsafe {
+            device.create_sampler(
+                &vk::SamplerCreateInfo::default()
+                    .mag_filter(vk::Filter::LINEAR)
+                    .min_filter(vk::Filter::LINEAR)
+                    .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                    // Longitude wraps around, latitude does not
+                    .address_mode_u(vk::SamplerAddressMode::REPEAT)
+                    .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+                None,
+            )?
+        };
+        material.write_sampler(1, 0, sampler);
+
+        Ok(Self {
+            _material_factory: material_factory,
+            material,
+            sampler,
+            device,
+        })
+    }
+
+    #[cfg(feature = "hot-reload")]
+    pub fn material_factory_mut(&mut self) -> &mut MaterialFactory {
+        &mut self._material_factory
+    }
+
+    /// Load an `.hdr` or `.exr` file and convert it into a cubemap with faces of `face_size` texels
+    pub fn convert_file(
+        &self,
+        path: &Path,
+        face_size: u32,
+        dev: &RenderDevice,
+    ) -> Result<CubemapTexture> {
+        log::info!("Converting equirectangular image to cubemap: {:?}", path);
+        let image = image::open(path)?;
+        let equirect = dev.create_hdr_texture(&path.display().to_string(), &image)?;
+        self.convert(&equirect, face_size, dev)
+    }
+
+    pub fn convert(
+        &self,
+        equirect: &ColorTexture,
+        face_size: u32,
+        dev: &RenderDevice,
+    ) -> Result<CubemapTexture> {
+        let mut cubemap =
+            dev.create_storage_cubemap_texture(&format!("{} (cubemap)", equirect.name), face_size)?;
+        let faces_view = cubemap.create_view(vk::ImageViewType::TYPE_2D_ARRAY)?;
+
+        self.material.write_sampled_image(
+            0,
+            0,
+            equirect.view,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        );
+        self.material.write_storage_image(2, 0, faces_view);
+
+        let group_count = face_size.div_ceil(WORKGROUP_SIZE);
+        // Recorded on the graphics queue, which samples the cubemap, so neither texture has to
+        // change queue family ownership. `equirect` must have been uploaded on it too.
+        let result =
+            dev.immediate_graphics_submit(|cmd: vk::CommandBuffer, device: &ash::Device| {
+                let _label = dev
+                    .debug_utils
+                    .scoped_label(cmd, "Equirectangular to cubemap");
+                cubemap.transition_layout(
+                    cmd,
+                    vk::ImageLayout::UNDEFINED,
+                    vk::ImageLayout::GENERAL,
+                );
+
+                self.material.bind_pipeline(cmd)?;
+                self.material.bind_descriptor_sets(cmd);
+                unsafe {
+                    // One invocation per texel, one Z slice per cube face
+                    device.cmd_dispatch(cmd, group_count, group_count, 6);
+                }
+
+                cubemap.transition_layout(
+                    cmd,
+                    vk::ImageLayout::GENERAL,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                );
+
+                Ok(())
+            });
+
+        // The submission has completed (or failed) by now, so the view is no longer in use
+        unsafe {
+            self.device.destroy_image_view(faces_view, None);
+        }
+        result?;
+
+        Ok(cubemap)
+    }
+}
+
+impl Drop for EquirectToCubemapConverter {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_sampler(self.sampler, None);
+        }
+    }
+}
@@ -0,0 +1,979 @@
+//! glTF 2.0 import. `GltfImport` reads the meshes, textures, samplers, materials and node
+//! hierarchy of a `.gltf` or `.glb` file on the CPU, and `RenderStorage::load_gltf` uploads them.
+
+use crate::resources::animation::{AnimationChannel, AnimationClip, ChannelValues, Interpolation};
+use crate::resources::bounds::{Aabb, BoundingSphere};
+use crate::resources::mesh::Mesh;
+use crate::resources::vertex::Vertex;
+use ash::vk;
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+use glam::{Mat4, Quat, UVec4, Vec2, Vec3, Vec4};
+use std::path::Path;
+
+/// Local transform of a scene node relative to its parent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodeTransform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Default for NodeTransform {
+    fn default() -> Self {
+        Self {
+            translation: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
+        }
+    }
+}
+
+impl NodeTransform {
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
+    }
+
+    /// Blend towards `other` by `t`, interpolating the rotation spherically
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale.lerp(other.scale, t),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneNode {
+    pub name: Option<String>,
+    pub transform: NodeTransform,
+    /// Index into `ModelScene::meshes`
+    pub mesh: Option<usize>,
+    /// Index into `ModelScene::skins` of the joints deforming `mesh`
+    pub skin: Option<usize>,
+    /// Indices into `ModelScene::nodes`
+    pub children: Vec<usize>,
+}
+
+/// Joints of a skinned mesh, which the joint indices of its vertices index
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneSkin {
+    pub name: Option<String>,
+    /// Indices into `ModelScene::nodes`
+    pub joints: Vec<usize>,
+    /// Transform from the scene's space to each joint's, in the pose the mesh was bound in
+    pub inverse_bind_matrices: Vec<Mat4>,
+}
+
+impl SceneSkin {
+    /// Transform of every joint from the bind pose to the pose of `world_transforms`, see
+    /// `ModelScene::posed_world_transforms`. Drawing a mesh with this skin uses it as
+    /// `DrawInstance::joints`, while the instance's transform places the whole scene.
+    pub fn joint_palette(&self, world_transforms: &[Mat4]) -> Vec<Mat4> {
+        self.joints
+            .iter()
+            .enumerate()
+            .map(|(joint, &node)| {
+                let world = world_transforms.get(node).copied().unwrap_or_default();
+                let inverse_bind = self
+                    .inverse_bind_matrices
+                    .get(joint)
+                    .copied()
+                    .unwrap_or_default();
+                world * inverse_bind
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneMesh {
+    pub name: Option<String>,
+    /// Index into `ModelScene::materials` of every primitive of the mesh, in order.
+    /// `None` for primitives that use the default material.
+    pub primitive_materials: Vec<Option<usize>>,
+    /// Whether every primitive has joints and weights, so the mesh is drawn with a
+    /// `SceneSkin`'s joint palette
+    pub skinned: bool,
+    /// Bounds of every primitive, in the mesh's space, at rest
+    pub aabb: Aabb,
+    pub bounding_sphere: BoundingSphere,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlphaMode {
+    #[default]
+    Opaque,
+    /// Fully transparent below `MaterialParams::alpha_cutoff`, fully opaque otherwise
+    Mask,
+    Blend,
+}
+
+/// Metallic-roughness parameters of a glTF material
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialParams {
+    pub name: Option<String>,
+    pub base_color_factor: Vec4,
+    /// Whether the material has a base color texture, which is multiplied by `base_color_factor`
+    pub has_base_color_texture: bool,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub alpha_mode: AlphaMode,
+    pub alpha_cutoff: f32,
+    pub double_sided: bool,
+}
+
+impl Default for MaterialParams {
+    /// The glTF default material, used by primitives without one
+    fn default() -> Self {
+        Self {
+            name: None,
+            base_color_factor: Vec4::ONE,
+            has_base_color_texture: false,
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            alpha_mode: AlphaMode::Opaque,
+            alpha_cutoff: 0.5,
+            double_sided: false,
+        }
+    }
+}
+
+/// Scene graph and materials of a loaded glTF file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ModelScene {
+    /// Name the file was loaded under, i.e. its file name without extension
+    pub name: String,
+    pub nodes: Vec<SceneNode>,
+    /// Nodes without a parent in the scene, indices into `nodes`
+    pub roots: Vec<usize>,
+    pub meshes: Vec<SceneMesh>,
+    pub materials: Vec<MaterialParams>,
+    pub skins: Vec<SceneSkin>,
+    pub animations: Vec<AnimationClip>,
+}
+
+impl ModelScene {
+    /// Transform of every node relative to the scene root, in the same order as `nodes`.
+    /// Nodes that are not reachable from `roots` keep their local transform.
+    pub fn world_transforms(&self) -> Vec<Mat4> {
+        let rest_pose = self
+            .nodes
+            .iter()
+            .map(|node| node.transform)
+            .collect::<Vec<_>>();
+        self.posed_world_transforms(&rest_pose)
+    }
+
+    /// Like `world_transforms`, with `pose` as the local transform of every node instead,
+    /// e.g. from `AnimationPlayer::pose`
+    pub fn posed_world_transforms(&self, pose: &[NodeTransform]) -> Vec<Mat4> {
+        let local = |index: usize| pose.get(index).copied().unwrap_or_default().matrix();
+        let mut world = (0..self.nodes.len()).map(local).collect::<Vec<_>>();
+
+        let mut stack = self
+            .roots
+            .iter()
+            .map(|&root| (root, Mat4::IDENTITY))
+            .collect::<Vec<_>>();
+        while let Some((index, parent)) = stack.pop() {
+            let Some(node) = self.nodes.get(index) else {
+                continue;
+            };
+            world[index] = parent * local(index);
+            stack.extend(node.children.iter().map(|&child| (child, world[index])));
+        }
+        world
+    }
+}
+
+/// How a texture is sampled, deduplicated into one `vk::Sampler` per distinct description
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct SamplerDesc {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    pub address_mode_u: vk::SamplerAddressMode,
+    pub address_mode_v: vk::SamplerAddressMode,
+}
+
+impl SamplerDesc {
+    fn from_gltf(sampler: &gltf::texture::Sampler) -> Self {
+        use gltf::texture::{MagFilter, MinFilter};
+
+        // Filters are left to the implementation when unspecified, linear looks best
+        let mag_filter = match sampler.mag_filter() {
+            Some(MagFilter::Nearest) => vk::Filter::NEAREST,
+            Some(MagFilter::Linear) | None => vk::Filter::LINEAR,
+        };
+        let (min_filter, mipmap_mode) = match sampler.min_filter() {
+            Some(MinFilter::Nearest) | Some(MinFilter::NearestMipmapNearest) => {
+                (vk::Filter::NEAREST, vk::SamplerMipmapMode::NEAREST)
+            }
+            Some(MinFilter::NearestMipmapLinear) => {
+                (vk::Filter::NEAREST, vk::SamplerMipmapMode::LINEAR)
+            }
+            Some(MinFilter::Linear) | Some(MinFilter::LinearMipmapNearest) => {
+                (vk::Filter::LINEAR, vk::SamplerMipmapMode::NEAREST)
+            }
+            Some(MinFilter::LinearMipmapLinear) | None => {
+                (vk::Filter::LINEAR, vk::SamplerMipmapMode::LINEAR)
+            }
+        };
+
+        Self {
+            mag_filter,
+            min_filter,
+            mipmap_mode,
+            address_mode_u: address_mode(sampler.wrap_s()),
+            address_mode_v: address_mode(sampler.wrap_t()),
+        }
+    }
+
+    pub fn create_info(&self) -> vk::SamplerCreateInfo<'static> {
+        vk::SamplerCreateInfo::default()
+            .mag_filter(self.mag_filter)
+            .min_filter(self.min_filter)
+            .mipmap_mode(self.mipmap_mode)
+            .address_mode_u(self.address_mode_u)
+            .address_mode_v(self.address_mode_v)
+            .address_mode_w(vk::SamplerAddressMode::REPEAT)
+    }
+}
+
+fn address_mode(wrapping: gltf::texture::WrappingMode) -> vk::SamplerAddressMode {
+    match wrapping {
+        gltf::texture::WrappingMode::ClampToEdge => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        gltf::texture::WrappingMode::MirroredRepeat => vk::SamplerAddressMode::MIRRORED_REPEAT,
+        gltf::texture::WrappingMode::Repeat => vk::SamplerAddressMode::REPEAT,
+    }
+}
+
+/// Decoded image, converted to RGBA8 so it can be uploaded as a color texture
+pub(crate) struct GltfImage {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub rgba8: Vec<u8>,
+}
+
+/// Base color texture of a material
+pub(crate) struct GltfMaterialTexture {
+    /// Index into `GltfImport::images`
+    pub image: usize,
+    pub sampler: SamplerDesc,
+}
+
+/// Everything in a glTF file, before it is uploaded
+pub(crate) struct GltfImport {
+    pub scene: ModelScene,
+    /// Primitives of every mesh in `scene.meshes`, always indexed
+    pub meshes: Vec<Vec<Mesh>>,
+    pub images: Vec<GltfImage>,
+    /// Base color texture of every material in `scene.materials`
+    pub material_textures: Vec<Option<GltfMaterialTexture>>,
+}
+
+impl GltfImport {
+    /// Load a `.gltf` file with its external buffers and images, or a self-contained `.glb` file.
+    /// The scene is named after the file name.
+    pub fn load(path: &Path) -> Result<Self> {
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| eyre!("Invalid glTF file name: {:?}", path))?;
+        let (document, buffers, images) = gltf::import(path)
+            .map_err(|e| eyre!("Failed to import glTF file {:?}: {}", path, e))?;
+        Self::from_document(name, &document, &buffers, &images)
+    }
+
+    /// Load a `.glb` file or a `.gltf` file whose buffers and images are embedded as data URIs
+    #[cfg(test)]
+    pub fn from_slice(name: &str, bytes: &[u8]) -> Result<Self> {
+        let (document, buffers, images) = gltf::import_slice(bytes)
+            .map_err(|e| eyre!("Failed to import glTF data {}: {}", name, e))?;
+        Self::from_document(name, &document, &buffers, &images)
+    }
+
+    fn from_document(
+        name: &str,
+        document: &gltf::Document,
+        buffers: &[gltf::buffer::Data],
+        images: &[gltf::image::Data],
+    ) -> Result<Self> {
+        let mut scene_meshes = Vec::new();
+        let mut meshes = Vec::new();
+        for mesh in document.meshes() {
+            let mut primitive_materials = Vec::new();
+            let mut primitives = Vec::new();
+            let mut skinned = true;
+            for primitive in mesh.primitives() {
+                primitives.push(read_primitive(&primitive, buffers).map_err(|e| {
+                    eyre!(
+                        "Mesh {} primitive {} of {}: {}",
+                        mesh.name().unwrap_or("<unnamed>"),
+                        primitive.index(),
+                        name,
+                        e
+                    )
+                })?);
+                primitive_materials.push(primitive.material().index());
+                skinned &= primitive.get(&gltf::Semantic::Joints(0)).is_some()
+                    && primitive.get(&gltf::Semantic::Weights(0)).is_some();
+            }
+            let positions = primitives.iter().flat_map(|primitive: &Mesh| {
+                primitive.vertices.iter().map(|vertex| vertex.position)
+            });
+            scene_meshes.push(SceneMesh {
+                name: mesh.name().map(str::to_owned),
+                primitive_materials,
+                skinned,
+                aabb: Aabb::from_points(positions.clone()),
+                bounding_sphere: BoundingSphere::from_points(positions),
+            });
+            meshes.push(primitives);
+        }
+
+        let mut materials = Vec::new();
+        let mut material_textures = Vec::new();
+        for material in document.materials() {
+            let pbr = material.pbr_metallic_roughness();
+            let texture = pbr.base_color_texture().map(|info| {
+                let texture = info.texture();
+                GltfMaterialTexture {
+                    image: texture.source().index(),
+                    sampler: SamplerDesc::from_gltf(&texture.sampler()),
+                }
+            });
+
+            materials.push(MaterialParams {
+                name: material.name().map(str::to_owned),
+                base_color_factor: Vec4::from_array(pbr.base_color_factor()),
+                has_base_color_texture: texture.is_some(),
+                metallic_factor: pbr.metallic_factor(),
+                roughness_factor: pbr.roughness_factor(),
+                alpha_mode: match material.alpha_mode() {
+                    gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+                    gltf::material::AlphaMode::Mask => AlphaMode::Mask,
+                    gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+                },
+                alpha_cutoff: material.alpha_cutoff().unwrap_or(0.5),
+                double_sided: material.double_sided(),
+            });
+            material_textures.push(texture);
+        }
+
+        let nodes = document
+            .nodes()
+            .map(|node| {
+                let (translation, rotation, scale) = node.transform().decomposed();
+                SceneNode {
+                    name: node.name().map(str::to_owned),
+                    transform: NodeTransform {
+                        translation: Vec3::from_array(translation),
+                        rotation: Quat::from_array(rotation),
+                        scale: Vec3::from_array(scale),
+                    },
+                    mesh: node.mesh().map(|mesh| mesh.index()),
+                    skin: node.skin().map(|skin| skin.index()),
+                    children: node.children().map(|child| child.index()).collect(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Files without scenes are still expected to have a hierarchy of nodes
+        let roots = match document
+            .default_scene()
+            .or_else(|| document.scenes().next())
+        {
+            Some(scene) => scene.nodes().map(|node| node.index()).collect(),
+            None => (0..nodes.len())
+                .filter(|&index| !nodes.iter().any(|node| node.children.contains(&index)))
+                .collect(),
+        };
+
+        let skins = document
+            .skins()
+            .map(|skin| {
+                let reader =
+                    skin.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+                SceneSkin {
+                    name: skin.name().map(str::to_owned),
+                    joints: skin.joints().map(|joint| joint.index()).collect(),
+                    // Identity matrices when there are none
+                    inverse_bind_matrices: match reader.read_inverse_bind_matrices() {
+                        Some(matrices) => matrices.map(|m| Mat4::from_cols_array_2d(&m)).collect(),
+                        None => vec![Mat4::IDENTITY; skin.joints().count()],
+                    },
+                }
+            })
+            .collect();
+
+        let animations = document
+            .animations()
+            .map(|animation| read_animation(&animation, buffers))
+            .collect::<Result<Vec<_>>>()
+            .map_err(|e| eyre!("Animation of {}: {}", name, e))?;
+
+        let images = document
+            .images()
+            .zip(images)
+            .map(|(image, data)| {
+                Ok(GltfImage {
+                    name: format!(
+                        "{} {}",
+                        name,
+                        image
+                            .name()
+                            .map(str::to_owned)
+                            .unwrap_or_else(|| format!("image {}", image.index()))
+                    ),
+                    width: data.width,
+                    height: data.height,
+                    rgba8: rgba8_pixels(data)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            scene: ModelScene {
+                name: name.to_owned(),
+                nodes,
+                roots,
+                meshes: scene_meshes,
+                materials,
+                skins,
+                animations,
+            },
+            meshes,
+            images,
+            material_textures,
+        })
+    }
+}
+
+fn read_primitive(primitive: &gltf::Primitive, buffers: &[gltf::buffer::Data]) -> Result<Mesh> {
+    if primitive.mode() != gltf::mesh::Mode::Triangles {
+        return Err(eyre!(
+            "Only triangle lists are supported, not {:?}",
+            primitive.mode()
+        ));
+    }
+
+    let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+    let positions = reader
+        .read_positions()
+        .ok_or_else(|| eyre!("Primitive has no positions"))?
+        .map(Vec3::from_array)
+        .collect::<Vec<_>>();
+    let normals = reader
+        .read_normals()
+        .map(|normals| normals.map(Vec3::from_array).collect::<Vec<_>>());
+    let colors = reader.read_colors(0).map(|colors| {
+        colors
+            .into_rgb_f32()
+            .map(Vec3::from_array)
+            .collect::<Vec<_>>()
+    });
+    let texcoords = reader.read_tex_coords(0).map(|texcoords| {
+        texcoords
+            .into_f32()
+            .map(Vec2::from_array)
+            .collect::<Vec<_>>()
+    });
+    let tangents = reader
+        .read_tangents()
+        .map(|tangents| tangents.map(Vec4::from_array).collect::<Vec<_>>());
+    let texcoords1 = reader.read_tex_coords(1).map(|texcoords| {
+        texcoords
+            .into_f32()
+            .map(Vec2::from_array)
+            .collect::<Vec<_>>()
+    });
+    let joints = reader.read_joints(0).map(|joints| {
+        joints
+            .into_u16()
+            .map(|joints| UVec4::from_array(joints.map(u32::from)))
+            .collect::<Vec<_>>()
+    });
+    let weights = reader
+        .read_weights(0)
+        .map(|weights| weights.into_f32().map(Vec4::from_array).collect::<Vec<_>>());
+
+    let vertices = positions
+        .iter()
+        .enumerate()
+        .map(|(i, &position)| Vertex {
+            position,
+            normal: normals
+                .as_ref()
+                .and_then(|normals| normals.get(i).copied())
+                .unwrap_or(Vec3::Z),
+            color: colors
+                .as_ref()
+                .and_then(|colors| colors.get(i).copied())
+                .unwrap_or(Vec3::ONE),
+            texcoord: texcoords
+                .as_ref()
+                .and_then(|texcoords| texcoords.get(i).copied())
+                .unwrap_or(Vec2::ZERO),
+            tangent: tangents
+                .as_ref()
+                .and_then(|tangents| tangents.get(i).copied())
+                .unwrap_or(Vec4::ZERO),
+            texcoord1: texcoords1
+                .as_ref()
+                .and_then(|texcoords| texcoords.get(i).copied())
+                .unwrap_or(Vec2::ZERO),
+            joints: joints
+                .as_ref()
+                .and_then(|joints| joints.get(i).copied())
+                .unwrap_or(UVec4::ZERO),
+            weights: weights
+                .as_ref()
+                .and_then(|weights| weights.get(i).copied())
+                .unwrap_or(Vec4::ZERO),
+        })
+        .collect::<Vec<_>>();
+
+    // Models need either all or none of their meshes indexed, so every primitive gets indices
+    let indices = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+        None => (0..vertices.len() as u32).collect(),
+    };
+    if let Some(&index) = indices
+        .iter()
+        .find(|&&index| index as usize >= vertices.len())
+    {
+        return Err(eyre!(
+            "Index {} is out of bounds for {} vertices",
+            index,
+            vertices.len()
+        ));
+    }
+
+    // glTF asks for flat normals and MikkTSpace tangents when a primitive has none
+    let mut mesh = Mesh::new(vertices, Some(indices));
+    if normals.is_none() {
+        mesh.compute_flat_normals();
+    }
+    if tangents.is_none() && texcoords.is_some() {
+        if let Err(e) = mesh.compute_tangents() {
+            log::warn!("{}, leaving them zeroed", e);
+        }
+    }
+    Ok(mesh)
+}
+
+/// Morph target weights are not supported, so their channels are left out
+fn read_animation(
+    animation: &gltf::Animation,
+    buffers: &[gltf::buffer::Data],
+) -> Result<AnimationClip> {
+    use gltf::animation::util::ReadOutputs;
+
+    let mut channels = Vec::new();
+    for channel in animation.channels() {
+        let reader = channel.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+        let times = reader
+            .read_inputs()
+            .ok_or_else(|| eyre!("Channel {} has no keyframe times", channel.index()))?
+            .collect::<Vec<_>>();
+        let values = match reader.read_outputs() {
+            Some(ReadOutputs::Translations(values)) => {
+                ChannelValues::Translation(values.map(Vec3::from_array).collect())
+            }
+            Some(ReadOutputs::Rotations(values)) => {
+                ChannelValues::Rotation(values.into_f32().map(Quat::from_array).collect())
+            }
+            Some(ReadOutputs::Scales(values)) => {
+                ChannelValues::Scale(values.map(Vec3::from_array).collect())
+            }
+            Some(ReadOutputs::MorphTargetWeights(_)) => continue,
+            None => return Err(eyre!("Channel {} has no keyframe values", channel.index())),
+        };
+        let interpolation = match channel.sampler().interpolation() {
+            gltf::animation::Interpolation::Step => Interpolation::Step,
+            gltf::animation::Interpolation::Linear => Interpolation::Linear,
+            gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+        };
+        channels.push(AnimationChannel {
+            node: channel.target().node().index(),
+            interpolation,
+            times,
+            values,
+        });
+    }
+
+    let duration = channels
+        .iter()
+        .filter_map(|channel| channel.times.last().copied())
+        .fold(0.0, f32::max);
+    Ok(AnimationClip {
+        name: animation.name().map(str::to_owned),
+        channels,
+        duration,
+    })
+}
+
+/// glTF images keep the channels and bit depth they were decoded with
+fn rgba8_pixels(data: &gltf::image::Data) -> Result<Vec<u8>> {
+    use gltf::image::Format;
+    use image::{DynamicImage, ImageBuffer};
+
+    let (width, height) = (data.width, data.height);
+    let bytes = data.pixels.clone();
+    let u16s = || {
+        data.pixels
+            .chunks_exact(2)
+            .map(|pair| u16::from_ne_bytes([pair[0], pair[1]]))
+            .collect::<Vec<_>>()
+    };
+    let f32s = || {
+        data.pixels
+            .chunks_exact(4)
+            .map(|quad| f32::from_ne_bytes([quad[0], quad[1], quad[2], quad[3]]))
+            .collect::<Vec<_>>()
+    };
+
+    let image = match data.format {
+        Format::R8 => ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageLuma8),
+        Format::R8G8 => ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageLumaA8),
+        Format::R8G8B8 => ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageRgb8),
+        Format::R8G8B8A8 => {
+            ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageRgba8)
+        }
+        Format::R16 => ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageLuma16),
+        Format::R16G16 => {
+            ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageLumaA16)
+        }
+        Format::R16G16B16 => {
+            ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageRgb16)
+        }
+        Format::R16G16B16A16 => {
+            ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageRgba16)
+        }
+        Format::R32G32B32FLOAT => {
+            ImageBuffer::from_raw(width, height, f32s()).map(DynamicImage::ImageRgb32F)
+        }
+        Format::R32G32B32A32FLOAT => {
+            ImageBuffer::from_raw(width, height, f32s()).map(DynamicImage::ImageRgba32F)
+        }
+    }
+    .ok_or_else(|| {
+        eyre!(
+            "Image data does not match its {}x{} {:?} size",
+            width,
+            height,
+            data.format
+        )
+    })?;
+
+    Ok(image.to_rgba8().into_raw())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    /// Standard base64 with padding, for embedding buffers as data URIs
+    fn base64(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut encoded = String::new();
+        for chunk in bytes.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, &byte)| n | (byte as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+                } else {
+                    encoded.push('=');
+                }
+            }
+        }
+        encoded
+    }
+
+    /// A triangle mesh used by a child node, once with indices and once without
+    fn two_node_gltf() -> String {
+        let positions: [[f32; 3]; 3] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+        let indices: [u16; 3] = [0, 2, 1];
+        let mut buffer = bytemuck::cast_slice::<_, u8>(&positions).to_vec();
+        buffer.extend_from_slice(bytemuck::cast_slice(&indices));
+        buffer.extend_from_slice(&[0, 0]); // Padding to a multiple of 4 bytes
+
+        format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "scene": 0,
+                "scenes": [{{ "nodes": [0] }}],
+                "nodes": [
+                    {{ "name": "Root", "translation": [0, 0, 5], "children": [1] }},
+                    {{ "name": "Child", "scale": [2, 2, 2], "mesh": 0 }}
+                ],
+                "meshes": [{{
+                    "name": "Triangle",
+                    "primitives": [
+                        {{ "attributes": {{ "POSITION": 0 }}, "indices": 1, "material": 0 }},
+                        {{ "attributes": {{ "POSITION": 0 }} }}
+                    ]
+                }}],
+                "materials": [{{
+                    "name": "Red",
+                    "pbrMetallicRoughness": {{ "baseColorFactor": [1, 0, 0, 1], "metallicFactor": 0 }},
+                    "alphaMode": "MASK",
+                    "doubleSided": true
+                }}],
+                "accessors": [
+                    {{
+                        "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                        "min": [0, 0, 0], "max": [1, 1, 0]
+                    }},
+                    {{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }}
+                ],
+                "bufferViews": [
+                    {{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }},
+                    {{ "buffer": 0, "byteOffset": 36, "byteLength": 6 }}
+                ],
+                "buffers": [{{
+                    "byteLength": {},
+                    "uri": "data:application/octet-stream;base64,{}"
+                }}]
+            }}"#,
+            buffer.len(),
+            base64(&buffer)
+        )
+    }
+
+    #[test]
+    fn reads_meshes_materials_and_hierarchy() {
+        let import = GltfImport::from_slice("Test", two_node_gltf().as_bytes()).unwrap();
+        let scene = &import.scene;
+
+        assert_eq!(scene.name, "Test");
+        assert_eq!(scene.roots, [0]);
+        assert_eq!(scene.nodes[0].children, [1]);
+        assert_eq!(scene.nodes[1].mesh, Some(0));
+        assert_eq!(scene.meshes[0].primitive_materials, [Some(0), None]);
+
+        let material = &scene.materials[0];
+        assert_eq!(material.name.as_deref(), Some("Red"));
+        assert_eq!(material.base_color_factor, Vec4::new(1.0, 0.0, 0.0, 1.0));
+        assert_eq!(material.metallic_factor, 0.0);
+        assert_eq!(material.roughness_factor, 1.0);
+        assert_eq!(material.alpha_mode, AlphaMode::Mask);
+        assert!(material.double_sided && !material.has_base_color_texture);
+
+        let primitives = &import.meshes[0];
+        // Without normals every triangle gets its own vertices, in the order of the indices
+        assert_eq!(primitives[0].indices.as_deref(), Some(&[0, 1, 2][..]));
+        assert_eq!(primitives[0].vertices[1].position, Vec3::Y);
+        assert_eq!(primitives[0].vertices[2].position, Vec3::X);
+        assert_eq!(primitives[0].vertices[2].color, Vec3::ONE);
+        assert!(
+            primitives[0]
+                .vertices
+                .iter()
+                .all(|v| v.normal == Vec3::NEG_Z)
+        );
+        // The primitive without indices gets sequential ones
+        assert_eq!(primitives[1].indices.as_deref(), Some(&[0, 1, 2][..]));
+        assert_eq!(primitives[1].vertices[1].position, Vec3::X);
+        assert!(primitives[1].vertices.iter().all(|v| v.normal == Vec3::Z));
+
+        let mesh = &scene.meshes[0];
+        assert_eq!(mesh.aabb.min, Vec3::ZERO);
+        assert_eq!(mesh.aabb.max, Vec3::new(1.0, 1.0, 0.0));
+        assert_eq!(mesh.bounding_sphere.center, Vec3::new(0.5, 0.5, 0.0));
+    }
+
+    #[test]
+    fn world_transforms_apply_parents_first() {
+        let import = GltfImport::from_slice("Test", two_node_gltf().as_bytes()).unwrap();
+        let world = import.scene.world_transforms();
+
+        let child_origin = world[1].transform_point3(Vec3::ZERO);
+        let child_x = world[1].transform_point3(Vec3::X);
+        assert_eq!(child_origin, Vec3::new(0.0, 0.0, 5.0));
+        assert_eq!(child_x, Vec3::new(2.0, 0.0, 5.0));
+    }
+
+    /// A triangle skinned to a hip joint and a spine joint above it, with a clip that
+    /// steps the hip and bends the spine
+    fn skinned_gltf() -> String {
+        let positions: [[f32; 3]; 3] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 2.0, 0.0]];
+        let joints: [[u16; 4]; 3] = [[0, 0, 0, 0], [0, 0, 0, 0], [1, 0, 0, 0]];
+        let weights: [[f32; 4]; 3] = [
+            [1.0, 0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0, 0.0],
+        ];
+        let inverse_bind_matrices = [Mat4::IDENTITY, Mat4::from_translation(Vec3::NEG_Y)];
+        let times: [f32; 2] = [0.0, 1.0];
+        let rotations = [Quat::IDENTITY, Quat::from_rotation_z(FRAC_PI_2)];
+        let translations: [[f32; 3]; 2] = [[0.0, 0.0, 0.0], [5.0, 0.0, 0.0]];
+
+        let mut buffer = bytemuck::cast_slice::<_, u8>(&positions).to_vec();
+        buffer.extend_from_slice(bytemuck::cast_slice(&joints));
+        buffer.extend_from_slice(bytemuck::cast_slice(&weights));
+        buffer.extend_from_slice(bytemuck::cast_slice(&inverse_bind_matrices));
+        buffer.extend_from_slice(bytemuck::cast_slice(&times));
+        buffer.extend_from_slice(bytemuck::cast_slice(&rotations));
+        buffer.extend_from_slice(bytemuck::cast_slice(&translations));
+
+        format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "scene": 0,
+                "scenes": [{{ "nodes": [0, 1] }}],
+                "nodes": [
+                    {{ "name": "Body", "mesh": 0, "skin": 0 }},
+                    {{ "name": "Hip", "children": [2] }},
+                    {{ "name": "Spine", "translation": [0, 1, 0] }}
+                ],
+                "meshes": [{{
+                    "primitives": [{{
+                        "attributes": {{ "POSITION": 0, "JOINTS_0": 1, "WEIGHTS_0": 2 }}
+                    }}]
+                }}],
+                "skins": [{{ "joints": [1, 2], "inverseBindMatrices": 3 }}],
+                "animations": [{{
+                    "name": "Bend",
+                    "channels": [
+                        {{ "sampler": 0, "target": {{ "node": 2, "path": "rotation" }} }},
+                        {{ "sampler": 1, "target": {{ "node": 1, "path": "translation" }} }}
+                    ],
+                    "samplers": [
+                        {{ "input": 4, "output": 5 }},
+                        {{ "input": 4, "output": 6, "interpolation": "STEP" }}
+                    ]
+                }}],
+                "accessors": [
+                    {{
+                        "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                        "min": [0, 0, 0], "max": [1, 2, 0]
+                    }},
+                    {{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "VEC4" }},
+                    {{ "bufferView": 2, "componentType": 5126, "count": 3, "type": "VEC4" }},
+                    {{ "bufferView": 3, "componentType": 5126, "count": 2, "type": "MAT4" }},
+                    {{
+                        "bufferView": 4, "componentType": 5126, "count": 2, "type": "SCALAR",
+                        "min": [0], "max": [1]
+                    }},
+                    {{ "bufferView": 5, "componentType": 5126, "count": 2, "type": "VEC4" }},
+                    {{ "bufferView": 6, "componentType": 5126, "count": 2, "type": "VEC3" }}
+                ],
+                "bufferViews": [
+                    {{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }},
+                    {{ "buffer": 0, "byteOffset": 36, "byteLength": 24 }},
+                    {{ "buffer": 0, "byteOffset": 60, "byteLength": 48 }},
+                    {{ "buffer": 0, "byteOffset": 108, "byteLength": 128 }},
+                    {{ "buffer": 0, "byteOffset": 236, "byteLength": 8 }},
+                    {{ "buffer": 0, "byteOffset": 244, "byteLength": 32 }},
+                    {{ "buffer": 0, "byteOffset": 276, "byteLength": 24 }}
+                ],
+                "buffers": [{{
+                    "byteLength": {},
+                    "uri": "data:application/octet-stream;base64,{}"
+                }}]
+            }}"#,
+            buffer.len(),
+            base64(&buffer)
+        )
+    }
+
+    #[test]
+    fn reads_skins_and_animations() {
+        let import = GltfImport::from_slice("Test", skinned_gltf().as_bytes()).unwrap();
+        let scene = &import.scene;
+
+        assert_eq!(scene.nodes[0].skin, Some(0));
+        assert!(scene.meshes[0].skinned);
+        let vertices = &import.meshes[0][0].vertices;
+        assert_eq!(vertices[2].joints, UVec4::new(1, 0, 0, 0));
+        assert_eq!(vertices[2].weights, Vec4::X);
+
+        let skin = &scene.skins[0];
+        assert_eq!(skin.joints, [1, 2]);
+        assert_eq!(
+            skin.inverse_bind_matrices[1],
+            Mat4::from_translation(Vec3::NEG_Y)
+        );
+
+        let clip = &scene.animations[0];
+        assert_eq!(clip.name.as_deref(), Some("Bend"));
+        assert_eq!(clip.duration, 1.0);
+        assert_eq!(clip.channels[1].interpolation, Interpolation::Step);
+
+        // The bind pose leaves the vertices where they are
+        let rest = skin.joint_palette(&scene.world_transforms());
+        assert!(
+            rest.iter()
+                .all(|joint| joint.abs_diff_eq(Mat4::IDENTITY, 1e-6))
+        );
+
+        // Bending the spine swings the top vertex around it, the hip holds until its next key
+        let mut pose = scene
+            .nodes
+            .iter()
+            .map(|node| node.transform)
+            .collect::<Vec<_>>();
+        clip.sample(1.0, &mut pose);
+        let bent = skin.joint_palette(&scene.posed_world_transforms(&pose));
+        let top = bent[1].transform_point3(vertices[2].position);
+        assert!(top.abs_diff_eq(Vec3::new(4.0, 1.0, 0.0), 1e-6));
+
+        let mut pose = scene
+            .nodes
+            .iter()
+            .map(|node| node.transform)
+            .collect::<Vec<_>>();
+        clip.sample(0.5, &mut pose);
+        assert_eq!(pose[1].translation, Vec3::ZERO);
+    }
+
+    #[test]
+    fn meshes_without_joints_are_not_skinned() {
+        let import = GltfImport::from_slice("Test", two_node_gltf().as_bytes()).unwrap();
+        assert!(!import.scene.meshes[0].skinned);
+        assert_eq!(import.scene.nodes[1].skin, None);
+        assert!(import.scene.skins.is_empty() && import.scene.animations.is_empty());
+    }
+
+    #[test]
+    fn converts_images_to_rgba8() {
+        let data = gltf::image::Data {
+            pixels: vec![10, 20, 30, 40, 50, 60],
+            format: gltf::image::Format::R8G8B8,
+            width: 2,
+            height: 1,
+        };
+        assert_eq!(
+            rgba8_pixels(&data).unwrap(),
+            [10, 20, 30, 255, 40, 50, 60, 255]
+        );
+
+        let data = gltf::image::Data {
+            pixels: [u16::MAX, 0]
+                .iter()
+                .flat_map(|value| value.to_ne_bytes())
+                .collect(),
+            format: gltf::image::Format::R16G16,
+            width: 1,
+            height: 1,
+        };
+        assert_eq!(rgba8_pixels(&data).unwrap(), [255, 255, 255, 0]);
+
+        let truncated = gltf::image::Data {
+            pixels: vec![0; 3],
+            format: gltf::image::Format::R8G8B8A8,
+            width: 1,
+            height: 1,
+        };
+        assert!(rgba8_pixels(&truncated).is_err());
+    }
+}
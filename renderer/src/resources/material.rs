@@ -1,21 +1,34 @@
+use crate::context::debug_utils::DebugUtils;
+use crate::context::desc_set_layout_builder::DescriptorBinding;
 use crate::resources::{
+    render_target::RenderTargetFormats,
     resource_type::RenderResourceType,
     shader::{ComputeShader, GraphicsShader},
-    vertex::VertexInputDescription,
+    shader_object::{DynamicGraphicsState, ShaderObjectBackend, ShaderObjectProgram},
+    vertex::VertexLayout,
 };
+use crate::utils::GuardResultExt;
 use ash::vk;
-use color_eyre::eyre::{OptionExt, eyre};
+use color_eyre::eyre::{OptionExt, WrapErr, eyre};
 use color_eyre::{Result, Section};
 use gpu_descriptor::{DescriptorAllocator, DescriptorSetLayoutCreateFlags, DescriptorTotalCount};
 use gpu_descriptor_ash::AshDescriptorDevice;
-use std::ffi::CString;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// What gets bound when a material is used: either a monolithic pipeline,
+/// or shader objects with all of their state set dynamically
+#[derive(Clone)]
+enum MaterialPipeline {
+    Pipeline(vk::Pipeline),
+    ShaderObjects(Arc<ShaderObjectProgram>),
+}
 
 /// You can think of a Material as a shader instance that you can bind resources and data to.
 /// You only need to create a Material once, and then you can use it to render multiple objects.
 /// You only need to switch the Material when you want to change the shader or pipeline.
 pub(crate) struct Material {
-    pipeline: vk::Pipeline,
+    /// Shared with the factory, so that rebuilding the factory updates its materials too
+    pipeline: Arc<RwLock<MaterialPipeline>>,
     pipeline_layout: vk::PipelineLayout,
     pipeline_bind_point: vk::PipelineBindPoint,
     descriptor_set: gpu_descriptor::DescriptorSet<vk::DescriptorSet>,
@@ -35,13 +48,79 @@ impl Material {
         }
     }
 
-    pub fn bind_pipeline(&self, command_buffer: vk::CommandBuffer) {
+    pub fn bind_pipeline(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        bind_pipeline(
+            &self.pipeline,
+            self.pipeline_bind_point,
+            &self.device,
+            command_buffer,
+        )
+    }
+
+    /// `range` bytes of `buffer` from `offset`, for a uniform or storage buffer binding
+    pub fn write_buffer(
+        &self,
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+        buffer: vk::Buffer,
+        offset: vk::DeviceSize,
+        range: vk::DeviceSize,
+    ) {
+        let buffer_infos = [vk::DescriptorBufferInfo::default()
+            .buffer(buffer)
+            .offset(offset)
+            .range(range)];
+        let write = vk::WriteDescriptorSet::default()
+            .dst_set(*self.descriptor_set.raw())
+            .dst_binding(binding)
+            .descriptor_type(descriptor_type)
+            .buffer_info(&buffer_infos);
         unsafe {
-            self.device
-                .cmd_bind_pipeline(command_buffer, self.pipeline_bind_point, self.pipeline);
+            self.device.update_descriptor_sets(&[write], &[]);
         }
     }
 
+    pub fn write_sampled_image(
+        &self,
+        binding: u32,
+        array_element: u32,
+        view: vk::ImageView,
+        layout: vk::ImageLayout,
+    ) {
+        let image_info = vk::DescriptorImageInfo::default()
+            .image_view(view)
+            .image_layout(layout);
+        self.write_image_descriptor(
+            binding,
+            array_element,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            image_info,
+        );
+    }
+
+    /// Storage images are always accessed in the `GENERAL` layout
+    pub fn write_storage_image(&self, binding: u32, array_element: u32, view: vk::ImageView) {
+        let image_info = vk::DescriptorImageInfo::default()
+            .image_view(view)
+            .image_layout(vk::ImageLayout::GENERAL);
+        self.write_image_descriptor(
+            binding,
+            array_element,
+            vk::DescriptorType::STORAGE_IMAGE,
+            image_info,
+        );
+    }
+
+    pub fn write_sampler(&self, binding: u32, array_element: u32, sampler: vk::Sampler) {
+        let image_info = vk::DescriptorImageInfo::default().sampler(sampler);
+        self.write_image_descriptor(
+            binding,
+            array_element,
+            vk::DescriptorType::SAMPLER,
+            image_info,
+        );
+    }
+
     pub fn bind_descriptor_sets(&self, command_buffer: vk::CommandBuffer) {
         let descriptor_sets = [*self.descriptor_set.raw()];
         unsafe {
@@ -55,13 +134,62 @@ impl Material {
             );
         }
     }
+
+    fn write_image_descriptor(
+        &self,
+        binding: u32,
+        array_element: u32,
+        descriptor_type: vk::DescriptorType,
+        image_info: vk::DescriptorImageInfo,
+    ) {
+        let image_infos = [image_info];
+        let write = vk::WriteDescriptorSet::default()
+            .dst_set(*self.descriptor_set.raw())
+            .dst_binding(binding)
+            .dst_array_element(array_element)
+            .descriptor_type(descriptor_type)
+            .image_info(&image_infos);
+        unsafe {
+            self.device.update_descriptor_sets(&[write], &[]);
+        }
+    }
+}
+
+fn bind_pipeline(
+    pipeline: &RwLock<MaterialPipeline>,
+    pipeline_bind_point: vk::PipelineBindPoint,
+    device: &ash::Device,
+    command_buffer: vk::CommandBuffer,
+) -> Result<()> {
+    match &*pipeline.read().eyre()? {
+        MaterialPipeline::Pipeline(pipeline) => unsafe {
+            device.cmd_bind_pipeline(command_buffer, pipeline_bind_point, *pipeline);
+        },
+        MaterialPipeline::ShaderObjects(program) => program.bind(command_buffer),
+    }
+    Ok(())
+}
+
+/// How to build a factory's pipeline again once its shader code changes
+#[cfg(feature = "hot-reload")]
+enum MaterialRecipe {
+    Graphics {
+        shader: GraphicsShader,
+        builder: Box<GraphicsMaterialFactoryBuilder>,
+    },
+    Compute {
+        shader: ComputeShader,
+        builder: Box<ComputeMaterialFactoryBuilder>,
+    },
 }
 
 pub(crate) struct MaterialFactory {
-    pipeline: vk::Pipeline,
+    pipeline: Arc<RwLock<MaterialPipeline>>,
     pipeline_layout: vk::PipelineLayout,
     pipeline_bind_point: vk::PipelineBindPoint,
     descriptor_set_layout: vk::DescriptorSetLayout,
+    #[cfg(feature = "hot-reload")]
+    recipe: MaterialRecipe,
 
     device: Arc<ash::Device>,
     descriptor_allocator: Arc<Mutex<DescriptorAllocator<vk::DescriptorPool, vk::DescriptorSet>>>,
@@ -71,7 +199,7 @@ impl MaterialFactory {
     pub fn create_material(&'_ mut self) -> Result<Material> {
         let descriptor_set = self.allocate_descriptor_set()?;
         Ok(Material {
-            pipeline: self.pipeline,
+            pipeline: self.pipeline.clone(),
             pipeline_layout: self.pipeline_layout,
             pipeline_bind_point: self.pipeline_bind_point,
             descriptor_set,
@@ -79,6 +207,51 @@ impl MaterialFactory {
         })
     }
 
+    /// Bind the factory's pipeline without a material of its own, for drawing with the
+    /// descriptor sets of another material sharing its pipeline layout
+    pub fn bind_pipeline(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        bind_pipeline(
+            &self.pipeline,
+            self.pipeline_bind_point,
+            &self.device,
+            command_buffer,
+        )
+    }
+
+    #[cfg(feature = "hot-reload")]
+    pub fn shader_name(&self) -> &str {
+        match &self.recipe {
+            MaterialRecipe::Graphics { shader, .. } => &shader.name,
+            MaterialRecipe::Compute { shader, .. } => &shader.name,
+        }
+    }
+
+    /// Rebuild the pipeline from the current code of the shader, for this factory and every
+    /// material created from it. The previous pipeline is destroyed, so the GPU must be idle.
+    #[cfg(feature = "hot-reload")]
+    pub fn reload(&mut self) -> Result<()> {
+        let mut rebuilt = match &self.recipe {
+            MaterialRecipe::Graphics { shader, builder } => builder
+                .clone_without_shader()
+                .with_shader(shader.reload()?)
+                .build()?,
+            MaterialRecipe::Compute { shader, builder } => builder
+                .clone_without_shader()
+                .with_shader(shader.reload()?)
+                .build()?,
+        };
+        let new_pipeline = rebuilt.pipeline.read().eyre()?.clone();
+        // The old shader is dropped along with `rebuilt`
+        std::mem::swap(&mut self.recipe, &mut rebuilt.recipe);
+
+        let old_pipeline = std::mem::replace(&mut *self.pipeline.write().eyre()?, new_pipeline);
+        // Shader objects are destroyed along with the last reference to them
+        if let MaterialPipeline::Pipeline(pipeline) = old_pipeline {
+            unsafe { self.device.destroy_pipeline(pipeline, None) };
+        }
+        Ok(())
+    }
+
     fn allocate_descriptor_set(
         &mut self,
     ) -> Result<gpu_descriptor::DescriptorSet<vk::DescriptorSet>> {
@@ -122,36 +295,48 @@ impl MaterialFactory {
     }
 }
 
-pub(crate) struct GraphicsMaterialFactoryBuilder<'a> {
-    vertex_input_description: VertexInputDescription,
-    input_assembly: vk::PipelineInputAssemblyStateCreateInfo<'a>,
-    rasterization: vk::PipelineRasterizationStateCreateInfo<'a>,
+pub(crate) struct GraphicsMaterialFactoryBuilder {
+    vertex_layout: VertexLayout,
+    input_assembly: vk::PipelineInputAssemblyStateCreateInfo<'static>,
+    rasterization: vk::PipelineRasterizationStateCreateInfo<'static>,
     color_blend_attachment: vk::PipelineColorBlendAttachmentState,
-    multisample: vk::PipelineMultisampleStateCreateInfo<'a>,
-    depth_stencil: vk::PipelineDepthStencilStateCreateInfo<'a>,
+    multisample: vk::PipelineMultisampleStateCreateInfo<'static>,
+    depth_stencil: vk::PipelineDepthStencilStateCreateInfo<'static>,
+    /// Only used when the shader has tessellation stages
+    tessellation: vk::PipelineTessellationStateCreateInfo<'static>,
     color_attachment_format: vk::Format,
-    rendering_info: vk::PipelineRenderingCreateInfo<'a>,
+    rendering_info: vk::PipelineRenderingCreateInfo<'static>,
     shader: Option<GraphicsShader>,
     pipeline_layout: Option<vk::PipelineLayout>,
     descriptor_set_layout: Option<vk::DescriptorSetLayout>,
+    descriptor_set_layout_bindings: Vec<DescriptorBinding>,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+    shader_objects: Option<ShaderObjectBackend>,
+    mesh_shaders_supported: bool,
 
     device: Arc<ash::Device>,
     descriptor_allocator: Arc<Mutex<DescriptorAllocator<vk::DescriptorPool, vk::DescriptorSet>>>,
+    pipeline_cache: vk::PipelineCache,
+    debug_utils: DebugUtils,
 }
 
-impl<'a> GraphicsMaterialFactoryBuilder<'a> {
+impl GraphicsMaterialFactoryBuilder {
     pub fn new(
         device: Arc<ash::Device>,
         descriptor_allocator: Arc<
             Mutex<DescriptorAllocator<vk::DescriptorPool, vk::DescriptorSet>>,
         >,
+        pipeline_cache: vk::PipelineCache,
+        debug_utils: DebugUtils,
     ) -> Self {
-        let vertex_input_description = VertexInputDescription::default();
+        let vertex_layout = VertexLayout::default();
         let input_assembly = Self::default_input_assembly_info();
         let rasterization = Self::default_rasterization_info();
         let color_blend_attachment = Self::default_color_blend_state();
         let multisample = Self::default_multisample_info();
         let depth_stencil = Self::default_depth_stencil_info();
+        let tessellation =
+            vk::PipelineTessellationStateCreateInfo::default().patch_control_points(3);
         let color_attachment_format = vk::Format::UNDEFINED;
         let rendering_info = vk::PipelineRenderingCreateInfo::default();
         let shader = None;
@@ -159,20 +344,27 @@ impl<'a> GraphicsMaterialFactoryBuilder<'a> {
         let descriptor_set_layout = None;
 
         Self {
-            vertex_input_description,
+            vertex_layout,
             input_assembly,
             rasterization,
             color_blend_attachment,
             multisample,
             depth_stencil,
+            tessellation,
             color_attachment_format,
             rendering_info,
             shader,
             pipeline_layout,
             descriptor_set_layout,
+            descriptor_set_layout_bindings: Vec::new(),
+            push_constant_ranges: Vec::new(),
+            shader_objects: None,
+            mesh_shaders_supported: false,
 
             device,
             descriptor_allocator,
+            pipeline_cache,
+            debug_utils,
         }
     }
 
@@ -186,8 +378,26 @@ impl<'a> GraphicsMaterialFactoryBuilder<'a> {
         self
     }
 
-    pub fn with_descriptor_set_layout(mut self, layout: vk::DescriptorSetLayout) -> Self {
+    /// The bindings are what the shader is checked against, see `DescriptorSetLayoutBuilder::describe`
+    pub fn with_descriptor_set_layout(
+        mut self,
+        layout: vk::DescriptorSetLayout,
+        bindings: &[DescriptorBinding],
+    ) -> Self {
         let _ = self.descriptor_set_layout.replace(layout);
+        self.descriptor_set_layout_bindings = bindings.to_vec();
+        self
+    }
+
+    /// Shader objects are created against the layouts directly, so these must match the pipeline layout
+    pub fn with_push_constant_ranges(mut self, ranges: &[vk::PushConstantRange]) -> Self {
+        self.push_constant_ranges = ranges.to_vec();
+        self
+    }
+
+    /// Build linked shader objects instead of a pipeline
+    pub fn with_shader_objects(mut self, backend: ShaderObjectBackend) -> Self {
+        let _ = self.shader_objects.replace(backend);
         self
     }
 
@@ -272,6 +482,24 @@ impl<'a> GraphicsMaterialFactoryBuilder<'a> {
         self
     }
 
+    pub fn with_stencil_attachment_format(mut self, format: vk::Format) -> Self {
+        self.rendering_info.stencil_attachment_format = format;
+        self
+    }
+
+    pub fn with_sample_count(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.multisample.rasterization_samples = samples;
+        self
+    }
+
+    /// Set every attachment format and the sample count to match a render target
+    pub fn with_render_target_formats(self, formats: RenderTargetFormats) -> Self {
+        self.with_color_attachment_format(formats.color)
+            .with_depth_attachment_format(formats.depth)
+            .with_stencil_attachment_format(formats.stencil())
+            .with_sample_count(formats.samples)
+    }
+
     pub fn with_depth_test(mut self, enable: bool, compare: Option<vk::CompareOp>) -> Self {
         self.depth_stencil.depth_test_enable = if enable { vk::TRUE } else { vk::FALSE };
         self.depth_stencil.depth_write_enable = if enable { vk::TRUE } else { vk::FALSE };
@@ -289,29 +517,37 @@ impl<'a> GraphicsMaterialFactoryBuilder<'a> {
         self
     }
 
-    pub fn with_vertex_input(mut self, description: VertexInputDescription) -> Self {
-        self.vertex_input_description = description;
+    /// Keep depth testing but stop writing to the depth buffer, e.g. for skyboxes
+    pub fn with_depth_write(mut self, enable: bool) -> Self {
+        self.depth_stencil.depth_write_enable = if enable { vk::TRUE } else { vk::FALSE };
         self
     }
 
-    pub fn build(mut self) -> Result<MaterialFactory> {
-        let device = self.device;
+    /// The layout of the vertex buffers the material's models are packed with
+    pub fn with_vertex_input(mut self, layout: VertexLayout) -> Self {
+        self.vertex_layout = layout;
+        self
+    }
+
+    /// Whether shaders may have task and mesh stages, i.e. `VK_EXT_mesh_shader` is enabled
+    pub fn with_mesh_shaders_supported(mut self, supported: bool) -> Self {
+        self.mesh_shaders_supported = supported;
+        self
+    }
+
+    /// Vertices per patch for shaders with tessellation stages, which always draw patch lists
+    pub fn with_patch_control_points(mut self, count: u32) -> Self {
+        self.tessellation.patch_control_points = count;
+        self
+    }
 
+    pub fn build(mut self) -> Result<MaterialFactory> {
         let shader = self
             .shader
             .take()
             .ok_or_eyre("No shader provided for GraphicsMaterialBuilder")?;
-        let shader_main_fn_name = CString::new("main")?;
-        let shader_stages = vec![
-            vk::PipelineShaderStageCreateInfo::default()
-                .stage(vk::ShaderStageFlags::VERTEX)
-                .module(shader.vert_mod)
-                .name(&shader_main_fn_name),
-            vk::PipelineShaderStageCreateInfo::default()
-                .stage(vk::ShaderStageFlags::FRAGMENT)
-                .module(shader.frag_mod)
-                .name(&shader_main_fn_name),
-        ];
+        #[cfg(feature = "hot-reload")]
+        let recipe_builder = Box::new(self.clone_without_shader());
 
         let pipeline_layout = self
             .pipeline_layout
@@ -323,12 +559,132 @@ impl<'a> GraphicsMaterialFactoryBuilder<'a> {
             .take()
             .ok_or_eyre("No descriptor set layout provided for GraphicsMaterialBuilder")?;
 
-        let viewport_state = vk::PipelineViewportStateCreateInfo {
-            viewport_count: 1,
-            scissor_count: 1,
-            ..Default::default()
+        let mesh_stages = vk::ShaderStageFlags::TASK_EXT | vk::ShaderStageFlags::MESH_EXT;
+        if shader.stage_flags().intersects(mesh_stages) && !self.mesh_shaders_supported {
+            return Err(eyre!(
+                "Shader {} has mesh stages, but the device does not support VK_EXT_mesh_shader",
+                shader.display_name()
+            ));
+        }
+
+        self.vertex_layout.validate().wrap_err_with(|| {
+            format!("Invalid vertex layout for shader {}", shader.display_name())
+        })?;
+        if let Some(reflection) = &shader.reflection {
+            reflection.validate(
+                &shader.display_name(),
+                &[&self.descriptor_set_layout_bindings],
+                &self.push_constant_ranges,
+                Some(&self.vertex_layout.input_description()),
+            )?;
+        }
+
+        let pipeline = match self.shader_objects.take() {
+            Some(backend) => {
+                let program = self.build_shader_objects(&shader, descriptor_set_layout, backend)?;
+                MaterialPipeline::ShaderObjects(Arc::new(program))
+            }
+            None => MaterialPipeline::Pipeline(self.build_pipeline(&shader, pipeline_layout)?),
         };
 
+        Ok(MaterialFactory {
+            pipeline: Arc::new(RwLock::new(pipeline)),
+            pipeline_layout,
+            pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+            descriptor_set_layout,
+            #[cfg(feature = "hot-reload")]
+            recipe: MaterialRecipe::Graphics {
+                shader,
+                builder: recipe_builder,
+            },
+            device: self.device,
+            descriptor_allocator: self.descriptor_allocator,
+        })
+    }
+
+    #[cfg(feature = "hot-reload")]
+    fn clone_without_shader(&self) -> Self {
+        Self {
+            vertex_layout: self.vertex_layout.clone(),
+            input_assembly: self.input_assembly,
+            rasterization: self.rasterization,
+            color_blend_attachment: self.color_blend_attachment,
+            multisample: self.multisample,
+            depth_stencil: self.depth_stencil,
+            tessellation: self.tessellation,
+            color_attachment_format: self.color_attachment_format,
+            // Re-pointed at the clone's own format in `build_pipeline`
+            rendering_info: self.rendering_info,
+            shader: None,
+            pipeline_layout: self.pipeline_layout,
+            descriptor_set_layout: self.descriptor_set_layout,
+            descriptor_set_layout_bindings: self.descriptor_set_layout_bindings.clone(),
+            push_constant_ranges: self.push_constant_ranges.clone(),
+            shader_objects: self.shader_objects.clone(),
+            mesh_shaders_supported: self.mesh_shaders_supported,
+            device: self.device.clone(),
+            descriptor_allocator: self.descriptor_allocator.clone(),
+            pipeline_cache: self.pipeline_cache,
+            debug_utils: self.debug_utils.clone(),
+        }
+    }
+
+    fn build_shader_objects(
+        &self,
+        shader: &GraphicsShader,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+        backend: ShaderObjectBackend,
+    ) -> Result<ShaderObjectProgram> {
+        let (vertex_bindings, vertex_attributes) =
+            DynamicGraphicsState::vertex_input_from(&self.vertex_layout.input_description());
+        let state = DynamicGraphicsState {
+            vertex_bindings,
+            vertex_attributes,
+            topology: self.topology_for(shader),
+            patch_control_points: self.tessellation.patch_control_points,
+            primitive_restart_enable: self.input_assembly.primitive_restart_enable == vk::TRUE,
+            polygon_mode: self.rasterization.polygon_mode,
+            line_width: self.rasterization.line_width,
+            cull_mode: self.rasterization.cull_mode,
+            front_face: self.rasterization.front_face,
+            samples: self.multisample.rasterization_samples,
+            depth_test_enable: self.depth_stencil.depth_test_enable == vk::TRUE,
+            depth_write_enable: self.depth_stencil.depth_write_enable == vk::TRUE,
+            depth_compare_op: self.depth_stencil.depth_compare_op,
+            color_blend_attachment: self.color_blend_attachment,
+        };
+
+        ShaderObjectProgram::new(
+            shader,
+            &[descriptor_set_layout],
+            &self.push_constant_ranges,
+            state,
+            backend,
+            &self.debug_utils,
+            self.device.clone(),
+        )
+    }
+
+    fn build_pipeline(
+        &mut self,
+        shader: &GraphicsShader,
+        pipeline_layout: vk::PipelineLayout,
+    ) -> Result<vk::Pipeline> {
+        let shader_stages = shader
+            .stages
+            .iter()
+            .map(|stage| {
+                vk::PipelineShaderStageCreateInfo::default()
+                    .stage(stage.stage)
+                    .module(stage.module)
+                    .name(&stage.entry_point)
+            })
+            .collect::<Vec<_>>();
+        let input_assembly = self.input_assembly.topology(self.topology_for(shader));
+
+        // The counts come from the dynamic state as well
+        let viewport_state = vk::PipelineViewportStateCreateInfo::default();
+
         let color_blend_info = vk::PipelineColorBlendStateCreateInfo {
             logic_op_enable: vk::FALSE,
             logic_op: vk::LogicOp::COPY,
@@ -338,56 +694,74 @@ impl<'a> GraphicsMaterialFactoryBuilder<'a> {
         };
 
         // Use dynamic state for viewport and scissor configuration
-        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_states = [
+            vk::DynamicState::VIEWPORT_WITH_COUNT,
+            vk::DynamicState::SCISSOR_WITH_COUNT,
+        ];
         let dynamic_info =
             vk::PipelineDynamicStateCreateInfo::default().dynamic_states(&dynamic_states);
 
+        let vertex_input_description = self.vertex_layout.input_description();
         let vertex_input = vk::PipelineVertexInputStateCreateInfo::default()
-            .vertex_attribute_descriptions(&self.vertex_input_description.attributes)
-            .vertex_binding_descriptions(&self.vertex_input_description.bindings)
-            .flags(self.vertex_input_description.flags);
+            .vertex_attribute_descriptions(&vertex_input_description.attributes)
+            .vertex_binding_descriptions(&vertex_input_description.bindings)
+            .flags(vertex_input_description.flags);
+
+        // The builder has been moved since `with_color_attachment_format`, so point at the format again
+        if self.rendering_info.color_attachment_count > 0 {
+            self.rendering_info.p_color_attachment_formats = &self.color_attachment_format;
+        }
 
-        let pipeline_info = vk::GraphicsPipelineCreateInfo::default()
+        // Mesh pipelines ignore the vertex input and input assembly states
+        let mut pipeline_info = vk::GraphicsPipelineCreateInfo::default()
             .push_next(&mut self.rendering_info)
             .stages(&shader_stages)
             .layout(pipeline_layout)
             .vertex_input_state(&vertex_input)
-            .input_assembly_state(&self.input_assembly)
+            .input_assembly_state(&input_assembly)
             .viewport_state(&viewport_state)
             .rasterization_state(&self.rasterization)
             .multisample_state(&self.multisample)
             .color_blend_state(&color_blend_info)
             .depth_stencil_state(&self.depth_stencil)
             .dynamic_state(&dynamic_info);
+        if has_tessellation(shader) {
+            pipeline_info = pipeline_info.tessellation_state(&self.tessellation);
+        }
 
         let pipeline = unsafe {
-            match device.create_graphics_pipelines(
-                vk::PipelineCache::null(),
-                &[pipeline_info],
-                None,
-            ) {
+            match self
+                .device
+                .create_graphics_pipelines(self.pipeline_cache, &[pipeline_info], None)
+            {
                 Ok(pipelines) => Ok(pipelines),
-                Err(_) => Err(eyre!("Failed to create graphic pipelines")),
+                Err((_, e)) => Err(eyre!(
+                    "Failed to create graphics pipeline for shader {}: {e}",
+                    shader.display_name()
+                )),
             }
         }?[0];
+        self.debug_utils
+            .set_object_name(pipeline, &shader.display_name());
 
-        Ok(MaterialFactory {
-            pipeline,
-            pipeline_layout,
-            pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
-            descriptor_set_layout,
-            device,
-            descriptor_allocator: self.descriptor_allocator,
-        })
+        Ok(pipeline)
+    }
+
+    fn topology_for(&self, shader: &GraphicsShader) -> vk::PrimitiveTopology {
+        if has_tessellation(shader) {
+            vk::PrimitiveTopology::PATCH_LIST
+        } else {
+            self.input_assembly.topology
+        }
     }
 
-    fn default_input_assembly_info() -> vk::PipelineInputAssemblyStateCreateInfo<'a> {
+    fn default_input_assembly_info() -> vk::PipelineInputAssemblyStateCreateInfo<'static> {
         vk::PipelineInputAssemblyStateCreateInfo::default()
             .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
             .primitive_restart_enable(false)
     }
 
-    fn default_rasterization_info() -> vk::PipelineRasterizationStateCreateInfo<'a> {
+    fn default_rasterization_info() -> vk::PipelineRasterizationStateCreateInfo<'static> {
         vk::PipelineRasterizationStateCreateInfo::default()
             .depth_clamp_enable(false)
             // Discards all primitives before rasterization stage if true
@@ -417,7 +791,7 @@ impl<'a> GraphicsMaterialFactoryBuilder<'a> {
             .alpha_blend_op(vk::BlendOp::ADD)
     }
 
-    fn default_multisample_info() -> vk::PipelineMultisampleStateCreateInfo<'a> {
+    fn default_multisample_info() -> vk::PipelineMultisampleStateCreateInfo<'static> {
         vk::PipelineMultisampleStateCreateInfo::default()
             .sample_shading_enable(false)
             // 1 sample per pixel means no multisampling
@@ -427,7 +801,7 @@ impl<'a> GraphicsMaterialFactoryBuilder<'a> {
             .alpha_to_one_enable(false)
     }
 
-    fn default_depth_stencil_info() -> vk::PipelineDepthStencilStateCreateInfo<'a> {
+    fn default_depth_stencil_info() -> vk::PipelineDepthStencilStateCreateInfo<'static> {
         vk::PipelineDepthStencilStateCreateInfo::default()
             .depth_test_enable(true)
             .depth_write_enable(true)
@@ -439,13 +813,18 @@ impl<'a> GraphicsMaterialFactoryBuilder<'a> {
     }
 }
 
+/// Layouts that are not provided are generated from the shader's reflection
 pub(crate) struct ComputeMaterialFactoryBuilder {
     shader: Option<ComputeShader>,
     pipeline_layout: Option<vk::PipelineLayout>,
     descriptor_set_layout: Option<vk::DescriptorSetLayout>,
+    descriptor_set_layout_bindings: Vec<DescriptorBinding>,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
 
     device: Arc<ash::Device>,
     descriptor_allocator: Arc<Mutex<DescriptorAllocator<vk::DescriptorPool, vk::DescriptorSet>>>,
+    pipeline_cache: vk::PipelineCache,
+    debug_utils: DebugUtils,
 }
 
 impl ComputeMaterialFactoryBuilder {
@@ -454,13 +833,19 @@ impl ComputeMaterialFactoryBuilder {
         descriptor_allocator: Arc<
             Mutex<DescriptorAllocator<vk::DescriptorPool, vk::DescriptorSet>>,
         >,
+        pipeline_cache: vk::PipelineCache,
+        debug_utils: DebugUtils,
     ) -> Self {
         Self {
             shader: None,
             pipeline_layout: None,
             descriptor_set_layout: None,
+            descriptor_set_layout_bindings: Vec::new(),
+            push_constant_ranges: Vec::new(),
             device,
             descriptor_allocator,
+            pipeline_cache,
+            debug_utils,
         }
     }
 
@@ -469,13 +854,24 @@ impl ComputeMaterialFactoryBuilder {
         self
     }
 
-    pub fn with_pipeline_layout(mut self, layout: vk::PipelineLayout) -> Self {
+    /// The push-constant ranges must be the ones the layout was created with
+    pub fn with_pipeline_layout(
+        mut self,
+        layout: vk::PipelineLayout,
+        push_constant_ranges: &[vk::PushConstantRange],
+    ) -> Self {
         let _ = self.pipeline_layout.replace(layout);
+        self.push_constant_ranges = push_constant_ranges.to_vec();
         self
     }
 
-    pub fn with_descriptor_set_layout(mut self, layout: vk::DescriptorSetLayout) -> Self {
+    pub fn with_descriptor_set_layout(
+        mut self,
+        layout: vk::DescriptorSetLayout,
+        bindings: &[DescriptorBinding],
+    ) -> Self {
         let _ = self.descriptor_set_layout.replace(layout);
+        self.descriptor_set_layout_bindings = bindings.to_vec();
         self
     }
 
@@ -484,6 +880,9 @@ impl ComputeMaterialFactoryBuilder {
             .shader
             .take()
             .ok_or_eyre("No shader provided for ComputeMaterialBuilder")?;
+        self.resolve_layouts(&shader)?;
+        #[cfg(feature = "hot-reload")]
+        let recipe_builder = Box::new(self.clone_without_shader());
         let pipeline_layout = self
             .pipeline_layout
             .take()
@@ -494,33 +893,107 @@ impl ComputeMaterialFactoryBuilder {
             .take()
             .ok_or_eyre("No descriptor set layout provided for GraphicsMaterialBuilder")?;
 
-        let name = CString::new("main")?;
         let stage_info = vk::PipelineShaderStageCreateInfo::default()
             .stage(vk::ShaderStageFlags::COMPUTE)
             .module(shader.comp_mod)
-            .name(&name);
+            .name(&shader.entry_point);
 
         let pipeline_info = vk::ComputePipelineCreateInfo::default()
             .layout(pipeline_layout)
             .stage(stage_info);
         let pipeline = unsafe {
-            match self.device.create_compute_pipelines(
-                vk::PipelineCache::null(),
-                &[pipeline_info],
-                None,
-            ) {
+            match self
+                .device
+                .create_compute_pipelines(self.pipeline_cache, &[pipeline_info], None)
+            {
                 Ok(pipelines) => Ok(pipelines),
-                Err(_) => Err(eyre!("Failed to create compute pipeline")),
+                Err((_, e)) => Err(eyre!(
+                    "Failed to create compute pipeline for shader {}: {e}",
+                    shader.name
+                )),
             }
         }?[0];
+        self.debug_utils.set_object_name(pipeline, &shader.name);
 
         Ok(MaterialFactory {
-            pipeline,
+            pipeline: Arc::new(RwLock::new(MaterialPipeline::Pipeline(pipeline))),
             pipeline_layout,
             pipeline_bind_point: vk::PipelineBindPoint::COMPUTE,
             descriptor_set_layout,
+            #[cfg(feature = "hot-reload")]
+            recipe: MaterialRecipe::Compute {
+                shader,
+                builder: recipe_builder,
+            },
             device: self.device,
             descriptor_allocator: self.descriptor_allocator,
         })
     }
+
+    /// Generate the layouts that were not provided, and check the shader against those that were
+    fn resolve_layouts(&mut self, shader: &ComputeShader) -> Result<()> {
+        let Some(reflection) = &shader.reflection else {
+            return match (self.pipeline_layout, self.descriptor_set_layout) {
+                (Some(_), Some(_)) => Ok(()),
+                _ => Err(eyre!(
+                    "Shader {} could not be reflected, so its layouts must be provided",
+                    shader.name
+                )),
+            };
+        };
+
+        if self.descriptor_set_layout.is_none() {
+            let layout_builder = reflection.descriptor_set_layout_builder()?;
+            self.descriptor_set_layout_bindings = layout_builder.describe();
+            let layout = layout_builder.build(
+                vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL,
+                &self.device,
+            )?;
+            let _ = self.descriptor_set_layout.replace(layout);
+        }
+
+        if let (None, Some(descriptor_set_layout)) =
+            (self.pipeline_layout, self.descriptor_set_layout)
+        {
+            self.push_constant_ranges = reflection.push_constant_ranges();
+            let set_layouts = [descriptor_set_layout];
+            let layout = unsafe {
+                self.device.create_pipeline_layout(
+                    &vk::PipelineLayoutCreateInfo::default()
+                        .set_layouts(&set_layouts)
+                        .push_constant_ranges(&self.push_constant_ranges),
+                    None,
+                )?
+            };
+            let _ = self.pipeline_layout.replace(layout);
+        }
+
+        reflection.validate(
+            &shader.name,
+            &[&self.descriptor_set_layout_bindings],
+            &self.push_constant_ranges,
+            None,
+        )
+    }
+
+    #[cfg(feature = "hot-reload")]
+    fn clone_without_shader(&self) -> Self {
+        Self {
+            shader: None,
+            pipeline_layout: self.pipeline_layout,
+            descriptor_set_layout: self.descriptor_set_layout,
+            descriptor_set_layout_bindings: self.descriptor_set_layout_bindings.clone(),
+            push_constant_ranges: self.push_constant_ranges.clone(),
+            device: self.device.clone(),
+            descriptor_allocator: self.descriptor_allocator.clone(),
+            pipeline_cache: self.pipeline_cache,
+            debug_utils: self.debug_utils.clone(),
+        }
+    }
+}
+
+fn has_tessellation(shader: &GraphicsShader) -> bool {
+    shader
+        .stage_flags()
+        .contains(vk::ShaderStageFlags::TESSELLATION_CONTROL)
 }
@@ -0,0 +1,425 @@
+use crate::context::device::RenderDevice;
+use crate::resources::{
+    material::{GraphicsMaterialFactoryBuilder, MaterialFactory},
+    shader::GraphicsShader,
+    shader_variant::ShaderVariant,
+    vertex::VertexLayout,
+};
+use crate::storage::shader_data::PerMaterialData;
+use ash::vk;
+use color_eyre::Result;
+use color_eyre::eyre::{OptionExt, eyre};
+use serde::Deserialize;
+use std::path::Path;
+
+/// A material described in a `.ron` or `.toml` file instead of through builder calls.
+///
+/// Only `shader` is required, everything else falls back to an opaque, depth-tested,
+/// back-face-culled triangle list:
+/// ```ron
+/// (
+///     shader: "default",
+///     variant: ["ALPHA_TEST"],
+///     blend: Alpha,
+///     cull: None,
+///     depth: (test: true, write: false),
+///     params: (texture_index: 2),
+/// )
+/// ```
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MaterialDefinition {
+    /// Name of the shader pair in `shaders/`, without the `.vert`/`.frag` extension
+    pub shader: String,
+    /// Keywords selecting a variant declared by the shader with `#pragma variants`
+    #[serde(default)]
+    pub variant: Vec<String>,
+    /// Set for WGSL shaders, whose stages all live in `<shader>.wgsl`.
+    /// GLSL shaders have a file per stage, each starting at `main`.
+    #[serde(default)]
+    pub entry_points: Option<WgslEntryPoints>,
+    /// Vertices per patch, for shaders with tessellation stages
+    #[serde(default)]
+    pub patch_control_points: Option<u32>,
+    #[serde(default)]
+    pub blend: BlendMode,
+    #[serde(default)]
+    pub cull: CullMode,
+    #[serde(default)]
+    pub front_face: FrontFace,
+    #[serde(default)]
+    pub depth: DepthState,
+    #[serde(default)]
+    pub topology: Topology,
+    #[serde(default)]
+    pub polygon_mode: PolygonMode,
+    #[serde(default)]
+    pub params: MaterialParams,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WgslEntryPoints {
+    pub vertex: String,
+    pub fragment: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub(crate) enum BlendMode {
+    #[default]
+    Opaque,
+    /// Make sure transparent objects are rendered AFTER the opaque ones
+    Alpha,
+    Additive,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub(crate) enum CullMode {
+    None,
+    Front,
+    #[default]
+    Back,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub(crate) enum FrontFace {
+    #[default]
+    Clockwise,
+    CounterClockwise,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub(crate) enum CompareOp {
+    Never,
+    Less,
+    Equal,
+    #[default]
+    LessOrEqual,
+    Greater,
+    NotEqual,
+    GreaterOrEqual,
+    Always,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DepthState {
+    pub test: bool,
+    pub write: bool,
+    pub compare: CompareOp,
+}
+
+impl Default for DepthState {
+    fn default() -> Self {
+        Self {
+            test: true,
+            write: true,
+            compare: CompareOp::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub(crate) enum Topology {
+    PointList,
+    LineList,
+    LineStrip,
+    #[default]
+    TriangleList,
+    TriangleStrip,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub(crate) enum PolygonMode {
+    #[default]
+    Fill,
+    /// Wireframe, requires the `fillModeNonSolid` device feature
+    Line,
+    Point,
+}
+
+/// Mirrors `PerMaterialData`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MaterialParams {
+    pub texture_index: u32,
+    pub sampler_index: u32,
+}
+
+impl MaterialDefinition {
+    /// Parse a definition file, picking the format from its extension
+    pub fn load(path: &Path) -> Result<Self> {
+        log::info!("Loading material definition from file: {:?}", path);
+
+        let source = std::fs::read_to_string(path)?;
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or_eyre(format!("Material file has no extension: {:?}", path))?;
+        match ext {
+            "ron" => Self::from_ron(&source),
+            "toml" => Self::from_toml(&source),
+            _ => Err(eyre!("Material format not recognized for file: {:?}", path)),
+        }
+    }
+
+    pub fn from_ron(source: &str) -> Result<Self> {
+        Ok(ron::from_str(source)?)
+    }
+
+    pub fn from_toml(source: &str) -> Result<Self> {
+        Ok(toml::from_str(source)?)
+    }
+
+    /// Blended materials are drawn after every opaque one
+    pub fn is_blended(&self) -> bool {
+        self.blend != BlendMode::Opaque
+    }
+
+    pub fn per_material_data(&self) -> PerMaterialData {
+        PerMaterialData {
+            texture_index: self.params.texture_index,
+            sampler_index: self.params.sampler_index,
+        }
+    }
+
+    /// `builder` has the layouts and target formats, since every material shares the bindless
+    /// ones. Models are drawn from vertices in `vertex_layout`, or pulled by the shader.
+    pub fn build_factory(
+        &self,
+        builder: GraphicsMaterialFactoryBuilder,
+        vertex_pulling: bool,
+        vertex_layout: &VertexLayout,
+        dev: &RenderDevice,
+    ) -> Result<MaterialFactory> {
+        let (variant, vertex_input) = self.vertex_input(vertex_pulling, vertex_layout)?;
+        let shader = match &self.entry_points {
+            Some(entry_points) => GraphicsShader::from_wgsl(
+                &self.shader,
+                &[
+                    (vk::ShaderStageFlags::VERTEX, entry_points.vertex.as_str()),
+                    (
+                        vk::ShaderStageFlags::FRAGMENT,
+                        entry_points.fragment.as_str(),
+                    ),
+                ],
+                dev.logical.clone(),
+            )?,
+            None => GraphicsShader::new(&self.shader, &variant, dev.logical.clone())?,
+        };
+        let mut builder = builder
+            .with_shader(shader)
+            .with_vertex_input(vertex_input)
+            .with_input_topology(self.topology.into())
+            .with_polygon_mode(self.polygon_mode.into())
+            .with_cull_mode(self.cull.into(), self.front_face.into())
+            .with_depth_test(self.depth.test, Some(self.depth.compare.into()))
+            .with_depth_write(self.depth.test && self.depth.write);
+        if let Some(count) = self.patch_control_points {
+            builder = builder.with_patch_control_points(count);
+        }
+        let builder = match self.blend {
+            BlendMode::Opaque => builder.with_blending_disabled(),
+            BlendMode::Alpha => builder.with_alpha_blending_enabled(),
+            BlendMode::Additive => builder.with_additive_blending_enabled(),
+        };
+        builder.build()
+    }
+
+    /// The shader variant and the vertex buffers its pipeline reads. Pulled vertices are
+    /// fetched by the `VERTEX_PULLING` variant, so no vertex buffers are bound for them.
+    fn vertex_input(
+        &self,
+        vertex_pulling: bool,
+        vertex_layout: &VertexLayout,
+    ) -> Result<(ShaderVariant, VertexLayout)> {
+        match (&self.entry_points, vertex_pulling) {
+            (Some(_), _) if !self.variant.is_empty() => {
+                Err(eyre!("WGSL shader {} has no variants", self.shader))
+            }
+            (Some(_), true) => Err(eyre!("WGSL shader {} cannot pull vertices", self.shader)),
+            (None, true) => Ok((
+                ShaderVariant::new(
+                    self.variant
+                        .iter()
+                        .map(String::as_str)
+                        .chain(["VERTEX_PULLING"]),
+                ),
+                VertexLayout::empty(),
+            )),
+            (_, false) => Ok((ShaderVariant::new(&self.variant), vertex_layout.clone())),
+        }
+    }
+}
+
+impl From<CullMode> for vk::CullModeFlags {
+    fn from(mode: CullMode) -> Self {
+        match mode {
+            CullMode::None => vk::CullModeFlags::NONE,
+            CullMode::Front => vk::CullModeFlags::FRONT,
+            CullMode::Back => vk::CullModeFlags::BACK,
+        }
+    }
+}
+
+impl From<FrontFace> for vk::FrontFace {
+    fn from(face: FrontFace) -> Self {
+        match face {
+            FrontFace::Clockwise => vk::FrontFace::CLOCKWISE,
+            FrontFace::CounterClockwise => vk::FrontFace::COUNTER_CLOCKWISE,
+        }
+    }
+}
+
+impl From<CompareOp> for vk::CompareOp {
+    fn from(op: CompareOp) -> Self {
+        match op {
+            CompareOp::Never => vk::CompareOp::NEVER,
+            CompareOp::Less => vk::CompareOp::LESS,
+            CompareOp::Equal => vk::CompareOp::EQUAL,
+            CompareOp::LessOrEqual => vk::CompareOp::LESS_OR_EQUAL,
+            CompareOp::Greater => vk::CompareOp::GREATER,
+            CompareOp::NotEqual => vk::CompareOp::NOT_EQUAL,
+            CompareOp::GreaterOrEqual => vk::CompareOp::GREATER_OR_EQUAL,
+            CompareOp::Always => vk::CompareOp::ALWAYS,
+        }
+    }
+}
+
+impl From<Topology> for vk::PrimitiveTopology {
+    fn from(topology: Topology) -> Self {
+        match topology {
+            Topology::PointList => vk::PrimitiveTopology::POINT_LIST,
+            Topology::LineList => vk::PrimitiveTopology::LINE_LIST,
+            Topology::LineStrip => vk::PrimitiveTopology::LINE_STRIP,
+            Topology::TriangleList => vk::PrimitiveTopology::TRIANGLE_LIST,
+            Topology::TriangleStrip => vk::PrimitiveTopology::TRIANGLE_STRIP,
+        }
+    }
+}
+
+impl From<PolygonMode> for vk::PolygonMode {
+    fn from(mode: PolygonMode) -> Self {
+        match mode {
+            PolygonMode::Fill => vk::PolygonMode::FILL,
+            PolygonMode::Line => vk::PolygonMode::LINE,
+            PolygonMode::Point => vk::PolygonMode::POINT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_shader_is_required() {
+        let def = MaterialDefinition::from_ron(r#"(shader: "default")"#).unwrap();
+        assert_eq!(def.shader, "default");
+        assert!(def.variant.is_empty());
+        assert_eq!(def.entry_points, None);
+        assert_eq!(def.patch_control_points, None);
+        assert_eq!(def.blend, BlendMode::Opaque);
+        assert_eq!(def.cull, CullMode::Back);
+        assert_eq!(def.depth, DepthState::default());
+        assert_eq!(def.topology, Topology::TriangleList);
+        assert_eq!(def.polygon_mode, PolygonMode::Fill);
+        assert_eq!(def.params, MaterialParams::default());
+    }
+
+    #[test]
+    fn ron_and_toml_describe_the_same_material() {
+        let ron = MaterialDefinition::from_ron(
+            r#"(
+                shader: "default",
+                variant: ["ALPHA_TEST"],
+                blend: Alpha,
+                cull: None,
+                depth: (write: false),
+                params: (texture_index: 3, sampler_index: 1),
+            )"#,
+        )
+        .unwrap();
+        let toml = MaterialDefinition::from_toml(
+            r#"
+            shader = "default"
+            variant = ["ALPHA_TEST"]
+            blend = "Alpha"
+            cull = "None"
+
+            [depth]
+            write = false
+
+            [params]
+            texture_index = 3
+            sampler_index = 1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(ron, toml);
+        assert_eq!(ron.variant, ["ALPHA_TEST"]);
+        assert!(ron.depth.test);
+        assert!(!ron.depth.write);
+        assert_eq!(ron.per_material_data().texture_index, 3);
+        assert_eq!(ron.per_material_data().sampler_index, 1);
+    }
+
+    #[test]
+    fn wgsl_entry_points_and_tessellation() {
+        let ron = MaterialDefinition::from_ron(
+            r#"(
+                shader: "terrain",
+                entry_points: Some((vertex: "vs_main", fragment: "fs_main")),
+                patch_control_points: Some(4),
+            )"#,
+        )
+        .unwrap();
+        let toml = MaterialDefinition::from_toml(
+            r#"
+            shader = "terrain"
+            patch_control_points = 4
+
+            [entry_points]
+            vertex = "vs_main"
+            fragment = "fs_main"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(ron, toml);
+        let entry_points = ron.entry_points.unwrap();
+        assert_eq!(entry_points.vertex, "vs_main");
+        assert_eq!(entry_points.fragment, "fs_main");
+        assert_eq!(ron.patch_control_points, Some(4));
+    }
+
+    #[test]
+    fn pulled_vertices_select_the_variant_without_vertex_buffers() {
+        let def = MaterialDefinition::from_ron(r#"(shader: "default", variant: ["ALPHA_TEST"])"#)
+            .unwrap();
+
+        let (variant, layout) = def.vertex_input(true, &VertexLayout::pulled()).unwrap();
+        assert_eq!(
+            variant,
+            ShaderVariant::new(["ALPHA_TEST", "VERTEX_PULLING"])
+        );
+        assert_eq!(layout, VertexLayout::empty());
+
+        let (variant, layout) = def.vertex_input(false, &VertexLayout::default()).unwrap();
+        assert_eq!(variant, ShaderVariant::new(["ALPHA_TEST"]));
+        assert_eq!(layout, VertexLayout::default());
+
+        let wgsl = MaterialDefinition::from_ron(
+            r#"(shader: "terrain", entry_points: Some((vertex: "vs_main", fragment: "fs_main")))"#,
+        )
+        .unwrap();
+        assert!(wgsl.vertex_input(true, &VertexLayout::pulled()).is_err());
+        let (_, layout) = wgsl.vertex_input(false, &VertexLayout::default()).unwrap();
+        assert_eq!(layout, VertexLayout::default());
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(MaterialDefinition::from_ron(r#"(shader: "default", shiny: true)"#).is_err());
+        assert!(MaterialDefinition::from_toml("shader = \"default\"\nblend = \"Glow\"").is_err());
+    }
+}
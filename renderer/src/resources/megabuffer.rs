@@ -1,5 +1,6 @@
 use super::buffer::Buffer;
 use crate::context::commands::TransferCommandEncoder;
+use crate::context::debug_utils::DebugUtils;
 use ash::vk;
 use color_eyre::Result;
 use color_eyre::eyre::{OptionExt, eyre};
@@ -30,9 +31,11 @@ impl PartialEq for Megabuffer {
 
 pub(crate) trait MegabufferExt {
     fn new(
+        name: &str,
         size: u64,
         alignment: u64,
         buf_usage: vk::BufferUsageFlags,
+        debug_utils: &DebugUtils,
         memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
         device: Arc<ash::Device>,
         transfer: Arc<TransferCommandEncoder>,
@@ -40,7 +43,10 @@ pub(crate) trait MegabufferExt {
     fn allocate_region(&self, size: u64) -> Result<AllocatedMegabufferRegion>;
     fn deallocate_region(&self, region: &mut AllocatedMegabufferRegion) -> Result<()>;
     fn defragment(&self) -> Result<()>;
+    /// Copy every allocated region from the staging buffer to the buffer shaders read
     fn upload(&self) -> Result<()>;
+    /// Copy one region from the staging buffer, e.g. a region rewritten every frame
+    fn upload_region(&self, region: &AllocatedMegabufferRegion) -> Result<()>;
     fn write<T>(
         &self,
         data: &[T],
@@ -49,20 +55,27 @@ pub(crate) trait MegabufferExt {
     where
         T: Copy;
     fn aligned_size(&self, size: u64) -> Result<u64>;
+    /// Address of the start of the buffer, for shaders reading it through buffer references
+    fn device_address(&self) -> Result<vk::DeviceAddress>;
+    /// The buffer shaders read, for binding it or writing it into descriptors
+    fn raw_buffer(&self) -> Result<vk::Buffer>;
 }
 
 impl MegabufferExt for Megabuffer {
     fn new(
+        name: &str,
         size: u64,
         alignment: u64,
         buf_usage: vk::BufferUsageFlags,
 
+        debug_utils: &DebugUtils,
         memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
         device: Arc<ash::Device>,
         transfer: Arc<TransferCommandEncoder>,
     ) -> Result<Megabuffer> {
         log::info!(
-            "Creating Megabuffer with size: {}, alignment: {}, usage: {:?}",
+            "Creating Megabuffer {} with size: {}, alignment: {}, usage: {:?}",
+            name,
             size,
             alignment,
             buf_usage
@@ -70,21 +83,25 @@ impl MegabufferExt for Megabuffer {
 
         let mem_usage = vk_mem::MemoryUsage::AutoPreferDevice;
         let buffer = Arc::new(Mutex::new(Buffer::new(
+            name,
             size,
             alignment,
             buf_usage,
             mem_usage,
             false,
+            debug_utils,
             memory_allocator.clone(),
             device.clone(),
         )?));
 
         let staging_buffer = Arc::new(Mutex::new(Buffer::new(
+            &format!("{name} (staging)"),
             size,
             alignment,
             vk::BufferUsageFlags::TRANSFER_SRC,
             vk_mem::MemoryUsage::AutoPreferHost,
             true,
+            debug_utils,
             memory_allocator.clone(),
             device.clone(),
         )?));
@@ -197,33 +214,40 @@ impl MegabufferExt for Megabuffer {
     fn upload(&self) -> Result<()> {
         let guard = self.inner.lock().map_err(|e| eyre!(e.to_string()))?;
 
-        guard
-            .transfer
-            .immediate_submit(|cmd: vk::CommandBuffer, device: &ash::Device| {
-                let copy_regions = guard
-                    .free_regions
-                    .iter()
-                    .map(|region| vk::BufferCopy {
-                        src_offset: region.offset,
-                        dst_offset: region.offset,
-                        size: region.size,
-                    })
-                    .collect::<Vec<vk::BufferCopy>>();
-
-                let src_guard = guard
-                    .staging_buffer
-                    .lock()
-                    .map_err(|e| eyre!(e.to_string()))?;
-                let dst_guard = guard.buffer.lock().map_err(|e| eyre!(e.to_string()))?;
-
-                unsafe {
-                    device.cmd_copy_buffer(cmd, src_guard.buffer, dst_guard.buffer, &copy_regions);
-                }
+        // Allocated regions are the gaps between the sorted free regions
+        let buffer_size = guard.buffer.lock().map_err(|e| eyre!(e.to_string()))?.size;
+        let mut free_regions = guard.free_regions.iter().collect::<Vec<_>>();
+        free_regions.sort_by_key(|r| r.offset);
+        let mut copy_regions = Vec::new();
+        let mut offset = 0;
+        for (free_offset, free_size) in free_regions
+            .iter()
+            .map(|r| (r.offset, r.size))
+            .chain([(buffer_size, 0)])
+        {
+            if free_offset > offset {
+                copy_regions.push(vk::BufferCopy {
+                    src_offset: offset,
+                    dst_offset: offset,
+                    size: free_offset - offset,
+                });
+            }
+            offset = free_offset + free_size;
+        }
+        guard.copy_from_staging(&copy_regions)
+    }
 
-                Ok(())
-            })?;
+    fn upload_region(&self, region: &AllocatedMegabufferRegion) -> Result<()> {
+        if !region.belongs_to_megabuffer(self) {
+            return Err(eyre!("Region does not belong to this megabuffer"));
+        }
 
-        Ok(())
+        let guard = self.inner.lock().map_err(|e| eyre!(e.to_string()))?;
+        guard.copy_from_staging(&[vk::BufferCopy {
+            src_offset: region.offset,
+            dst_offset: region.offset,
+            size: region.size,
+        }])
     }
 
     fn write<T>(
@@ -253,6 +277,20 @@ impl MegabufferExt for Megabuffer {
 
         Ok(guard.aligned_size(size))
     }
+
+    fn device_address(&self) -> Result<vk::DeviceAddress> {
+        let guard = self.inner.lock().map_err(|e| eyre!(e.to_string()))?;
+        let buffer_guard = guard.buffer.lock().map_err(|e| eyre!(e.to_string()))?;
+
+        Ok(buffer_guard.device_address())
+    }
+
+    fn raw_buffer(&self) -> Result<vk::Buffer> {
+        let guard = self.inner.lock().map_err(|e| eyre!(e.to_string()))?;
+        let buffer_guard = guard.buffer.lock().map_err(|e| eyre!(e.to_string()))?;
+
+        Ok(buffer_guard.buffer)
+    }
 }
 
 struct MegabufferInner {
@@ -269,49 +307,82 @@ struct MegabufferInner {
 }
 
 impl MegabufferInner {
+    fn copy_from_staging(&self, copy_regions: &[vk::BufferCopy]) -> Result<()> {
+        if copy_regions.is_empty() {
+            return Ok(());
+        }
+
+        self.transfer
+            .immediate_submit(|cmd: vk::CommandBuffer, device: &ash::Device| {
+                let src_guard = self
+                    .staging_buffer
+                    .lock()
+                    .map_err(|e| eyre!(e.to_string()))?;
+                let dst_guard = self.buffer.lock().map_err(|e| eyre!(e.to_string()))?;
+
+                unsafe {
+                    device.cmd_copy_buffer(cmd, src_guard.buffer, dst_guard.buffer, copy_regions);
+                }
+
+                Ok(())
+            })
+    }
+
     fn aligned_size(&self, size: u64) -> u64 {
-        (size + self.alignment - 1) & !(self.alignment - 1)
+        aligned_size(size, self.alignment)
     }
 
-    /// Find a free region that can fit the allocation and splits it into 2 free regions if possible
-    /// Returns the index of the free region that fits the allocation
     fn find_free_region_for_allocation(&mut self, alloc_size: u64) -> Option<usize> {
-        let (region_index, new_region) = self
-            .free_regions
-            .iter_mut()
-            .enumerate()
-            // Find the first free region that can fit the allocation
-            .find(|(_, region)| {
-                region.size >= alloc_size
-            })
-            .map(|(i, region)| {
-                // Split the free region into 2 regions:
-                // 1. A free region that fits the allocation exactly
-                // 2. The remaining free region
-                let offset = region.offset;
-                region.offset += alloc_size;
-                region.size -= alloc_size;
-                (
-                    // Index of the remaining free region
-                    i,
-                    // The free region that fits the allocation exactly,
-                    // ready to be inserted into the free regions vector
-                    FreeMegabufferRegion {
-                        offset,
-                        size: alloc_size,
-                    },
-                )
-            })?;
-
-        // Insert the new free region into the free regions vector
-        if self.free_regions[region_index].size == 0 {
-            self.free_regions[region_index] = new_region;
-        } else {
-            self.free_regions.insert(region_index, new_region);
-        }
+        find_free_region_for_allocation(&mut self.free_regions, alloc_size)
+    }
+}
+
+/// Round `size` up to a multiple of `alignment`, which need not be a power of two
+/// (e.g. the least common multiple with a vertex stride)
+fn aligned_size(size: u64, alignment: u64) -> u64 {
+    size.div_ceil(alignment) * alignment
+}
 
-        Some(region_index)
+/// Find a free region that can fit the allocation and splits it into 2 free regions if possible
+/// Returns the index of the free region that fits the allocation
+fn find_free_region_for_allocation(
+    free_regions: &mut Vec<FreeMegabufferRegion>,
+    alloc_size: u64,
+) -> Option<usize> {
+    let (region_index, new_region) = free_regions
+        .iter_mut()
+        .enumerate()
+        // Find the first free region that can fit the allocation
+        .find(|(_, region)| {
+            region.size >= alloc_size
+        })
+        .map(|(i, region)| {
+            // Split the free region into 2 regions:
+            // 1. A free region that fits the allocation exactly
+            // 2. The remaining free region
+            let offset = region.offset;
+            region.offset += alloc_size;
+            region.size -= alloc_size;
+            (
+                // Index of the remaining free region
+                i,
+                // The free region that fits the allocation exactly,
+                // ready to be inserted into the free regions vector
+                FreeMegabufferRegion {
+                    offset,
+                    size: alloc_size,
+                },
+            )
+        })?;
+
+    // Insert the new free region into the free regions vector
+    if free_regions[region_index].size == 0 {
+        free_regions[region_index] = new_region;
+    } else {
+        free_regions.insert(region_index, new_region);
     }
+
+    Some(region_index)
 }
 
 impl PartialEq for MegabufferInner {
@@ -337,6 +408,15 @@ pub(crate) struct AllocatedMegabufferRegion {
 }
 
 impl AllocatedMegabufferRegion {
+    /// Byte offset of the region from the start of its megabuffer
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
     pub fn write<T>(&mut self, data: &[T]) -> Result<presser::CopyRecord>
     where
         T: Copy,
@@ -434,3 +514,26 @@ impl Drop for AllocatedMegabufferRegion {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_start_at_multiples_of_any_alignment() {
+        // lcm(32, 20), from a 20-byte vertex stride
+        let alignment = 160;
+        let mut free_regions = vec![FreeMegabufferRegion {
+            offset: 0,
+            size: 16 * 1024,
+        }];
+        let offsets = [64, 600, 20, 160, 1].map(|size| {
+            let index =
+                find_free_region_for_allocation(&mut free_regions, aligned_size(size, alignment))
+                    .unwrap();
+            free_regions.remove(index).offset
+        });
+        assert_eq!(offsets, [0, 160, 800, 960, 1120]);
+        assert_eq!(free_regions[0].offset, 1280);
+    }
+}
@@ -28,18 +28,21 @@ impl Mesh {
                 normal: [0.0, 0.0, 1.0].into(),
                 color: [1.0, 0.0, 0.0].into(),
                 texcoord: [0.0, 1.0].into(),
+                ..Default::default()
             },
             Vertex { // Bottom right
                 position: [0.5, -0.5, 0.0].into(),
                 normal: [0.0, 0.0, 1.0].into(),
                 color: [0.0, 1.0, 0.0].into(),
                 texcoord: [1.0, 1.0].into(),
+                ..Default::default()
             },
             Vertex { // Top
                 position: [0.0, 0.5, 0.0].into(),
                 normal: [0.0, 0.0, 1.0].into(),
                 color: [0.0, 0.0, 1.0].into(),
                 texcoord: [0.5, 0.0].into(),
+                ..Default::default()
             },
         ];
 
@@ -55,24 +58,28 @@ impl Mesh {
                 normal: [0.0, 0.0, 1.0].into(),
                 color: [1.0, 0.0, 0.0].into(),
                 texcoord: [0.0, 0.0].into(),
+                ..Default::default()
             },
             Vertex { // Bottom left
                 position: [-1.0, -1.0, 0.0].into(),
                 normal: [0.0, 0.0, 1.0].into(),
                 color: [0.0, 1.0, 0.0].into(),
                 texcoord: [0.0, 1.0].into(),
+                ..Default::default()
             },
             Vertex { // Top right
                 position: [1.0, 1.0, 0.0].into(),
                 normal: [0.0, 0.0, 1.0].into(),
                 color: [0.0, 0.0, 1.0].into(),
                 texcoord: [1.0, 0.0].into(),
+                ..Default::default()
             },
             Vertex { // Bottom right
                 position: [1.0, -1.0, 0.0].into(),
                 normal: [0.0, 0.0, 1.0].into(),
                 color: [1.0, 1.0, 0.0].into(),
                 texcoord: [1.0, 1.0].into(),
+                ..Default::default()
             },
         ];
 
@@ -0,0 +1,623 @@
+//! Data derived from a mesh's vertices and reorderings of its indices, applied to imported
+//! meshes before they are uploaded.
+
+use crate::resources::bounds::{Aabb, BoundingSphere};
+use crate::resources::mesh::Mesh;
+use crate::resources::vertex::Vertex;
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+use glam::Vec3;
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+
+/// Entries of the LRU cache modelled when ordering triangles for the vertex cache
+const VERTEX_CACHE_SIZE: usize = 32;
+/// Entries of the FIFO cache simulated to find where a triangle order starts over
+const SIMULATED_CACHE_SIZE: usize = 16;
+
+impl Mesh {
+    /// Average of the normals of the triangles around each position, weighted by their angle
+    /// at it so the result does not depend on how faces were triangulated. Vertices split at
+    /// texture seams still share a normal.
+    pub fn compute_smooth_normals(&mut self) {
+        let mut sums = HashMap::<[u32; 3], Vec3>::new();
+        for triangle in self.triangles() {
+            let normal = self.weighted_normal(triangle).normalize_or_zero();
+            let positions = triangle.map(|index| self.vertices[index as usize].position);
+            for corner in 0..3 {
+                let position = positions[corner];
+                let angle = (positions[(corner + 1) % 3] - position)
+                    .angle_between(positions[(corner + 2) % 3] - position);
+                *sums.entry(position_key(position)).or_default() += normal * angle;
+            }
+        }
+        for vertex in &mut self.vertices {
+            if let Some(sum) = sums.get(&position_key(vertex.position)) {
+                vertex.normal = sum.normalize_or(Vec3::Z);
+            }
+        }
+    }
+
+    /// Give every triangle its own vertices, with the triangle's normal
+    pub fn compute_flat_normals(&mut self) {
+        let vertices = self
+            .triangles()
+            .into_iter()
+            .flat_map(|triangle| {
+                let normal = self.weighted_normal(triangle).normalize_or(Vec3::Z);
+                triangle.map(|index| Vertex {
+                    normal,
+                    ..self.vertices[index as usize]
+                })
+            })
+            .collect::<Vec<_>>();
+        if self.indices.is_some() {
+            self.indices = Some((0..vertices.len() as u32).collect());
+        }
+        self.vertices = vertices;
+    }
+
+    /// MikkTSpace tangents from the positions, normals and texcoords, with the handedness of
+    /// the bitangent in `w` like glTF. A vertex shared by triangles that disagree keeps the
+    /// tangent of the last one.
+    pub fn compute_tangents(&mut self) -> Result<()> {
+        let triangles = self.triangles();
+        let mut geometry = TangentGeometry {
+            vertices: &mut self.vertices,
+            triangles: &triangles,
+        };
+        if !bevy_mikktspace::generate_tangents(&mut geometry) {
+            return Err(eyre!(
+                "Failed to generate tangents for {} triangles",
+                triangles.len()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn aabb(&self) -> Aabb {
+        Aabb::from_points(self.vertices.iter().map(|vertex| vertex.position))
+    }
+
+    pub fn bounding_sphere(&self) -> BoundingSphere {
+        BoundingSphere::from_points(self.vertices.iter().map(|vertex| vertex.position))
+    }
+
+    /// Merge vertices whose attributes all match within `tolerance`, indexing the mesh if it
+    /// was not. Attributes are snapped to a grid of that size, so 0 only merges exact copies.
+    pub fn weld(&mut self, tolerance: f32) {
+        let mut welded = Vec::new();
+        let mut welded_indices = HashMap::new();
+        let remap = self
+            .vertices
+            .iter()
+            .map(|vertex| {
+                *welded_indices
+                    .entry(vertex_key(vertex, tolerance))
+                    .or_insert_with(|| {
+                        welded.push(*vertex);
+                        welded.len() as u32 - 1
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        self.indices = Some(match self.indices.take() {
+            Some(indices) => indices.iter().map(|&index| remap[index as usize]).collect(),
+            None => remap,
+        });
+        self.vertices = welded;
+    }
+
+    /// Reorder triangles so consecutive ones reuse vertices still in the post-transform cache,
+    /// with Tom Forsyth's linear-speed algorithm. Meshes without indices are left as they are.
+    pub fn optimize_vertex_cache(&mut self) {
+        if let Some(indices) = &self.indices {
+            self.indices = Some(vertex_cache_order(indices, self.vertices.len()));
+        }
+    }
+
+    /// Draw clusters of triangles facing away from the mesh's center first, so they hide more
+    /// of the rest, like meshoptimizer's overdraw optimizer. Clusters break where the vertex
+    /// cache starts over, which keeps most of the order from `optimize_vertex_cache`.
+    pub fn optimize_overdraw(&mut self) {
+        let Some(indices) = &self.indices else {
+            return;
+        };
+        let triangles = self.triangles();
+        let centroid = |triangles: &[[u32; 3]]| {
+            let (sum, weight) =
+                triangles
+                    .iter()
+                    .fold((Vec3::ZERO, 0.0), |(sum, weight), &triangle| {
+                        let area = self.weighted_normal(triangle).length();
+                        let center = triangle
+                            .map(|index| self.vertices[index as usize].position)
+                            .into_iter()
+                            .sum::<Vec3>()
+                            / 3.0;
+                        (sum + center * area, weight + area)
+                    });
+            if weight > 0.0 {
+                sum / weight
+            } else {
+                Vec3::ZERO
+            }
+        };
+        let mesh_centroid = centroid(&triangles);
+
+        let mut clusters = cold_cache_clusters(indices)
+            .into_iter()
+            .map(|range| {
+                let cluster = &triangles[range.clone()];
+                let normal = cluster
+                    .iter()
+                    .map(|&triangle| self.weighted_normal(triangle))
+                    .sum::<Vec3>()
+                    .normalize_or_zero();
+                ((centroid(cluster) - mesh_centroid).dot(normal), range)
+            })
+            .collect::<Vec<_>>();
+        clusters.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+        self.indices = Some(
+            clusters
+                .into_iter()
+                .flat_map(|(_, range)| indices[range.start * 3..range.end * 3].iter().copied())
+                .collect(),
+        );
+    }
+
+    /// Renumber vertices in the order the indices first use them, dropping unused ones,
+    /// so vertex fetches move forward through memory
+    pub fn optimize_vertex_fetch(&mut self) {
+        let Some(indices) = &mut self.indices else {
+            return;
+        };
+        let mut remap = vec![None; self.vertices.len()];
+        let mut vertices = Vec::with_capacity(self.vertices.len());
+        for index in indices.iter_mut() {
+            let vertex = self.vertices[*index as usize];
+            *index = *remap[*index as usize].get_or_insert_with(|| {
+                vertices.push(vertex);
+                vertices.len() as u32 - 1
+            });
+        }
+        self.vertices = vertices;
+    }
+
+    /// Vertex cache, overdraw and vertex fetch optimizations, in that order
+    pub fn optimize(&mut self) {
+        self.optimize_vertex_cache();
+        self.optimize_overdraw();
+        self.optimize_vertex_fetch();
+    }
+
+    /// Vertex indices of every triangle, consecutive vertices forming triangles without indices
+    pub(super) fn triangles(&self) -> Vec<[u32; 3]> {
+        match &self.indices {
+            Some(indices) => indices
+                .chunks_exact(3)
+                .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+                .collect(),
+            None => (0..self.vertices.len() as u32 / 3)
+                .map(|triangle| [0, 1, 2].map(|corner| triangle * 3 + corner))
+                .collect(),
+        }
+    }
+
+    /// Normal of a triangle with a length of twice its area
+    fn weighted_normal(&self, triangle: [u32; 3]) -> Vec3 {
+        let [a, b, c] = triangle.map(|index| self.vertices[index as usize].position);
+        (b - a).cross(c - a)
+    }
+}
+
+/// Positions that compare equal give the same key, including zeroes of either sign
+pub(super) fn position_key(position: Vec3) -> [u32; 3] {
+    (position + Vec3::ZERO).to_array().map(f32::to_bits)
+}
+
+fn vertex_key(vertex: &Vertex, tolerance: f32) -> Vec<u32> {
+    let floats = [
+        vertex.position.to_array().as_slice(),
+        &vertex.normal.to_array(),
+        &vertex.color.to_array(),
+        &vertex.texcoord.to_array(),
+        &vertex.tangent.to_array(),
+        &vertex.texcoord1.to_array(),
+        &vertex.weights.to_array(),
+    ]
+    .concat();
+    floats
+        .into_iter()
+        .map(|value| {
+            if tolerance > 0.0 {
+                (value / tolerance).round() as i32 as u32
+            } else {
+                (value + 0.0).to_bits()
+            }
+        })
+        .chain(vertex.joints.to_array())
+        .collect()
+}
+
+/// Triangles emitted greedily by the score of their vertices, which favors vertices in the
+/// cache and vertices with few triangles left
+fn vertex_cache_order(indices: &[u32], vertex_count: usize) -> Vec<u32> {
+    let triangle_count = indices.len() / 3;
+    let mut vertex_triangles = vec![Vec::new(); vertex_count];
+    for (triangle, corners) in indices.chunks_exact(3).enumerate() {
+        for &index in corners {
+            vertex_triangles[index as usize].push(triangle);
+        }
+    }
+    let mut live_triangles = vertex_triangles
+        .iter()
+        .map(|triangles| triangles.len() as u32)
+        .collect::<Vec<_>>();
+    let mut cache_positions = vec![None; vertex_count];
+    let mut vertex_scores = live_triangles
+        .iter()
+        .map(|&live| forsyth_score(None, live))
+        .collect::<Vec<_>>();
+    let mut emitted = vec![false; triangle_count];
+
+    let mut cache = Vec::<u32>::with_capacity(VERTEX_CACHE_SIZE + 3);
+    let mut order = Vec::with_capacity(indices.len());
+    let mut best_triangle = None;
+    let mut next_unemitted = 0;
+    for _ in 0..triangle_count {
+        let triangle = best_triangle.unwrap_or_else(|| {
+            while emitted[next_unemitted] {
+                next_unemitted += 1;
+            }
+            next_unemitted
+        });
+        emitted[triangle] = true;
+        let corners = &indices[triangle * 3..triangle * 3 + 3];
+        order.extend_from_slice(corners);
+
+        // Move the triangle's vertices to the front of the cache, evicting the oldest
+        let mut updated = Vec::with_capacity(VERTEX_CACHE_SIZE + 3);
+        for &index in corners {
+            live_triangles[index as usize] -= 1;
+            if !updated.contains(&index) {
+                updated.push(index);
+            }
+        }
+        updated.extend(cache.iter().filter(|index| !corners.contains(index)));
+        for (position, &index) in updated.iter().enumerate() {
+            cache_positions[index as usize] = (position < VERTEX_CACHE_SIZE).then_some(position);
+            vertex_scores[index as usize] = forsyth_score(
+                cache_positions[index as usize],
+                live_triangles[index as usize],
+            );
+        }
+
+        // Only triangles around the vertices whose score changed can become the best
+        best_triangle = None;
+        let mut best_score = f32::MIN;
+        for &index in &updated {
+            for &candidate in &vertex_triangles[index as usize] {
+                if emitted[candidate] {
+                    continue;
+                }
+                let score = indices[candidate * 3..candidate * 3 + 3]
+                    .iter()
+                    .map(|&index| vertex_scores[index as usize])
+                    .sum::<f32>();
+                if score > best_score {
+                    best_score = score;
+                    best_triangle = Some(candidate);
+                }
+            }
+        }
+
+        updated.truncate(VERTEX_CACHE_SIZE);
+        cache = updated;
+    }
+    order
+}
+
+/// Weights from Tom Forsyth's "Linear-Speed Vertex Cache Optimisation"
+fn forsyth_score(cache_position: Option<usize>, live_triangles: u32) -> f32 {
+    if live_triangles == 0 {
+        return -1.0;
+    }
+    let cache_score = match cache_position {
+        // The vertices of the last triangle score the same, whichever order they were in
+        Some(position) if position < 3 => 0.75,
+        Some(position) => (1.0 - (position - 3) as f32 / (VERTEX_CACHE_SIZE - 3) as f32).powf(1.5),
+        None => 0.0,
+    };
+    cache_score + 2.0 * (live_triangles as f32).powf(-0.5)
+}
+
+/// Vertices of each triangle missing a FIFO cache, as a GPU would transform them
+fn simulate_fifo_cache(indices: &[u32]) -> Vec<u32> {
+    let mut cache = VecDeque::with_capacity(SIMULATED_CACHE_SIZE);
+    indices
+        .chunks_exact(3)
+        .map(|corners| {
+            let mut misses = 0;
+            for &index in corners {
+                if !cache.contains(&index) {
+                    misses += 1;
+                    if cache.len() == SIMULATED_CACHE_SIZE {
+                        cache.pop_front();
+                    }
+                    cache.push_back(index);
+                }
+            }
+            misses
+        })
+        .collect()
+}
+
+/// Ranges of triangles starting where none of a triangle's vertices are in the cache
+fn cold_cache_clusters(indices: &[u32]) -> Vec<Range<usize>> {
+    let misses = simulate_fifo_cache(indices);
+    let mut starts = misses
+        .iter()
+        .enumerate()
+        .filter(|&(triangle, &misses)| triangle == 0 || misses == 3)
+        .map(|(triangle, _)| triangle)
+        .collect::<Vec<_>>();
+    starts.push(misses.len());
+    starts.windows(2).map(|pair| pair[0]..pair[1]).collect()
+}
+
+struct TangentGeometry<'a> {
+    vertices: &'a mut [Vertex],
+    triangles: &'a [[u32; 3]],
+}
+
+impl TangentGeometry<'_> {
+    fn vertex(&self, face: usize, vert: usize) -> &Vertex {
+        &self.vertices[self.triangles[face][vert] as usize]
+    }
+}
+
+impl bevy_mikktspace::Geometry for TangentGeometry<'_> {
+    fn num_faces(&self) -> usize {
+        self.triangles.len()
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).position.to_array()
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).normal.to_array()
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        self.vertex(face, vert).texcoord.to_array()
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.vertices[self.triangles[face][vert] as usize].tangent = tangent.into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec2;
+
+    /// Triangles by their vertices, each rotated to start at its smallest index so triangles
+    /// compare equal whichever corner they start at, but not if their winding flipped
+    fn sorted_triangles(mesh: &Mesh) -> Vec<[[u32; 8]; 3]> {
+        let mut triangles = mesh
+            .triangles()
+            .into_iter()
+            .map(|triangle| {
+                let mut corners = triangle.map(|index| {
+                    let vertex = mesh.vertices[index as usize];
+                    [
+                        vertex.position.to_array().as_slice(),
+                        &vertex.normal.to_array(),
+                        &vertex.texcoord.to_array(),
+                    ]
+                    .concat()
+                    .try_into()
+                    .map(|floats: [f32; 8]| floats.map(f32::to_bits))
+                    .unwrap()
+                });
+                let first = (0..3).min_by_key(|&i| corners[i]).unwrap();
+                corners.rotate_left(first);
+                corners
+            })
+            .collect::<Vec<_>>();
+        triangles.sort();
+        triangles
+    }
+
+    fn acmr(mesh: &Mesh) -> f32 {
+        let misses = simulate_fifo_cache(mesh.indices.as_ref().unwrap());
+        misses.iter().sum::<u32>() as f32 / misses.len() as f32
+    }
+
+    /// Deterministically scrambled triangle order
+    fn shuffle_triangles(mesh: &mut Mesh) {
+        let mut triangles = mesh.triangles();
+        let mut state = 0x2545_f491_u32;
+        for i in (1..triangles.len()).rev() {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            triangles.swap(i, state as usize % (i + 1));
+        }
+        mesh.indices = Some(triangles.concat());
+    }
+
+    #[test]
+    fn smooth_normals_are_shared_across_split_vertices() {
+        let mut cube = Mesh::new_cube(2.0);
+        cube.compute_smooth_normals();
+        for vertex in &cube.vertices {
+            assert!(
+                vertex
+                    .normal
+                    .abs_diff_eq(vertex.position / 3f32.sqrt(), 1e-6)
+            );
+        }
+    }
+
+    #[test]
+    fn flat_normals_split_every_triangle() {
+        let mut sphere = Mesh::new_uv_sphere(1.0, 8, 4);
+        let triangle_count = sphere.triangles().len();
+        sphere.compute_flat_normals();
+        assert_eq!(sphere.vertices.len(), triangle_count * 3);
+        for triangle in sphere.triangles() {
+            let normal = sphere.weighted_normal(triangle).normalize();
+            for index in triangle {
+                assert_eq!(sphere.vertices[index as usize].normal, normal);
+            }
+        }
+    }
+
+    #[test]
+    fn tangents_follow_texcoords() {
+        let mut plane = Mesh::new_plane(2.0, 2.0, 2, 2);
+        plane.compute_tangents().unwrap();
+        for vertex in &plane.vertices {
+            assert!(vertex.tangent.truncate().abs_diff_eq(Vec3::X, 1e-5));
+            // Texcoord v grows along Z, which the bitangent must point along
+            let bitangent = vertex.normal.cross(vertex.tangent.truncate()) * vertex.tangent.w;
+            assert!(bitangent.abs_diff_eq(Vec3::Z, 1e-5));
+        }
+    }
+
+    #[test]
+    fn bounds() {
+        let mut cylinder = Mesh::new_cylinder(1.0, 4.0, 16);
+        for vertex in &mut cylinder.vertices {
+            vertex.position += Vec3::new(1.0, 2.0, 3.0);
+        }
+        let aabb = cylinder.aabb();
+        assert!(aabb.min.abs_diff_eq(Vec3::new(0.0, 0.0, 2.0), 1e-5));
+        assert!(aabb.max.abs_diff_eq(Vec3::new(2.0, 4.0, 4.0), 1e-5));
+
+        let sphere = cylinder.bounding_sphere();
+        assert!(sphere.center.abs_diff_eq(Vec3::new(1.0, 2.0, 3.0), 1e-5));
+        assert!((sphere.radius - 5f32.sqrt()).abs() < 1e-5);
+        assert!(cylinder.vertices.iter().all(|vertex| {
+            aabb.contains(vertex.position)
+                && vertex.position.distance(sphere.center) <= sphere.radius + 1e-5
+        }));
+
+        assert_eq!(Mesh::new(Vec::new(), None).aabb(), Aabb::default());
+    }
+
+    #[test]
+    fn welding_merges_identical_vertices() {
+        let cube = Mesh::new_cube(1.0);
+        let triangles = sorted_triangles(&cube);
+
+        // One vertex per corner of every triangle, without indices
+        let mut unindexed = Mesh::new(
+            cube.triangles()
+                .concat()
+                .iter()
+                .map(|&index| cube.vertices[index as usize])
+                .collect(),
+            None,
+        );
+        unindexed.weld(0.0);
+        assert_eq!(unindexed.vertices.len(), 24);
+        assert_eq!(sorted_triangles(&unindexed), triangles);
+
+        // Within the tolerance, corners that only differ in their normals and texcoords merge
+        let mut nudged = Mesh::new_cube(1.0);
+        for vertex in &mut nudged.vertices {
+            vertex.position += Vec3::splat(vertex.normal.x * 1e-4);
+            vertex.normal = Vec3::Y;
+            vertex.texcoord = Vec2::ZERO;
+        }
+        nudged.weld(1e-2);
+        assert_eq!(nudged.vertices.len(), 8);
+        assert_eq!(nudged.triangles().len(), 12);
+    }
+
+    #[test]
+    fn vertex_cache_order_reuses_vertices() {
+        let mut plane = Mesh::new_plane(1.0, 1.0, 32, 32);
+        shuffle_triangles(&mut plane);
+        let triangles = sorted_triangles(&plane);
+        let shuffled_acmr = acmr(&plane);
+
+        plane.optimize_vertex_cache();
+        assert_eq!(sorted_triangles(&plane), triangles);
+        // Each vertex of a grid is shared by up to 6 triangles, so it could approach 0.5
+        let optimized_acmr = acmr(&plane);
+        assert!(shuffled_acmr > 2.0, "{}", shuffled_acmr);
+        assert!(optimized_acmr < 0.8, "{}", optimized_acmr);
+    }
+
+    #[test]
+    fn overdraw_order_draws_outer_shells_first() {
+        let inner = Mesh::new_cube(1.0);
+        let outer = Mesh::new_cube(4.0);
+        let offset = inner.vertices.len() as u32;
+        let mut nested = Mesh::new(
+            [inner.vertices.as_slice(), &outer.vertices].concat(),
+            Some(
+                inner
+                    .indices
+                    .clone()
+                    .unwrap()
+                    .into_iter()
+                    .chain(
+                        outer
+                            .indices
+                            .clone()
+                            .unwrap()
+                            .iter()
+                            .map(|&index| index + offset),
+                    )
+                    .collect(),
+            ),
+        );
+        let triangles = sorted_triangles(&nested);
+
+        nested.optimize_overdraw();
+        assert_eq!(sorted_triangles(&nested), triangles);
+        let first_inner = nested
+            .indices
+            .as_ref()
+            .unwrap()
+            .iter()
+            .position(|&index| index < offset)
+            .unwrap();
+        assert_eq!(first_inner, 36);
+    }
+
+    #[test]
+    fn optimization_keeps_triangles_and_compacts_vertices() {
+        let mut sphere = Mesh::new_uv_sphere(1.0, 24, 12);
+        shuffle_triangles(&mut sphere);
+        let triangles = sorted_triangles(&sphere);
+        let shuffled_acmr = acmr(&sphere);
+
+        sphere.optimize();
+        assert_eq!(sorted_triangles(&sphere), triangles);
+        assert!(acmr(&sphere) < shuffled_acmr / 2.0);
+
+        // Vertices appear in the order the indices first use them, and the poles' spare
+        // seam vertices are gone
+        let indices = sphere.indices.as_ref().unwrap();
+        let mut next = 0;
+        for &index in indices {
+            assert!(index <= next);
+            next = next.max(index + 1);
+        }
+        assert_eq!(next as usize, sphere.vertices.len());
+        assert_eq!(sphere.vertices.len(), 25 * 13 - 2);
+    }
+}
@@ -1,10 +1,26 @@
+pub(crate) mod animation;
+pub(crate) mod bounds;
 pub(crate) mod buffer;
+pub(crate) mod cubemap;
+pub(crate) mod gltf_loader;
+pub(crate) mod obj_loader;
+pub(crate) mod procedural;
 pub(crate) mod texture;
 pub(crate) mod material;
+pub(crate) mod material_def;
 pub(crate) mod megabuffer;
 pub(crate) mod mesh;
+pub(crate) mod mesh_processing;
 pub(crate) mod model;
+pub(crate) mod render_target;
 pub(crate) mod shader;
+pub(crate) mod shader_object;
+pub(crate) mod shader_reflection;
+pub(crate) mod shader_variant;
+#[cfg(feature = "hot-reload")]
+pub(crate) mod shader_watcher;
+pub(crate) mod simplify;
+pub(crate) mod skybox;
 pub(crate) mod vertex;
 
 pub(crate) mod resource_type;
@@ -1,7 +1,7 @@
+use super::bounds::{Aabb, BoundingSphere};
 use super::megabuffer::{AllocatedMegabufferRegion, Megabuffer, MegabufferExt};
 use super::mesh::Mesh;
-use crate::resources::vertex::Vertex;
-use crate::storage::shader_data::PerVertexData;
+use crate::resources::vertex::{Vertex, VertexLayout, VertexSemantic};
 use crate::viewport::RenderViewport;
 use color_eyre::eyre::{Result, eyre};
 use glam::Vec3;
@@ -20,7 +20,12 @@ impl FullscreenQuad {
         vpt: &RenderViewport,
     ) -> Result<Self> {
         let quad_mesh = Mesh::new_quad();
-        let quad_model = Model::new(vec![quad_mesh], vertex_megabuffer, index_megabuffer)?;
+        let quad_model = Model::new(
+            vec![quad_mesh],
+            VertexLayout::default(),
+            vertex_megabuffer,
+            index_megabuffer,
+        )?;
         let mut quad = Self {
             quad_model,
             // Assume a square image by default
@@ -66,11 +71,12 @@ impl FullscreenQuad {
             .iter()
             .map(|v| {
                 let p = v.position;
-                let mut vertex = v.as_shader_data();
-                vertex.position = Vec3::new(p[0] * x, p[1] * y, p[2]);
-                vertex
+                Vertex {
+                    position: Vec3::new(p[0] * x, p[1] * y, p[2]),
+                    ..**v
+                }
             })
-            .collect::<Vec<PerVertexData>>();
+            .collect::<Vec<Vertex>>();
         self.quad_model
             .write_vertex_buffer(&vertices_merged, vertex_megabuffer)?;
 
@@ -78,15 +84,44 @@ impl FullscreenQuad {
     }
 }
 
+/// Where one mesh of a model is in the megabuffers, for an indexed draw
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Submesh {
+    /// Into the whole index megabuffer
+    pub first_index: u32,
+    pub index_count: u32,
+    /// Added to every index, since the indices of each mesh start at 0 at its first vertex
+    pub vertex_offset: i32,
+}
+
+/// A coarser index set for every mesh of a model, drawn with the same vertices
+struct Lod {
+    index_megabuffer_region: AllocatedMegabufferRegion,
+    /// One per mesh, in order
+    index_counts: Vec<u32>,
+    /// How far the surface may deviate from the full meshes, in the model's space
+    error: f32,
+}
+
 pub struct Model {
     meshes: Vec<Mesh>,
-    vertex_megabuffer_region: Option<AllocatedMegabufferRegion>,
+    /// How the vertices are packed into `vertex_megabuffer_regions`
+    vertex_layout: VertexLayout,
+    /// One region per stream of the vertex layout
+    vertex_megabuffer_regions: Vec<AllocatedMegabufferRegion>,
     index_megabuffer_region: Option<AllocatedMegabufferRegion>,
+    /// From finest to coarsest, after the full meshes
+    lods: Vec<Lod>,
+    /// Length of the joint palette the vertices index, 0 unless the layout has joints
+    joint_count: u32,
+    aabb: Aabb,
+    bounding_sphere: BoundingSphere,
 }
 
 impl Model {
     pub fn new(
         meshes: Vec<Mesh>,
+        vertex_layout: VertexLayout,
         vertex_megabuffer: &Megabuffer,
         index_megabuffer: &Megabuffer,
     ) -> Result<Self> {
@@ -109,17 +144,13 @@ impl Model {
             ));
         }
 
-        // Collect all vertices from all meshes
+        // Collect all vertices from all meshes and upload each stream of the layout
         let vertices = meshes
             .iter()
-            .flat_map(|m| m.vertices.iter())
-            .map(|v| v.as_shader_data())
-            .collect::<Vec<PerVertexData>>();
-
-        // Upload all vertices to the vertex buffer
-        let vertex_buffer_region_size = (vertices.len() * size_of::<PerVertexData>()) as u64;
-        let vertex_buffer_region = vertex_megabuffer.allocate_region(vertex_buffer_region_size)?;
-        vertex_megabuffer.write(&vertices, &vertex_buffer_region)?;
+            .flat_map(|m| m.vertices.iter().copied())
+            .collect::<Vec<Vertex>>();
+        let vertex_buffer_regions =
+            Self::upload_vertices(&vertices, &vertex_layout, vertex_megabuffer)?;
 
         // Upload all indices to the index buffer if the model has indices
         let index_buffer_region = if has_indices {
@@ -138,32 +169,222 @@ impl Model {
             None
         };
 
+        // Joints without weight may be left at any index
+        let joint_count = if vertex_layout.has(VertexSemantic::Joints) {
+            vertices
+                .iter()
+                .flat_map(|v| v.joints.to_array().into_iter().zip(v.weights.to_array()))
+                .filter(|&(_, weight)| weight > 0.0)
+                .map(|(joint, _)| joint + 1)
+                .max()
+                .unwrap_or(0)
+        } else {
+            0
+        };
+
+        let aabb = Aabb::from_points(vertices.iter().map(|v| v.position));
+        let bounding_sphere = BoundingSphere::from_points(vertices.iter().map(|v| v.position));
+
         Ok(Self {
             meshes,
-            vertex_megabuffer_region: Some(vertex_buffer_region),
+            vertex_layout,
+            vertex_megabuffer_regions: vertex_buffer_regions,
             index_megabuffer_region: index_buffer_region,
+            lods: Vec::new(),
+            joint_count,
+            aabb,
+            bounding_sphere,
         })
     }
 
+    /// Replace the contents of the vertex buffer, packing the vertices with the model's layout
     pub fn write_vertex_buffer(
         &mut self,
-        vertices: &[PerVertexData],
+        vertices: &[Vertex],
+        vertex_megabuffer: &Megabuffer,
+    ) -> Result<()> {
+        for mut region in self.vertex_megabuffer_regions.drain(..) {
+            vertex_megabuffer.deallocate_region(&mut region)?;
+        }
+
+        self.vertex_megabuffer_regions =
+            Self::upload_vertices(vertices, &self.vertex_layout, vertex_megabuffer)?;
+        Ok(())
+    }
+
+    fn upload_vertices(
+        vertices: &[Vertex],
+        vertex_layout: &VertexLayout,
         vertex_megabuffer: &Megabuffer,
+    ) -> Result<Vec<AllocatedMegabufferRegion>> {
+        vertex_layout
+            .pack(vertices)?
+            .iter()
+            .map(|bytes| {
+                let mut region = vertex_megabuffer.allocate_region(bytes.len() as u64)?;
+                region.write(bytes)?;
+                Ok(region)
+            })
+            .collect()
+    }
+
+    /// Index of the model's first vertex in the vertex megabuffer, i.e. `PerDrawData::vertex_offset`
+    /// for shaders using vertex pulling. `None` unless the layout has a single stream,
+    /// whose stride divides the offset of the model's region.
+    pub fn first_vertex(&self) -> Option<u32> {
+        let [region] = self.vertex_megabuffer_regions.as_slice() else {
+            return None;
+        };
+        let stride = u64::from(self.vertex_layout.streams()[0].stride());
+        (region.offset() % stride == 0).then(|| (region.offset() / stride) as u32)
+    }
+
+    /// Byte offset of each stream of the layout in the vertex megabuffer, to bind it at
+    pub fn vertex_buffer_offsets(&self) -> Vec<u64> {
+        self.vertex_megabuffer_regions
+            .iter()
+            .map(|region| region.offset())
+            .collect()
+    }
+
+    /// Add a coarser level of detail, e.g. generated offline, with one index set per mesh in
+    /// order. `error` is how far it deviates from the full meshes in the model's space, and
+    /// may not be less than that of the previous level.
+    pub fn add_lod(
+        &mut self,
+        mesh_indices: &[Vec<u32>],
+        error: f32,
+        index_megabuffer: &Megabuffer,
     ) -> Result<()> {
-        if self.vertex_megabuffer_region.is_none() {
-            return Err(eyre!("Model does not have a vertex buffer region"));
+        if self.index_megabuffer_region.is_none() {
+            return Err(eyre!("Levels of detail need a model with indices"));
+        }
+        if mesh_indices.len() != self.meshes.len() {
+            return Err(eyre!(
+                "Level of detail has {} index sets for {} meshes",
+                mesh_indices.len(),
+                self.meshes.len()
+            ));
         }
+        let previous_error = self.lod_errors().last().copied().unwrap_or_default();
+        if error < previous_error {
+            return Err(eyre!(
+                "Level of detail error {} is less than the previous level's {}",
+                error,
+                previous_error
+            ));
+        }
+
+        let indices = mesh_indices.concat();
+        let index_megabuffer_region =
+            index_megabuffer.allocate_region((indices.len() * size_of::<u32>()) as u64)?;
+        index_megabuffer.write(&indices, &index_megabuffer_region)?;
+        self.lods.push(Lod {
+            index_megabuffer_region,
+            index_counts: mesh_indices
+                .iter()
+                .map(|indices| indices.len() as u32)
+                .collect(),
+            error,
+        });
+        Ok(())
+    }
 
-        vertex_megabuffer.deallocate_region(&mut self.vertex_megabuffer_region.take().unwrap())?;
+    /// Simplify the meshes into up to `count` coarser levels of detail, each with about half
+    /// the indices of the previous one, stopping early once simplifying any further would
+    /// exceed `max_error` or barely removes anything. Models without indices get none.
+    pub fn generate_lods(
+        &mut self,
+        count: usize,
+        max_error: f32,
+        index_megabuffer: &Megabuffer,
+    ) -> Result<()> {
+        if self.index_megabuffer_region.is_none() {
+            return Ok(());
+        }
 
-        let mut vertex_megabuffer_region =
-            vertex_megabuffer.allocate_region(std::mem::size_of_val(vertices) as u64)?;
-        vertex_megabuffer_region.write(vertices)?;
+        let mut previous_index_count = self.get_indices_merged().map_or(0, |indices| indices.len());
+        for level in 1..=count {
+            let (mesh_indices, errors): (Vec<_>, Vec<_>) = self
+                .meshes
+                .iter()
+                .map(|mesh| {
+                    let index_count = mesh.indices.as_ref().map_or(0, Vec::len);
+                    mesh.simplify(index_count >> level, max_error)
+                })
+                .unzip();
+            let index_count = mesh_indices.iter().map(Vec::len).sum::<usize>();
+            // Not worth the memory or the switch for less than a quarter fewer triangles
+            if index_count == 0 || index_count * 4 > previous_index_count * 3 {
+                break;
+            }
+            let error = errors.into_iter().fold(0.0, f32::max);
+            self.add_lod(&mesh_indices, error, index_megabuffer)?;
+            previous_index_count = index_count;
+        }
 
-        self.vertex_megabuffer_region = Some(vertex_megabuffer_region);
+        log::debug!(
+            "Generated {} levels of detail, down to {} indices",
+            self.lods.len(),
+            previous_index_count
+        );
         Ok(())
     }
 
+    /// How far each level of detail deviates from the full meshes, starting with 0 for them
+    pub fn lod_errors(&self) -> Vec<f32> {
+        std::iter::once(0.0)
+            .chain(self.lods.iter().map(|lod| lod.error))
+            .collect()
+    }
+
+    /// One per mesh, in order, with the indices of level of detail `lod` where 0 is the full
+    /// meshes. `None` if the model has no indices or no such level.
+    pub fn submeshes(&self, lod: usize) -> Option<Vec<Submesh>> {
+        let full = self.index_megabuffer_region.as_ref()?;
+        let (region, index_counts) = match lod {
+            0 => (
+                full,
+                self.meshes
+                    .iter()
+                    .map(|mesh| mesh.indices.as_ref().map_or(0, Vec::len) as u32)
+                    .collect(),
+            ),
+            _ => {
+                let lod = self.lods.get(lod - 1)?;
+                (&lod.index_megabuffer_region, lod.index_counts.clone())
+            }
+        };
+
+        let mut first_index = (region.offset() / size_of::<u32>() as u64) as u32;
+        let mut vertex_offset = 0;
+        let submeshes = self
+            .meshes
+            .iter()
+            .zip(index_counts)
+            .map(|(mesh, index_count)| {
+                let submesh = Submesh {
+                    first_index,
+                    index_count,
+                    vertex_offset,
+                };
+                first_index += index_count;
+                vertex_offset += mesh.vertices.len() as i32;
+                submesh
+            })
+            .collect();
+        Some(submeshes)
+    }
+
+    /// Whether the vertices are packed with joints and weights, to be drawn with a palette
+    pub fn is_skinned(&self) -> bool {
+        self.vertex_layout.has(VertexSemantic::Joints)
+    }
+
+    pub fn joint_count(&self) -> u32 {
+        self.joint_count
+    }
+
     pub fn get_vertices_merged(&self) -> Vec<&Vertex> {
         self.meshes.iter().flat_map(|m| m.vertices.iter()).collect()
     }
@@ -184,6 +405,15 @@ impl Model {
     pub fn get_meshes(&self) -> &Vec<Mesh> {
         &self.meshes
     }
+
+    /// Bounds of every mesh, in the model's space
+    pub fn aabb(&self) -> Aabb {
+        self.aabb
+    }
+
+    pub fn bounding_sphere(&self) -> BoundingSphere {
+        self.bounding_sphere
+    }
 }
 
 impl PartialEq for Model {
@@ -0,0 +1,481 @@
+//! Wavefront OBJ and MTL import. Polygons are triangulated as fans and every distinct
+//! position/texcoord/normal triplet becomes one indexed `Vertex`.
+
+use crate::resources::mesh::Mesh;
+use crate::resources::vertex::Vertex;
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+use glam::{Vec2, Vec3};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The MTL parameters the renderer uses
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ObjMaterial {
+    pub name: String,
+    /// `Kd`, baked into the vertex colors of the meshes using the material
+    pub diffuse_color: Vec3,
+    /// `map_Kd`, relative to the working directory
+    pub diffuse_texture: Option<PathBuf>,
+}
+
+impl Default for ObjMaterial {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            diffuse_color: Vec3::ONE,
+            diffuse_texture: None,
+        }
+    }
+}
+
+/// Faces of one object or group that share a material
+#[derive(Debug)]
+pub(crate) struct ObjMesh {
+    /// Name of the `o` or `g` statement the faces belong to
+    pub name: String,
+    /// Index into `ObjImport::materials`
+    pub material: Option<usize>,
+    pub mesh: Mesh,
+}
+
+#[derive(Debug)]
+pub(crate) struct ObjImport {
+    pub meshes: Vec<ObjMesh>,
+    pub materials: Vec<ObjMaterial>,
+}
+
+impl ObjImport {
+    /// Load an OBJ file along with the MTL files it references, which are looked up next to it
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| eyre!("Failed to read OBJ file {:?}: {}", path, e))?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+
+        Self::parse(&source, |mtl_name| {
+            let mtl_path = directory.join(mtl_name);
+            match std::fs::read_to_string(&mtl_path) {
+                Ok(mtl_source) => parse_mtl(&mtl_source, directory),
+                Err(e) => {
+                    log::warn!("Ignoring MTL file {:?} of {:?}: {}", mtl_path, path, e);
+                    Ok(Vec::new())
+                }
+            }
+        })
+        .map_err(|e| eyre!("Failed to parse OBJ file {:?}: {}", path, e))
+    }
+
+    /// `load_mtl` returns the materials of an `mtllib` statement's file
+    pub fn parse(
+        source: &str,
+        mut load_mtl: impl FnMut(&str) -> Result<Vec<ObjMaterial>>,
+    ) -> Result<Self> {
+        let mut positions = Vec::new();
+        let mut texcoords = Vec::new();
+        let mut normals = Vec::new();
+        let mut materials = Vec::<ObjMaterial>::new();
+        let mut meshes = Vec::new();
+        let mut builder = MeshBuilder::new(String::new(), None);
+
+        for (line_index, line) in source.lines().enumerate() {
+            let line_error = |message: String| eyre!("line {}: {}", line_index + 1, message);
+            let mut words = line.split_whitespace();
+            let Some(keyword) = words.next() else {
+                continue;
+            };
+            let rest = words.clone().collect::<Vec<_>>().join(" ");
+
+            match keyword {
+                "v" => positions.push(Vec3::from_array(parse_floats(words).map_err(line_error)?)),
+                "vt" => {
+                    // V points up in OBJ files, but down in Vulkan texture space
+                    let [u, v] = parse_floats(words).map_err(line_error)?;
+                    texcoords.push(Vec2::new(u, 1.0 - v));
+                }
+                "vn" => normals.push(Vec3::from_array(parse_floats(words).map_err(line_error)?)),
+                "f" => {
+                    let corners = words
+                        .map(|corner| {
+                            parse_corner(corner, positions.len(), texcoords.len(), normals.len())
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(line_error)?;
+                    if corners.len() < 3 {
+                        return Err(line_error(format!(
+                            "Face has {} vertices, at least 3 are needed",
+                            corners.len()
+                        )));
+                    }
+                    let color = builder
+                        .material
+                        .map_or(Vec3::ONE, |index| materials[index].diffuse_color);
+                    builder.add_polygon(&corners, &positions, &texcoords, &normals, color);
+                }
+                "o" | "g" => {
+                    let material = builder.material;
+                    let previous =
+                        std::mem::replace(&mut builder, MeshBuilder::new(rest, material));
+                    meshes.extend(previous.build());
+                }
+                "usemtl" => {
+                    let material = materials.iter().position(|material| material.name == rest);
+                    if material.is_none() {
+                        log::warn!("Line {}: unknown material {:?}", line_index + 1, rest);
+                    }
+                    // Meshes have a single material, so a new one starts with the same name
+                    let name = builder.name.clone();
+                    let previous =
+                        std::mem::replace(&mut builder, MeshBuilder::new(name, material));
+                    meshes.extend(previous.build());
+                }
+                "mtllib" => materials.extend(load_mtl(&rest)?),
+                // Smoothing groups, lines, points and free-form geometry are not needed
+                _ => {}
+            }
+        }
+        meshes.extend(builder.build());
+
+        if meshes.is_empty() {
+            return Err(eyre!("OBJ file has no faces"));
+        }
+        Ok(Self { meshes, materials })
+    }
+}
+
+/// Parse an MTL file, resolving texture paths relative to `directory`
+pub(crate) fn parse_mtl(source: &str, directory: &Path) -> Result<Vec<ObjMaterial>> {
+    let mut materials = Vec::<ObjMaterial>::new();
+
+    for (line_index, line) in source.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        let Some(keyword) = words.next() else {
+            continue;
+        };
+        let rest = words.clone().collect::<Vec<_>>().join(" ");
+
+        if keyword == "newmtl" {
+            materials.push(ObjMaterial {
+                name: rest,
+                ..Default::default()
+            });
+            continue;
+        }
+        let Some(material) = materials.last_mut() else {
+            continue;
+        };
+        match keyword {
+            "Kd" => {
+                material.diffuse_color = Vec3::from_array(
+                    parse_floats(words)
+                        .map_err(|message| eyre!("line {}: {}", line_index + 1, message))?,
+                )
+            }
+            // Options like `-s 1 1 1` come before the file name, which is assumed to have no spaces
+            "map_Kd" => {
+                material.diffuse_texture = words.next_back().map(|file| directory.join(file))
+            }
+            _ => {}
+        }
+    }
+    Ok(materials)
+}
+
+fn parse_floats<'a, const N: usize>(
+    mut words: impl Iterator<Item = &'a str>,
+) -> Result<[f32; N], String> {
+    let mut values = [0.0; N];
+    for value in &mut values {
+        let word = words
+            .next()
+            .ok_or_else(|| format!("Expected {} numbers", N))?;
+        *value = word
+            .parse()
+            .map_err(|_| format!("Invalid number {:?}", word))?;
+    }
+    Ok(values)
+}
+
+/// Zero-based indices of a face corner's position, texcoord and normal
+type Corner = (usize, Option<usize>, Option<usize>);
+
+/// Parse `v`, `v/vt`, `v//vn` or `v/vt/vn`, where negative indices count back from the last element
+fn parse_corner(
+    corner: &str,
+    position_count: usize,
+    texcoord_count: usize,
+    normal_count: usize,
+) -> Result<Corner, String> {
+    let resolve = |index: &str, count: usize| -> Result<usize, String> {
+        let index = index
+            .parse::<i64>()
+            .map_err(|_| format!("Invalid index {:?} in {:?}", index, corner))?;
+        let resolved = match index {
+            1.. => index - 1,
+            ..0 => count as i64 + index,
+            0 => -1,
+        };
+        if resolved < 0 || resolved >= count as i64 {
+            return Err(format!("Index {} in {:?} is out of bounds", index, corner));
+        }
+        Ok(resolved as usize)
+    };
+
+    let mut parts = corner.split('/');
+    let position = resolve(parts.next().unwrap_or_default(), position_count)?;
+    let texcoord = match parts.next() {
+        Some("") | None => None,
+        Some(index) => Some(resolve(index, texcoord_count)?),
+    };
+    let normal = match parts.next() {
+        Some("") | None => None,
+        Some(index) => Some(resolve(index, normal_count)?),
+    };
+    Ok((position, texcoord, normal))
+}
+
+struct MeshBuilder {
+    name: String,
+    material: Option<usize>,
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+    /// Index of the vertex created for each distinct corner
+    corner_vertices: HashMap<Corner, u32>,
+    missing_normals: bool,
+    missing_texcoords: bool,
+}
+
+impl MeshBuilder {
+    fn new(name: String, material: Option<usize>) -> Self {
+        Self {
+            name,
+            material,
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            corner_vertices: HashMap::new(),
+            missing_normals: false,
+            missing_texcoords: false,
+        }
+    }
+
+    /// Triangulate the polygon as a fan, which assumes it is convex
+    fn add_polygon(
+        &mut self,
+        corners: &[Corner],
+        positions: &[Vec3],
+        texcoords: &[Vec2],
+        normals: &[Vec3],
+        color: Vec3,
+    ) {
+        let indices = corners
+            .iter()
+            .map(|&corner| {
+                *self.corner_vertices.entry(corner).or_insert_with(|| {
+                    let (position, texcoord, normal) = corner;
+                    self.missing_texcoords |= texcoord.is_none();
+                    self.missing_normals |= normal.is_none();
+                    self.vertices.push(Vertex {
+                        position: positions[position],
+                        normal: normal.map_or(Vec3::Z, |normal| normals[normal]),
+                        color,
+                        texcoord: texcoord.map_or(Vec2::ZERO, |texcoord| texcoords[texcoord]),
+                        ..Default::default()
+                    });
+                    self.vertices.len() as u32 - 1
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for i in 1..indices.len() - 1 {
+            self.indices
+                .extend([indices[0], indices[i], indices[i + 1]]);
+        }
+    }
+
+    /// `None` if no faces were added. Meshes with corners missing normals get smooth normals,
+    /// and meshes with texcoords on every corner get tangents.
+    fn build(self) -> Option<ObjMesh> {
+        if self.indices.is_empty() {
+            return None;
+        }
+        let mut mesh = Mesh::new(self.vertices, Some(self.indices));
+        if self.missing_normals {
+            mesh.compute_smooth_normals();
+        }
+        if !self.missing_texcoords {
+            if let Err(e) = mesh.compute_tangents() {
+                log::warn!("{} of OBJ mesh {}, leaving them zeroed", e, self.name);
+            }
+        }
+        Some(ObjMesh {
+            name: self.name,
+            material: self.material,
+            mesh,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    const DUNGEON_OBJ_DIR: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/../assets/kaykit_dungeon/obj");
+
+    fn parse(source: &str) -> ObjImport {
+        ObjImport::parse(source, |_| {
+            Ok(vec![
+                ObjMaterial {
+                    name: "red".to_owned(),
+                    diffuse_color: Vec3::X,
+                    diffuse_texture: None,
+                },
+                ObjMaterial {
+                    name: "green".to_owned(),
+                    diffuse_color: Vec3::Y,
+                    diffuse_texture: None,
+                },
+            ])
+        })
+        .unwrap()
+    }
+
+    fn dungeon_obj_files() -> Vec<PathBuf> {
+        let mut paths = std::fs::read_dir(DUNGEON_OBJ_DIR)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "obj"))
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn triangulates_polygons_as_fans() {
+        let import = parse(
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv -1 1 0\n\
+             f 1 2 3 4 5\n",
+        );
+        let mesh = &import.meshes[0].mesh;
+        assert_eq!(mesh.vertices.len(), 5);
+        assert_eq!(
+            mesh.indices.as_deref(),
+            Some(&[0, 1, 2, 0, 2, 3, 0, 3, 4][..])
+        );
+    }
+
+    #[test]
+    fn deduplicates_corners_and_resolves_negative_indices() {
+        let import = parse(
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 1\nvn 0 0 1\n\
+             f 1/1/1 2/1/1 3/2/1\n\
+             f -3/-2/-1 -1/-1/-1 3//1\n",
+        );
+        let mesh = &import.meshes[0].mesh;
+        // The second face reuses two corners of the first, and adds one without a texcoord
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices.as_deref(), Some(&[0, 1, 2, 0, 2, 3][..]));
+        assert_eq!(mesh.vertices[0].texcoord, Vec2::new(0.0, 1.0));
+        assert_eq!(mesh.vertices[2].texcoord, Vec2::new(1.0, 0.0));
+        assert_eq!(mesh.vertices[3].normal, Vec3::Z);
+    }
+
+    #[test]
+    fn splits_objects_groups_and_materials() {
+        let import = parse(
+            "mtllib colors.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\n\
+             o first\nusemtl red\nf 1 2 3\nusemtl green\nf 1 2 3\n\
+             g second\nf 3 2 1\n\
+             o empty\n",
+        );
+        let meshes = import
+            .meshes
+            .iter()
+            .map(|mesh| (mesh.name.as_str(), mesh.material))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            meshes,
+            [("first", Some(0)), ("first", Some(1)), ("second", Some(1))]
+        );
+        assert_eq!(import.meshes[0].mesh.vertices[0].color, Vec3::X);
+        assert_eq!(import.meshes[2].mesh.vertices[0].color, Vec3::Y);
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_indices() {
+        let result = ObjImport::parse("v 0 0 0\nv 1 0 0\nf 1 2 3\n", |_| Ok(Vec::new()));
+        assert!(result.unwrap_err().to_string().contains("line 3"));
+    }
+
+    #[test]
+    fn parses_mtl_files() {
+        let materials = parse_mtl(
+            "newmtl texture\nKd 0.5 0.25 1.0\nmap_Kd -s 1 1 1 dungeon_texture.png\n",
+            Path::new("textures"),
+        )
+        .unwrap();
+        assert_eq!(
+            materials,
+            [ObjMaterial {
+                name: "texture".to_owned(),
+                diffuse_color: Vec3::new(0.5, 0.25, 1.0),
+                diffuse_texture: Some(Path::new("textures").join("dungeon_texture.png")),
+            }]
+        );
+    }
+
+    #[test]
+    fn imports_every_dungeon_obj_file() {
+        let paths = dungeon_obj_files();
+        assert!(!paths.is_empty(), "No OBJ files in {}", DUNGEON_OBJ_DIR);
+
+        for path in paths {
+            let source = std::fs::read_to_string(&path).unwrap();
+            let import = ObjImport::load(&path).unwrap();
+
+            // Every polygon with n corners becomes n - 2 triangles
+            let expected_triangles = source
+                .lines()
+                .filter(|line| line.starts_with("f "))
+                .map(|line| line.split_whitespace().count() - 3)
+                .sum::<usize>();
+            let triangles = import
+                .meshes
+                .iter()
+                .map(|mesh| mesh.mesh.indices.as_ref().unwrap().len() / 3)
+                .sum::<usize>();
+            assert_eq!(triangles, expected_triangles, "{:?}", path);
+
+            for ObjMesh { mesh, material, .. } in &import.meshes {
+                let indices = mesh.indices.as_ref().unwrap();
+                assert!(
+                    indices
+                        .iter()
+                        .all(|&index| (index as usize) < mesh.vertices.len()),
+                    "{:?}",
+                    path
+                );
+                // Deduplication leaves every vertex referenced and no two vertices identical
+                let used = indices.iter().collect::<HashSet<_>>();
+                assert_eq!(used.len(), mesh.vertices.len(), "{:?}", path);
+                let distinct = mesh
+                    .vertices
+                    .iter()
+                    .map(|vertex| {
+                        [
+                            vertex.position.to_array(),
+                            vertex.normal.to_array(),
+                            [vertex.texcoord.x, vertex.texcoord.y, 0.0],
+                        ]
+                        .map(|values| values.map(f32::to_bits))
+                    })
+                    .collect::<HashSet<_>>();
+                assert_eq!(distinct.len(), mesh.vertices.len(), "{:?}", path);
+
+                let material = &import.materials[material.expect("Every mesh has a material")];
+                let texture = material.diffuse_texture.as_ref().unwrap();
+                assert!(texture.is_file(), "{:?} of {:?} is missing", texture, path);
+            }
+        }
+    }
+}
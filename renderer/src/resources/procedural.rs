@@ -0,0 +1,501 @@
+//! Meshes generated from a few parameters, for map tiles and debugging.
+//!
+//! Y is up and solids are centered on the origin, except hex prisms which stand on the XZ plane.
+//! Triangles are counter-clockwise seen from outside, which `FrontFace::CLOCKWISE` treats as
+//! front-facing because the projection does not flip Y.
+
+use crate::resources::mesh::Mesh;
+use crate::resources::vertex::Vertex;
+use glam::{Vec2, Vec3};
+use std::f32::consts::{PI, TAU};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HexOrientation {
+    /// Two edges parallel to the X axis
+    #[default]
+    FlatTop,
+    /// Two corners on the Z axis
+    PointyTop,
+}
+
+/// A hexagonal prism standing on the XZ plane, with its top face at `height`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HexPrism {
+    pub orientation: HexOrientation,
+    /// Distance from the center to a corner
+    pub radius: f32,
+    pub height: f32,
+    /// Width of the 45° chamfer around the top face, 0 for sharp edges.
+    /// Clamped to the height and to the distance from the center to an edge.
+    pub bevel: f32,
+}
+
+impl Default for HexPrism {
+    fn default() -> Self {
+        Self {
+            orientation: HexOrientation::FlatTop,
+            radius: 1.0,
+            height: 0.25,
+            bevel: 0.0,
+        }
+    }
+}
+
+impl Mesh {
+    /// Every face is flat shaded, with top and bottom texcoords mapped from XZ
+    /// and side texcoords running around the prism
+    pub fn new_hex_prism(hex: &HexPrism) -> Self {
+        let apothem = hex.radius * (PI / 6.0).cos();
+        let bevel = hex.bevel.clamp(0.0, hex.height.min(apothem));
+        let first_angle = match hex.orientation {
+            HexOrientation::FlatTop => 0.0,
+            HexOrientation::PointyTop => PI / 6.0,
+        };
+        let directions = (0..6)
+            .map(|i| {
+                let angle = first_angle + i as f32 * PI / 3.0;
+                Vec3::new(angle.cos(), 0.0, angle.sin())
+            })
+            .collect::<Vec<_>>();
+        let ring = |radius: f32, y: f32| {
+            directions
+                .iter()
+                .map(|&direction| direction * radius + Vec3::Y * y)
+                .collect::<Vec<_>>()
+        };
+        let planar_texcoord = |position: Vec3| {
+            Vec2::new(position.x, position.z) / (2.0 * hex.radius) + Vec2::splat(0.5)
+        };
+        let side_texcoord = |corner: usize, y: f32| {
+            Vec2::new(corner as f32 / 6.0, 1.0 - y / hex.height.max(f32::EPSILON))
+        };
+
+        let top = ring(hex.radius - bevel / (PI / 6.0).cos(), hex.height);
+        let shoulder = ring(hex.radius, hex.height - bevel);
+        let bottom = ring(hex.radius, 0.0);
+
+        let mut builder = MeshBuilder::default();
+        builder.face(
+            &top.iter()
+                .map(|&position| vertex(position, Vec3::Y, planar_texcoord(position)))
+                .collect::<Vec<_>>(),
+        );
+        builder.face(
+            &bottom
+                .iter()
+                .map(|&position| vertex(position, Vec3::NEG_Y, planar_texcoord(position)))
+                .collect::<Vec<_>>(),
+        );
+        for i in 0..6 {
+            let j = (i + 1) % 6;
+            let outward = (directions[i] + directions[j]).normalize();
+            if bevel > 0.0 {
+                let normal = (outward + Vec3::Y).normalize();
+                builder.face(&[
+                    vertex(top[i], normal, side_texcoord(i, hex.height)),
+                    vertex(top[j], normal, side_texcoord(i + 1, hex.height)),
+                    vertex(shoulder[j], normal, side_texcoord(i + 1, shoulder[j].y)),
+                    vertex(shoulder[i], normal, side_texcoord(i, shoulder[i].y)),
+                ]);
+            }
+            builder.face(&[
+                vertex(shoulder[i], outward, side_texcoord(i, shoulder[i].y)),
+                vertex(shoulder[j], outward, side_texcoord(i + 1, shoulder[j].y)),
+                vertex(bottom[j], outward, side_texcoord(i + 1, 0.0)),
+                vertex(bottom[i], outward, side_texcoord(i, 0.0)),
+            ]);
+        }
+        builder.build()
+    }
+
+    /// Each face has its own vertices and the whole texture
+    pub fn new_cube(size: f32) -> Self {
+        let half = size / 2.0;
+        let mut builder = MeshBuilder::default();
+        for normal in [
+            Vec3::X,
+            Vec3::NEG_X,
+            Vec3::Y,
+            Vec3::NEG_Y,
+            Vec3::Z,
+            Vec3::NEG_Z,
+        ] {
+            // Two axes spanning the face, so corners go around it
+            let u = normal.any_orthonormal_vector();
+            let v = normal.cross(u);
+            let corners = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].map(|(a, b)| {
+                vertex(
+                    (normal + u * a + v * b) * half,
+                    normal,
+                    Vec2::new((a + 1.0) / 2.0, (1.0 - b) / 2.0),
+                )
+            });
+            builder.face(&corners);
+        }
+        builder.build()
+    }
+
+    /// `segments` around the Y axis (at least 3) and `rings` from pole to pole (at least 2).
+    /// The texture wraps around once, with a seam of duplicated vertices.
+    pub fn new_uv_sphere(radius: f32, segments: u32, rings: u32) -> Self {
+        let segments = segments.max(3);
+        let rings = rings.max(2);
+
+        let mut builder = MeshBuilder::default();
+        for ring in 0..=rings {
+            let polar = ring as f32 / rings as f32 * PI;
+            for segment in 0..=segments {
+                // The seam reuses the angle of the first segment so positions match exactly
+                let azimuth = (segment % segments) as f32 / segments as f32 * TAU;
+                let normal = Vec3::new(
+                    polar.sin() * azimuth.cos(),
+                    polar.cos(),
+                    polar.sin() * azimuth.sin(),
+                );
+                builder.vertices.push(vertex(
+                    normal * radius,
+                    normal,
+                    Vec2::new(segment as f32 / segments as f32, ring as f32 / rings as f32),
+                ));
+            }
+        }
+        builder.grid(segments + 1, rings);
+        builder.build()
+    }
+
+    /// Centered on the origin along the Y axis, with smooth sides and flat caps.
+    /// `segments` is at least 3.
+    pub fn new_cylinder(radius: f32, height: f32, segments: u32) -> Self {
+        let segments = segments.max(3);
+        let half_height = height / 2.0;
+        let directions = (0..=segments)
+            .map(|segment| {
+                let angle = (segment % segments) as f32 / segments as f32 * TAU;
+                Vec3::new(angle.cos(), 0.0, angle.sin())
+            })
+            .collect::<Vec<_>>();
+
+        let mut builder = MeshBuilder::default();
+        for (row, y) in [half_height, -half_height].into_iter().enumerate() {
+            for (segment, &direction) in directions.iter().enumerate() {
+                builder.vertices.push(vertex(
+                    direction * radius + Vec3::Y * y,
+                    direction,
+                    Vec2::new(segment as f32 / segments as f32, row as f32),
+                ));
+            }
+        }
+        builder.grid(segments + 1, 1);
+
+        for (normal, y) in [(Vec3::Y, half_height), (Vec3::NEG_Y, -half_height)] {
+            let cap = directions[..segments as usize]
+                .iter()
+                .map(|&direction| {
+                    let texcoord = Vec2::new(direction.x, direction.z) / 2.0 + Vec2::splat(0.5);
+                    vertex(direction * radius + Vec3::Y * y, normal, texcoord)
+                })
+                .collect::<Vec<_>>();
+            builder.face(&cap);
+        }
+        builder.build()
+    }
+
+    /// A plane on XZ facing up, split into `subdivisions_x` by `subdivisions_z` quads (at least 1)
+    pub fn new_plane(width: f32, depth: f32, subdivisions_x: u32, subdivisions_z: u32) -> Self {
+        let subdivisions_x = subdivisions_x.max(1);
+        let subdivisions_z = subdivisions_z.max(1);
+
+        let mut builder = MeshBuilder::default();
+        for z in 0..=subdivisions_z {
+            for x in 0..=subdivisions_x {
+                let texcoord = Vec2::new(
+                    x as f32 / subdivisions_x as f32,
+                    z as f32 / subdivisions_z as f32,
+                );
+                let position =
+                    Vec3::new((texcoord.x - 0.5) * width, 0.0, (texcoord.y - 0.5) * depth);
+                builder.vertices.push(vertex(position, Vec3::Y, texcoord));
+            }
+        }
+        builder.grid(subdivisions_x + 1, subdivisions_z);
+        builder.build()
+    }
+
+    /// Lines on XZ bounding `cells` by `cells` squares of `cell_size`, centered on the origin.
+    /// The indices are pairs for materials drawing `LINE_LIST` topology.
+    pub fn new_wire_grid(cell_size: f32, cells: u32) -> Self {
+        let half = cell_size * cells as f32 / 2.0;
+        let mut vertices = Vec::new();
+        for line in 0..=cells {
+            let offset = line as f32 * cell_size - half;
+            let t = line as f32 / cells.max(1) as f32;
+            vertices.extend([
+                vertex(Vec3::new(offset, 0.0, -half), Vec3::Y, Vec2::new(t, 0.0)),
+                vertex(Vec3::new(offset, 0.0, half), Vec3::Y, Vec2::new(t, 1.0)),
+                vertex(Vec3::new(-half, 0.0, offset), Vec3::Y, Vec2::new(0.0, t)),
+                vertex(Vec3::new(half, 0.0, offset), Vec3::Y, Vec2::new(1.0, t)),
+            ]);
+        }
+        let indices = (0..vertices.len() as u32).collect();
+        Mesh::new(vertices, Some(indices))
+    }
+}
+
+fn vertex(position: Vec3, normal: Vec3, texcoord: Vec2) -> Vertex {
+    Vertex {
+        position,
+        normal,
+        color: Vec3::ONE,
+        texcoord,
+        ..Default::default()
+    }
+}
+
+#[derive(Default)]
+struct MeshBuilder {
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+}
+
+impl MeshBuilder {
+    /// Add a convex polygon with its own vertices, with corners in order around it
+    fn face(&mut self, corners: &[Vertex]) {
+        let first = self.vertices.len() as u32;
+        self.vertices.extend_from_slice(corners);
+        for i in 1..corners.len() as u32 - 1 {
+            self.triangle(first, first + i, first + i + 1);
+        }
+    }
+
+    /// Connect rows of `row_length` vertices already added, one after the other
+    fn grid(&mut self, row_length: u32, row_count: u32) {
+        let first = self.vertices.len() as u32 - row_length * (row_count + 1);
+        for row in 0..row_count {
+            for column in 0..row_length - 1 {
+                let a = first + row * row_length + column;
+                let b = a + 1;
+                let c = b + row_length;
+                let d = a + row_length;
+                self.triangle(a, b, c);
+                self.triangle(a, c, d);
+            }
+        }
+    }
+
+    /// Ordered to face the way the vertex normals point. Degenerate triangles, like the ones
+    /// at the poles of spheres, are dropped.
+    fn triangle(&mut self, a: u32, b: u32, c: u32) {
+        let [pa, pb, pc] = [a, b, c].map(|i| self.vertices[i as usize].position);
+        let face_normal = (pb - pa).cross(pc - pa);
+        if face_normal.length_squared() <= f32::EPSILON * f32::EPSILON {
+            return;
+        }
+
+        let vertex_normals = [a, b, c]
+            .map(|i| self.vertices[i as usize].normal)
+            .into_iter()
+            .sum::<Vec3>();
+        if face_normal.dot(vertex_normals) >= 0.0 {
+            self.indices.extend([a, b, c]);
+        } else {
+            self.indices.extend([a, c, b]);
+        }
+    }
+
+    fn build(self) -> Mesh {
+        Mesh::new(self.vertices, Some(self.indices))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn triangles(mesh: &Mesh) -> Vec<[Vertex; 3]> {
+        mesh.indices
+            .as_ref()
+            .unwrap()
+            .chunks_exact(3)
+            .map(|triangle| {
+                [triangle[0], triangle[1], triangle[2]].map(|i| mesh.vertices[i as usize])
+            })
+            .collect()
+    }
+
+    /// Every triangle faces the way its vertex normals point, which is the front face
+    /// for `FrontFace::CLOCKWISE`, and every normal has unit length
+    fn assert_wound_along_normals(mesh: &Mesh) {
+        for [a, b, c] in triangles(mesh) {
+            let face_normal = (b.position - a.position)
+                .cross(c.position - a.position)
+                .normalize();
+            for vertex in [a, b, c] {
+                assert!((vertex.normal.length() - 1.0).abs() < 1e-5);
+                assert!(
+                    face_normal.dot(vertex.normal) > 0.0,
+                    "Triangle {:?} faces {} but has normal {}",
+                    [a.position, b.position, c.position],
+                    face_normal,
+                    vertex.normal
+                );
+            }
+        }
+    }
+
+    /// Every edge of a closed surface is shared by exactly two triangles running it in opposite
+    /// directions, so the winding is consistent and there are no holes
+    fn assert_closed(mesh: &Mesh) {
+        let key = |position: Vec3| (position * 1e4).round().as_ivec3().to_array();
+        let mut edges = HashMap::<_, i32>::new();
+        for [a, b, c] in triangles(mesh) {
+            for (from, to) in [(a, b), (b, c), (c, a)] {
+                let (from, to) = (key(from.position), key(to.position));
+                if from < to {
+                    *edges.entry((from, to)).or_default() += 1;
+                } else {
+                    *edges.entry((to, from)).or_default() -= 1;
+                }
+            }
+        }
+        assert!(edges.values().all(|&count| count == 0), "{:?}", edges);
+    }
+
+    fn triangle_count(mesh: &Mesh) -> usize {
+        mesh.indices.as_ref().unwrap().len() / 3
+    }
+
+    #[test]
+    fn hex_prisms() {
+        for orientation in [HexOrientation::FlatTop, HexOrientation::PointyTop] {
+            let sharp = Mesh::new_hex_prism(&HexPrism {
+                orientation,
+                ..Default::default()
+            });
+            assert_eq!(sharp.vertices.len(), 6 + 6 + 6 * 4);
+            assert_eq!(triangle_count(&sharp), 4 + 4 + 6 * 2);
+            assert_wound_along_normals(&sharp);
+            assert_closed(&sharp);
+
+            let beveled = Mesh::new_hex_prism(&HexPrism {
+                orientation,
+                radius: 2.0,
+                height: 0.5,
+                bevel: 0.1,
+            });
+            assert_eq!(beveled.vertices.len(), 6 + 6 + 2 * 6 * 4);
+            assert_eq!(triangle_count(&beveled), 4 + 4 + 2 * 6 * 2);
+            assert_wound_along_normals(&beveled);
+            assert_closed(&beveled);
+
+            let top = beveled
+                .vertices
+                .iter()
+                .filter(|vertex| vertex.normal == Vec3::Y)
+                .map(|vertex| vertex.position)
+                .collect::<Vec<_>>();
+            assert!(top.iter().all(|position| position.y == 0.5));
+            // The chamfer insets the top edges by the bevel width
+            let inset_apothem = 2.0 * (PI / 6.0).cos() - 0.1;
+            assert!(top.iter().all(|position| {
+                (position.with_y(0.0).length() - inset_apothem / (PI / 6.0).cos()).abs() < 1e-5
+            }));
+        }
+
+        let corner_x = |orientation| {
+            Mesh::new_hex_prism(&HexPrism {
+                orientation,
+                ..Default::default()
+            })
+            .vertices
+            .iter()
+            .map(|vertex| vertex.position.x)
+            .fold(f32::MIN, f32::max)
+        };
+        assert!((corner_x(HexOrientation::FlatTop) - 1.0).abs() < 1e-6);
+        assert!((corner_x(HexOrientation::PointyTop) - (PI / 6.0).cos()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn oversized_bevels_are_clamped() {
+        let mesh = Mesh::new_hex_prism(&HexPrism {
+            bevel: 10.0,
+            ..Default::default()
+        });
+        assert!(mesh.vertices.iter().all(|vertex| vertex.position.y >= 0.0));
+        assert_wound_along_normals(&mesh);
+    }
+
+    #[test]
+    fn cubes() {
+        let mesh = Mesh::new_cube(2.0);
+        assert_eq!(mesh.vertices.len(), 24);
+        assert_eq!(triangle_count(&mesh), 12);
+        assert_wound_along_normals(&mesh);
+        assert_closed(&mesh);
+        assert!(
+            mesh.vertices
+                .iter()
+                .all(|vertex| vertex.position.abs().max_element() == 1.0
+                    && vertex.position.dot(vertex.normal) == 1.0)
+        );
+    }
+
+    #[test]
+    fn uv_spheres() {
+        let mesh = Mesh::new_uv_sphere(0.5, 16, 8);
+        assert_eq!(mesh.vertices.len(), 17 * 9);
+        // One triangle per segment at each pole, and two per segment on the rings between
+        assert_eq!(triangle_count(&mesh), 16 * 2 * (8 - 1));
+        assert_wound_along_normals(&mesh);
+        assert_closed(&mesh);
+        for vertex in &mesh.vertices {
+            assert!((vertex.position.length() - 0.5).abs() < 1e-6);
+            assert!(vertex.normal.abs_diff_eq(vertex.position * 2.0, 1e-6));
+        }
+    }
+
+    #[test]
+    fn cylinders() {
+        let mesh = Mesh::new_cylinder(1.0, 2.0, 12);
+        assert_eq!(mesh.vertices.len(), 2 * 13 + 2 * 12);
+        assert_eq!(triangle_count(&mesh), 2 * 12 + 2 * (12 - 2));
+        assert_wound_along_normals(&mesh);
+        assert_closed(&mesh);
+        assert!(
+            mesh.vertices
+                .iter()
+                .filter(|vertex| vertex.normal.y == 0.0)
+                .all(|vertex| vertex.position.y.abs() == 1.0
+                    && (vertex.position.with_y(0.0) - vertex.normal).length() < 1e-6)
+        );
+    }
+
+    #[test]
+    fn planes() {
+        let mesh = Mesh::new_plane(4.0, 2.0, 4, 3);
+        assert_eq!(mesh.vertices.len(), 5 * 4);
+        assert_eq!(triangle_count(&mesh), 4 * 3 * 2);
+        assert_wound_along_normals(&mesh);
+
+        let (min, max) = mesh.vertices.iter().fold(
+            (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+            |(min, max), vertex| (min.min(vertex.position), max.max(vertex.position)),
+        );
+        assert_eq!(min, Vec3::new(-2.0, 0.0, -1.0));
+        assert_eq!(max, Vec3::new(2.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn wire_grids() {
+        let mesh = Mesh::new_wire_grid(1.0, 4);
+        let indices = mesh.indices.as_ref().unwrap();
+        assert_eq!(mesh.vertices.len(), 5 * 4);
+        assert_eq!(indices.len() % 2, 0);
+        for line in indices.chunks_exact(2) {
+            let [a, b] = [line[0], line[1]].map(|i| mesh.vertices[i as usize].position);
+            assert_eq!((b - a).length(), 4.0);
+            assert!(a.x == b.x || a.z == b.z);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use ash::vk;
+
+/// The set of attachment formats a graphics pipeline is compiled against.
+/// A pipeline can only be used inside a rendering scope whose attachments match exactly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct RenderTargetFormats {
+    pub color: vk::Format,
+    pub depth: vk::Format,
+    pub samples: vk::SampleCountFlags,
+}
+
+impl Default for RenderTargetFormats {
+    fn default() -> Self {
+        Self {
+            color: vk::Format::R8G8B8A8_SRGB,
+            depth: vk::Format::D32_SFLOAT,
+            samples: vk::SampleCountFlags::TYPE_1,
+        }
+    }
+}
+
+impl RenderTargetFormats {
+    pub fn is_multisampled(&self) -> bool {
+        self.samples != vk::SampleCountFlags::TYPE_1
+    }
+
+    pub fn has_stencil(&self) -> bool {
+        format_has_stencil(self.depth)
+    }
+
+    /// `UNDEFINED` when the depth format has no stencil component
+    pub fn stencil(&self) -> vk::Format {
+        if self.has_stencil() {
+            self.depth
+        } else {
+            vk::Format::UNDEFINED
+        }
+    }
+
+    pub fn depth_attachment_layout(&self) -> vk::ImageLayout {
+        if self.has_stencil() {
+            vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+        } else {
+            vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL
+        }
+    }
+}
+
+pub(crate) fn format_has_stencil(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::S8_UINT
+            | vk::Format::D16_UNORM_S8_UINT
+            | vk::Format::D24_UNORM_S8_UINT
+            | vk::Format::D32_SFLOAT_S8_UINT
+    )
+}
+
+/// Depth-stencil attachments have to be viewed with both aspects
+pub(crate) fn depth_format_aspect(format: vk::Format) -> vk::ImageAspectFlags {
+    if format_has_stencil(format) {
+        vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+    } else {
+        vk::ImageAspectFlags::DEPTH
+    }
+}
@@ -1,52 +1,248 @@
+use crate::resources::shader_reflection::ShaderReflection;
+use crate::resources::shader_variant::ShaderVariant;
 use ash::vk;
 use color_eyre::Result;
-use color_eyre::eyre::OptionExt;
+use color_eyre::eyre::{OptionExt, eyre};
 use rust_embed::RustEmbed;
-use std::path::Path;
+use std::ffi::CString;
 use std::sync::Arc;
+#[cfg(feature = "hot-reload")]
+use {
+    crate::utils::GuardResultExt,
+    std::collections::HashMap,
+    std::sync::{LazyLock, RwLock},
+};
 
 #[derive(RustEmbed)]
 #[folder = "shaders-built/"]
 struct ShadersEmbed;
 
+/// SPIR-V recompiled while running, keyed like the embedded files, e.g. `default+ALPHA_TEST.frag.spv`.
+/// It takes precedence over the code embedded at build time.
+#[cfg(feature = "hot-reload")]
+static RELOADED_SHADER_CODE: LazyLock<RwLock<HashMap<String, Vec<u8>>>> =
+    LazyLock::new(Default::default);
+
+/// File extensions of the graphics stages, in the order the stages run
+const GRAPHICS_STAGE_FILES: [(&str, vk::ShaderStageFlags); 7] = [
+    ("task", vk::ShaderStageFlags::TASK_EXT),
+    ("mesh", vk::ShaderStageFlags::MESH_EXT),
+    ("vert", vk::ShaderStageFlags::VERTEX),
+    ("tesc", vk::ShaderStageFlags::TESSELLATION_CONTROL),
+    ("tese", vk::ShaderStageFlags::TESSELLATION_EVALUATION),
+    ("geom", vk::ShaderStageFlags::GEOMETRY),
+    ("frag", vk::ShaderStageFlags::FRAGMENT),
+];
+
+/// Where the SPIR-V of a shader came from, so that it can be loaded again
+#[cfg(feature = "hot-reload")]
+#[derive(Clone, Copy)]
+enum ShaderSource {
+    /// One GLSL file per stage, each starting at `main`
+    StageFiles,
+    /// A single WGSL file holding the entry points of every stage
+    Wgsl,
+}
+
+pub struct ShaderStage {
+    pub stage: vk::ShaderStageFlags,
+    pub module: vk::ShaderModule,
+    /// Shader objects are created straight from the SPIR-V instead of from the module
+    pub code: Vec<u8>,
+    pub entry_point: CString,
+}
+
 pub struct GraphicsShader {
-    pub vert_mod: vk::ShaderModule,
-    pub frag_mod: vk::ShaderModule,
+    pub name: String,
+    pub variant: ShaderVariant,
+    /// In the order the stages run, e.g. vertex, geometry, fragment
+    pub stages: Vec<ShaderStage>,
+    /// `None` when naga cannot parse the SPIR-V, in which case the layouts are not checked
+    pub reflection: Option<ShaderReflection>,
+    #[cfg(feature = "hot-reload")]
+    source: ShaderSource,
     device: Arc<ash::Device>,
 }
 
 pub struct ComputeShader {
+    pub name: String,
     pub comp_mod: vk::ShaderModule,
+    pub entry_point: CString,
+    pub reflection: Option<ShaderReflection>,
+    #[cfg(feature = "hot-reload")]
+    source: ShaderSource,
     device: Arc<ash::Device>,
 }
 
 impl GraphicsShader {
-    pub fn new(shader_name: &str, device: Arc<ash::Device>) -> Result<Self> {
-        let vert_mod =
-            create_shader_module((&format!("{}.vert.spv", shader_name)).as_ref(), &device)?;
-        let frag_mod =
-            create_shader_module((&format!("{}.frag.spv", shader_name)).as_ref(), &device)?;
-        Ok(Self {
-            vert_mod,
-            frag_mod,
+    /// Load the variant of a GLSL shader compiled with the given keywords, see `shader_variant`.
+    /// Every stage with a file is used, e.g. `default.vert`, `default.geom` and `default.frag`.
+    pub fn new(
+        shader_name: &str,
+        variant: &ShaderVariant,
+        device: Arc<ash::Device>,
+    ) -> Result<Self> {
+        let file_stem = variant.file_stem(shader_name);
+        let mut stage_codes = Vec::new();
+        for (ext, stage) in GRAPHICS_STAGE_FILES {
+            if let Some(code) = find_shader_code(&format!("{}.{}.spv", file_stem, ext))? {
+                stage_codes.push((stage, code, "main"));
+            }
+        }
+
+        let mut shader = Self {
+            name: shader_name.to_owned(),
+            variant: variant.clone(),
+            stages: Vec::new(),
+            reflection: None,
+            #[cfg(feature = "hot-reload")]
+            source: ShaderSource::StageFiles,
             device,
-        })
+        };
+        shader.create_stages(stage_codes)?;
+        Ok(shader)
+    }
+
+    /// Load a WGSL shader, whose stages all start at their own entry point in `<shader_name>.wgsl`
+    pub fn from_wgsl(
+        shader_name: &str,
+        entry_points: &[(vk::ShaderStageFlags, &str)],
+        device: Arc<ash::Device>,
+    ) -> Result<Self> {
+        let code = load_shader_code(&format!("{}.wgsl.spv", shader_name))?;
+        let mut stage_codes = entry_points
+            .iter()
+            .map(|&(stage, entry_point)| (stage, code.clone(), entry_point))
+            .collect::<Vec<_>>();
+        stage_codes.sort_by_key(|(stage, _, _)| stage_order(*stage));
+
+        let mut shader = Self {
+            name: shader_name.to_owned(),
+            variant: ShaderVariant::default(),
+            stages: Vec::new(),
+            reflection: None,
+            #[cfg(feature = "hot-reload")]
+            source: ShaderSource::Wgsl,
+            device,
+        };
+        shader.create_stages(stage_codes)?;
+        Ok(shader)
+    }
+
+    /// Load the shader again from the same files, e.g. after they were recompiled
+    #[cfg(feature = "hot-reload")]
+    pub fn reload(&self) -> Result<Self> {
+        match self.source {
+            ShaderSource::StageFiles => Self::new(&self.name, &self.variant, self.device.clone()),
+            ShaderSource::Wgsl => {
+                let entry_points = self
+                    .stages
+                    .iter()
+                    .map(|stage| Ok((stage.stage, stage.entry_point.to_str()?)))
+                    .collect::<Result<Vec<_>>>()?;
+                Self::from_wgsl(&self.name, &entry_points, self.device.clone())
+            }
+        }
+    }
+
+    /// The shader name followed by its variant keywords, e.g. `default+ALPHA_TEST`
+    pub fn display_name(&self) -> String {
+        self.variant.file_stem(&self.name)
+    }
+
+    pub fn stage_flags(&self) -> vk::ShaderStageFlags {
+        self.stages
+            .iter()
+            .fold(vk::ShaderStageFlags::empty(), |flags, stage| {
+                flags | stage.stage
+            })
+    }
+
+    /// Modules created before a failure are destroyed along with `self`
+    fn create_stages(
+        &mut self,
+        stage_codes: Vec<(vk::ShaderStageFlags, Vec<u8>, &str)>,
+    ) -> Result<()> {
+        let stages = stage_codes
+            .iter()
+            .map(|(stage, _, _)| *stage)
+            .collect::<Vec<_>>();
+        check_graphics_stages(&stages)
+            .map_err(|e| eyre!("Shader {} has invalid stages: {e}", self.display_name()))?;
+
+        for (stage, code, entry_point) in stage_codes {
+            let module = create_shader_module(&code, &self.device)?;
+            self.stages.push(ShaderStage {
+                stage,
+                module,
+                code,
+                entry_point: CString::new(entry_point)?,
+            });
+        }
+        self.reflection = reflect(
+            &self.display_name(),
+            self.stages
+                .iter()
+                .map(|stage| (stage.code.as_slice(), &stage.entry_point)),
+        );
+        Ok(())
     }
 }
 
 impl ComputeShader {
-    pub fn new(shader_name: &str, device: Arc<ash::Device>) -> Result<Self> {
-        let comp_mod =
-            create_shader_module((&format!("{}.comp.spv", shader_name)).as_ref(), &device)?;
-        Ok(Self { comp_mod, device })
+    /// Load a GLSL compute shader starting at `entry_point` in `<shader_name>.comp`
+    pub fn new(shader_name: &str, entry_point: &str, device: Arc<ash::Device>) -> Result<Self> {
+        let comp_code = load_shader_code(&format!("{}.comp.spv", shader_name))?;
+        let entry_point = CString::new(entry_point)?;
+        let reflection = reflect(shader_name, [(comp_code.as_slice(), &entry_point)]);
+        Ok(Self {
+            name: shader_name.to_owned(),
+            comp_mod: create_shader_module(&comp_code, &device)?,
+            entry_point,
+            reflection,
+            #[cfg(feature = "hot-reload")]
+            source: ShaderSource::StageFiles,
+            device,
+        })
+    }
+
+    /// Load a compute shader starting at `entry_point` in `<shader_name>.wgsl`
+    pub fn from_wgsl(
+        shader_name: &str,
+        entry_point: &str,
+        device: Arc<ash::Device>,
+    ) -> Result<Self> {
+        let code = load_shader_code(&format!("{}.wgsl.spv", shader_name))?;
+        let entry_point = CString::new(entry_point)?;
+        let reflection = reflect(shader_name, [(code.as_slice(), &entry_point)]);
+        Ok(Self {
+            name: shader_name.to_owned(),
+            comp_mod: create_shader_module(&code, &device)?,
+            entry_point,
+            reflection,
+            #[cfg(feature = "hot-reload")]
+            source: ShaderSource::Wgsl,
+            device,
+        })
+    }
+
+    /// Load the shader again from the same file, e.g. after it was recompiled
+    #[cfg(feature = "hot-reload")]
+    pub fn reload(&self) -> Result<Self> {
+        let entry_point = self.entry_point.to_str()?;
+        match self.source {
+            ShaderSource::StageFiles => Self::new(&self.name, entry_point, self.device.clone()),
+            ShaderSource::Wgsl => Self::from_wgsl(&self.name, entry_point, self.device.clone()),
+        }
     }
 }
 
 impl Drop for GraphicsShader {
     fn drop(&mut self) {
         unsafe {
-            self.device.destroy_shader_module(self.vert_mod, None);
-            self.device.destroy_shader_module(self.frag_mod, None);
+            for stage in &self.stages {
+                self.device.destroy_shader_module(stage.module, None);
+            }
         }
     }
 }
@@ -59,20 +255,151 @@ impl Drop for ComputeShader {
     }
 }
 
-fn create_shader_module(filepath: &Path, device: &ash::Device) -> Result<vk::ShaderModule> {
-    log::info!("Creating shader module from file: {:?}", filepath);
+/// Position of a stage in `GRAPHICS_STAGE_FILES`, unknown stages go last
+fn stage_order(stage: vk::ShaderStageFlags) -> usize {
+    GRAPHICS_STAGE_FILES
+        .iter()
+        .position(|(_, file_stage)| *file_stage == stage)
+        .unwrap_or(GRAPHICS_STAGE_FILES.len())
+}
 
-    let filepath = filepath.to_str().ok_or_eyre("Invalid shader file path")?;
-    let embedded_file =
-        ShadersEmbed::get(filepath).ok_or_eyre("Shader not found in embedded resources")?;
-    let bytes = embedded_file.data;
+/// A graphics shader is either a vertex pipeline, optionally with tessellation and geometry,
+/// or a mesh pipeline, optionally with a task stage, and always ends in a fragment stage
+fn check_graphics_stages(stages: &[vk::ShaderStageFlags]) -> Result<()> {
+    let mut flags = vk::ShaderStageFlags::empty();
+    for &stage in stages {
+        if stage_order(stage) == GRAPHICS_STAGE_FILES.len() {
+            return Err(eyre!("{stage:?} is not a graphics stage"));
+        }
+        if flags.contains(stage) {
+            return Err(eyre!("{stage:?} appears more than once"));
+        }
+        flags |= stage;
+    }
+
+    let has = |stage| flags.contains(stage);
+    if !has(vk::ShaderStageFlags::FRAGMENT) {
+        return Err(eyre!("there is no fragment stage"));
+    }
+    match (
+        has(vk::ShaderStageFlags::VERTEX),
+        has(vk::ShaderStageFlags::MESH_EXT),
+    ) {
+        (true, true) => return Err(eyre!("vertex and mesh stages cannot be combined")),
+        (false, false) => return Err(eyre!("there is neither a vertex nor a mesh stage")),
+        _ => {}
+    }
+    if has(vk::ShaderStageFlags::TASK_EXT) && !has(vk::ShaderStageFlags::MESH_EXT) {
+        return Err(eyre!("a task stage needs a mesh stage"));
+    }
+    if has(vk::ShaderStageFlags::TESSELLATION_CONTROL)
+        != has(vk::ShaderStageFlags::TESSELLATION_EVALUATION)
+    {
+        return Err(eyre!(
+            "tessellation needs both a control and an evaluation stage"
+        ));
+    }
+    Ok(())
+}
+
+fn load_shader_code(filepath: &str) -> Result<Vec<u8>> {
+    // A missing variant was not declared with `#pragma variants` in the shader
+    find_shader_code(filepath)?
+        .ok_or_eyre(format!("Shader {filepath} not found in embedded resources"))
+}
+
+fn find_shader_code(filepath: &str) -> Result<Option<Vec<u8>>> {
+    #[cfg(feature = "hot-reload")]
+    if let Some(code) = RELOADED_SHADER_CODE.read().eyre()?.get(filepath) {
+        log::info!("Loading recompiled shader code: {:?}", filepath);
+        return Ok(Some(code.clone()));
+    }
+
+    let Some(embedded_file) = ShadersEmbed::get(filepath) else {
+        return Ok(None);
+    };
+    log::info!("Loading shader code from file: {:?}", filepath);
+    let bytes = embedded_file.data.into_owned();
 
     assert_eq!(bytes.len() % 4, 0, "Shader bytecode must be a multiple of 4 bytes");
 
-    let shader_module_info =
-        vk::ShaderModuleCreateInfo::default().code(bytemuck::cast_slice(&bytes));
+    Ok(Some(bytes))
+}
+
+#[cfg(feature = "hot-reload")]
+pub(crate) fn set_reloaded_shader_code(filepath: String, code: Vec<u8>) -> Result<()> {
+    RELOADED_SHADER_CODE.write().eyre()?.insert(filepath, code);
+    Ok(())
+}
+
+fn reflect<'a>(
+    shader_name: &str,
+    stages: impl IntoIterator<Item = (&'a [u8], &'a CString)>,
+) -> Option<ShaderReflection> {
+    stages
+        .into_iter()
+        .try_fold(
+            ShaderReflection::default(),
+            |reflection, (code, entry_point)| {
+                let entry_point = entry_point.to_str()?;
+                Ok(reflection.merge(ShaderReflection::from_spirv(code, entry_point)?))
+            },
+        )
+        .inspect_err(|e: &color_eyre::Report| {
+            log::warn!(
+                "Could not reflect shader {shader_name}, its layouts will not be checked: {e:?}"
+            )
+        })
+        .ok()
+}
+
+fn create_shader_module(code: &[u8], device: &ash::Device) -> Result<vk::ShaderModule> {
+    let shader_module_info = vk::ShaderModuleCreateInfo::default().code(bytemuck::cast_slice(code));
 
     let shader_module = unsafe { device.create_shader_module(&shader_module_info, None)? };
 
     Ok(shader_module)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vertex_and_mesh_pipelines_are_valid() {
+        use vk::ShaderStageFlags as S;
+        assert!(check_graphics_stages(&[S::VERTEX, S::FRAGMENT]).is_ok());
+        assert!(
+            check_graphics_stages(&[
+                S::VERTEX,
+                S::TESSELLATION_CONTROL,
+                S::TESSELLATION_EVALUATION,
+                S::GEOMETRY,
+                S::FRAGMENT,
+            ])
+            .is_ok()
+        );
+        assert!(check_graphics_stages(&[S::MESH_EXT, S::FRAGMENT]).is_ok());
+        assert!(check_graphics_stages(&[S::TASK_EXT, S::MESH_EXT, S::FRAGMENT]).is_ok());
+    }
+
+    #[test]
+    fn invalid_stage_combinations_are_rejected() {
+        use vk::ShaderStageFlags as S;
+        assert!(check_graphics_stages(&[S::VERTEX]).is_err());
+        assert!(check_graphics_stages(&[S::FRAGMENT]).is_err());
+        assert!(check_graphics_stages(&[S::VERTEX, S::MESH_EXT, S::FRAGMENT]).is_err());
+        assert!(check_graphics_stages(&[S::VERTEX, S::TASK_EXT, S::FRAGMENT]).is_err());
+        assert!(check_graphics_stages(&[S::VERTEX, S::TESSELLATION_CONTROL, S::FRAGMENT]).is_err());
+        assert!(check_graphics_stages(&[S::VERTEX, S::FRAGMENT, S::FRAGMENT]).is_err());
+        assert!(check_graphics_stages(&[S::VERTEX, S::COMPUTE, S::FRAGMENT]).is_err());
+    }
+
+    #[test]
+    fn stages_are_ordered_like_the_pipeline() {
+        use vk::ShaderStageFlags as S;
+        let mut stages = vec![S::FRAGMENT, S::GEOMETRY, S::VERTEX];
+        stages.sort_by_key(|stage| stage_order(*stage));
+        assert_eq!(stages, [S::VERTEX, S::GEOMETRY, S::FRAGMENT]);
+    }
+}
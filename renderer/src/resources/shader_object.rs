@@ -0,0 +1,262 @@
+use crate::context::debug_utils::DebugUtils;
+use crate::resources::{shader::GraphicsShader, vertex::VertexInputDescription};
+use ash::vk;
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+use std::sync::Arc;
+
+/// `VK_EXT_shader_object` entry points, only present when the device supports the extension
+#[derive(Clone)]
+pub(crate) struct ShaderObjectBackend {
+    pub loader: ash::ext::shader_object::Device,
+    /// Some state can only be set, and some stages only be unbound, when the matching feature is enabled
+    pub features: vk::PhysicalDeviceFeatures,
+    /// Features of `VK_EXT_mesh_shader`, whose stages have to be unbound as well
+    pub task_shader: bool,
+    pub mesh_shader: bool,
+}
+
+/// Everything a graphics pipeline would bake in, which shader objects have to set at record time
+pub(crate) struct DynamicGraphicsState {
+    pub vertex_bindings: Vec<vk::VertexInputBindingDescription2EXT<'static>>,
+    pub vertex_attributes: Vec<vk::VertexInputAttributeDescription2EXT<'static>>,
+    pub topology: vk::PrimitiveTopology,
+    pub patch_control_points: u32,
+    pub primitive_restart_enable: bool,
+    pub polygon_mode: vk::PolygonMode,
+    pub line_width: f32,
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    pub samples: vk::SampleCountFlags,
+    pub depth_test_enable: bool,
+    pub depth_write_enable: bool,
+    pub depth_compare_op: vk::CompareOp,
+    pub color_blend_attachment: vk::PipelineColorBlendAttachmentState,
+}
+
+impl DynamicGraphicsState {
+    pub fn vertex_input_from(
+        description: &VertexInputDescription,
+    ) -> (
+        Vec<vk::VertexInputBindingDescription2EXT<'static>>,
+        Vec<vk::VertexInputAttributeDescription2EXT<'static>>,
+    ) {
+        let bindings = description
+            .bindings
+            .iter()
+            .map(|binding| {
+                vk::VertexInputBindingDescription2EXT::default()
+                    .binding(binding.binding)
+                    .stride(binding.stride)
+                    .input_rate(binding.input_rate)
+                    .divisor(1)
+            })
+            .collect();
+        let attributes = description
+            .attributes
+            .iter()
+            .map(|attribute| {
+                vk::VertexInputAttributeDescription2EXT::default()
+                    .location(attribute.location)
+                    .binding(attribute.binding)
+                    .format(attribute.format)
+                    .offset(attribute.offset)
+            })
+            .collect();
+        (bindings, attributes)
+    }
+}
+
+/// Linked shader objects for every stage of a shader, the pipeline-less counterpart of a graphics `vk::Pipeline`.
+/// Binding it sets every piece of state the draw depends on, so it does not inherit anything
+/// from whatever was bound before.
+pub(crate) struct ShaderObjectProgram {
+    shaders: Vec<(vk::ShaderStageFlags, vk::ShaderEXT)>,
+    state: DynamicGraphicsState,
+
+    backend: ShaderObjectBackend,
+    device: Arc<ash::Device>,
+}
+
+impl ShaderObjectProgram {
+    pub fn new(
+        shader: &GraphicsShader,
+        set_layouts: &[vk::DescriptorSetLayout],
+        push_constant_ranges: &[vk::PushConstantRange],
+        state: DynamicGraphicsState,
+        backend: ShaderObjectBackend,
+        debug_utils: &DebugUtils,
+        device: Arc<ash::Device>,
+    ) -> Result<Self> {
+        let has_task_stage = shader
+            .stage_flags()
+            .contains(vk::ShaderStageFlags::TASK_EXT);
+        let create_infos = shader
+            .stages
+            .iter()
+            .enumerate()
+            .map(|(i, stage)| {
+                let next_stage = shader
+                    .stages
+                    .get(i + 1)
+                    .map_or(vk::ShaderStageFlags::empty(), |next| next.stage);
+                let mut flags = vk::ShaderCreateFlagsEXT::LINK_STAGE;
+                if stage.stage == vk::ShaderStageFlags::MESH_EXT && !has_task_stage {
+                    flags |= vk::ShaderCreateFlagsEXT::NO_TASK_SHADER;
+                }
+                vk::ShaderCreateInfoEXT::default()
+                    .flags(flags)
+                    .stage(stage.stage)
+                    .next_stage(next_stage)
+                    .code_type(vk::ShaderCodeTypeEXT::SPIRV)
+                    .code(&stage.code)
+                    .name(&stage.entry_point)
+                    .set_layouts(set_layouts)
+                    .push_constant_ranges(push_constant_ranges)
+            })
+            .collect::<Vec<_>>();
+
+        let shaders = unsafe { backend.loader.create_shaders(&create_infos, None) }.map_err(
+            |(shaders, e)| {
+                // Shaders that were created before the failure still have to be destroyed
+                for shader in shaders
+                    .into_iter()
+                    .filter(|shader| *shader != vk::ShaderEXT::null())
+                {
+                    unsafe { backend.loader.destroy_shader(shader, None) };
+                }
+                eyre!("Failed to create shader objects: {e}")
+            },
+        )?;
+        let shaders = shader
+            .stages
+            .iter()
+            .map(|stage| stage.stage)
+            .zip(shaders)
+            .collect::<Vec<_>>();
+        for (stage, shader_object) in &shaders {
+            debug_utils.set_object_name(
+                *shader_object,
+                &format!("{} {:?}", shader.display_name(), stage),
+            );
+        }
+
+        Ok(Self {
+            shaders,
+            state,
+            backend,
+            device,
+        })
+    }
+
+    pub fn bind(&self, command_buffer: vk::CommandBuffer) {
+        let loader = &self.backend.loader;
+        let features = &self.backend.features;
+        let state = &self.state;
+
+        // Every stage the device supports must have a shader or be explicitly unbound
+        let mut stages = vec![vk::ShaderStageFlags::VERTEX, vk::ShaderStageFlags::FRAGMENT];
+        if features.tessellation_shader == vk::TRUE {
+            stages.push(vk::ShaderStageFlags::TESSELLATION_CONTROL);
+            stages.push(vk::ShaderStageFlags::TESSELLATION_EVALUATION);
+        }
+        if features.geometry_shader == vk::TRUE {
+            stages.push(vk::ShaderStageFlags::GEOMETRY);
+        }
+        if self.backend.task_shader {
+            stages.push(vk::ShaderStageFlags::TASK_EXT);
+        }
+        if self.backend.mesh_shader {
+            stages.push(vk::ShaderStageFlags::MESH_EXT);
+        }
+        let shaders = stages
+            .iter()
+            .map(|stage| {
+                self.shaders
+                    .iter()
+                    .find(|(shader_stage, _)| shader_stage == stage)
+                    .map_or(vk::ShaderEXT::null(), |(_, shader)| *shader)
+            })
+            .collect::<Vec<_>>();
+        let has_tessellation = self
+            .shaders
+            .iter()
+            .any(|(stage, _)| *stage == vk::ShaderStageFlags::TESSELLATION_CONTROL);
+
+        let blend = &state.color_blend_attachment;
+        let blend_equation = vk::ColorBlendEquationEXT::default()
+            .src_color_blend_factor(blend.src_color_blend_factor)
+            .dst_color_blend_factor(blend.dst_color_blend_factor)
+            .color_blend_op(blend.color_blend_op)
+            .src_alpha_blend_factor(blend.src_alpha_blend_factor)
+            .dst_alpha_blend_factor(blend.dst_alpha_blend_factor)
+            .alpha_blend_op(blend.alpha_blend_op);
+
+        unsafe {
+            loader.cmd_bind_shaders(command_buffer, &stages, &shaders);
+
+            // Vertex input and input assembly
+            loader.cmd_set_vertex_input(
+                command_buffer,
+                &state.vertex_bindings,
+                &state.vertex_attributes,
+            );
+            loader.cmd_set_primitive_topology(command_buffer, state.topology);
+            loader.cmd_set_primitive_restart_enable(command_buffer, state.primitive_restart_enable);
+            if has_tessellation {
+                loader.cmd_set_patch_control_points(command_buffer, state.patch_control_points);
+                loader.cmd_set_tessellation_domain_origin(
+                    command_buffer,
+                    vk::TessellationDomainOrigin::UPPER_LEFT,
+                );
+            }
+
+            // Rasterization
+            loader.cmd_set_rasterizer_discard_enable(command_buffer, false);
+            loader.cmd_set_polygon_mode(command_buffer, state.polygon_mode);
+            self.device
+                .cmd_set_line_width(command_buffer, state.line_width);
+            loader.cmd_set_cull_mode(command_buffer, state.cull_mode);
+            loader.cmd_set_front_face(command_buffer, state.front_face);
+            loader.cmd_set_depth_bias_enable(command_buffer, false);
+            if features.depth_clamp == vk::TRUE {
+                loader.cmd_set_depth_clamp_enable(command_buffer, false);
+            }
+
+            // Multisampling
+            loader.cmd_set_rasterization_samples(command_buffer, state.samples);
+            loader.cmd_set_sample_mask(command_buffer, state.samples, &[vk::SampleMask::MAX]);
+            loader.cmd_set_alpha_to_coverage_enable(command_buffer, false);
+            if features.alpha_to_one == vk::TRUE {
+                loader.cmd_set_alpha_to_one_enable(command_buffer, false);
+            }
+
+            // Depth and stencil
+            loader.cmd_set_depth_test_enable(command_buffer, state.depth_test_enable);
+            loader.cmd_set_depth_write_enable(command_buffer, state.depth_write_enable);
+            loader.cmd_set_depth_compare_op(command_buffer, state.depth_compare_op);
+            if features.depth_bounds == vk::TRUE {
+                loader.cmd_set_depth_bounds_test_enable(command_buffer, false);
+            }
+            loader.cmd_set_stencil_test_enable(command_buffer, false);
+
+            // Color blending
+            if features.logic_op == vk::TRUE {
+                loader.cmd_set_logic_op_enable(command_buffer, false);
+            }
+            loader.cmd_set_color_blend_enable(command_buffer, 0, &[blend.blend_enable]);
+            loader.cmd_set_color_blend_equation(command_buffer, 0, &[blend_equation]);
+            loader.cmd_set_color_write_mask(command_buffer, 0, &[blend.color_write_mask]);
+        }
+    }
+}
+
+impl Drop for ShaderObjectProgram {
+    fn drop(&mut self) {
+        unsafe {
+            for (_, shader) in &self.shaders {
+                self.backend.loader.destroy_shader(*shader, None);
+            }
+        }
+    }
+}
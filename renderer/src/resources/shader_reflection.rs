@@ -0,0 +1,829 @@
+use crate::context::desc_set_layout_builder::{DescriptorBinding, DescriptorSetLayoutBuilder};
+use crate::resources::vertex::VertexInputDescription;
+use ash::vk;
+use color_eyre::Result;
+use color_eyre::eyre::{OptionExt, eyre};
+use naga::{AddressSpace, ArraySize, ImageClass, ScalarKind, TypeInner};
+use std::collections::BTreeMap;
+
+/// The interface a shader declares, read back from its SPIR-V
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ShaderReflection {
+    /// Bindings of each descriptor set, sorted by binding number
+    pub descriptor_sets: BTreeMap<u32, Vec<DescriptorBinding>>,
+    /// Size of the push-constant block and the stages declaring it
+    pub push_constants: Option<(u32, vk::ShaderStageFlags)>,
+    pub vertex_inputs: Vec<VertexInput>,
+}
+
+/// A location read by the vertex stage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct VertexInput {
+    pub location: u32,
+    pub kind: ScalarKind,
+    pub components: u32,
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub(crate) enum LayoutMismatch {
+    #[error("set {set} is used, but the pipeline layout only has {set_count} set layout(s)")]
+    MissingSet { set: u32, set_count: usize },
+    #[error(
+        "set {set} binding {binding} ({descriptor_type:?}) is not in the descriptor set layout"
+    )]
+    MissingBinding {
+        set: u32,
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+    },
+    #[error("set {set} binding {binding} is {shader:?} in the shader but {layout:?} in the layout")]
+    DescriptorType {
+        set: u32,
+        binding: u32,
+        shader: vk::DescriptorType,
+        layout: vk::DescriptorType,
+    },
+    #[error(
+        "set {set} binding {binding} has {shader} descriptors in the shader but {layout} in the layout"
+    )]
+    DescriptorCount {
+        set: u32,
+        binding: u32,
+        shader: u32,
+        layout: u32,
+    },
+    #[error("set {set} binding {binding} is used by {shader:?} but only visible to {layout:?}")]
+    DescriptorStages {
+        set: u32,
+        binding: u32,
+        shader: vk::ShaderStageFlags,
+        layout: vk::ShaderStageFlags,
+    },
+    #[error(
+        "the push constants are {size} bytes, but the ranges for {stages:?} only cover {covered}"
+    )]
+    PushConstantSize {
+        size: u32,
+        stages: vk::ShaderStageFlags,
+        covered: u32,
+    },
+    #[error("vertex input location {location} has no attribute")]
+    MissingVertexAttribute { location: u32 },
+    #[error(
+        "vertex input location {location} is {shader:?} but its attribute format is {format:?}"
+    )]
+    VertexAttributeFormat {
+        location: u32,
+        shader: ScalarKind,
+        format: vk::Format,
+    },
+}
+
+impl ShaderReflection {
+    pub fn from_spirv(code: &[u8], entry_point: &str) -> Result<Self> {
+        let module = naga::front::spv::parse_u8_slice(code, &naga::front::spv::Options::default())?;
+        Self::from_module(&module, entry_point)
+    }
+
+    /// Only the stage of `entry_point` is reflected, but with every resource of the module,
+    /// so a module shared by several entry points is over-approximated.
+    /// naga only understands vertex, fragment and compute stages.
+    pub fn from_module(module: &naga::Module, entry_point: &str) -> Result<Self> {
+        let entry_point = module
+            .entry_points
+            .iter()
+            .find(|candidate| candidate.name == entry_point)
+            .ok_or_eyre(format!("Shader has no entry point named {entry_point}"))?;
+        let stage = match entry_point.stage {
+            naga::ShaderStage::Vertex => vk::ShaderStageFlags::VERTEX,
+            naga::ShaderStage::Fragment => vk::ShaderStageFlags::FRAGMENT,
+            naga::ShaderStage::Compute => vk::ShaderStageFlags::COMPUTE,
+        };
+
+        let mut reflection = Self::default();
+        for (_, variable) in module.global_variables.iter() {
+            if variable.space == AddressSpace::PushConstant {
+                let size = module.types[variable.ty].inner.size(module.to_ctx());
+                reflection.push_constants = Some((size, stage));
+                continue;
+            }
+            let Some(resource_binding) = &variable.binding else {
+                continue;
+            };
+            let Some((descriptor_type, count)) =
+                reflect_descriptor(module, variable.space, variable.ty)
+            else {
+                continue;
+            };
+            reflection
+                .descriptor_sets
+                .entry(resource_binding.group)
+                .or_default()
+                .push(DescriptorBinding {
+                    binding: resource_binding.binding,
+                    descriptor_type,
+                    count,
+                    stages: stage,
+                });
+        }
+        for bindings in reflection.descriptor_sets.values_mut() {
+            bindings.sort_by_key(|binding| binding.binding);
+        }
+
+        if stage == vk::ShaderStageFlags::VERTEX {
+            for argument in &entry_point.function.arguments {
+                // Inputs are either separate arguments or the members of a struct
+                match &module.types[argument.ty].inner {
+                    TypeInner::Struct { members, .. } => {
+                        reflection
+                            .vertex_inputs
+                            .extend(members.iter().filter_map(|member| {
+                                reflect_vertex_input(module, member.binding.as_ref(), member.ty)
+                            }))
+                    }
+                    _ => reflection.vertex_inputs.extend(reflect_vertex_input(
+                        module,
+                        argument.binding.as_ref(),
+                        argument.ty,
+                    )),
+                }
+            }
+            reflection.vertex_inputs.sort_by_key(|input| input.location);
+        }
+
+        Ok(reflection)
+    }
+
+    /// Combine the reflections of the stages of a single program
+    pub fn merge(mut self, other: Self) -> Self {
+        for (set, bindings) in other.descriptor_sets {
+            let merged = self.descriptor_sets.entry(set).or_default();
+            for binding in bindings {
+                match merged
+                    .iter_mut()
+                    .find(|merged| merged.binding == binding.binding)
+                {
+                    Some(merged) => merged.stages |= binding.stages,
+                    None => merged.push(binding),
+                }
+            }
+            merged.sort_by_key(|binding| binding.binding);
+        }
+        self.push_constants = match (self.push_constants, other.push_constants) {
+            (Some((size, stages)), Some((other_size, other_stages))) => {
+                Some((size.max(other_size), stages | other_stages))
+            }
+            (push_constants, other_push_constants) => push_constants.or(other_push_constants),
+        };
+        self.vertex_inputs.extend(other.vertex_inputs);
+        self
+    }
+
+    /// Fails with every mismatch between the shader and the layouts it is about to be used with
+    pub fn validate(
+        &self,
+        shader_name: &str,
+        set_layouts: &[&[DescriptorBinding]],
+        push_constant_ranges: &[vk::PushConstantRange],
+        vertex_input: Option<&VertexInputDescription>,
+    ) -> Result<()> {
+        let mut mismatches = self.check_layouts(set_layouts, push_constant_ranges);
+        if let Some(vertex_input) = vertex_input {
+            mismatches.extend(self.check_vertex_input(vertex_input));
+        }
+        if mismatches.is_empty() {
+            return Ok(());
+        }
+
+        let list = mismatches
+            .iter()
+            .map(|mismatch| format!("\n  - {mismatch}"))
+            .collect::<String>();
+        Err(eyre!(
+            "Shader {shader_name} does not match its layouts:{list}"
+        ))
+    }
+
+    pub fn check_layouts(
+        &self,
+        set_layouts: &[&[DescriptorBinding]],
+        push_constant_ranges: &[vk::PushConstantRange],
+    ) -> Vec<LayoutMismatch> {
+        let mut mismatches = Vec::new();
+
+        for (&set, bindings) in &self.descriptor_sets {
+            let Some(layout) = set_layouts.get(set as usize) else {
+                mismatches.push(LayoutMismatch::MissingSet {
+                    set,
+                    set_count: set_layouts.len(),
+                });
+                continue;
+            };
+            for shader in bindings {
+                let Some(layout) = layout
+                    .iter()
+                    .find(|layout| layout.binding == shader.binding)
+                else {
+                    mismatches.push(LayoutMismatch::MissingBinding {
+                        set,
+                        binding: shader.binding,
+                        descriptor_type: shader.descriptor_type,
+                    });
+                    continue;
+                };
+                mismatches.extend(check_binding(set, shader, layout));
+            }
+        }
+
+        if let Some((size, stages)) = self.push_constants {
+            let covered = covered_push_constant_bytes(push_constant_ranges, stages);
+            if covered < size {
+                mismatches.push(LayoutMismatch::PushConstantSize {
+                    size,
+                    stages,
+                    covered,
+                });
+            }
+        }
+
+        mismatches
+    }
+
+    /// Component counts may differ, missing components default and extra ones are ignored
+    pub fn check_vertex_input(&self, description: &VertexInputDescription) -> Vec<LayoutMismatch> {
+        self.vertex_inputs
+            .iter()
+            .filter_map(|input| {
+                let Some(attribute) = description
+                    .attributes
+                    .iter()
+                    .find(|attribute| attribute.location == input.location)
+                else {
+                    return Some(LayoutMismatch::MissingVertexAttribute {
+                        location: input.location,
+                    });
+                };
+                match format_scalar_kind(attribute.format) {
+                    Some(kind) if kind != input.kind => {
+                        Some(LayoutMismatch::VertexAttributeFormat {
+                            location: input.location,
+                            shader: input.kind,
+                            format: attribute.format,
+                        })
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// A layout with exactly the shader's bindings, for materials that do not use the bindless one
+    pub fn descriptor_set_layout_builder(&self) -> Result<DescriptorSetLayoutBuilder<'static>> {
+        if self.descriptor_sets.keys().any(|&set| set != 0) {
+            return Err(eyre!(
+                "Layouts can only be generated for shaders using set 0"
+            ));
+        }
+
+        let bindings = self
+            .descriptor_sets
+            .get(&0)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        bindings
+            .iter()
+            .try_fold(DescriptorSetLayoutBuilder::new(), |builder, binding| {
+                let count = binding.count.ok_or_else(|| {
+                    eyre!(
+                        "Binding {} is a runtime-sized array, which needs an explicit layout",
+                        binding.binding
+                    )
+                })?;
+                Ok(builder.add_binding(
+                    binding.binding,
+                    binding.descriptor_type,
+                    count,
+                    binding.stages,
+                    vk::DescriptorBindingFlags::UPDATE_AFTER_BIND,
+                    None,
+                ))
+            })
+    }
+
+    /// Materials push constants to every stage, so the range has to cover them all
+    pub fn push_constant_ranges(&self) -> Vec<vk::PushConstantRange> {
+        self.push_constants
+            .iter()
+            .map(|&(size, _)| {
+                vk::PushConstantRange::default()
+                    .stage_flags(vk::ShaderStageFlags::ALL)
+                    .offset(0)
+                    .size(size)
+            })
+            .collect()
+    }
+}
+
+fn reflect_descriptor(
+    module: &naga::Module,
+    space: AddressSpace,
+    ty: naga::Handle<naga::Type>,
+) -> Option<(vk::DescriptorType, Option<u32>)> {
+    let (base, count) = match module.types[ty].inner {
+        TypeInner::BindingArray { base, size } => match size {
+            ArraySize::Constant(size) => (base, Some(size.get())),
+            ArraySize::Dynamic => (base, None),
+        },
+        _ => (ty, Some(1)),
+    };
+
+    let descriptor_type = match space {
+        AddressSpace::Uniform => vk::DescriptorType::UNIFORM_BUFFER,
+        AddressSpace::Storage { .. } => vk::DescriptorType::STORAGE_BUFFER,
+        AddressSpace::Handle => match module.types[base].inner {
+            TypeInner::Sampler { .. } => vk::DescriptorType::SAMPLER,
+            TypeInner::Image {
+                class: ImageClass::Storage { .. },
+                ..
+            } => vk::DescriptorType::STORAGE_IMAGE,
+            TypeInner::Image { .. } => vk::DescriptorType::SAMPLED_IMAGE,
+            TypeInner::AccelerationStructure => vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some((descriptor_type, count))
+}
+
+fn reflect_vertex_input(
+    module: &naga::Module,
+    binding: Option<&naga::Binding>,
+    ty: naga::Handle<naga::Type>,
+) -> Option<VertexInput> {
+    // Built-ins such as `gl_VertexIndex` are not fed by attributes
+    let Some(naga::Binding::Location { location, .. }) = binding else {
+        return None;
+    };
+    let (kind, components) = match module.types[ty].inner {
+        TypeInner::Scalar(scalar) => (scalar.kind, 1),
+        TypeInner::Vector { size, scalar } => (scalar.kind, size as u32),
+        _ => return None,
+    };
+    Some(VertexInput {
+        location: *location,
+        kind,
+        components,
+    })
+}
+
+fn check_binding(
+    set: u32,
+    shader: &DescriptorBinding,
+    layout: &DescriptorBinding,
+) -> Vec<LayoutMismatch> {
+    let mut mismatches = Vec::new();
+
+    // Combined image samplers are reflected as the image they sample
+    let compatible_types = shader.descriptor_type == layout.descriptor_type
+        || (shader.descriptor_type == vk::DescriptorType::SAMPLED_IMAGE
+            && layout.descriptor_type == vk::DescriptorType::COMBINED_IMAGE_SAMPLER);
+    if !compatible_types {
+        mismatches.push(LayoutMismatch::DescriptorType {
+            set,
+            binding: shader.binding,
+            shader: shader.descriptor_type,
+            layout: layout.descriptor_type,
+        });
+    }
+
+    // Runtime-sized arrays fit any count
+    match (shader.count, layout.count) {
+        (Some(shader_count), Some(layout_count)) if shader_count > layout_count => {
+            mismatches.push(LayoutMismatch::DescriptorCount {
+                set,
+                binding: shader.binding,
+                shader: shader_count,
+                layout: layout_count,
+            });
+        }
+        _ => {}
+    }
+
+    if !layout.stages.contains(shader.stages) {
+        mismatches.push(LayoutMismatch::DescriptorStages {
+            set,
+            binding: shader.binding,
+            shader: shader.stages,
+            layout: layout.stages,
+        });
+    }
+
+    mismatches
+}
+
+/// How many bytes from offset 0 the ranges visible to all of `stages` cover without a gap
+fn covered_push_constant_bytes(
+    ranges: &[vk::PushConstantRange],
+    stages: vk::ShaderStageFlags,
+) -> u32 {
+    let mut ranges = ranges
+        .iter()
+        .filter(|range| range.stage_flags.contains(stages))
+        .collect::<Vec<_>>();
+    ranges.sort_by_key(|range| range.offset);
+
+    let mut covered = 0;
+    for range in ranges {
+        if range.offset > covered {
+            break;
+        }
+        covered = covered.max(range.offset + range.size);
+    }
+    covered
+}
+
+/// The scalar kind a vertex attribute format is read as, for the formats vertex buffers use
+fn format_scalar_kind(format: vk::Format) -> Option<ScalarKind> {
+    match format {
+        vk::Format::R32_SFLOAT
+        | vk::Format::R32G32_SFLOAT
+        | vk::Format::R32G32B32_SFLOAT
+        | vk::Format::R32G32B32A32_SFLOAT
+        | vk::Format::R16G16_SFLOAT
+        | vk::Format::R16G16B16A16_SFLOAT
+        | vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SNORM
+        | vk::Format::R16G16_UNORM
+        | vk::Format::R16G16_SNORM
+        | vk::Format::R16G16B16A16_UNORM
+        | vk::Format::R16G16B16A16_SNORM
+        | vk::Format::A2B10G10R10_UNORM_PACK32
+        | vk::Format::A2B10G10R10_SNORM_PACK32 => Some(ScalarKind::Float),
+        vk::Format::R32_UINT
+        | vk::Format::R32G32_UINT
+        | vk::Format::R32G32B32_UINT
+        | vk::Format::R32G32B32A32_UINT
+        | vk::Format::R16G16_UINT
+        | vk::Format::R16G16B16A16_UINT
+        | vk::Format::R8G8B8A8_UINT => Some(ScalarKind::Uint),
+        vk::Format::R32_SINT
+        | vk::Format::R32G32_SINT
+        | vk::Format::R32G32B32_SINT
+        | vk::Format::R32G32B32A32_SINT
+        | vk::Format::R16G16_SINT
+        | vk::Format::R16G16B16A16_SINT
+        | vk::Format::R8G8B8A8_SINT => Some(ScalarKind::Sint),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERTEX_SHADER: &str = "
+        struct PerDraw {
+            object_index: u32,
+            material_index: u32,
+        }
+        var<push_constant> per_draw: PerDraw;
+        @group(0) @binding(0) var<uniform> viewproj: mat4x4<f32>;
+        @group(0) @binding(2) var<storage, read> models: array<mat4x4<f32>>;
+        @group(0) @binding(3) var samplers: binding_array<sampler>;
+        @group(0) @binding(4) var textures: binding_array<texture_2d<f32>, 16>;
+
+        @vertex
+        fn main(
+            @location(0) position: vec3<f32>,
+            @location(1) texcoord: vec2<f32>,
+            @builtin(vertex_index) index: u32,
+        ) -> @builtin(position) vec4<f32> {
+            return viewproj * models[per_draw.object_index] * vec4<f32>(position, 1.0);
+        }
+    ";
+
+    const COMPUTE_SHADER: &str = "
+        struct Params {
+            face_size: u32,
+        }
+        var<push_constant> params: Params;
+        @group(0) @binding(0) var source: texture_2d<f32>;
+        @group(0) @binding(1) var source_sampler: sampler;
+        @group(0) @binding(2) var faces: texture_storage_2d_array<rgba16float, write>;
+
+        @compute @workgroup_size(8, 8, 1)
+        fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+            let uv = vec2<f32>(id.xy) / f32(params.face_size);
+            let color = textureSampleLevel(source, source_sampler, uv, 0.0);
+            textureStore(faces, id.xy, id.z, color);
+        }
+    ";
+
+    fn reflect(source: &str) -> ShaderReflection {
+        ShaderReflection::from_module(&naga::front::wgsl::parse_str(source).unwrap(), "main")
+            .unwrap()
+    }
+
+    fn layout_binding(
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+        count: u32,
+    ) -> DescriptorBinding {
+        DescriptorBinding {
+            binding,
+            descriptor_type,
+            count: Some(count),
+            stages: vk::ShaderStageFlags::ALL,
+        }
+    }
+
+    fn bindless_layout() -> Vec<DescriptorBinding> {
+        vec![
+            layout_binding(0, vk::DescriptorType::UNIFORM_BUFFER, 4),
+            layout_binding(1, vk::DescriptorType::STORAGE_BUFFER, 4),
+            layout_binding(2, vk::DescriptorType::STORAGE_BUFFER, 4),
+            layout_binding(3, vk::DescriptorType::SAMPLER, 16),
+            layout_binding(4, vk::DescriptorType::SAMPLED_IMAGE, 1024),
+        ]
+    }
+
+    fn push_constant_range(size: u32) -> vk::PushConstantRange {
+        vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::ALL)
+            .offset(0)
+            .size(size)
+    }
+
+    #[test]
+    fn reflects_bindings_push_constants_and_vertex_inputs() {
+        let reflection = reflect(VERTEX_SHADER);
+
+        let set = &reflection.descriptor_sets[&0];
+        let reflected = set
+            .iter()
+            .map(|binding| (binding.binding, binding.descriptor_type, binding.count))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reflected,
+            [
+                (0, vk::DescriptorType::UNIFORM_BUFFER, Some(1)),
+                (2, vk::DescriptorType::STORAGE_BUFFER, Some(1)),
+                (3, vk::DescriptorType::SAMPLER, None),
+                (4, vk::DescriptorType::SAMPLED_IMAGE, Some(16)),
+            ]
+        );
+        assert!(
+            set.iter()
+                .all(|binding| binding.stages == vk::ShaderStageFlags::VERTEX)
+        );
+        assert_eq!(
+            reflection.push_constants,
+            Some((8, vk::ShaderStageFlags::VERTEX))
+        );
+        assert_eq!(
+            reflection.vertex_inputs,
+            [
+                VertexInput {
+                    location: 0,
+                    kind: ScalarKind::Float,
+                    components: 3,
+                },
+                VertexInput {
+                    location: 1,
+                    kind: ScalarKind::Float,
+                    components: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn matching_layouts_pass() {
+        let reflection = reflect(VERTEX_SHADER);
+        let layout = bindless_layout();
+
+        assert_eq!(
+            reflection.check_layouts(&[&layout], &[push_constant_range(12)]),
+            []
+        );
+        assert_eq!(
+            reflection.check_vertex_input(&VertexInputDescription::default()),
+            []
+        );
+    }
+
+    #[test]
+    fn reports_every_mismatch() {
+        let reflection = reflect(VERTEX_SHADER);
+        let mut layout = bindless_layout();
+        layout.remove(2);
+        layout[2].descriptor_type = vk::DescriptorType::SAMPLED_IMAGE;
+        layout[3].count = Some(8);
+        layout[0].stages = vk::ShaderStageFlags::FRAGMENT;
+
+        assert_eq!(
+            reflection.check_layouts(&[&layout], &[push_constant_range(4)]),
+            [
+                LayoutMismatch::DescriptorStages {
+                    set: 0,
+                    binding: 0,
+                    shader: vk::ShaderStageFlags::VERTEX,
+                    layout: vk::ShaderStageFlags::FRAGMENT,
+                },
+                LayoutMismatch::MissingBinding {
+                    set: 0,
+                    binding: 2,
+                    descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                },
+                LayoutMismatch::DescriptorType {
+                    set: 0,
+                    binding: 3,
+                    shader: vk::DescriptorType::SAMPLER,
+                    layout: vk::DescriptorType::SAMPLED_IMAGE,
+                },
+                LayoutMismatch::DescriptorCount {
+                    set: 0,
+                    binding: 4,
+                    shader: 16,
+                    layout: 8,
+                },
+                LayoutMismatch::PushConstantSize {
+                    size: 8,
+                    stages: vk::ShaderStageFlags::VERTEX,
+                    covered: 4,
+                },
+            ]
+        );
+        assert_eq!(
+            reflection.check_layouts(&[], &[]),
+            [
+                LayoutMismatch::MissingSet {
+                    set: 0,
+                    set_count: 0
+                },
+                LayoutMismatch::PushConstantSize {
+                    size: 8,
+                    stages: vk::ShaderStageFlags::VERTEX,
+                    covered: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_vertex_input_mismatches() {
+        let reflection = reflect(VERTEX_SHADER);
+        let mut description = VertexInputDescription::default();
+        description.attributes[0].format = vk::Format::R32G32B32_UINT;
+        description.attributes.truncate(1);
+
+        assert_eq!(
+            reflection.check_vertex_input(&description),
+            [
+                LayoutMismatch::VertexAttributeFormat {
+                    location: 0,
+                    shader: ScalarKind::Float,
+                    format: vk::Format::R32G32B32_UINT,
+                },
+                LayoutMismatch::MissingVertexAttribute { location: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn push_constant_ranges_must_be_contiguous() {
+        let ranges = [
+            push_constant_range(4),
+            vk::PushConstantRange::default()
+                .stage_flags(vk::ShaderStageFlags::ALL)
+                .offset(8)
+                .size(8),
+        ];
+        assert_eq!(
+            covered_push_constant_bytes(&ranges, vk::ShaderStageFlags::VERTEX),
+            4
+        );
+
+        let fragment_only = [vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .offset(0)
+            .size(16)];
+        assert_eq!(
+            covered_push_constant_bytes(&fragment_only, vk::ShaderStageFlags::VERTEX),
+            0
+        );
+    }
+
+    #[test]
+    fn merging_stages_combines_visibility() {
+        let vertex = reflect(VERTEX_SHADER);
+        let mut fragment = vertex.clone();
+        for binding in fragment.descriptor_sets.get_mut(&0).unwrap() {
+            binding.stages = vk::ShaderStageFlags::FRAGMENT;
+        }
+        fragment.push_constants = Some((16, vk::ShaderStageFlags::FRAGMENT));
+        fragment.vertex_inputs.clear();
+
+        let merged = vertex.merge(fragment);
+        assert!(merged.descriptor_sets[&0].iter().all(|binding| {
+            binding.stages == vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT
+        }));
+        assert_eq!(
+            merged.push_constants,
+            Some((
+                16,
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT
+            ))
+        );
+        assert_eq!(merged.vertex_inputs.len(), 2);
+    }
+
+    #[test]
+    fn generated_layout_matches_the_shader() {
+        let reflection = reflect(COMPUTE_SHADER);
+        let layout = reflection
+            .descriptor_set_layout_builder()
+            .unwrap()
+            .describe();
+
+        assert_eq!(
+            layout
+                .iter()
+                .map(|binding| binding.descriptor_type)
+                .collect::<Vec<_>>(),
+            [
+                vk::DescriptorType::SAMPLED_IMAGE,
+                vk::DescriptorType::SAMPLER,
+                vk::DescriptorType::STORAGE_IMAGE,
+            ]
+        );
+        assert_eq!(
+            reflection.check_layouts(&[&layout], &reflection.push_constant_ranges()),
+            []
+        );
+        // Runtime-sized arrays have no count to create the layout with
+        assert!(
+            reflect(VERTEX_SHADER)
+                .descriptor_set_layout_builder()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn reflects_the_requested_entry_point() {
+        let module = naga::front::wgsl::parse_str(
+            "
+            @group(0) @binding(0) var<uniform> tint: vec4<f32>;
+
+            @vertex
+            fn vs_main(@location(0) position: vec3<f32>) -> @builtin(position) vec4<f32> {
+                return vec4<f32>(position, 1.0);
+            }
+
+            @fragment
+            fn fs_main() -> @location(0) vec4<f32> {
+                return tint;
+            }
+            ",
+        )
+        .unwrap();
+
+        let vertex = ShaderReflection::from_module(&module, "vs_main").unwrap();
+        assert_eq!(vertex.vertex_inputs.len(), 1);
+        assert_eq!(
+            vertex.descriptor_sets[&0][0].stages,
+            vk::ShaderStageFlags::VERTEX
+        );
+
+        let fragment = ShaderReflection::from_module(&module, "fs_main").unwrap();
+        assert!(fragment.vertex_inputs.is_empty());
+        assert_eq!(
+            fragment.descriptor_sets[&0][0].stages,
+            vk::ShaderStageFlags::FRAGMENT
+        );
+
+        assert!(ShaderReflection::from_module(&module, "main").is_err());
+    }
+
+    #[test]
+    fn reflects_spirv() {
+        let module = naga::front::wgsl::parse_str(COMPUTE_SHADER).unwrap();
+        let info = naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::all(),
+        )
+        .validate(&module)
+        .unwrap();
+        let words =
+            naga::back::spv::write_vec(&module, &info, &naga::back::spv::Options::default(), None)
+                .unwrap();
+
+        let reflection =
+            ShaderReflection::from_spirv(bytemuck::cast_slice(&words), "main").unwrap();
+        assert_eq!(
+            reflection,
+            ShaderReflection::from_module(&module, "main").unwrap()
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Shader permutations selected by feature keywords.
+//!
+//! A GLSL shader declares the keywords it supports with a pragma, e.g.
+//! `#pragma variants ALPHA_TEST VERTEX_COLOR`, and tests them with `#ifdef`.
+//! `build.rs` compiles every combination of the keywords declared by a shader's stages,
+//! each keyword defined as `1`, so all stages of a shader agree on its variants.
+//!
+//! This file is also compiled into `build.rs`, so it only depends on `std`.
+//! Without hot reloading, only `build.rs` enumerates the variants.
+#![cfg_attr(not(feature = "hot-reload"), allow(dead_code))]
+
+use std::collections::BTreeSet;
+
+const VARIANTS_PRAGMA: &str = "#pragma variants";
+
+/// Every variant doubles the number of SPIR-V files embedded in the binary
+pub(crate) const MAX_KEYWORDS_PER_SHADER: usize = 6;
+
+/// The set of keywords a shader variant is compiled with. The empty set is the base shader.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ShaderVariant {
+    /// Sorted and deduplicated, so the same keywords always name the same file
+    keywords: Vec<String>,
+}
+
+impl ShaderVariant {
+    pub fn new<S: AsRef<str>>(keywords: impl IntoIterator<Item = S>) -> Self {
+        let keywords: BTreeSet<String> = keywords
+            .into_iter()
+            .map(|keyword| keyword.as_ref().to_owned())
+            .collect();
+        Self {
+            keywords: keywords.into_iter().collect(),
+        }
+    }
+
+    pub fn keywords(&self) -> &[String] {
+        &self.keywords
+    }
+
+    /// Name of the embedded SPIR-V files of this variant without the stage extension,
+    /// e.g. `default` or `default+ALPHA_TEST+VERTEX_COLOR`
+    pub fn file_stem(&self, shader_name: &str) -> String {
+        std::iter::once(shader_name)
+            .chain(self.keywords.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join("+")
+    }
+
+    /// Every combination of `keywords`, starting with the base shader
+    pub fn all_of(keywords: &[String]) -> Vec<Self> {
+        (0..1u32 << keywords.len())
+            .map(|mask| {
+                Self::new(
+                    keywords
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| mask & (1 << i) != 0)
+                        .map(|(_, keyword)| keyword),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Collect the keywords declared by the `#pragma variants` lines of a shader's stages
+pub(crate) fn declared_keywords<S: AsRef<str>>(
+    stage_sources: impl IntoIterator<Item = S>,
+) -> Result<Vec<String>, String> {
+    let mut keywords = BTreeSet::new();
+    for source in stage_sources {
+        for line in source.as_ref().lines() {
+            let Some(declared) = line.trim().strip_prefix(VARIANTS_PRAGMA) else {
+                continue;
+            };
+            for keyword in declared.split_whitespace() {
+                if !is_valid_keyword(keyword) {
+                    return Err(format!("Invalid shader variant keyword: {keyword:?}"));
+                }
+                keywords.insert(keyword.to_owned());
+            }
+        }
+    }
+
+    if keywords.len() > MAX_KEYWORDS_PER_SHADER {
+        return Err(format!(
+            "A shader declares {} variant keywords, at most {} are supported",
+            keywords.len(),
+            MAX_KEYWORDS_PER_SHADER
+        ));
+    }
+    Ok(keywords.into_iter().collect())
+}
+
+/// Keywords become preprocessor macros and part of file names
+fn is_valid_keyword(keyword: &str) -> bool {
+    keyword
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_uppercase() || c == '_')
+        && keyword
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keywords_are_sorted_and_deduplicated() {
+        let variant = ShaderVariant::new(["VERTEX_COLOR", "ALPHA_TEST", "VERTEX_COLOR"]);
+        assert_eq!(variant.keywords(), ["ALPHA_TEST", "VERTEX_COLOR"]);
+        assert_eq!(variant, ShaderVariant::new(["ALPHA_TEST", "VERTEX_COLOR"]));
+    }
+
+    #[test]
+    fn base_variant_keeps_the_shader_file_name() {
+        assert_eq!(ShaderVariant::default().file_stem("default"), "default");
+        assert_eq!(
+            ShaderVariant::new(["VERTEX_COLOR", "ALPHA_TEST"]).file_stem("default"),
+            "default+ALPHA_TEST+VERTEX_COLOR"
+        );
+    }
+
+    #[test]
+    fn all_of_enumerates_every_combination() {
+        let keywords = ["ALPHA_TEST".to_owned(), "SKINNING".to_owned()];
+        let variants = ShaderVariant::all_of(&keywords);
+        assert_eq!(
+            variants,
+            [
+                ShaderVariant::default(),
+                ShaderVariant::new(["ALPHA_TEST"]),
+                ShaderVariant::new(["SKINNING"]),
+                ShaderVariant::new(["ALPHA_TEST", "SKINNING"]),
+            ]
+        );
+        assert_eq!(ShaderVariant::all_of(&[]), [ShaderVariant::default()]);
+    }
+
+    #[test]
+    fn keywords_are_merged_across_stages() {
+        let vert = "#version 450\n#pragma variants SKINNING VERTEX_COLOR\nvoid main() {}";
+        let frag = "#version 450\n  #pragma variants ALPHA_TEST VERTEX_COLOR\nvoid main() {}";
+        assert_eq!(
+            declared_keywords([vert, frag]).unwrap(),
+            ["ALPHA_TEST", "SKINNING", "VERTEX_COLOR"]
+        );
+        assert!(declared_keywords(["#version 450"]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_keywords_are_rejected() {
+        assert!(declared_keywords(["#pragma variants alpha_test"]).is_err());
+        assert!(declared_keywords(["#pragma variants 2SIDED"]).is_err());
+        assert!(declared_keywords(["#pragma variants A+B"]).is_err());
+        assert!(declared_keywords(["#pragma variants A B C D E F G"]).is_err());
+    }
+}
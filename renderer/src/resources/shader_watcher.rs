@@ -0,0 +1,195 @@
+use crate::resources::shader::set_reloaded_shader_code;
+use crate::resources::shader_variant::{ShaderVariant, declared_keywords};
+use color_eyre::Result;
+use color_eyre::eyre::{OptionExt, eyre};
+use naga::{
+    back::spv,
+    front::wgsl,
+    valid::{Capabilities, ValidationFlags, Validator},
+};
+use notify::{EventKind, RecursiveMode, Watcher};
+use shaderc::{OptimizationLevel, ShaderKind, SpirvVersion};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+/// Watches `shaders/` and recompiles changed shaders in-process, the same way `build.rs` does.
+/// A shader that fails to compile is logged and keeps its last good SPIR-V.
+pub(crate) struct ShaderWatcher {
+    _watcher: notify::RecommendedWatcher,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+    compiler: shaderc::Compiler,
+}
+
+impl ShaderWatcher {
+    pub fn new() -> Result<Self> {
+        let shaders_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("shaders");
+
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        watcher.watch(&shaders_dir, RecursiveMode::NonRecursive)?;
+        let compiler = shaderc::Compiler::new().ok_or_eyre("Failed to create shaderc compiler")?;
+
+        log::info!("Watching {:?} for shader changes", shaders_dir);
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+            compiler,
+        })
+    }
+
+    /// Recompile the shaders changed since the last poll, returning the names of those that
+    /// compiled, e.g. `default` for `default.frag`
+    pub fn poll(&self) -> HashSet<String> {
+        let mut changed_paths = HashSet::<PathBuf>::new();
+        for event in self.events.try_iter() {
+            match event {
+                Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                    changed_paths.extend(event.paths);
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("Shader watcher error: {e}"),
+            }
+        }
+
+        changed_paths
+            .into_iter()
+            // Editors write temporary and backup files next to the shaders
+            .filter(|path| ShaderLanguage::of(path).is_some() && path.is_file())
+            .filter_map(|path| match self.recompile(&path) {
+                Ok(shader_name) => Some(shader_name),
+                Err(e) => {
+                    log::error!(
+                        "Failed to recompile {:?}, keeping the last good version: {e:?}",
+                        path
+                    );
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn recompile(&self, path: &Path) -> Result<String> {
+        let shader_name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_eyre("Shader file name is not valid UTF-8")?;
+
+        // Declaring a keyword in one stage adds variants to the others, so every stage of the
+        // shader is recompiled. Nothing is replaced unless all of them compile.
+        let mut compiled = Vec::new();
+        match ShaderLanguage::of(path) {
+            Some(ShaderLanguage::Glsl(_)) => {
+                let stages = glsl_stages(path.parent().ok_or_eyre("No parent")?, shader_name)?;
+                let keywords = declared_keywords(stages.iter().map(|(_, _, source)| source))
+                    .map_err(|e| eyre!(e))?;
+                for variant in ShaderVariant::all_of(&keywords) {
+                    for (ext, kind, source) in &stages {
+                        let file_name = format!("{}.{}", variant.file_stem(shader_name), ext);
+                        let spv_binary = self.compile_glsl(source, *kind, &file_name, &variant)?;
+                        compiled.push((file_name, spv_binary));
+                    }
+                }
+            }
+            Some(ShaderLanguage::Wgsl) => {
+                let spv_binary = compile_wgsl(&fs::read_to_string(path)?)?;
+                compiled.push((format!("{}.wgsl", shader_name), spv_binary));
+            }
+            None => return Err(eyre!("Shader language not recognized for file: {:?}", path)),
+        }
+
+        for (file_name, spv_binary) in compiled {
+            set_reloaded_shader_code(
+                format!("{}.spv", file_name),
+                bytemuck::cast_slice(&spv_binary).to_vec(),
+            )?;
+            log::info!("Recompiled shader {}", file_name);
+        }
+
+        Ok(shader_name.to_owned())
+    }
+
+    fn compile_glsl(
+        &self,
+        source: &str,
+        kind: ShaderKind,
+        file_name: &str,
+        variant: &ShaderVariant,
+    ) -> Result<Vec<u32>> {
+        let mut options = shaderc::CompileOptions::new()
+            .ok_or_eyre("Failed to create shaderc compile options")?;
+        for keyword in variant.keywords() {
+            options.add_macro_definition(keyword, Some("1"));
+        }
+        // Same settings as `build.rs` uses for the profile the renderer was built with
+        if cfg!(debug_assertions) {
+            options.set_optimization_level(OptimizationLevel::Zero);
+            options.set_generate_debug_info();
+        } else {
+            options.set_optimization_level(OptimizationLevel::Performance);
+        }
+        if matches!(kind, ShaderKind::Task | ShaderKind::Mesh) {
+            options.set_target_spirv(SpirvVersion::V1_4);
+        }
+        let artifact =
+            self.compiler
+                .compile_into_spirv(source, kind, file_name, "main", Some(&options))?;
+        Ok(artifact.as_binary().to_vec())
+    }
+}
+
+/// The GLSL stages of a shader as `(extension, kind, source)`, e.g. `default.vert` and `default.frag`
+fn glsl_stages(shaders_dir: &Path, shader_name: &str) -> Result<Vec<(String, ShaderKind, String)>> {
+    let mut stages = Vec::new();
+    for entry in fs::read_dir(shaders_dir)? {
+        let path = entry?.path();
+        let Some(ShaderLanguage::Glsl(kind)) = ShaderLanguage::of(&path) else {
+            continue;
+        };
+        if path.file_stem().and_then(|stem| stem.to_str()) != Some(shader_name) {
+            continue;
+        }
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or_eyre("Shader file extension is not valid UTF-8")?;
+        stages.push((ext.to_owned(), kind, fs::read_to_string(&path)?));
+    }
+    Ok(stages)
+}
+
+fn compile_wgsl(source: &str) -> Result<Vec<u32>> {
+    let module = wgsl::parse_str(source)?;
+    let mut validator = Validator::new(ValidationFlags::all(), Capabilities::all());
+    let validation_info = validator.validate(&module)?;
+    let mut options = spv::Options::default();
+    options
+        .flags
+        .set(spv::WriterFlags::DEBUG, cfg!(debug_assertions));
+    Ok(spv::write_vec(&module, &validation_info, &options, None)?)
+}
+
+enum ShaderLanguage {
+    Glsl(ShaderKind),
+    /// WGSL declares its stages in the source
+    Wgsl,
+}
+
+impl ShaderLanguage {
+    fn of(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "vert" => Some(Self::Glsl(ShaderKind::Vertex)),
+            "tesc" => Some(Self::Glsl(ShaderKind::TessControl)),
+            "tese" => Some(Self::Glsl(ShaderKind::TessEvaluation)),
+            "geom" => Some(Self::Glsl(ShaderKind::Geometry)),
+            "frag" => Some(Self::Glsl(ShaderKind::Fragment)),
+            "task" => Some(Self::Glsl(ShaderKind::Task)),
+            "mesh" => Some(Self::Glsl(ShaderKind::Mesh)),
+            "comp" => Some(Self::Glsl(ShaderKind::Compute)),
+            "wgsl" => Some(Self::Wgsl),
+            _ => None,
+        }
+    }
+}
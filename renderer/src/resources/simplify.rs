@@ -0,0 +1,260 @@
+//! Quadric error mesh simplification, for levels of detail drawn with a mesh's own vertices
+
+use crate::resources::mesh::Mesh;
+use crate::resources::mesh_processing::position_key;
+use glam::{DMat3, DVec3};
+use std::collections::HashMap;
+
+impl Mesh {
+    /// Indices of a coarser version of the mesh with at most about `target_index_count`
+    /// indices, collapsing edges in order of their quadric error (Garland and Heckbert) until
+    /// the target is reached or every collapse left would exceed `max_error`. Collapses only
+    /// move vertices onto others, so the result indexes the mesh's vertices unchanged.
+    /// Open borders and vertices split at attribute seams stay in place.
+    ///
+    /// Also returns an upper bound of how far the surface moved, in the mesh's space.
+    pub fn simplify(&self, target_index_count: usize, max_error: f32) -> (Vec<u32>, f32) {
+        let positions = self
+            .vertices
+            .iter()
+            .map(|vertex| vertex.position.as_dvec3())
+            .collect::<Vec<_>>();
+        let mut triangles = self.triangles();
+
+        // Vertices at the same position move together, which is only possible when all the
+        // triangles at that position use the same vertex
+        let mut group_ids = HashMap::new();
+        let groups = self
+            .vertices
+            .iter()
+            .map(|vertex| {
+                let next = group_ids.len();
+                *group_ids
+                    .entry(position_key(vertex.position))
+                    .or_insert(next)
+            })
+            .collect::<Vec<_>>();
+        let mut group_vertices = vec![None; group_ids.len()];
+        let mut locked = vec![false; group_ids.len()];
+        for &index in triangles.iter().flatten() {
+            let group = groups[index as usize];
+            match group_vertices[group] {
+                None => group_vertices[group] = Some(index),
+                Some(vertex) if vertex != index => locked[group] = true,
+                Some(_) => {}
+            }
+        }
+
+        // Edges of a single triangle are on an open border
+        let mut edge_triangles = HashMap::<(usize, usize), u32>::new();
+        for &triangle in &triangles {
+            for (a, b) in edges(triangle) {
+                let (a, b) = (groups[a as usize], groups[b as usize]);
+                *edge_triangles.entry((a.min(b), a.max(b))).or_default() += 1;
+            }
+        }
+        for (&(a, b), &count) in &edge_triangles {
+            if count == 1 {
+                locked[a] = true;
+                locked[b] = true;
+            }
+        }
+
+        let mut quadrics = vec![Quadric::ZERO; group_ids.len()];
+        for &triangle in &triangles {
+            let quadric = Quadric::from_triangle(triangle.map(|index| positions[index as usize]));
+            for index in triangle {
+                quadrics[groups[index as usize]].add(&quadric);
+            }
+        }
+
+        let max_cost = f64::from(max_error).powi(2);
+        let target_triangle_count = target_index_count / 3;
+        let mut cost_reached = 0.0_f64;
+        while triangles.len() > target_triangle_count {
+            let mut vertex_triangles = vec![Vec::new(); self.vertices.len()];
+            for (triangle_index, triangle) in triangles.iter().enumerate() {
+                for &index in triangle {
+                    vertex_triangles[index as usize].push(triangle_index);
+                }
+            }
+
+            let mut collapses = triangles
+                .iter()
+                .flat_map(|&triangle| edges(triangle))
+                .flat_map(|(a, b)| [(a, b), (b, a)])
+                .filter(|&(from, to)| {
+                    let (from_group, to_group) = (groups[from as usize], groups[to as usize]);
+                    !locked[from_group] && from_group != to_group
+                })
+                .map(|(from, to)| {
+                    let cost = quadrics[groups[from as usize]].error(positions[to as usize]);
+                    (cost, from, to)
+                })
+                .filter(|&(cost, ..)| cost <= max_cost)
+                .collect::<Vec<_>>();
+            collapses.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+            // Each pass only collapses edges whose triangles no earlier collapse of the pass
+            // changed, so the flip test sees the triangles as they will be
+            let mut remap = (0..self.vertices.len() as u32).collect::<Vec<_>>();
+            let mut touched = vec![false; self.vertices.len()];
+            let mut removed = 0;
+            for (cost, from, to) in collapses {
+                if triangles.len() - removed <= target_triangle_count {
+                    break;
+                }
+                if touched[from as usize] || touched[to as usize] {
+                    continue;
+                }
+                let around = &vertex_triangles[from as usize];
+                if around
+                    .iter()
+                    .any(|&triangle| flips(triangles[triangle], from, to, &positions))
+                {
+                    continue;
+                }
+
+                remap[from as usize] = to;
+                let quadric = quadrics[groups[from as usize]];
+                quadrics[groups[to as usize]].add(&quadric);
+                cost_reached = cost_reached.max(cost);
+                for &triangle in around {
+                    for index in triangles[triangle] {
+                        touched[index as usize] = true;
+                    }
+                    if triangles[triangle].contains(&to) {
+                        removed += 1;
+                    }
+                }
+            }
+            if removed == 0 {
+                break;
+            }
+
+            triangles = triangles
+                .into_iter()
+                .map(|triangle| triangle.map(|index| remap[index as usize]))
+                .filter(|&[a, b, c]| a != b && b != c && c != a)
+                .collect();
+        }
+
+        (triangles.concat(), cost_reached.max(0.0).sqrt() as f32)
+    }
+}
+
+/// Sum of the squared distances to a set of planes, as `p·Ap + 2b·p + c`
+#[derive(Clone, Copy, Debug)]
+struct Quadric {
+    a: DMat3,
+    b: DVec3,
+    c: f64,
+}
+
+impl Quadric {
+    const ZERO: Self = Self {
+        a: DMat3::ZERO,
+        b: DVec3::ZERO,
+        c: 0.0,
+    };
+
+    /// Of the triangle's plane, or zero if it is degenerate
+    fn from_triangle([a, b, c]: [DVec3; 3]) -> Self {
+        let Some(normal) = (b - a).cross(c - a).try_normalize() else {
+            return Self::ZERO;
+        };
+        let distance = -normal.dot(a);
+        Self {
+            a: DMat3::from_cols(normal * normal.x, normal * normal.y, normal * normal.z),
+            b: normal * distance,
+            c: distance * distance,
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.a += other.a;
+        self.b += other.b;
+        self.c += other.c;
+    }
+
+    fn error(&self, position: DVec3) -> f64 {
+        position.dot(self.a * position) + 2.0 * self.b.dot(position) + self.c
+    }
+}
+
+fn edges([a, b, c]: [u32; 3]) -> [(u32, u32); 3] {
+    [(a, b), (b, c), (c, a)]
+}
+
+/// Whether moving `from` onto `to` turns the triangle over or flattens it, unless the
+/// triangle has both and disappears
+fn flips(triangle: [u32; 3], from: u32, to: u32, positions: &[DVec3]) -> bool {
+    if triangle.contains(&to) {
+        return false;
+    }
+    let normal = |[a, b, c]: [DVec3; 3]| (b - a).cross(c - a);
+    let before = triangle.map(|index| positions[index as usize]);
+    let after = triangle.map(|index| positions[if index == from { to } else { index } as usize]);
+    normal(before).dot(normal(after)) <= 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec3;
+
+    /// Normal of each triangle with a length of twice its area
+    fn weighted_normals(mesh: &Mesh, indices: &[u32]) -> Vec<Vec3> {
+        indices
+            .chunks_exact(3)
+            .map(|triangle| {
+                let [a, b, c] =
+                    [0, 1, 2].map(|corner| mesh.vertices[triangle[corner] as usize].position);
+                (b - a).cross(c - a)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flat_interior_collapses_onto_the_border() {
+        let plane = Mesh::new_plane(2.0, 2.0, 8, 8);
+        let (indices, error) = plane.simplify(0, 1e-4);
+
+        assert!(indices.len() < plane.indices.as_ref().unwrap().len() / 4);
+        assert!(error < 1e-4);
+        for &index in &indices {
+            let position = plane.vertices[index as usize].position;
+            assert!(position.x.abs() == 1.0 || position.z.abs() == 1.0);
+        }
+        // Still covering the plane once, facing up
+        let normals = weighted_normals(&plane, &indices);
+        assert!(normals.iter().all(|normal| normal.y > 0.0));
+        let area = normals.iter().map(|normal| normal.y).sum::<f32>() / 2.0;
+        assert!((area - 4.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn curved_surface_reaches_the_target_within_the_error() {
+        let sphere = Mesh::new_uv_sphere(1.0, 32, 16);
+        let index_count = sphere.indices.as_ref().unwrap().len();
+
+        let (indices, error) = sphere.simplify(index_count / 4, 0.5);
+        assert!(indices.len() <= index_count / 4);
+        assert!(error > 0.0 && error <= 0.5);
+        // No triangle turned inward
+        for (normal, triangle) in weighted_normals(&sphere, &indices)
+            .into_iter()
+            .zip(indices.chunks_exact(3))
+        {
+            let center = triangle
+                .iter()
+                .map(|&index| sphere.vertices[index as usize].position)
+                .sum::<Vec3>();
+            assert!(normal.dot(center) >= 0.0);
+        }
+
+        let (strict, strict_error) = sphere.simplify(0, 0.01);
+        assert!(strict_error <= 0.01);
+        assert!(strict.len() > indices.len());
+    }
+}
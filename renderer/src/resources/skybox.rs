@@ -0,0 +1,153 @@
+use crate::camera::Camera;
+use crate::context::commands::CommandEncoder;
+use crate::context::desc_set_layout_builder::DescriptorSetLayoutBuilder;
+use crate::context::device::RenderDevice;
+use crate::resources::{
+    material::{Material, MaterialFactory},
+    render_target::RenderTargetFormats,
+    resource_type::RenderResourceType,
+    shader::GraphicsShader,
+    shader_variant::ShaderVariant,
+    texture::CubemapTexture,
+    vertex::VertexLayout,
+};
+use ash::vk;
+use color_eyre::Result;
+use glam::Mat4;
+use std::sync::Arc;
+
+/// The skybox cube is generated in the vertex shader from `gl_VertexIndex`
+const SKYBOX_VERTEX_COUNT: u32 = 36;
+
+/// A cubemap drawn behind the rest of the scene.
+pub(crate) struct Skybox {
+    _cubemap: CubemapTexture,
+    material: Material,
+    sampler: vk::Sampler,
+    device: Arc<ash::Device>,
+}
+
+impl Skybox {
+    pub fn new(
+        cubemap: CubemapTexture,
+        material_factory: &mut MaterialFactory,
+        device: Arc<ash::Device>,
+    ) -> Result<Self> {
+        let sampler = unsafe {
+            device.create_sampler(
+                &vk::SamplerCreateInfo::default()
+                    .mag_filter(vk::Filter::LINEAR)
+                    .min_filter(vk::Filter::LINEAR)
+                    .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                    .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+                None,
+            )?
+        };
+
+        let material = material_factory.create_material()?;
+        material.write_sampled_image(
+            0,
+            0,
+            cubemap.view,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        );
+        material.write_sampler(1, 0, sampler);
+
+        Ok(Self {
+            _cubemap: cubemap,
+            material,
+            sampler,
+            device,
+        })
+    }
+
+    /// Must be recorded inside a rendering scope whose depth buffer was cleared to 1.0,
+    /// with targets of the formats `material_factory` was created for
+    pub fn draw(
+        &self,
+        cmd: &CommandEncoder,
+        cam: &Camera,
+        extent: vk::Extent2D,
+        material_factory: &MaterialFactory,
+    ) -> Result<()> {
+        let viewproj: Mat4 = cam.get_skybox_viewproj_mat(extent.width as f32, extent.height as f32);
+
+        material_factory.bind_pipeline(cmd.command_buffer)?;
+        self.material.bind_descriptor_sets(cmd.command_buffer);
+        self.material
+            .update_push_constants(cmd.command_buffer, bytemuck::bytes_of(&viewproj));
+        cmd.draw(SKYBOX_VERTEX_COUNT, 1);
+        Ok(())
+    }
+
+    pub fn create_material_factory(
+        target_formats: RenderTargetFormats,
+        dev: &RenderDevice,
+    ) -> Result<MaterialFactory> {
+        let device = &dev.logical;
+        let layout_builder = DescriptorSetLayoutBuilder::new()
+            .add_binding(
+                // Cubemap
+                0,
+                RenderResourceType::SampledImage.descriptor_type(),
+                1,
+                vk::ShaderStageFlags::FRAGMENT,
+                vk::DescriptorBindingFlags::UPDATE_AFTER_BIND,
+                None,
+            )
+            .add_binding(
+                // Sampler
+                1,
+                RenderResourceType::Sampler.descriptor_type(),
+                1,
+                vk::ShaderStageFlags::FRAGMENT,
+                vk::DescriptorBindingFlags::UPDATE_AFTER_BIND,
+                None,
+            );
+        let descriptor_set_layout_bindings = layout_builder.describe();
+        let descriptor_set_layout = layout_builder.build(
+            vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL,
+            device,
+        )?;
+
+        // The rotation-only view-projection matrix is the only per-draw data
+        let push_constant_ranges = [vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::ALL)
+            .offset(0)
+            .size(size_of::<Mat4>() as u32)];
+        let set_layouts = [descriptor_set_layout];
+        let pipeline_layout = unsafe {
+            device.create_pipeline_layout(
+                &vk::PipelineLayoutCreateInfo::default()
+                    .set_layouts(&set_layouts)
+                    .push_constant_ranges(&push_constant_ranges),
+                None,
+            )?
+        };
+
+        let shader = GraphicsShader::new("skybox", &ShaderVariant::default(), device.clone())?;
+        dev.create_graphics_material_factory_builder()
+            .with_shader(shader)
+            .with_pipeline_layout(pipeline_layout)
+            .with_descriptor_set_layout(descriptor_set_layout, &descriptor_set_layout_bindings)
+            .with_push_constant_ranges(&push_constant_ranges)
+            .with_vertex_input(VertexLayout::empty())
+            // The camera sits inside the cube, so there is no meaningful back face
+            .with_cull_mode(vk::CullModeFlags::NONE, vk::FrontFace::CLOCKWISE)
+            .with_blending_disabled()
+            .with_depth_test(true, Some(vk::CompareOp::LESS_OR_EQUAL))
+            .with_depth_write(false)
+            .with_render_target_formats(target_formats)
+            .build()
+    }
+}
+
+impl Drop for Skybox {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_sampler(self.sampler, None);
+        }
+    }
+}
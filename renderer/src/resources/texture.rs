@@ -1,5 +1,7 @@
 use super::buffer::Buffer;
+use super::render_target::depth_format_aspect;
 use crate::context::commands::TransferCommandEncoder;
+use crate::context::debug_utils::DebugUtils;
 use ash::vk;
 use color_eyre::eyre::Result;
 use color_eyre::eyre::eyre;
@@ -35,6 +37,20 @@ impl DerefMut for DepthTexture {
     }
 }
 
+#[repr(transparent)]
+pub(crate) struct CubemapTexture(pub Texture);
+impl Deref for CubemapTexture {
+    type Target = Texture;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl DerefMut for CubemapTexture {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
 #[repr(transparent)]
 pub(crate) struct StorageTexture(pub Texture);
 impl Deref for StorageTexture {
@@ -54,16 +70,25 @@ pub(crate) struct TextureCreateInfo {
     pub extent: vk::Extent3D,
     pub usage: vk::ImageUsageFlags,
     pub aspect: vk::ImageAspectFlags,
+    /// 6 for cubemaps, 1 for regular 2D textures
+    pub array_layers: u32,
+    /// Anything above `TYPE_1` is only valid for attachments
+    pub samples: vk::SampleCountFlags,
+    pub flags: vk::ImageCreateFlags,
+    pub view_type: vk::ImageViewType,
     /// Should be true for larger images like fullscreen images
     pub use_dedicated_memory: bool,
 }
 
 pub(crate) struct Texture {
+    /// Debug name of the image and its view
+    pub name: String,
     pub image: vk::Image,
     pub view: vk::ImageView,
     pub format: vk::Format,
     pub extent: vk::Extent3D,
     pub aspect: vk::ImageAspectFlags,
+    pub array_layers: u32,
 
     allocation: Option<vk_mem::Allocation>, // GPU-only memory block
     memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
@@ -76,19 +101,22 @@ impl Texture {
     /// This means that unless you are making a depth image or storage image, you will need to call
     /// `upload()`
     fn new(
+        name: &str,
         create_info: &TextureCreateInfo,
+        debug_utils: &DebugUtils,
         memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
         device: Arc<ash::Device>,
     ) -> Result<Texture> {
         let (image, allocation) = unsafe {
             let image_info = vk::ImageCreateInfo::default()
+                .flags(create_info.flags)
                 .format(create_info.format)
                 .usage(create_info.usage)
                 .extent(create_info.extent)
                 .image_type(vk::ImageType::TYPE_2D)
                 .mip_levels(1)
-                .array_layers(1)
-                .samples(vk::SampleCountFlags::TYPE_1)
+                .array_layers(create_info.array_layers)
+                .samples(create_info.samples)
                 .tiling(vk::ImageTiling::OPTIMAL);
             let allocation_info = vk_mem::AllocationCreateInfo {
                 usage: vk_mem::MemoryUsage::AutoPreferDevice,
@@ -107,25 +135,29 @@ impl Texture {
 
         let view = {
             let info = vk::ImageViewCreateInfo::default()
-                .view_type(vk::ImageViewType::TYPE_2D)
+                .view_type(create_info.view_type)
                 .image(image)
                 .format(create_info.format)
                 .subresource_range(vk::ImageSubresourceRange {
                     base_mip_level: 0,
                     level_count: 1,
                     base_array_layer: 0,
-                    layer_count: 1,
+                    layer_count: create_info.array_layers,
                     aspect_mask: create_info.aspect,
                 });
             unsafe { device.create_image_view(&info, None)? }
         };
+        debug_utils.set_object_name(image, name);
+        debug_utils.set_object_name(view, name);
 
         Ok(Self {
+            name: name.to_owned(),
             image,
             view,
             format: create_info.format,
             extent: create_info.extent,
             aspect: create_info.aspect,
+            array_layers: create_info.array_layers,
 
             allocation: Some(allocation),
             memory_allocator,
@@ -135,10 +167,12 @@ impl Texture {
 
     /// Create a 32-bit shader-readable texture from a byte array
     pub fn new_color_texture_from_bytes(
+        name: &str,
         width: u32,
         height: u32,
         data: Option<&[u8]>,
         use_dedicated_memory: bool,
+        debug_utils: &DebugUtils,
         memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
         device: Arc<ash::Device>,
         transfer: &TransferCommandEncoder,
@@ -155,12 +189,16 @@ impl Texture {
                     | vk::ImageUsageFlags::TRANSFER_DST
                     | vk::ImageUsageFlags::TRANSFER_SRC,
                 aspect: vk::ImageAspectFlags::COLOR,
+                array_layers: 1,
+                samples: vk::SampleCountFlags::TYPE_1,
+                flags: vk::ImageCreateFlags::empty(),
+                view_type: vk::ImageViewType::TYPE_2D,
                 use_dedicated_memory,
             };
-            let mut image = Self::new(&create_info, memory_allocator, device)?;
+            let mut image = Self::new(name, &create_info, debug_utils, memory_allocator, device)?;
 
             if let Some(data) = data {
-                image.upload(data, transfer)?;
+                image.upload(data, debug_utils, transfer)?;
             }
 
             image
@@ -170,8 +208,10 @@ impl Texture {
     }
 
     pub fn new_color_texture_from_image(
+        name: &str,
         image: &image::DynamicImage,
         use_dedicated_memory: bool,
+        debug_utils: &DebugUtils,
         memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
         device: Arc<ash::Device>,
         transfer: &TransferCommandEncoder,
@@ -180,36 +220,158 @@ impl Texture {
         let width = image.width();
         let height = image.height();
         Self::new_color_texture_from_bytes(
+            name,
             width,
             height,
             Some(&data),
             use_dedicated_memory,
+            debug_utils,
             memory_allocator,
             device,
             transfer,
         )
     }
 
-    /// Create a special type of texture used for the depth buffer
+    /// Create a 64-bit floating point shader-readable texture from an HDR image (e.g. `.hdr` or `.exr`).
+    /// Half floats keep the range of the image and, unlike 32-bit floats, can always be filtered linearly.
+    pub fn new_hdr_texture_from_image(
+        name: &str,
+        image: &image::DynamicImage,
+        debug_utils: &DebugUtils,
+        memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
+        device: Arc<ash::Device>,
+        transfer: &TransferCommandEncoder,
+    ) -> Result<ColorTexture> {
+        let data: Vec<u16> = image
+            .to_rgba32f()
+            .into_raw()
+            .into_iter()
+            .map(f16_bits)
+            .collect();
+        let create_info = TextureCreateInfo {
+            format: vk::Format::R16G16B16A16_SFLOAT,
+            extent: vk::Extent3D {
+                width: image.width(),
+                height: image.height(),
+                depth: 1,
+            },
+            usage: vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+            aspect: vk::ImageAspectFlags::COLOR,
+            array_layers: 1,
+            samples: vk::SampleCountFlags::TYPE_1,
+            flags: vk::ImageCreateFlags::empty(),
+            view_type: vk::ImageViewType::TYPE_2D,
+            use_dedicated_memory: true, // Equirectangular maps are usually large
+        };
+        let mut image = Self::new(name, &create_info, debug_utils, memory_allocator, device)?;
+        image.upload(bytemuck::cast_slice(&data), debug_utils, transfer)?;
+
+        Ok(ColorTexture(image))
+    }
+
+    /// Create a color texture that can be rendered into and then copied to the swapchain.
+    /// With MSAA enabled, this is the single-sampled texture the multisampled one resolves into.
+    pub fn new_draw_color_texture(
+        name: &str,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        debug_utils: &DebugUtils,
+        memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
+        device: Arc<ash::Device>,
+    ) -> Result<ColorTexture> {
+        let create_info = TextureCreateInfo {
+            format,
+            extent: vk::Extent3D {
+                width,
+                height,
+                depth: 1,
+            },
+            usage: vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+            aspect: vk::ImageAspectFlags::COLOR,
+            array_layers: 1,
+            samples: vk::SampleCountFlags::TYPE_1,
+            flags: vk::ImageCreateFlags::empty(),
+            view_type: vk::ImageViewType::TYPE_2D,
+            use_dedicated_memory: true, // Assuming the draw image will be used as a fullscreen attachment
+        };
+        Ok(ColorTexture(Self::new(
+            name,
+            &create_info,
+            debug_utils,
+            memory_allocator,
+            device,
+        )?))
+    }
+
+    /// Create a multisampled color attachment. Its contents never leave the rendering scope,
+    /// they are resolved into a single-sampled draw color texture instead.
+    pub fn new_msaa_color_texture(
+        name: &str,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        samples: vk::SampleCountFlags,
+        debug_utils: &DebugUtils,
+        memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
+        device: Arc<ash::Device>,
+    ) -> Result<ColorTexture> {
+        let create_info = TextureCreateInfo {
+            format,
+            extent: vk::Extent3D {
+                width,
+                height,
+                depth: 1,
+            },
+            usage: vk::ImageUsageFlags::COLOR_ATTACHMENT
+                | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+            aspect: vk::ImageAspectFlags::COLOR,
+            array_layers: 1,
+            samples,
+            flags: vk::ImageCreateFlags::empty(),
+            view_type: vk::ImageViewType::TYPE_2D,
+            use_dedicated_memory: true,
+        };
+        Ok(ColorTexture(Self::new(
+            name,
+            &create_info,
+            debug_utils,
+            memory_allocator,
+            device,
+        )?))
+    }
+
+    /// Create a special type of texture used for the depth buffer.
+    /// Formats with a stencil component are viewed with both the depth and stencil aspects.
     pub fn new_depth_texture(
+        name: &str,
         width: u32,
         height: u32,
+        format: vk::Format,
+        samples: vk::SampleCountFlags,
+        debug_utils: &DebugUtils,
         memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
         device: Arc<ash::Device>,
     ) -> Result<DepthTexture> {
         let create_info = TextureCreateInfo {
-            format: vk::Format::D32_SFLOAT,
+            format,
             extent: vk::Extent3D {
                 width,
                 height,
                 depth: 1,
             },
             usage: vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
-            aspect: vk::ImageAspectFlags::DEPTH,
+            aspect: depth_format_aspect(format),
+            array_layers: 1,
+            samples,
+            flags: vk::ImageCreateFlags::empty(),
+            view_type: vk::ImageViewType::TYPE_2D,
             use_dedicated_memory: true, // Assuming the depth image will be used as a fullscreen attachment
         };
         Ok(DepthTexture(Self::new(
+            name,
             &create_info,
+            debug_utils,
             memory_allocator,
             device,
         )?))
@@ -217,10 +379,12 @@ impl Texture {
 
     /// Create a special type of texture likely used by compute shaders
     pub fn new_storage_texture(
+        name: &str,
         width: u32,
         height: u32,
         use_dedicated_memory: bool,
 
+        debug_utils: &DebugUtils,
         memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
         device: Arc<ash::Device>,
     ) -> Result<StorageTexture> {
@@ -238,14 +402,115 @@ impl Texture {
                 extent,
                 usage,
                 aspect: vk::ImageAspectFlags::COLOR,
+                array_layers: 1,
+                samples: vk::SampleCountFlags::TYPE_1,
+                flags: vk::ImageCreateFlags::empty(),
+                view_type: vk::ImageViewType::TYPE_2D,
                 use_dedicated_memory,
             };
-            Texture::new(&create_info, memory_allocator, device)?
+            Texture::new(name, &create_info, debug_utils, memory_allocator, device)?
         };
 
         Ok(StorageTexture(image))
     }
 
+    /// Create a shader-readable cubemap from six square faces,
+    /// ordered +X, -X, +Y, -Y, +Z, -Z as Vulkan expects
+    pub fn new_cubemap_texture_from_faces(
+        name: &str,
+        faces: &[image::DynamicImage; 6],
+        debug_utils: &DebugUtils,
+        memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
+        device: Arc<ash::Device>,
+        transfer: &TransferCommandEncoder,
+    ) -> Result<CubemapTexture> {
+        let face_size = faces[0].width();
+        if faces
+            .iter()
+            .any(|face| face.width() != face_size || face.height() != face_size)
+        {
+            return Err(eyre!(
+                "All cubemap faces must be square and of the same size"
+            ));
+        }
+
+        // Layers are tightly packed one after another in the staging buffer
+        let data = faces
+            .iter()
+            .flat_map(|face| face.to_rgba8().into_raw())
+            .collect::<Vec<u8>>();
+
+        let create_info = TextureCreateInfo {
+            format: vk::Format::R8G8B8A8_SRGB,
+            extent: vk::Extent3D {
+                width: face_size,
+                height: face_size,
+                depth: 1,
+            },
+            usage: vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+            aspect: vk::ImageAspectFlags::COLOR,
+            array_layers: 6,
+            samples: vk::SampleCountFlags::TYPE_1,
+            flags: vk::ImageCreateFlags::CUBE_COMPATIBLE,
+            view_type: vk::ImageViewType::CUBE,
+            use_dedicated_memory: true,
+        };
+        let mut image = Self::new(name, &create_info, debug_utils, memory_allocator, device)?;
+        image.upload(&data, debug_utils, transfer)?;
+
+        Ok(CubemapTexture(image))
+    }
+
+    /// Create an empty cubemap that compute shaders can write into through a 2D array view.
+    /// The contents are undefined until a compute pass (e.g. the equirectangular conversion) fills it.
+    pub fn new_storage_cubemap_texture(
+        name: &str,
+        face_size: u32,
+        debug_utils: &DebugUtils,
+        memory_allocator: Arc<Mutex<vk_mem::Allocator>>,
+        device: Arc<ash::Device>,
+    ) -> Result<CubemapTexture> {
+        let create_info = TextureCreateInfo {
+            format: vk::Format::R16G16B16A16_SFLOAT,
+            extent: vk::Extent3D {
+                width: face_size,
+                height: face_size,
+                depth: 1,
+            },
+            usage: vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE,
+            aspect: vk::ImageAspectFlags::COLOR,
+            array_layers: 6,
+            samples: vk::SampleCountFlags::TYPE_1,
+            flags: vk::ImageCreateFlags::CUBE_COMPATIBLE,
+            view_type: vk::ImageViewType::CUBE,
+            use_dedicated_memory: true,
+        };
+        Ok(CubemapTexture(Self::new(
+            name,
+            &create_info,
+            debug_utils,
+            memory_allocator,
+            device,
+        )?))
+    }
+
+    /// Create an additional view over all layers of this texture.
+    /// The caller is responsible for destroying the returned view.
+    pub fn create_view(&self, view_type: vk::ImageViewType) -> Result<vk::ImageView> {
+        let info = vk::ImageViewCreateInfo::default()
+            .view_type(view_type)
+            .image(self.image)
+            .format(self.format)
+            .subresource_range(vk::ImageSubresourceRange {
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: self.array_layers,
+                aspect_mask: self.aspect,
+            });
+        Ok(unsafe { self.device.create_image_view(&info, None)? })
+    }
+
     pub fn transition_layout(
         &mut self,
         cmd: vk::CommandBuffer,
@@ -256,6 +521,7 @@ impl Texture {
             cmd,
             self.image,
             self.aspect,
+            self.array_layers,
             old_layout,
             new_layout,
             self.device.as_ref(),
@@ -292,13 +558,20 @@ impl Texture {
         );
     }
 
-    fn upload(&mut self, data: &[u8], transfer: &TransferCommandEncoder) -> Result<()> {
+    fn upload(
+        &mut self,
+        data: &[u8],
+        debug_utils: &DebugUtils,
+        transfer: &TransferCommandEncoder,
+    ) -> Result<()> {
         let mut staging_buffer = Buffer::new(
+            &format!("{} (staging)", self.name),
             data.len() as u64,
             256,
             vk::BufferUsageFlags::TRANSFER_SRC,
             vk_mem::MemoryUsage::AutoPreferHost,
             true,
+            debug_utils,
             self.memory_allocator.clone(),
             self.device.clone(),
         )?;
@@ -309,7 +582,7 @@ impl Texture {
                 base_mip_level: 0,
                 level_count: 1,
                 base_array_layer: 0,
-                layer_count: 1,
+                layer_count: self.array_layers,
             };
 
             let img_barrier_to_transfer = vk::ImageMemoryBarrier {
@@ -345,7 +618,7 @@ impl Texture {
                     aspect_mask: self.aspect,
                     mip_level: 0,
                     base_array_layer: 0,
-                    layer_count: 1,
+                    layer_count: self.array_layers,
                 },
                 image_extent: self.extent,
                 ..Default::default()
@@ -373,7 +646,8 @@ impl Texture {
                 device.cmd_pipeline_barrier(
                     cmd,
                     vk::PipelineStageFlags::TRANSFER,
-                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER
+                        | vk::PipelineStageFlags::COMPUTE_SHADER,
                     vk::DependencyFlags::empty(),
                     &[],
                     &[],
@@ -457,10 +731,11 @@ fn copy_vkimage_to_vkimage(
     }
 }
 
-fn transition_image_layout(
+pub(crate) fn transition_image_layout(
     cmd: vk::CommandBuffer,
     image: vk::Image,
     image_aspect: vk::ImageAspectFlags,
+    layer_count: u32,
     old_layout: vk::ImageLayout,
     new_layout: vk::ImageLayout,
     device: &ash::Device,
@@ -481,7 +756,7 @@ fn transition_image_layout(
             base_mip_level: 0,
             level_count: 1,
             base_array_layer: 0,
-            layer_count: 1,
+            layer_count,
         },
         image,
         ..Default::default()
@@ -497,3 +772,55 @@ fn transition_image_layout(
         device.cmd_pipeline_barrier2(cmd, &dep_info);
     }
 }
+
+/// Bits of the nearest half float, rounding ties to even. Values past the half range are
+/// clamped to the largest finite half.
+fn f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let magnitude = value.abs();
+    if value.is_nan() {
+        return sign | 0x7e00;
+    }
+    if magnitude >= 65504.0 {
+        return sign | 0x7bff;
+    }
+    // Below the smallest normal half, in steps of the smallest subnormal one
+    if magnitude < 2f32.powi(-14) {
+        return sign | (magnitude * 2f32.powi(24)).round_ties_even() as u16;
+    }
+
+    // Rebiased from 127 to 15, which keeps it positive above the smallest normal half
+    let exponent = ((bits >> 23) & 0xff) - (127 - 15);
+    let mantissa = bits & 0x7f_ffff;
+    let truncated = (exponent << 10) | (mantissa >> 13);
+    let rest = mantissa & 0x1fff;
+    // A carry out of the mantissa bumps the exponent, which is the right rounding too
+    let round_up = rest > 0x1000 || (rest == 0x1000 && truncated & 1 == 1);
+    sign | (truncated + u32::from(round_up)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_floats_round_to_nearest_even_and_clamp() {
+        assert_eq!(f16_bits(0.0), 0x0000);
+        assert_eq!(f16_bits(-0.0), 0x8000);
+        assert_eq!(f16_bits(1.0), 0x3c00);
+        assert_eq!(f16_bits(-2.0), 0xc000);
+        assert_eq!(f16_bits(0.5), 0x3800);
+        assert_eq!(f16_bits(65504.0), 0x7bff);
+        assert_eq!(f16_bits(1.0e6), 0x7bff);
+        assert_eq!(f16_bits(f32::NEG_INFINITY), 0xfbff);
+        assert_eq!(f16_bits(2f32.powi(-14)), 0x0400);
+        assert_eq!(f16_bits(2f32.powi(-24)), 0x0001);
+        assert_eq!(f16_bits(2f32.powi(-26)), 0x0000);
+        // Halfway between two halves, towards the even one
+        assert_eq!(f16_bits(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(f16_bits(1.0 + 3.0 * 2f32.powi(-11)), 0x3c02);
+        assert_eq!(f16_bits(2.0 - 2f32.powi(-12)), 0x4000);
+        assert_eq!(f16_bits(f32::NAN) & 0x7e00, 0x7e00);
+    }
+}
@@ -1,16 +1,383 @@
 use ash::vk;
-use glam::{Vec2, Vec3};
-use std::mem::offset_of;
-use crate::storage::shader_data::PerVertexData;
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+use glam::{UVec4, Vec2, Vec3, Vec4};
+use std::ops::Range;
 
-#[derive(Debug)]
+/// Every attribute a mesh can provide. Which of them reach the GPU, and in which format,
+/// is decided by the `VertexLayout` the mesh's model is packed with.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Vertex {
     pub position: Vec3,
     pub normal: Vec3,
     pub color: Vec3,
     pub texcoord: Vec2,
+    /// Handedness in `w`
+    pub tangent: Vec4,
+    pub texcoord1: Vec2,
+    pub joints: UVec4,
+    pub weights: Vec4,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VertexSemantic {
+    Position,
+    Normal,
+    Tangent,
+    Color,
+    Texcoord0,
+    Texcoord1,
+    Joints,
+    Weights,
+}
+
+impl VertexSemantic {
+    /// Missing components read as 0, except the alpha of colors which is 1
+    fn read(self, vertex: &Vertex) -> [f32; 4] {
+        match self {
+            VertexSemantic::Position => vertex.position.extend(0.0).to_array(),
+            VertexSemantic::Normal => vertex.normal.extend(0.0).to_array(),
+            VertexSemantic::Tangent => vertex.tangent.to_array(),
+            VertexSemantic::Color => vertex.color.extend(1.0).to_array(),
+            VertexSemantic::Texcoord0 => [vertex.texcoord.x, vertex.texcoord.y, 0.0, 0.0],
+            VertexSemantic::Texcoord1 => [vertex.texcoord1.x, vertex.texcoord1.y, 0.0, 0.0],
+            VertexSemantic::Joints => vertex.joints.as_vec4().to_array(),
+            VertexSemantic::Weights => vertex.weights.to_array(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VertexAttribute {
+    pub semantic: VertexSemantic,
+    pub location: u32,
+    pub format: vk::Format,
+    /// Byte offset within a vertex of its stream
+    pub offset: u32,
+}
+
+/// Attributes interleaved in one vertex buffer binding
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VertexStream {
+    pub attributes: Vec<VertexAttribute>,
+}
+
+impl VertexStream {
+    /// Size of one vertex, rounded up to 4 bytes
+    pub fn stride(&self) -> u32 {
+        let end = self
+            .attributes
+            .iter()
+            .map(|attribute| {
+                attribute.offset + format_encoding(attribute.format).map_or(0, |(_, size)| size)
+            })
+            .max()
+            .unwrap_or(0);
+        end.next_multiple_of(4)
+    }
+}
+
+/// How vertices are split into streams and encoded, shared by the models packing their
+/// vertex buffers and the materials whose pipelines read them.
+/// Stream `n` is bound to vertex buffer binding `n`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VertexLayout {
+    streams: Vec<VertexStream>,
+}
+
+impl Default for VertexLayout {
+    /// The position and texcoord read by the default shaders
+    fn default() -> Self {
+        Self::empty()
+            .with_attribute(0, VertexSemantic::Position, vk::Format::R32G32B32_SFLOAT)
+            .with_attribute(1, VertexSemantic::Texcoord0, vk::Format::R32G32_SFLOAT)
+    }
+}
+
+impl VertexLayout {
+    /// For shaders that generate their vertices from `gl_VertexIndex`
+    pub fn empty() -> Self {
+        Self {
+            streams: Vec::new(),
+        }
+    }
+
+    /// The single 32-byte stream that shaders using vertex pulling read through a buffer reference.
+    /// Matches `PulledVertex` in `default.vert`.
+    pub fn pulled() -> Self {
+        Self::empty()
+            .with_attribute(0, VertexSemantic::Position, vk::Format::R32G32B32_SFLOAT)
+            .with_attribute(1, VertexSemantic::Texcoord0, vk::Format::R32G32_SFLOAT)
+            .with_attribute(2, VertexSemantic::Normal, vk::Format::R32G32B32_SFLOAT)
+    }
+
+    /// The default layout with the joints and weights read by the `SKINNING` variant of
+    /// `default.vert`, in the same stream
+    pub fn skinned() -> Self {
+        Self::default()
+            .with_attribute(2, VertexSemantic::Joints, vk::Format::R16G16B16A16_UINT)
+            .with_attribute(3, VertexSemantic::Weights, vk::Format::R32G32B32A32_SFLOAT)
+    }
+
+    /// Whether any stream has an attribute with `semantic`
+    pub fn has(&self, semantic: VertexSemantic) -> bool {
+        self.streams
+            .iter()
+            .flat_map(|stream| &stream.attributes)
+            .any(|attribute| attribute.semantic == semantic)
+    }
+
+    /// Start a new stream, which following attributes are added to
+    pub fn with_stream(mut self) -> Self {
+        self.streams.push(VertexStream::default());
+        self
+    }
+
+    /// Add an attribute right after the previous one of the current stream
+    pub fn with_attribute(
+        self,
+        location: u32,
+        semantic: VertexSemantic,
+        format: vk::Format,
+    ) -> Self {
+        let offset = self.streams.last().map_or(0, VertexStream::stride);
+        self.with_attribute_at(location, semantic, format, offset)
+    }
+
+    /// Add an attribute at an explicit byte offset of the current stream
+    pub fn with_attribute_at(
+        mut self,
+        location: u32,
+        semantic: VertexSemantic,
+        format: vk::Format,
+        offset: u32,
+    ) -> Self {
+        if self.streams.is_empty() {
+            self.streams.push(VertexStream::default());
+        }
+        self.streams
+            .last_mut()
+            .unwrap()
+            .attributes
+            .push(VertexAttribute {
+                semantic,
+                location,
+                format,
+                offset,
+            });
+        self
+    }
+
+    pub fn streams(&self) -> &[VertexStream] {
+        &self.streams
+    }
+
+    /// Fails on unsupported formats, overlapping attributes and reused locations
+    pub fn validate(&self) -> Result<()> {
+        let mut locations = Vec::new();
+        for (index, stream) in self.streams.iter().enumerate() {
+            if stream.attributes.is_empty() {
+                return Err(eyre!("Vertex stream {} has no attributes", index));
+            }
+
+            let mut ranges = Vec::<Range<u32>>::new();
+            for attribute in &stream.attributes {
+                let (encoding, size) = format_encoding(attribute.format).ok_or_else(|| {
+                    eyre!(
+                        "Vertex attribute at location {} has unsupported format {:?}",
+                        attribute.location,
+                        attribute.format
+                    )
+                })?;
+                if encoding.is_integer() && attribute.semantic != VertexSemantic::Joints {
+                    return Err(eyre!(
+                        "{:?} at location {} cannot use the integer format {:?}",
+                        attribute.semantic,
+                        attribute.location,
+                        attribute.format
+                    ));
+                }
+                if locations.contains(&attribute.location) {
+                    return Err(eyre!(
+                        "Vertex attribute location {} is used more than once",
+                        attribute.location
+                    ));
+                }
+                locations.push(attribute.location);
+
+                let range = attribute.offset..attribute.offset + size;
+                if let Some(other) = ranges
+                    .iter()
+                    .find(|other| range.start < other.end && other.start < range.end)
+                {
+                    return Err(eyre!(
+                        "Vertex attribute at location {} (bytes {:?}) overlaps bytes {:?} of stream {}",
+                        attribute.location,
+                        range,
+                        other,
+                        index
+                    ));
+                }
+                ranges.push(range);
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode the vertices into the bytes of each stream
+    pub fn pack(&self, vertices: &[Vertex]) -> Result<Vec<Vec<u8>>> {
+        self.validate()?;
+
+        self.streams
+            .iter()
+            .map(|stream| {
+                let stride = stream.stride() as usize;
+                let mut bytes = vec![0; stride * vertices.len()];
+                for (vertex, vertex_bytes) in vertices.iter().zip(bytes.chunks_exact_mut(stride)) {
+                    for attribute in &stream.attributes {
+                        let (encoding, size) = format_encoding(attribute.format).unwrap();
+                        let start = attribute.offset as usize;
+                        encoding.encode(
+                            attribute,
+                            vertex,
+                            &mut vertex_bytes[start..start + size as usize],
+                        )?;
+                    }
+                }
+                Ok(bytes)
+            })
+            .collect()
+    }
+
+    pub fn input_description(&self) -> VertexInputDescription {
+        let bindings = self
+            .streams
+            .iter()
+            .enumerate()
+            .map(|(binding, stream)| vk::VertexInputBindingDescription {
+                binding: binding as u32,
+                stride: stream.stride(),
+                input_rate: vk::VertexInputRate::VERTEX,
+            })
+            .collect();
+        let attributes =
+            self.streams
+                .iter()
+                .enumerate()
+                .flat_map(|(binding, stream)| {
+                    stream.attributes.iter().map(move |attribute| {
+                        vk::VertexInputAttributeDescription {
+                            binding: binding as u32,
+                            location: attribute.location,
+                            format: attribute.format,
+                            offset: attribute.offset,
+                        }
+                    })
+                })
+                .collect();
+
+        VertexInputDescription {
+            bindings,
+            attributes,
+            flags: vk::PipelineVertexInputStateCreateFlags::empty(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ComponentEncoding {
+    Float32,
+    Unorm8,
+    Snorm8,
+    Unorm16,
+    Snorm16,
+    Uint8,
+    Uint16,
+    Uint32,
+}
+
+impl ComponentEncoding {
+    fn is_integer(self) -> bool {
+        matches!(
+            self,
+            ComponentEncoding::Uint8 | ComponentEncoding::Uint16 | ComponentEncoding::Uint32
+        )
+    }
+
+    fn component_size(self) -> usize {
+        match self {
+            ComponentEncoding::Unorm8 | ComponentEncoding::Snorm8 | ComponentEncoding::Uint8 => 1,
+            ComponentEncoding::Unorm16 | ComponentEncoding::Snorm16 | ComponentEncoding::Uint16 => {
+                2
+            }
+            ComponentEncoding::Float32 | ComponentEncoding::Uint32 => 4,
+        }
+    }
+
+    fn encode(self, attribute: &VertexAttribute, vertex: &Vertex, bytes: &mut [u8]) -> Result<()> {
+        let component_size = self.component_size();
+        let values = attribute.semantic.read(vertex);
+        let joints = vertex.joints.to_array();
+
+        for (i, component) in bytes.chunks_exact_mut(component_size).enumerate() {
+            let value = values[i];
+            match self {
+                ComponentEncoding::Float32 => component.copy_from_slice(&value.to_le_bytes()),
+                ComponentEncoding::Unorm8 => {
+                    component[0] = (value.clamp(0.0, 1.0) * 255.0).round() as u8
+                }
+                ComponentEncoding::Snorm8 => {
+                    component[0] = ((value.clamp(-1.0, 1.0) * 127.0).round() as i8) as u8
+                }
+                ComponentEncoding::Unorm16 => component.copy_from_slice(
+                    &((value.clamp(0.0, 1.0) * 65535.0).round() as u16).to_le_bytes(),
+                ),
+                ComponentEncoding::Snorm16 => component.copy_from_slice(
+                    &((value.clamp(-1.0, 1.0) * 32767.0).round() as i16).to_le_bytes(),
+                ),
+                ComponentEncoding::Uint8
+                | ComponentEncoding::Uint16
+                | ComponentEncoding::Uint32 => {
+                    let joint = joints[i];
+                    let max = u32::MAX >> (32 - 8 * component_size);
+                    if joint > max {
+                        return Err(eyre!(
+                            "Joint index {} does not fit the format {:?} of location {}",
+                            joint,
+                            attribute.format,
+                            attribute.location
+                        ));
+                    }
+                    component.copy_from_slice(&joint.to_le_bytes()[..component_size]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How each component of a format is encoded, and the size of the whole attribute
+fn format_encoding(format: vk::Format) -> Option<(ComponentEncoding, u32)> {
+    let (encoding, components) = match format {
+        vk::Format::R32_SFLOAT => (ComponentEncoding::Float32, 1),
+        vk::Format::R32G32_SFLOAT => (ComponentEncoding::Float32, 2),
+        vk::Format::R32G32B32_SFLOAT => (ComponentEncoding::Float32, 3),
+        vk::Format::R32G32B32A32_SFLOAT => (ComponentEncoding::Float32, 4),
+        vk::Format::R8G8B8A8_UNORM => (ComponentEncoding::Unorm8, 4),
+        vk::Format::R8G8B8A8_SNORM => (ComponentEncoding::Snorm8, 4),
+        vk::Format::R16G16_UNORM => (ComponentEncoding::Unorm16, 2),
+        vk::Format::R16G16B16A16_UNORM => (ComponentEncoding::Unorm16, 4),
+        vk::Format::R16G16_SNORM => (ComponentEncoding::Snorm16, 2),
+        vk::Format::R16G16B16A16_SNORM => (ComponentEncoding::Snorm16, 4),
+        vk::Format::R8G8B8A8_UINT => (ComponentEncoding::Uint8, 4),
+        vk::Format::R16G16B16A16_UINT => (ComponentEncoding::Uint16, 4),
+        vk::Format::R32G32B32A32_UINT => (ComponentEncoding::Uint32, 4),
+        _ => return None,
+    };
+    Some((encoding, (encoding.component_size() * components) as u32))
+}
+
+#[derive(Clone)]
 pub struct VertexInputDescription {
     pub bindings: Vec<vk::VertexInputBindingDescription>,
     pub attributes: Vec<vk::VertexInputAttributeDescription>,
@@ -19,48 +386,177 @@ pub struct VertexInputDescription {
 
 impl Default for VertexInputDescription {
     fn default() -> Self {
-        Vertex::get_input_description()
+        VertexLayout::default().input_description()
     }
 }
 
-impl Vertex {
-    pub fn as_shader_data(&self) -> PerVertexData {
-        PerVertexData {
-            position: self.position,
-            texcoord: self.texcoord,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex() -> Vertex {
+        Vertex {
+            position: Vec3::new(1.0, 2.0, 3.0),
+            normal: Vec3::new(0.0, -1.0, 0.0),
+            color: Vec3::new(1.0, 0.5, 0.0),
+            texcoord: Vec2::new(0.25, 0.75),
+            tangent: Vec4::new(1.0, 0.0, 0.0, -1.0),
+            texcoord1: Vec2::new(0.5, 0.5),
+            joints: UVec4::new(1, 2, 300, 4),
+            weights: Vec4::new(0.5, 0.25, 0.25, 0.0),
         }
     }
 
-    pub fn get_input_description() -> VertexInputDescription {
-        let bindings = vec![vk::VertexInputBindingDescription {
-            binding: 0,
-            stride: size_of::<Vertex>() as u32,
-            input_rate: vk::VertexInputRate::VERTEX,
-        }];
-
-        let attributes = vec![
-            // Position
-            vk::VertexInputAttributeDescription {
-                binding: 0,
-                location: 0,
-                format: vk::Format::R32G32B32_SFLOAT,
-                offset: offset_of!(Vertex, position) as u32,
-            },
-            // Texcoord
-            vk::VertexInputAttributeDescription {
-                binding: 0,
-                location: 1,
-                format: vk::Format::R32G32_SFLOAT,
-                offset: offset_of!(Vertex, texcoord) as u32,
-            },
-        ];
-
-        let flags = vk::PipelineVertexInputStateCreateFlags::empty();
+    fn floats(bytes: &[u8]) -> Vec<f32> {
+        bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect()
+    }
 
-        VertexInputDescription {
-            bindings,
-            attributes,
-            flags,
-        }
+    #[test]
+    fn default_layout_stride_matches_packed_data() {
+        let layout = VertexLayout::default();
+        let streams = layout.pack(&[vertex(), Vertex::default()]).unwrap();
+        let description = layout.input_description();
+
+        assert_eq!(streams.len(), 1);
+        assert_eq!(description.bindings[0].stride, 20);
+        assert_eq!(streams[0].len(), 2 * 20);
+        assert_eq!(floats(&streams[0][..20]), [1.0, 2.0, 3.0, 0.25, 0.75]);
+        assert_eq!(
+            description
+                .attributes
+                .iter()
+                .map(|attribute| (attribute.location, attribute.offset))
+                .collect::<Vec<_>>(),
+            [(0, 0), (1, 12)]
+        );
+    }
+
+    #[test]
+    fn packs_multiple_streams_and_encodings() {
+        let layout = VertexLayout::empty()
+            .with_stream()
+            .with_attribute(0, VertexSemantic::Position, vk::Format::R32G32B32_SFLOAT)
+            .with_stream()
+            .with_attribute(1, VertexSemantic::Normal, vk::Format::R8G8B8A8_SNORM)
+            .with_attribute(2, VertexSemantic::Tangent, vk::Format::R32G32B32A32_SFLOAT)
+            .with_attribute(3, VertexSemantic::Texcoord1, vk::Format::R16G16_UNORM)
+            .with_attribute(4, VertexSemantic::Color, vk::Format::R8G8B8A8_UNORM)
+            .with_attribute(5, VertexSemantic::Joints, vk::Format::R16G16B16A16_UINT)
+            .with_attribute(6, VertexSemantic::Weights, vk::Format::R32G32B32A32_SFLOAT);
+        let streams = layout.pack(&[vertex()]).unwrap();
+        let description = layout.input_description();
+
+        assert_eq!(
+            description
+                .bindings
+                .iter()
+                .map(|binding| (binding.binding, binding.stride))
+                .collect::<Vec<_>>(),
+            [(0, 12), (1, 4 + 16 + 4 + 4 + 8 + 16)]
+        );
+        assert!(
+            description.attributes[1..]
+                .iter()
+                .all(|attribute| attribute.binding == 1)
+        );
+
+        let bytes = &streams[1];
+        assert_eq!(bytes[0..4], [0, (-127i8) as u8, 0, 0]);
+        assert_eq!(floats(&bytes[4..20]), [1.0, 0.0, 0.0, -1.0]);
+        assert_eq!(bytes[20..24], [0x00, 0x80, 0x00, 0x80]);
+        assert_eq!(bytes[24..28], [255, 128, 0, 255]);
+        let joints = bytes[28..36]
+            .chunks_exact(2)
+            .map(|chunk| u16::from_le_bytes(chunk.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(joints, [1, 2, 300, 4]);
+        assert_eq!(floats(&bytes[36..52]), [0.5, 0.25, 0.25, 0.0]);
+    }
+
+    #[test]
+    fn pulled_vertices_are_32_bytes() {
+        let layout = VertexLayout::pulled();
+        let streams = layout.pack(&[vertex()]).unwrap();
+
+        assert_eq!(layout.streams().len(), 1);
+        assert_eq!(layout.streams()[0].stride(), 32);
+        assert_eq!(
+            floats(&streams[0]),
+            [1.0, 2.0, 3.0, 0.25, 0.75, 0.0, -1.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn skinned_layout_extends_the_default_one() {
+        let layout = VertexLayout::skinned();
+        layout.validate().unwrap();
+
+        assert_eq!(layout.streams().len(), 1);
+        assert_eq!(layout.streams()[0].stride(), 20 + 8 + 16);
+        assert!(layout.has(VertexSemantic::Joints) && layout.has(VertexSemantic::Weights));
+        assert!(!VertexLayout::default().has(VertexSemantic::Joints));
+        let streams = layout.pack(&[vertex()]).unwrap();
+        assert_eq!(
+            streams[0][..20],
+            VertexLayout::default().pack(&[vertex()]).unwrap()[0]
+        );
+    }
+
+    #[test]
+    fn explicit_offsets_set_the_stride() {
+        let layout = VertexLayout::empty()
+            .with_attribute_at(1, VertexSemantic::Texcoord0, vk::Format::R32G32_SFLOAT, 16)
+            .with_attribute_at(0, VertexSemantic::Position, vk::Format::R32G32B32_SFLOAT, 0);
+        let streams = layout.pack(&[vertex()]).unwrap();
+
+        assert_eq!(layout.streams()[0].stride(), 24);
+        assert_eq!(floats(&streams[0]), [1.0, 2.0, 3.0, 0.0, 0.25, 0.75]);
+    }
+
+    #[test]
+    fn rejects_invalid_layouts() {
+        let overlapping = VertexLayout::empty()
+            .with_attribute(0, VertexSemantic::Position, vk::Format::R32G32B32_SFLOAT)
+            .with_attribute_at(1, VertexSemantic::Normal, vk::Format::R32G32B32_SFLOAT, 8);
+        assert!(
+            overlapping
+                .validate()
+                .unwrap_err()
+                .to_string()
+                .contains("overlaps")
+        );
+
+        let reused_location = VertexLayout::default().with_stream().with_attribute(
+            1,
+            VertexSemantic::Normal,
+            vk::Format::R32G32B32_SFLOAT,
+        );
+        assert!(reused_location.validate().is_err());
+
+        let integer_position = VertexLayout::empty().with_attribute(
+            0,
+            VertexSemantic::Position,
+            vk::Format::R32G32B32A32_UINT,
+        );
+        assert!(integer_position.validate().is_err());
+
+        let unsupported = VertexLayout::empty().with_attribute(
+            0,
+            VertexSemantic::Position,
+            vk::Format::R64G64B64_SFLOAT,
+        );
+        assert!(unsupported.validate().is_err());
+        assert!(VertexLayout::empty().with_stream().validate().is_err());
+
+        // Joint 300 does not fit in 8 bits
+        let small_joints = VertexLayout::empty().with_attribute(
+            0,
+            VertexSemantic::Joints,
+            vk::Format::R8G8B8A8_UINT,
+        );
+        assert!(small_joints.pack(&[vertex()]).is_err());
     }
 }
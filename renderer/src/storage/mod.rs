@@ -2,20 +2,35 @@ use crate::viewport::RenderViewport;
 use crate::{
     context::RenderContext,
     context::desc_set_layout_builder::DescriptorSetLayoutBuilder,
+    context::device::RenderDevice,
+    frame::culling::GpuCulling,
+    frame::instancing::ModelKey,
     resources::{
+        cubemap::EquirectToCubemapConverter,
+        gltf_loader::{GltfImport, ModelScene, SamplerDesc},
         material::{GraphicsMaterialFactoryBuilder, MaterialFactory},
-        megabuffer::Megabuffer,
-        model::FullscreenQuad,
+        material_def::MaterialDefinition,
+        megabuffer::{Megabuffer, MegabufferExt},
+        mesh::Mesh,
+        model::{FullscreenQuad, Model},
+        obj_loader::ObjImport,
+        render_target::RenderTargetFormats,
         resource_type::RenderResourceType,
         shader::GraphicsShader,
+        shader_variant::ShaderVariant,
+        skybox::Skybox,
         texture::{ColorTexture, StorageTexture},
+        vertex::VertexLayout,
     },
 };
 use ash::vk;
 use color_eyre::Result;
-use gpu_descriptor::DescriptorAllocator;
-use shader_data::PerDrawData;
-use std::sync::{Arc, Mutex};
+use color_eyre::eyre::{OptionExt, eyre};
+use shader_data::{PerDrawData, PerMaterialData};
+use std::collections::HashMap;
+#[cfg(feature = "hot-reload")]
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 
 pub(crate) mod shader_data;
 
@@ -24,72 +39,176 @@ const INDEX_BUFFER_SIZE: u64 = 1024 * 1024 * 64; // 64 MB
 const PER_FRAME_BUFFER_SIZE: u64 = 16 * 1024 * 1024; // 16 MB
 const PER_MATERIAL_BUFFER_SIZE: u64 = 16 * 1024 * 1024; // 16 MB
 const PER_OBJECT_BUFFER_SIZE: u64 = 16 * 1024 * 1024; // 16 MB
-const VERTEX_BUFFER_ALIGNMENT: u64 = 16;
+const JOINT_BUFFER_SIZE: u64 = 16 * 1024 * 1024; // 16 MB
+/// A multiple of the pulled vertex size, so models start at a whole vertex index
+const VERTEX_BUFFER_ALIGNMENT: u64 = 32;
 const INDEX_BUFFER_ALIGNMENT: u64 = 4;
+/// Coarser levels of detail simplified from every loaded model, at most
+const MODEL_LOD_COUNT: usize = 3;
+/// Furthest the simplified surface of a model may move, relative to its bounding sphere
+const MODEL_LOD_MAX_ERROR: f32 = 0.1;
 const STORAGE_BUFFER_ALIGNMENT: u64 = 16;
 const UNIFORM_BUFFER_ALIGNMENT: u64 = 256;
 
+/// The material factories drawing models, built against one set of render target formats
+pub(crate) struct TargetMaterialFactories {
+    pub bindless: MaterialFactory,
+    /// Draws skinned models with the same layouts, reading their vertices from vertex buffers
+    /// even with vertex pulling
+    pub skinned: MaterialFactory,
+    /// Built from `RenderStorage::material_definitions`, keyed by file name
+    pub defined: HashMap<String, MaterialFactory>,
+    /// Its layouts are defined the same for every set of formats, so a skybox created from
+    /// one factory can be drawn with the pipeline of any other
+    pub skybox: MaterialFactory,
+}
+
 pub(crate) struct RenderStorage {
+    /// Formats the frames render into, which `material_factories` always has factories for
+    pub target_formats: RenderTargetFormats,
+
     pub storage_textures: Vec<StorageTexture>,
     pub sampled_textures: Vec<ColorTexture>,
     pub samplers: Vec<vk::Sampler>,
+    /// Index into `samplers` of every sampler created for a glTF texture, shared by all files
+    pub sampler_indices: HashMap<SamplerDesc, u32>,
+    /// Index into `sampled_textures` of every texture loaded from an image file
+    pub texture_file_indices: HashMap<PathBuf, u32>,
+    /// Index into `sampled_textures` of the 1x1 white texture of untextured materials
+    pub white_texture_index: Option<u32>,
 
     pub vertex_megabuffer: Megabuffer,
+    /// Read by shaders using vertex pulling
+    pub vertex_buffer_address: vk::DeviceAddress,
+    /// Layout every loaded model is packed with, and the bindless material reads
+    pub vertex_layout: VertexLayout,
     pub index_megabuffer: Megabuffer,
     pub per_frame_megabuffer: Megabuffer,
     pub per_material_megabuffer: Megabuffer,
     pub per_object_megabuffer: Megabuffer,
-    pub bindless_material_factory: MaterialFactory,
+    /// Joint palettes of skinned instances
+    pub joint_megabuffer: Megabuffer,
+    pub bindless_descriptor_set_layout: vk::DescriptorSetLayout,
+    pub bindless_pipeline_layout: vk::PipelineLayout,
+    /// Whether the bindless factories pull their vertices
+    pub vertex_pulling: bool,
+    /// Factories of every set of formats added with `add_target_formats`
+    pub material_factories: HashMap<RenderTargetFormats, TargetMaterialFactories>,
+    /// Definitions loaded from material files, keyed by file name
+    pub material_definitions: HashMap<String, MaterialDefinition>,
+    /// Model of every mesh of each loaded glTF file with the material data of each of its
+    /// primitives, keyed by file name
+    pub gltf_models: HashMap<String, Vec<(Model, Vec<PerMaterialData>)>>,
+    /// Model and data of the material of each mesh of every loaded OBJ file, keyed by file name
+    pub obj_models: HashMap<String, (Model, Vec<PerMaterialData>)>,
+    /// `None` unless GPU culling was requested and is supported
+    pub gpu_culling: Option<GpuCulling>,
+    pub equirect_converter: EquirectToCubemapConverter,
 
     pub fullscreen_quad: FullscreenQuad,
+    pub skybox: Option<Skybox>,
 }
 
 impl RenderStorage {
-    pub fn new(ctx: &RenderContext, vpt: &RenderViewport) -> Result<Self> {
+    pub fn new(
+        ctx: &RenderContext,
+        vpt: &RenderViewport,
+        target_formats: RenderTargetFormats,
+        vertex_pulling: bool,
+        gpu_culling: bool,
+    ) -> Result<Self> {
         log::info!("Creating RenderStorage");
         
         let device = &ctx.dev;
 
+        let vertex_layout = if vertex_pulling {
+            VertexLayout::pulled()
+        } else {
+            VertexLayout::default()
+        };
+        // Regions start at a whole vertex, so every model has a `Model::first_vertex`
+        let vertex_alignment = vertex_layout
+            .streams()
+            .iter()
+            .map(|stream| u64::from(stream.stride()))
+            .fold(VERTEX_BUFFER_ALIGNMENT, lcm);
         let vertex_megabuffer = device.create_megabuffer(
+            "Vertex megabuffer",
             VERTEX_BUFFER_SIZE,
-            VERTEX_BUFFER_ALIGNMENT,
-            vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            vertex_alignment,
+            vk::BufferUsageFlags::VERTEX_BUFFER
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                | vk::BufferUsageFlags::TRANSFER_DST,
         )?;
+        let vertex_buffer_address = vertex_megabuffer.device_address()?;
 
         let index_megabuffer = device.create_megabuffer(
+            "Index megabuffer",
             INDEX_BUFFER_SIZE,
             INDEX_BUFFER_ALIGNMENT,
             vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
         )?;
 
         let per_frame_megabuffer = device.create_megabuffer(
+            "Per-frame megabuffer",
             PER_FRAME_BUFFER_SIZE,
             UNIFORM_BUFFER_ALIGNMENT,
             vk::BufferUsageFlags::UNIFORM_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
         )?;
 
         let per_material_megabuffer = device.create_megabuffer(
+            "Per-material megabuffer",
             PER_MATERIAL_BUFFER_SIZE,
             STORAGE_BUFFER_ALIGNMENT,
             vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
         )?;
 
         let per_object_megabuffer = device.create_megabuffer(
+            "Per-object megabuffer",
             PER_OBJECT_BUFFER_SIZE,
             STORAGE_BUFFER_ALIGNMENT,
             vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
         )?;
 
-        let bindless_material_factory = Self::create_bindless_material_factory(
-            device.logical.clone(),
-            device.descriptor_allocator.clone(),
+        let joint_megabuffer = device.create_megabuffer(
+            "Joint megabuffer",
+            JOINT_BUFFER_SIZE,
+            STORAGE_BUFFER_ALIGNMENT,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
         )?;
 
+        let bindless_descriptor_set_layout =
+            Self::create_bindless_descriptor_set_layout(&device.logical)?;
+        let bindless_pipeline_layout =
+            Self::create_bindless_pipeline_layout(bindless_descriptor_set_layout, &device.logical)?;
+
+        let gpu_culling = match (gpu_culling, device.multi_draw_indirect_count) {
+            (true, true) => Some(GpuCulling::new(
+                bindless_pipeline_layout,
+                &Self::bindless_push_constant_ranges(),
+                bindless_descriptor_set_layout,
+                &Self::bindless_descriptor_set_layout_builder().describe(),
+                device,
+            )?),
+            (true, false) => {
+                log::warn!(
+                    "GPU culling disabled, drawIndirectCount, multiDrawIndirect or \
+                     drawIndirectFirstInstance is unsupported"
+                );
+                None
+            }
+            (false, _) => None,
+        };
+
+        let equirect_converter = EquirectToCubemapConverter::new(device)?;
+
         let fullscreen_quad = FullscreenQuad::new(
             &vertex_megabuffer,
             &index_megabuffer,
             vpt,
         )?;
+        vertex_megabuffer.upload()?;
+        index_megabuffer.upload()?;
 
         let mut samplers = Vec::new();
         samplers.push(unsafe {
@@ -105,44 +224,451 @@ impl RenderStorage {
             )?
         });
 
-        Ok(Self {
+        let mut storage = Self {
+            target_formats,
+
             storage_textures: Vec::new(),
             sampled_textures: Vec::new(),
             samplers,
+            sampler_indices: HashMap::new(),
+            texture_file_indices: HashMap::new(),
+            white_texture_index: None,
 
             vertex_megabuffer,
+            vertex_buffer_address,
+            vertex_layout,
             index_megabuffer,
             per_frame_megabuffer,
             per_material_megabuffer,
             per_object_megabuffer,
-            bindless_material_factory,
+            joint_megabuffer,
+            bindless_descriptor_set_layout,
+            bindless_pipeline_layout,
+            vertex_pulling,
+            material_factories: HashMap::new(),
+            material_definitions: HashMap::new(),
+            gltf_models: HashMap::new(),
+            obj_models: HashMap::new(),
+            gpu_culling,
+            equirect_converter,
 
             fullscreen_quad,
+            skybox: None,
+        };
+        storage.add_target_formats(target_formats, device)?;
+        Ok(storage)
+    }
+
+    /// Build the bindless, skinned, defined and skybox factories against `formats`, unless they
+    /// already were. Later material definitions are built against them too.
+    pub fn add_target_formats(
+        &mut self,
+        formats: RenderTargetFormats,
+        dev: &RenderDevice,
+    ) -> Result<()> {
+        if self.material_factories.contains_key(&formats) {
+            return Ok(());
+        }
+
+        // Pulled vertices are fetched by the shader, so no vertex buffers are bound
+        let (variant, vertex_layout) = if self.vertex_pulling {
+            (
+                ShaderVariant::new(["VERTEX_PULLING"]),
+                VertexLayout::empty(),
+            )
+        } else {
+            (ShaderVariant::default(), VertexLayout::default())
+        };
+        let bindless =
+            self.create_bindless_material_factory(formats, &variant, vertex_layout, dev)?;
+        let skinned = self.create_bindless_material_factory(
+            formats,
+            &ShaderVariant::new(["SKINNING"]),
+            VertexLayout::skinned(),
+            dev,
+        )?;
+        let defined = self
+            .material_definitions
+            .iter()
+            .map(|(name, definition)| {
+                Ok((
+                    name.clone(),
+                    self.build_defined_factory(definition, formats, dev)?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        let skybox = Skybox::create_material_factory(formats, dev)?;
+
+        self.material_factories.insert(
+            formats,
+            TargetMaterialFactories {
+                bindless,
+                skinned,
+                defined,
+                skybox,
+            },
+        );
+        Ok(())
+    }
+
+    /// Factories to draw into targets of `formats`, see `add_target_formats`
+    pub fn material_factories(
+        &self,
+        formats: RenderTargetFormats,
+    ) -> Result<&TargetMaterialFactories> {
+        self.material_factories.get(&formats).ok_or_else(|| {
+            eyre!(
+                "No material factories for render target formats {:?}",
+                formats
+            )
+        })
+    }
+
+    pub fn material_factories_mut(
+        &mut self,
+        formats: RenderTargetFormats,
+    ) -> Result<&mut TargetMaterialFactories> {
+        self.material_factories.get_mut(&formats).ok_or_else(|| {
+            eyre!(
+                "No material factories for render target formats {:?}",
+                formats
+            )
         })
     }
 
+    /// Build a factory for every set of target formats from a material definition file, and keep
+    /// them and the definition under the file's name. Loading a file with the same name again
+    /// replaces the previous factories.
+    pub fn load_material_definition(&mut self, path: &Path, dev: &RenderDevice) -> Result<String> {
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_eyre(format!("Invalid material file name: {:?}", path))?
+            .to_owned();
+
+        let definition = MaterialDefinition::load(path)?;
+        // Built before any is inserted, so a failure leaves the previous ones in place
+        let factories = self
+            .material_factories
+            .keys()
+            .map(|&formats| {
+                Ok((
+                    formats,
+                    self.build_defined_factory(&definition, formats, dev)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (formats, factory) in factories {
+            if let Some(target_factories) = self.material_factories.get_mut(&formats) {
+                target_factories.defined.insert(name.clone(), factory);
+            }
+        }
+        self.material_definitions.insert(name.clone(), definition);
+
+        Ok(name)
+    }
+
+    fn build_defined_factory(
+        &self,
+        definition: &MaterialDefinition,
+        formats: RenderTargetFormats,
+        dev: &RenderDevice,
+    ) -> Result<MaterialFactory> {
+        definition.build_factory(
+            self.bindless_material_factory_builder(formats, dev),
+            self.vertex_pulling,
+            &self.vertex_layout,
+            dev,
+        )
+    }
+
+    /// Upload the meshes, textures and materials of a glTF file and keep them under the file's name,
+    /// returning its scene graph. Loading a file with the same name again replaces its models,
+    /// while its textures and samplers stay allocated.
+    pub fn load_gltf(&mut self, path: &Path, dev: &RenderDevice) -> Result<ModelScene> {
+        let import = GltfImport::load(path)?;
+        let name = import.scene.name.clone();
+
+        let models = import
+            .meshes
+            .into_iter()
+            .zip(&import.scene.meshes)
+            .map(|(mut primitives, scene_mesh)| {
+                primitives.iter_mut().for_each(Mesh::optimize);
+                let vertex_layout = if scene_mesh.skinned {
+                    VertexLayout::skinned()
+                } else {
+                    self.vertex_layout.clone()
+                };
+                let mut model = Model::new(
+                    primitives,
+                    vertex_layout,
+                    &self.vertex_megabuffer,
+                    &self.index_megabuffer,
+                )?;
+                self.generate_lods(&mut model)?;
+                Ok(model)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let texture_indices = import
+            .images
+            .iter()
+            .map(|image| {
+                let index = self.next_texture_index()?;
+                let texture = dev.create_color_texture(
+                    &image.name,
+                    image.width,
+                    image.height,
+                    Some(&image.rgba8),
+                    false,
+                )?;
+                self.sampled_textures.push(texture);
+                Ok(index)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Materials without a base color texture sample a white one, like the default material
+        let default_material = PerMaterialData {
+            texture_index: self.white_texture(dev)?,
+            sampler_index: 0,
+        };
+
+        let mut materials = Vec::new();
+        for texture in &import.material_textures {
+            let Some(texture) = texture else {
+                materials.push(default_material);
+                continue;
+            };
+            let sampler_index = self.sampler(texture.sampler, dev)?;
+            materials.push(PerMaterialData {
+                texture_index: *texture_indices
+                    .get(texture.image)
+                    .ok_or_eyre(format!("Invalid image index {} in {}", texture.image, name))?,
+                sampler_index,
+            });
+        }
+        self.vertex_megabuffer.upload()?;
+        self.index_megabuffer.upload()?;
+
+        log::info!(
+            "Loaded glTF {} with {} models, {} textures and {} materials",
+            name,
+            models.len(),
+            texture_indices.len(),
+            materials.len()
+        );
+        let models = models
+            .into_iter()
+            .zip(&import.scene.meshes)
+            .map(|(model, mesh)| {
+                let primitive_materials = mesh
+                    .primitive_materials
+                    .iter()
+                    .map(|material| {
+                        material
+                            .and_then(|index| materials.get(index).copied())
+                            .unwrap_or(default_material)
+                    })
+                    .collect();
+                (model, primitive_materials)
+            })
+            .collect();
+        self.gltf_models.insert(name, models);
+        Ok(import.scene)
+    }
+
+    /// Upload the meshes of an OBJ file as one model and keep it under the file's name.
+    /// Image files are uploaded once and shared by every OBJ file that references them.
+    pub fn load_obj(&mut self, path: &Path, dev: &RenderDevice) -> Result<String> {
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_eyre(format!("Invalid OBJ file name: {:?}", path))?
+            .to_owned();
+
+        let import = ObjImport::load(path)?;
+        let mut materials = Vec::new();
+        let mut meshes = Vec::new();
+        let mut mesh_names = Vec::new();
+        for obj_mesh in import.meshes {
+            let texture = obj_mesh
+                .material
+                .and_then(|material| import.materials[material].diffuse_texture.as_deref());
+            let texture_index = match texture {
+                Some(texture) => self.load_texture_file(texture, dev)?,
+                None => self.white_texture(dev)?,
+            };
+            materials.push(PerMaterialData {
+                texture_index,
+                sampler_index: 0,
+            });
+            let mut mesh = obj_mesh.mesh;
+            mesh.optimize();
+            meshes.push(mesh);
+            mesh_names.push(obj_mesh.name);
+        }
+
+        let mut model = Model::new(
+            meshes,
+            self.vertex_layout.clone(),
+            &self.vertex_megabuffer,
+            &self.index_megabuffer,
+        )?;
+        self.generate_lods(&mut model)?;
+        self.vertex_megabuffer.upload()?;
+        self.index_megabuffer.upload()?;
+        log::info!("Loaded OBJ {} with meshes {:?}", name, mesh_names);
+        self.obj_models.insert(name.clone(), (model, materials));
+        Ok(name)
+    }
+
+    fn generate_lods(&self, model: &mut Model) -> Result<()> {
+        let max_error = model.bounding_sphere().radius * MODEL_LOD_MAX_ERROR;
+        model.generate_lods(MODEL_LOD_COUNT, max_error, &self.index_megabuffer)
+    }
+
+    /// A loaded model with the material data of each of its meshes
+    pub fn model(&self, key: &ModelKey) -> Option<&(Model, Vec<PerMaterialData>)> {
+        match key {
+            ModelKey::Obj(name) => self.obj_models.get(name),
+            ModelKey::Gltf { file, mesh } => self.gltf_models.get(file)?.get(*mesh),
+        }
+    }
+
+    /// Upload an image file as an sRGB texture unless it already was, returning its index
+    fn load_texture_file(&mut self, path: &Path, dev: &RenderDevice) -> Result<u32> {
+        if let Some(&index) = self.texture_file_indices.get(path) {
+            return Ok(index);
+        }
+
+        let image = image::open(path)
+            .map_err(|e| eyre!("Failed to open texture {:?}: {}", path, e))?
+            .to_rgba8();
+        let index = self.next_texture_index()?;
+        let texture = dev.create_color_texture(
+            &path.to_string_lossy(),
+            image.width(),
+            image.height(),
+            Some(image.as_raw()),
+            false,
+        )?;
+        self.sampled_textures.push(texture);
+        self.texture_file_indices.insert(path.to_path_buf(), index);
+        Ok(index)
+    }
+
+    /// Index of a sampler matching `desc`, created unless an earlier texture already did
+    fn sampler(&mut self, desc: SamplerDesc, dev: &RenderDevice) -> Result<u32> {
+        if let Some(&index) = self.sampler_indices.get(&desc) {
+            return Ok(index);
+        }
+
+        let count = RenderResourceType::Sampler.descriptor_count();
+        let index = self.samplers.len() as u32;
+        if index >= count {
+            return Err(eyre!("All {} bindless sampler slots are in use", count));
+        }
+        let sampler = unsafe { dev.logical.create_sampler(&desc.create_info(), None)? };
+        self.samplers.push(sampler);
+        self.sampler_indices.insert(desc, index);
+        Ok(index)
+    }
+
+    /// Index the next texture pushed to `sampled_textures` gets, as long as the bindless set
+    /// has a slot left for it
+    fn next_texture_index(&self) -> Result<u32> {
+        let count = RenderResourceType::SampledImage.descriptor_count();
+        let index = self.sampled_textures.len() as u32;
+        if index >= count {
+            return Err(eyre!("All {} bindless texture slots are in use", count));
+        }
+        Ok(index)
+    }
+
+    fn white_texture(&mut self, dev: &RenderDevice) -> Result<u32> {
+        if let Some(index) = self.white_texture_index {
+            return Ok(index);
+        }
+
+        let index = self.next_texture_index()?;
+        let texture = dev.create_color_texture("white", 1, 1, Some(&[255; 4]), false)?;
+        self.sampled_textures.push(texture);
+        self.white_texture_index = Some(index);
+        Ok(index)
+    }
+
+    /// Rebuild every factory whose shader is in `changed_shaders`.
+    /// A factory that fails to rebuild keeps its previous pipeline.
+    #[cfg(feature = "hot-reload")]
+    pub fn reload_shaders(&mut self, changed_shaders: &HashSet<String>) {
+        let factories = std::iter::once(self.equirect_converter.material_factory_mut())
+            .chain(
+                self.gpu_culling
+                    .as_mut()
+                    .map(GpuCulling::material_factory_mut),
+            )
+            .chain(self.material_factories.values_mut().flat_map(|factories| {
+                [
+                    &mut factories.bindless,
+                    &mut factories.skinned,
+                    &mut factories.skybox,
+                ]
+                .into_iter()
+                .chain(factories.defined.values_mut())
+            }));
+
+        for factory in factories.filter(|factory| changed_shaders.contains(factory.shader_name())) {
+            match factory.reload() {
+                Ok(()) => log::info!("Reloaded materials using shader {}", factory.shader_name()),
+                Err(e) => log::error!(
+                    "Failed to reload materials using shader {}: {e:?}",
+                    factory.shader_name()
+                ),
+            }
+        }
+    }
+
     fn create_bindless_material_factory(
-        device: Arc<ash::Device>,
-        descriptor_allocator: Arc<
-            Mutex<DescriptorAllocator<vk::DescriptorPool, vk::DescriptorSet>>,
-        >,
+        &self,
+        target_formats: RenderTargetFormats,
+        variant: &ShaderVariant,
+        vertex_layout: VertexLayout,
+        dev: &RenderDevice,
     ) -> Result<MaterialFactory> {
-        let bindless_descriptor_set_layout = Self::create_bindless_descriptor_set_layout(&device)?;
-        let bindless_pipeline_layout =
-            Self::create_bindless_pipeline_layout(bindless_descriptor_set_layout, &device)?;
-        let default_shader = GraphicsShader::new("default", device.clone())?;
-        GraphicsMaterialFactoryBuilder::new(device, descriptor_allocator)
+        let default_shader = GraphicsShader::new("default", variant, dev.logical.clone())?;
+        self.bindless_material_factory_builder(target_formats, dev)
             .with_shader(default_shader)
-            .with_pipeline_layout(bindless_pipeline_layout)
-            .with_descriptor_set_layout(bindless_descriptor_set_layout)
-            .with_color_attachment_format(vk::Format::R8G8B8A8_SRGB)
-            .with_depth_attachment_format(vk::Format::D32_SFLOAT)
+            .with_vertex_input(vertex_layout)
             .build()
     }
 
+    /// Every graphics material shares the bindless layouts
+    fn bindless_material_factory_builder(
+        &self,
+        target_formats: RenderTargetFormats,
+        dev: &RenderDevice,
+    ) -> GraphicsMaterialFactoryBuilder {
+        dev.create_graphics_material_factory_builder()
+            .with_pipeline_layout(self.bindless_pipeline_layout)
+            .with_descriptor_set_layout(
+                self.bindless_descriptor_set_layout,
+                &Self::bindless_descriptor_set_layout_builder().describe(),
+            )
+            .with_push_constant_ranges(&Self::bindless_push_constant_ranges())
+            .with_render_target_formats(target_formats)
+    }
+
     fn create_bindless_descriptor_set_layout(
         device: &ash::Device,
     ) -> Result<vk::DescriptorSetLayout> {
+        Self::bindless_descriptor_set_layout_builder().build(
+            vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL,
+            device,
+        )
+    }
+
+    fn bindless_descriptor_set_layout_builder() -> DescriptorSetLayoutBuilder<'static> {
         DescriptorSetLayoutBuilder::new()
             .add_binding(
                 // Per-frame
@@ -189,9 +715,41 @@ impl RenderStorage {
                 RenderResourceType::SampledImage.descriptor_binding_flags(),
                 None,
             )
-            .build(
-                vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL,
-                device,
+            .add_binding(
+                // Draw candidates, read by the culling shader
+                5,
+                RenderResourceType::StorageBuffer.descriptor_type(),
+                RenderResourceType::StorageBuffer.descriptor_count(),
+                vk::ShaderStageFlags::ALL,
+                RenderResourceType::StorageBuffer.descriptor_binding_flags(),
+                None,
+            )
+            .add_binding(
+                // Indirect commands, written by the culling shader
+                6,
+                RenderResourceType::StorageBuffer.descriptor_type(),
+                RenderResourceType::StorageBuffer.descriptor_count(),
+                vk::ShaderStageFlags::ALL,
+                RenderResourceType::StorageBuffer.descriptor_binding_flags(),
+                None,
+            )
+            .add_binding(
+                // Indirect draw counts, written by the culling shader
+                7,
+                RenderResourceType::StorageBuffer.descriptor_type(),
+                RenderResourceType::StorageBuffer.descriptor_count(),
+                vk::ShaderStageFlags::ALL,
+                RenderResourceType::StorageBuffer.descriptor_binding_flags(),
+                None,
+            )
+            .add_binding(
+                // Joint palettes
+                8,
+                RenderResourceType::StorageBuffer.descriptor_type(),
+                RenderResourceType::StorageBuffer.descriptor_count(),
+                vk::ShaderStageFlags::ALL,
+                RenderResourceType::StorageBuffer.descriptor_binding_flags(),
+                None,
             )
     }
 
@@ -199,12 +757,7 @@ impl RenderStorage {
         bindless_descriptor_set_layout: vk::DescriptorSetLayout,
         device: &ash::Device,
     ) -> Result<vk::PipelineLayout> {
-        let push_constant_size = size_of::<PerDrawData>() as u32;
-        let push_constant_range = vk::PushConstantRange::default()
-            .stage_flags(vk::ShaderStageFlags::ALL)
-            .offset(0)
-            .size(push_constant_size);
-        let push_constant_ranges = [push_constant_range];
+        let push_constant_ranges = Self::bindless_push_constant_ranges();
 
         let set_layouts = [bindless_descriptor_set_layout];
         let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::default()
@@ -216,4 +769,23 @@ impl RenderStorage {
 
         Ok(pipeline_layout)
     }
+
+    fn bindless_push_constant_ranges() -> [vk::PushConstantRange; 1] {
+        let push_constant_size = size_of::<PerDrawData>() as u32;
+        let push_constant_range = vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::ALL)
+            .offset(0)
+            .size(push_constant_size);
+        [push_constant_range]
+    }
+}
+
+fn lcm(a: u64, b: u64) -> u64 {
+    let gcd = |mut a: u64, mut b: u64| {
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        a
+    };
+    a / gcd(a, b) * b
 }
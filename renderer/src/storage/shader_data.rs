@@ -1,5 +1,5 @@
 use bytemuck::{Pod, Zeroable};
-use glam::{Mat4, Vec2, Vec3};
+use glam::{Mat4, Vec3, Vec4};
 
 /// Data unique to each frame passed into uniform buffer
 #[repr(C)]
@@ -8,12 +8,16 @@ pub(crate) struct PerFrameData {
     pub viewproj: Mat4,
     pub near: f32,
     pub far: f32,
-    _padding: [f32; 2],
+    /// Device address of the vertex megabuffer, for shaders using vertex pulling
+    pub vertex_buffer_address: u64,
+    /// Left, right, bottom, top, near and far planes of the view frustum in world space.
+    /// Points with `dot(plane.xyz, point) + plane.w < 0` are outside of a plane.
+    pub frustum_planes: [Vec4; 6],
 }
 
 /// Data unique to each material passed as elements into a storage buffer
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone, Pod, Zeroable)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Pod, Zeroable)]
 pub(crate) struct PerMaterialData {
     pub texture_index: u32,
     pub sampler_index: u32,
@@ -24,21 +28,39 @@ pub(crate) struct PerMaterialData {
 #[derive(Debug, Default, Copy, Clone, Pod, Zeroable)]
 pub(crate) struct PerObjectData {
     pub model: Mat4,
-}
-
-/// Data unique to each vertex passed as elements into a vertex buffer
-#[repr(C)]
-#[derive(Debug, Default, Copy, Clone, Pod, Zeroable)]
-pub(crate) struct PerVertexData {
-    pub position: Vec3,
-    pub texcoord: Vec2,
+    /// Index of the object's first joint matrix in the joint palette buffer, if skinned
+    pub joint_offset: u32,
+    pub _padding: [u32; 3],
 }
 
 /// Data unique to each draw call passed as a push constant
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, Pod, Zeroable)]
 pub(crate) struct PerDrawData {
-    pub object_index: u32,
+    /// `PerObjectData` of the first instance, the others following it in order
+    pub base_object_index: u32,
     pub material_index: u32,
+    /// Index of the first vertex of the model in the vertex megabuffer (see `Model::first_vertex`)
     pub vertex_offset: u32,
 }
+
+/// One mesh of one object that the culling shader may turn into an indirect draw,
+/// passed as elements into a storage buffer
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Pod, Zeroable)]
+pub(crate) struct DrawCandidateData {
+    /// Bounding sphere of the mesh's model, in the model's space
+    pub center: Vec3,
+    pub radius: f32,
+    /// Becomes the draw's `firstInstance`, which shaders add `gl_InstanceIndex` to
+    pub object_index: u32,
+    pub first_index: u32,
+    pub index_count: u32,
+    /// Index of the mesh's first vertex in the whole vertex megabuffer
+    pub vertex_offset: i32,
+    /// Visible candidates of a group are compacted into the commands from this one
+    pub first_command: u32,
+    /// Index of the group's draw count
+    pub group_index: u32,
+    pub _padding: [u32; 2],
+}
@@ -8,8 +8,8 @@ use crate::context::instance::RenderInstance;
 use crate::context::queue::Queue;
 use crate::viewport::swapchain::{SwapchainImage, SwapchainImageExtent, SwapchainImageIndex};
 use ash::vk;
-use color_eyre::Result;
 use color_eyre::eyre::{OptionExt, eyre};
+use color_eyre::{Report, Result};
 use std::sync::Arc;
 use std::time::Duration;
 use swapchain::RenderSwapchain;
@@ -109,18 +109,14 @@ impl RenderViewport {
         let present_queue = &self.present_queue;
         assert!(present_queue.family.supports_present()); // Ensure the queue supports presentation
 
-        let present_result = unsafe {
-            self.swapchain
-                .swapchain_loader
-                .queue_present(present_queue.handle, &present_info)
-        };
+        let present_result =
+            present_queue.present(&self.swapchain.swapchain_loader, &present_info)?;
         match present_result {
             Ok(true) => Ok(PresentResult::ResizeRequested),
             Ok(false) => Ok(PresentResult::Success),
-            Err(err_code) => Err(eyre!(
-                "Failed to present frame. VkResult error code: {}",
-                err_code
-            )),
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => Ok(PresentResult::ResizeRequested),
+            // Keep the result in the chain so `RendererError` can tell what went wrong
+            Err(err_code) => Err(Report::new(err_code).wrap_err("Failed to present frame")),
         }
     }
 
@@ -130,15 +126,36 @@ impl RenderViewport {
         ins: &RenderInstance,
         dev: &RenderDevice,
     ) -> Result<()> {
-        unsafe {
-            dev.logical.device_wait_idle()?;
-        }
+        dev.wait_idle()?;
 
         self.swapchain = RenderSwapchain::new(&self.surface, &size, ins, dev)?;
 
         Ok(())
     }
 
+    /// Destroy the swapchain and surface, e.g. before the window gets a new surface.
+    /// The handles are nulled, so destroying again does nothing.
+    ///
+    /// # Safety
+    /// The swapchain images must not be in use, and the viewport must not be used afterwards
+    pub unsafe fn destroy(&mut self, dev: &RenderDevice) {
+        let swapchain = &mut self.swapchain;
+        unsafe {
+            for view in swapchain.swapchain_image_views.drain(..) {
+                dev.logical.destroy_image_view(view, None);
+            }
+            swapchain
+                .swapchain_loader
+                .destroy_swapchain(swapchain.swapchain, None);
+            self.surface
+                .surface_loader
+                .destroy_surface(self.surface.surface, None);
+        }
+        swapchain.swapchain_images.clear();
+        swapchain.swapchain = vk::SwapchainKHR::null();
+        self.surface.surface = vk::SurfaceKHR::null();
+    }
+
     pub fn get_size(&self) -> winit::dpi::PhysicalSize<u32> {
         winit::dpi::PhysicalSize::new(
             self.swapchain.swapchain_image_extent.width,
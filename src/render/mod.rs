@@ -25,7 +25,23 @@ fn create_renderer(
     world.insert_non_send_resource(renderer);
 }
 
-fn render_frame(mut renderer: NonSendMut<renderer::Renderer>, camera_qry: Query<&camera::Camera>) {
+fn render_frame(
+    mut renderer: NonSendMut<renderer::Renderer>,
+    camera_qry: Query<&camera::Camera>,
+    window_qry: Query<Entity, With<PrimaryWindow>>,
+    winit_windows: NonSend<WinitWindows>,
+) {
     let camera = camera_qry.single().unwrap();
-    renderer.render_frame(&camera.0).unwrap();
+    match renderer.render_frame(&camera.0) {
+        // The frame is skipped and the swapchain resized
+        Ok(()) | Err(renderer::RendererError::SwapchainOutOfDate) => {}
+        Err(e) if e.is_recoverable() => {
+            warn!("Recreating the renderer: {e}");
+            let winit_window = winit_windows
+                .get_window(window_qry.single().unwrap())
+                .unwrap();
+            renderer.recover(winit_window).unwrap();
+        }
+        Err(e) => panic!("Failed to render frame: {e}"),
+    }
 }